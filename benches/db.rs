@@ -0,0 +1,304 @@
+//! Per meter reads from event_t with many meters and months of readings, in
+//! each of the layouts micd's schema has had.
+//!
+//!     cargo bench --bench db
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rusqlite::types::ToSql;
+use rusqlite::{Connection, Row};
+use std::fs;
+use std::path::PathBuf;
+use time::{OffsetDateTime, UtcOffset};
+
+const METERS: i64 = 20;
+const DAYS: i64 = 90;
+// A reading every two minutes from each meter.
+const DAY_READINGS: i64 = 720;
+const STEP_MS: i64 = 120_000;
+const START_MS: i64 = 1586044800000;
+const DAY_MS: i64 = DAY_READINGS * STEP_MS;
+// Times were once stored as the site's local time in text.
+const TEXT_TFMT: &str = "%F %H:%M:%S%z";
+
+struct Layout {
+    name: &'static str,
+    schema: &'static str,
+    insert: &'static str,
+    text_ts: bool,
+    wal: bool,
+    cached: bool,
+}
+
+const LAYOUTS: &[Layout] = &[
+    // The first schema, before meters had a sequence. Only the time was
+    // indexed, and it was local text.
+    Layout {
+        name: "ts_index",
+        schema: "
+        CREATE TABLE meter_t (mac TEXT PRIMARY KEY, label TEXT);
+        CREATE TABLE event_t (
+            mac TEXT,
+            ts TEXT NOT NULL,
+            temp INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            hum INTEGER NOT NULL,
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        CREATE INDEX event_t_ts_idx ON event_t (ts);
+        ",
+        insert: "INSERT OR REPLACE INTO event_t (mac, ts, temp, ppm, hum) VALUES (:mac, :ts, :temp, :ppm, :hum)",
+        text_ts: true,
+        wal: false,
+        cached: false,
+    },
+    // Schema versions 2 to 6.
+    Layout {
+        name: "rowid_pk",
+        schema: "
+        CREATE TABLE meter_t (mac TEXT PRIMARY KEY, label TEXT);
+        CREATE TABLE event_t (
+            mac TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            seq INTEGER NOT NULL DEFAULT 0,
+            temp INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            hum INTEGER NOT NULL,
+            PRIMARY KEY (mac, ts),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        CREATE INDEX event_t_ts_idx ON event_t (ts);
+        ",
+        insert: INSERT_EVENT,
+        text_ts: false,
+        wal: false,
+        cached: false,
+    },
+    // Schema version 7, in wal mode with cached statements.
+    Layout {
+        name: "without_rowid",
+        schema: "
+        CREATE TABLE meter_t (mac TEXT PRIMARY KEY, label TEXT);
+        CREATE TABLE event_t (
+            mac TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            seq INTEGER NOT NULL DEFAULT 0,
+            temp INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            hum INTEGER NOT NULL,
+            PRIMARY KEY (mac, ts),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        ) WITHOUT ROWID;
+        CREATE INDEX event_t_ts_idx ON event_t (ts);
+        ",
+        insert: INSERT_EVENT,
+        text_ts: false,
+        wal: true,
+        cached: true,
+    },
+];
+
+const INSERT_METER: &str = "INSERT OR REPLACE INTO meter_t (mac) VALUES (:mac)";
+const INSERT_EVENT: &str = "INSERT OR IGNORE INTO event_t (mac, ts, seq, temp, ppm, hum) VALUES (:mac, :ts, :seq, :temp, :ppm, :hum)";
+const SELECT_RANGE: &str = "SELECT ts, temp, ppm, hum FROM event_t WHERE mac = :mac AND ts >= :min AND ts < :max ORDER BY ts ASC";
+const SELECT_FIRST: &str = "SELECT MIN(ts) FROM event_t WHERE mac = :mac AND ts >= :min";
+const SELECT_LAST: &str = "SELECT MAX(ts) FROM event_t WHERE mac = :mac";
+
+fn bench_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("micbench_{}_{}.db", name, std::process::id()))
+}
+
+fn remove_db(path: &PathBuf) {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(path.with_extension("db-wal"));
+    let _ = fs::remove_file(path.with_extension("db-shm"));
+}
+
+fn ts(layout: &Layout, ms: i64) -> Box<dyn ToSql> {
+    if layout.text_ts {
+        Box::new(
+            OffsetDateTime::from_unix_timestamp(ms / 1000)
+                .to_offset(UtcOffset::hours(10))
+                .format(TEXT_TFMT),
+        )
+    } else {
+        Box::new(ms)
+    }
+}
+
+// Text times are parsed as they're read, as micd did.
+fn read_ts(layout: &Layout, row: &Row) -> rusqlite::Result<i64> {
+    if layout.text_ts {
+        row.get::<usize, String>(0).map(|t| {
+            OffsetDateTime::parse(&t, TEXT_TFMT)
+                .expect("invalid ts")
+                .timestamp()
+                * 1000
+        })
+    } else {
+        row.get(0)
+    }
+}
+
+fn mac(m: i64) -> String {
+    format!("20:F8:5E:BE:29:{:02X}", m)
+}
+
+fn open(layout: &Layout, path: &PathBuf) -> Connection {
+    remove_db(path);
+    let conn = Connection::open(path).unwrap();
+    if layout.wal {
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+            .unwrap();
+    }
+    conn.execute_batch(layout.schema).unwrap();
+    conn
+}
+
+// One reading, as micd stores them as frames arrive.
+fn add(layout: &Layout, conn: &Connection, m: &str, i: i64) {
+    let ts = ts(layout, START_MS + i * STEP_MS);
+    let temp = 220 + i % 5;
+    let ppm = 400 + i % 300;
+    let hum = 500 - i % 11;
+    let mut params: Vec<(&str, &dyn ToSql)> = vec![
+        (":mac", &m),
+        (":ts", &*ts),
+        (":temp", &temp),
+        (":ppm", &ppm),
+        (":hum", &hum),
+    ];
+    if !layout.text_ts {
+        params.push((":seq", &i));
+    }
+    if layout.cached {
+        conn.prepare_cached(INSERT_METER)
+            .unwrap()
+            .execute_named(&[(":mac", &m)])
+            .unwrap();
+        conn.prepare_cached(layout.insert)
+            .unwrap()
+            .execute_named(&params)
+            .unwrap();
+    } else {
+        conn.execute_named(INSERT_METER, &[(":mac", &m)]).unwrap();
+        conn.execute_named(layout.insert, &params).unwrap();
+    }
+}
+
+// Meters report together, so their rows are interleaved in time.
+fn populate(layout: &Layout, path: &PathBuf) -> Connection {
+    let conn = open(layout, path);
+    let macs: Vec<String> = (0..METERS).map(mac).collect();
+    conn.execute_batch("BEGIN").unwrap();
+    for i in 0..DAY_READINGS * DAYS {
+        macs.iter().for_each(|m| add(layout, &conn, m, i));
+    }
+    conn.execute_batch("COMMIT").unwrap();
+    conn.execute_batch("ANALYZE").unwrap();
+    conn
+}
+
+fn range(layout: &Layout, conn: &Connection, m: &str, min: i64, max: i64) -> usize {
+    let (min, max) = (ts(layout, min), ts(layout, max));
+    let params: &[(&str, &dyn ToSql)] = &[(":mac", &m), (":min", &*min), (":max", &*max)];
+    let map = |row: &Row| read_ts(layout, row);
+    if layout.cached {
+        conn.prepare_cached(SELECT_RANGE)
+            .unwrap()
+            .query_map_named(params, map)
+            .unwrap()
+            .count()
+    } else {
+        conn.prepare(SELECT_RANGE)
+            .unwrap()
+            .query_map_named(params, map)
+            .unwrap()
+            .count()
+    }
+}
+
+fn bounds(layout: &Layout, conn: &Connection, m: &str) -> (i64, i64) {
+    let get = |sql: &str, params: &[(&str, &dyn ToSql)]| -> i64 {
+        if layout.cached {
+            conn.prepare_cached(sql)
+                .unwrap()
+                .query_row_named(params, |row| read_ts(layout, row))
+                .unwrap()
+        } else {
+            conn.query_row_named(sql, params, |row| read_ts(layout, row))
+                .unwrap()
+        }
+    };
+    let min = ts(layout, 0);
+    (
+        get(SELECT_FIRST, &[(":mac", &m), (":min", &*min)]),
+        get(SELECT_LAST, &[(":mac", &m)]),
+    )
+}
+
+fn bench_reads(c: &mut Criterion) {
+    let conns: Vec<(&Layout, PathBuf, Connection)> = LAYOUTS
+        .iter()
+        .map(|layout| {
+            let path = bench_path(layout.name);
+            let conn = populate(layout, &path);
+            println!(
+                "{}: {} readings in {} bytes",
+                layout.name,
+                METERS * DAYS * DAY_READINGS,
+                fs::metadata(&path).unwrap().len()
+            );
+            (layout, path, conn)
+        })
+        .collect();
+
+    // A day from the middle, as the daily report reads it.
+    let m = mac(METERS / 2);
+    let min = START_MS + DAY_MS * (DAYS / 2);
+    let max = min + DAY_MS;
+
+    let mut group = c.benchmark_group("range_day");
+    for (layout, _, conn) in conns.iter() {
+        group.bench_function(layout.name, |b| {
+            b.iter(|| assert!(range(layout, conn, &m, min, max) == DAY_READINGS as usize))
+        });
+    }
+    group.finish();
+
+    // The first and last readings, as finding the next report date does.
+    let mut group = c.benchmark_group("report_date");
+    for (layout, _, conn) in conns.iter() {
+        group.bench_function(layout.name, |b| {
+            b.iter(|| assert!(bounds(layout, conn, &m).0 == START_MS))
+        });
+    }
+    group.finish();
+
+    for (_, path, conn) in conns {
+        drop(conn);
+        remove_db(&path);
+    }
+}
+
+fn bench_ingest(c: &mut Criterion) {
+    // A round of readings from every meter, each in its own transaction.
+    let macs: Vec<String> = (0..METERS).map(mac).collect();
+
+    let mut group = c.benchmark_group("ingest");
+    group.sample_size(10);
+    for layout in LAYOUTS {
+        let path = bench_path(&format!("ingest_{}", layout.name));
+        group.bench_function(layout.name, |b| {
+            b.iter_batched(
+                || open(layout, &path),
+                |conn| macs.iter().for_each(|m| add(layout, &conn, m, 0)),
+                BatchSize::PerIteration,
+            )
+        });
+        remove_db(&path);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_reads, bench_ingest);
+criterion_main!(benches);
@@ -0,0 +1,208 @@
+//! Compare raw reading storage in sqlite's event_t with the segment store.
+//!
+//!     cargo bench --bench segment
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use mic::segment::{Reading, SegmentStore};
+use rusqlite::Connection;
+use std::fs;
+use std::path::PathBuf;
+use time::OffsetDateTime;
+
+const SRC: &str = "20:F8:5E:BE:29:D8";
+// A day of readings from one meter, every 30 seconds.
+const DAY_READINGS: i64 = 2880;
+const DAYS: i64 = 7;
+
+// The event_t schema and statements from micd's db.
+const SQLITE_SCHEMA: &str = "
+CREATE TABLE meter_t (
+    mac TEXT PRIMARY KEY,
+    label TEXT
+);
+CREATE TABLE event_t (
+    mac TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    seq INTEGER NOT NULL DEFAULT 0,
+    temp INTEGER NOT NULL,
+    ppm INTEGER NOT NULL,
+    hum INTEGER NOT NULL,
+    change_seq INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (mac, ts),
+    FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+) WITHOUT ROWID;
+CREATE INDEX event_t_ts_idx ON event_t (ts);
+CREATE INDEX event_t_change_seq_idx ON event_t (change_seq);
+CREATE TABLE change_seq_t (seq INTEGER NOT NULL);
+INSERT INTO change_seq_t (seq) VALUES (0);
+CREATE TRIGGER event_t_change_seq_trg AFTER INSERT ON event_t BEGIN
+    UPDATE change_seq_t SET seq = MAX(seq, NEW.change_seq);
+END;
+";
+
+fn bench_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("micbench_{}_{}", name, std::process::id()))
+}
+
+// A room drifting about, with the jitter of a real meter. The seed is fixed
+// so that runs compare.
+fn readings(days: i64) -> Vec<Reading> {
+    let start = OffsetDateTime::from_unix_timestamp(1586044800);
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut step = move |n: i64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % (2 * n + 1) as u64) as i64 - n
+    };
+    let (mut temp, mut ppm, mut hum) = (220, 600, 500);
+    (0..DAY_READINGS * days)
+        .map(|i| {
+            temp = (temp + step(1)).clamp(150, 300);
+            ppm = (ppm + step(12)).clamp(400, 3000);
+            hum = (hum + step(2)).clamp(200, 800);
+            Reading {
+                time: start + time::Duration::milliseconds(i * 30_000 + 250 + step(250)),
+                seq: i,
+                temp: temp as u16,
+                ppm: ppm as u16,
+                hum: hum as u16,
+            }
+        })
+        .collect()
+}
+
+fn ms(t: &OffsetDateTime) -> i64 {
+    t.timestamp() * 1000 + t.millisecond() as i64
+}
+
+fn sqlite_open(path: &PathBuf) -> Connection {
+    let _ = fs::remove_file(path);
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch(SQLITE_SCHEMA).unwrap();
+    conn
+}
+
+// One statement per reading, as micd does as frames arrive.
+fn sqlite_add(conn: &Connection, r: &Reading) {
+    conn.execute_named(
+        "INSERT OR REPLACE INTO meter_t (mac) VALUES (:mac)",
+        &[(":mac", &SRC)],
+    )
+    .unwrap();
+    conn.execute_named(
+        "INSERT OR IGNORE INTO event_t (mac, ts, seq, temp, ppm, hum, change_seq) VALUES (:mac, :ts, :seq, :temp, :ppm, :hum, (SELECT seq + 1 FROM change_seq_t))",
+        &[
+            (":mac", &SRC),
+            (":ts", &ms(&r.time)),
+            (":seq", &r.seq),
+            (":temp", &r.temp),
+            (":ppm", &r.ppm),
+            (":hum", &r.hum),
+        ],
+    )
+    .unwrap();
+}
+
+fn sqlite_range(conn: &Connection, min: i64, max: i64) -> usize {
+    let mut stmt = conn
+        .prepare_cached(
+            "SELECT ts, temp, ppm, hum FROM event_t WHERE mac = :mac AND ts >= :min AND ts < :max ORDER BY ts ASC",
+        )
+        .unwrap();
+    stmt.query_map_named(&[(":mac", &SRC), (":min", &min), (":max", &max)], |row| {
+        Ok((
+            row.get::<usize, i64>(0)?,
+            row.get::<usize, u16>(1)?,
+            row.get::<usize, u16>(2)?,
+            row.get::<usize, u16>(3)?,
+        ))
+    })
+    .unwrap()
+    .count()
+}
+
+fn bench_ingest(c: &mut Criterion) {
+    // An hour of readings each time, into an empty store.
+    let data = readings(1);
+    let data = &data[..120];
+
+    let mut group = c.benchmark_group("ingest_120");
+    group.sample_size(10);
+
+    let path = bench_path("ingest.db");
+    group.bench_function("sqlite", |b| {
+        b.iter_batched(
+            || sqlite_open(&path),
+            |conn| data.iter().for_each(|r| sqlite_add(&conn, r)),
+            BatchSize::PerIteration,
+        )
+    });
+    let _ = fs::remove_file(&path);
+
+    let dir = bench_path("ingest_seg");
+    group.bench_function("segment", |b| {
+        b.iter_batched(
+            || {
+                let _ = fs::remove_dir_all(&dir);
+                SegmentStore::open(&dir).unwrap()
+            },
+            |mut store| {
+                data.iter()
+                    .for_each(|r| store.append(SRC, r.clone()).unwrap());
+                store.flush().unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    let _ = fs::remove_dir_all(&dir);
+
+    group.finish();
+}
+
+fn bench_range(c: &mut Criterion) {
+    let data = readings(DAYS);
+
+    let path = bench_path("range.db");
+    let conn = sqlite_open(&path);
+    conn.execute_batch("BEGIN").unwrap();
+    data.iter().for_each(|r| sqlite_add(&conn, r));
+    conn.execute_batch("COMMIT").unwrap();
+
+    let dir = bench_path("range_seg");
+    let _ = fs::remove_dir_all(&dir);
+    let mut store = SegmentStore::open(&dir).unwrap();
+    data.iter()
+        .for_each(|r| store.append(SRC, r.clone()).unwrap());
+    store.flush().unwrap();
+
+    println!(
+        "{} readings: sqlite {} bytes, segments {} bytes",
+        data.len(),
+        fs::metadata(&path).unwrap().len(),
+        store.size_on_disk()
+    );
+
+    // The middle day, as the daily report reads it.
+    let min = data[(DAY_READINGS * 3) as usize].time;
+    let max = data[(DAY_READINGS * 4) as usize].time;
+
+    let mut group = c.benchmark_group("range_day");
+    group.bench_function("sqlite", |b| {
+        b.iter(|| assert!(sqlite_range(&conn, ms(&min), ms(&max)) == DAY_READINGS as usize))
+    });
+    group.bench_function("segment", |b| {
+        b.iter(|| {
+            assert!(store.get_event_range(SRC, &min, &max).unwrap().len() == DAY_READINGS as usize)
+        })
+    });
+    group.finish();
+
+    drop(store);
+    drop(conn);
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, bench_ingest, bench_range);
+criterion_main!(benches);
@@ -0,0 +1,321 @@
+use actix::prelude::*;
+use rusqlite::backup::Progress;
+use rusqlite::{Connection, DatabaseName, OpenFlags, NO_PARAMS};
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use crate::db;
+use crate::migrations;
+use crate::stop::{stop_when_idle, StopEvent};
+
+const BACKUP_PREFIX: &str = "micd-";
+const BACKUP_SUFFIX: &str = ".db";
+
+// Names sort in the order they were taken.
+pub fn backup_name(now: OffsetDateTime) -> String {
+    format!(
+        "{}{}{}",
+        BACKUP_PREFIX,
+        now.format("%Y%m%dT%H%M%SZ"),
+        BACKUP_SUFFIX
+    )
+}
+
+/// Remove all but the newest keep backups in dir. Returns how many were removed.
+fn rotate(dir: &Path, keep: usize) -> Result<usize, ()> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| {
+            error!("Unable to read backup dir {:?} -> {:?}", dir, e);
+            ()
+        })?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with(BACKUP_PREFIX) && n.ends_with(BACKUP_SUFFIX))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    if backups.len() <= keep {
+        return Ok(0);
+    }
+
+    backups.sort();
+    let remove = backups.len() - keep;
+    backups.iter().take(remove).try_for_each(|path| {
+        info!("Removing old backup {:?}", path);
+        fs::remove_file(path).map_err(|e| {
+            error!("Unable to remove old backup {:?} -> {:?}", path, e);
+            ()
+        })
+    })?;
+    Ok(remove)
+}
+
+/// Takes a copy of the live database on a schedule, keeping the newest few.
+pub struct BackupActor {
+    db_addr: Addr<db::DbActor>,
+    dir: PathBuf,
+    interval: Duration,
+    keep: usize,
+    busy: bool,
+    stopping: bool,
+}
+
+impl BackupActor {
+    pub fn new(db_addr: Addr<db::DbActor>, dir: PathBuf, interval: Duration, keep: usize) -> Self {
+        BackupActor {
+            db_addr,
+            dir,
+            interval,
+            keep,
+            busy: false,
+            stopping: false,
+        }
+    }
+
+    fn backup(&mut self, ctx: &mut Context<Self>) {
+        if self.busy {
+            info!("Previous backup is still running, skipping");
+            return;
+        }
+        self.busy = true;
+        let path = self.dir.join(backup_name(OffsetDateTime::now()));
+        info!("Backing up db to {:?} ...", path);
+
+        let fut = self.db_addr.send(db::DbBackup { path });
+        ctx.spawn(fut.into_actor(self).map(|r, act, ctx| {
+            match r {
+                Ok(Ok(_)) => {
+                    // Only rotate once we know the new backup is good.
+                    let _ = rotate(&act.dir, act.keep);
+                }
+                _ => error!("Backup failed, keeping existing backups"),
+            };
+            act.busy = false;
+            if act.stopping {
+                ctx.stop();
+            }
+        }));
+    }
+}
+
+impl Actor for BackupActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("Started backups to {:?} ...", self.dir);
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            error!("Unable to create backup dir {:?} -> {:?}", self.dir, e);
+        }
+        ctx.run_interval(self.interval, move |act, ctx| {
+            act.backup(ctx);
+        });
+    }
+}
+
+impl Handler<StopEvent> for BackupActor {
+    type Result = bool;
+
+    fn handle(&mut self, _msg: StopEvent, ctx: &mut Context<Self>) -> bool {
+        stop_when_idle("backups", &mut self.stopping, self.busy, ctx)
+    }
+}
+
+/// Held for as long as micd has the database at db_path open, so that it
+/// can't be restored underneath us. The lock goes when this is dropped, or
+/// when the process exits however it does.
+pub struct DbLock {
+    _file: fs::File,
+}
+
+impl DbLock {
+    pub fn acquire(db_path: &str) -> Result<Self, ()> {
+        let path = format!("{}.lock", db_path);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| {
+                error!("Unable to open lock file {} -> {:?}", path, e);
+                ()
+            })?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                error!("{} is in use by a running micd, stop it first", db_path);
+            } else {
+                error!("Unable to lock {} -> {:?}", path, e);
+            }
+            return Err(());
+        }
+        Ok(DbLock { _file: file })
+    }
+}
+
+/// Replace the database at db_path with the backup at src. The backup must
+/// be intact and from a schema this build can run, and the current database
+/// is saved alongside before it's replaced. Fails if micd is running.
+pub fn restore(db_path: &str, src: &str) -> Result<(), ()> {
+    let _lock = DbLock::acquire(db_path)?;
+    {
+        let src_conn =
+            Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|e| {
+                error!("Unable to open backup {} -> {:?}", src, e);
+                ()
+            })?;
+
+        let check: String = src_conn
+            .query_row("PRAGMA integrity_check", NO_PARAMS, |row| row.get(0))
+            .map_err(|e| {
+                error!("Unable to check backup {} -> {:?}", src, e);
+                ()
+            })?;
+        if check != "ok" {
+            error!("Backup {} failed its integrity check -> {}", src, check);
+            return Err(());
+        }
+
+        let version = migrations::get_version(&src_conn)?;
+        if version > migrations::SCHEMA_VERSION {
+            error!(
+                "Backup {} has schema version {}, which is newer than this micd supports ({})",
+                src,
+                version,
+                migrations::SCHEMA_VERSION
+            );
+            return Err(());
+        }
+        // Anything older is brought up to date when micd next starts.
+        info!("Backup {} has schema version {}", src, version);
+    }
+
+    let mut conn = Connection::open(db_path).map_err(|e| {
+        error!("Unable to open {} -> {:?}", db_path, e);
+        ()
+    })?;
+
+    let saved_path = format!("{}.pre-restore.bak", db_path);
+    info!("Saving {} to {} ...", db_path, saved_path);
+    conn.backup(DatabaseName::Main, &saved_path, None)
+        .map_err(|e| {
+            error!("sqlite backup error -> {:?}", e);
+            ()
+        })?;
+
+    // Going through sqlite, rather than replacing the file, means any wal
+    // belonging to the old database can't be applied to the restored one.
+    conn.restore(DatabaseName::Main, src, None::<fn(Progress)>)
+        .map_err(|e| {
+            error!("sqlite restore error -> {:?}", e);
+            ()
+        })?;
+
+    // Its change sequence goes back to the backup's, so sync must treat it
+    // as a different database.
+    migrations::renew_db_id(&conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::{backup_name, restore, rotate, DbLock};
+    use crate::migrations::{get_version, migrate, SCHEMA_VERSION};
+    use rusqlite::{Connection, NO_PARAMS};
+    use std::fs;
+    use std::path::PathBuf;
+    use time::OffsetDateTime;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("micd_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn meters(path: &str) -> i64 {
+        Connection::open(path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM meter_t", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_backup_rotate() {
+        let dir = test_dir("rotate");
+        let start = OffsetDateTime::from_unix_timestamp(1586055739);
+        (0..5).for_each(|d| {
+            let name = backup_name(start + std::time::Duration::from_secs(86400 * d));
+            fs::write(dir.join(name), b"").unwrap();
+        });
+        fs::write(dir.join("unrelated.db"), b"").unwrap();
+
+        assert!(rotate(&dir, 3) == Ok(2));
+        assert!(rotate(&dir, 3) == Ok(0));
+        // The oldest went, and other files are left alone.
+        assert!(!dir.join("micd-20200405T030219Z.db").exists());
+        assert!(dir.join("micd-20200409T030219Z.db").exists());
+        assert!(dir.join("unrelated.db").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backup_restore() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let dir = test_dir("restore");
+        let live = dir.join("live.db").to_str().unwrap().to_string();
+        let backup = dir.join("backup.db").to_str().unwrap().to_string();
+        let future = dir.join("future.db").to_str().unwrap().to_string();
+
+        let mut conn = Connection::open(&backup).unwrap();
+        migrate(&mut conn, "").unwrap();
+        conn.execute(
+            "INSERT INTO meter_t (mac) VALUES ('00:00:00:00:00:00')",
+            NO_PARAMS,
+        )
+        .unwrap();
+        drop(conn);
+
+        let mut conn = Connection::open(&live).unwrap();
+        migrate(&mut conn, "").unwrap();
+        drop(conn);
+
+        // Not while micd has it open.
+        let lock = DbLock::acquire(&live).unwrap();
+        assert!(restore(&live, &backup).is_err());
+        assert!(meters(&live) == 0);
+        drop(lock);
+
+        restore(&live, &backup).unwrap();
+        assert!(meters(&live) == 1);
+        assert!(meters(&format!("{}.pre-restore.bak", live)) == 0);
+
+        // A backup from a newer micd is refused, and the live db is untouched.
+        let mut conn = Connection::open(&future).unwrap();
+        migrate(&mut conn, "").unwrap();
+        conn.execute(
+            "INSERT INTO schema_version_t (version, applied) VALUES (9999, 'future')",
+            NO_PARAMS,
+        )
+        .unwrap();
+        drop(conn);
+
+        assert!(restore(&live, &future).is_err());
+        assert!(meters(&live) == 1);
+        assert!(get_version(&Connection::open(&live).unwrap()) == Ok(SCHEMA_VERSION));
+
+        // As is something that isn't a database.
+        fs::write(dir.join("junk.db"), b"not a database").unwrap();
+        assert!(restore(&live, dir.join("junk.db").to_str().unwrap()).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,426 @@
+//! Charts are drawn onto a canvas as shapes, which are then written out as
+//! svg, png or pdf. Sizes are in pixels, or points for pdf, from the top
+//! left.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+const FONT: &str = "Helvetica, Arial, sans-serif";
+
+/// What a chart is written out as. The names are as they appear at the
+/// end of chart urls, such as `ppm.png`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Format {
+    Svg,
+    Png,
+    Pdf,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "svg" => Ok(Format::Svg),
+            "png" => Ok(Format::Png),
+            "pdf" => Ok(Format::Pdf),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Format {
+    #[cfg_attr(not(feature = "gnuplot"), allow(dead_code))]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Svg => "svg",
+            Format::Png => "png",
+            Format::Pdf => "pdf",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Svg => "image/svg+xml",
+            Format::Png => "image/png",
+            Format::Pdf => "application/pdf",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+#[derive(Debug, Clone)]
+pub struct Text {
+    pub x: f32,
+    /// The baseline, or the middle of the text when centred.
+    pub y: f32,
+    pub text: String,
+    pub size: f32,
+    pub anchor: Anchor,
+    pub colour: String,
+    /// Degrees clockwise about x and y.
+    pub rotate: f32,
+    pub centred: bool,
+}
+
+impl Default for Text {
+    fn default() -> Self {
+        Text {
+            x: 0.0,
+            y: 0.0,
+            text: String::new(),
+            size: 12.0,
+            anchor: Anchor::Start,
+            colour: "#000000".to_string(),
+            rotate: 0.0,
+            centred: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Shape {
+    /// Filled, outlined, or both.
+    Rect {
+        x: f32,
+        y: f32,
+        w: f32,
+        h: f32,
+        fill: Option<String>,
+        opacity: f32,
+        stroke: Option<String>,
+    },
+    Line {
+        points: Vec<(f32, f32)>,
+        colour: String,
+        width: f32,
+    },
+    /// A filled outline, closed back to the first point.
+    Area {
+        points: Vec<(f32, f32)>,
+        colour: String,
+        opacity: f32,
+    },
+    Dot {
+        x: f32,
+        y: f32,
+        r: f32,
+        colour: String,
+    },
+    Text(Text),
+}
+
+/// Shapes in the order they're drawn, on a white background.
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    pub width: f32,
+    pub height: f32,
+    pub shapes: Vec<Shape>,
+}
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The red, green and blue of a colour written as `#rrggbb`. Anything else
+/// is black.
+pub fn rgb(colour: &str) -> (u8, u8, u8) {
+    let hex = |i: usize| {
+        colour
+            .get(i..i + 2)
+            .and_then(|h| u8::from_str_radix(h, 16).ok())
+            .unwrap_or(0)
+    };
+    if colour.len() == 7 && colour.starts_with('#') {
+        (hex(1), hex(3), hex(5))
+    } else {
+        (0, 0, 0)
+    }
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Canvas {
+            width: width as f32,
+            height: height as f32,
+            shapes: Vec::new(),
+        }
+    }
+
+    pub fn fill(&mut self, x: f32, y: f32, w: f32, h: f32, colour: &str, opacity: f32) {
+        self.shapes.push(Shape::Rect {
+            x,
+            y,
+            w,
+            h,
+            fill: Some(colour.to_string()),
+            opacity,
+            stroke: None,
+        });
+    }
+
+    pub fn outline(&mut self, x: f32, y: f32, w: f32, h: f32, colour: &str) {
+        self.shapes.push(Shape::Rect {
+            x,
+            y,
+            w,
+            h,
+            fill: None,
+            opacity: 1.0,
+            stroke: Some(colour.to_string()),
+        });
+    }
+
+    pub fn line(&mut self, points: Vec<(f32, f32)>, colour: &str, width: f32) {
+        self.shapes.push(Shape::Line {
+            points,
+            colour: colour.to_string(),
+            width,
+        });
+    }
+
+    pub fn area(&mut self, points: Vec<(f32, f32)>, colour: &str, opacity: f32) {
+        self.shapes.push(Shape::Area {
+            points,
+            colour: colour.to_string(),
+            opacity,
+        });
+    }
+
+    pub fn dot(&mut self, x: f32, y: f32, r: f32, colour: &str) {
+        self.shapes.push(Shape::Dot {
+            x,
+            y,
+            r,
+            colour: colour.to_string(),
+        });
+    }
+
+    pub fn text(&mut self, text: Text) {
+        self.shapes.push(Shape::Text(text));
+    }
+
+    /// Draw other's shapes onto this canvas, with its top left at x and y.
+    pub fn place(&mut self, other: Canvas, x: f32, y: f32) {
+        let moved = |points: Vec<(f32, f32)>| {
+            points
+                .into_iter()
+                .map(|(px, py)| (px + x, py + y))
+                .collect()
+        };
+        self.shapes
+            .extend(other.shapes.into_iter().map(|shape| match shape {
+                Shape::Rect {
+                    x: rx,
+                    y: ry,
+                    w,
+                    h,
+                    fill,
+                    opacity,
+                    stroke,
+                } => Shape::Rect {
+                    x: rx + x,
+                    y: ry + y,
+                    w,
+                    h,
+                    fill,
+                    opacity,
+                    stroke,
+                },
+                Shape::Line {
+                    points,
+                    colour,
+                    width,
+                } => Shape::Line {
+                    points: moved(points),
+                    colour,
+                    width,
+                },
+                Shape::Area {
+                    points,
+                    colour,
+                    opacity,
+                } => Shape::Area {
+                    points: moved(points),
+                    colour,
+                    opacity,
+                },
+                Shape::Dot {
+                    x: dx,
+                    y: dy,
+                    r,
+                    colour,
+                } => Shape::Dot {
+                    x: dx + x,
+                    y: dy + y,
+                    r,
+                    colour,
+                },
+                Shape::Text(t) => Shape::Text(Text {
+                    x: t.x + x,
+                    y: t.y + y,
+                    ..t
+                }),
+            }));
+    }
+
+    pub fn encode(&self, format: Format) -> Result<Vec<u8>, ()> {
+        match format {
+            Format::Svg => Ok(self.to_svg().into_bytes()),
+            Format::Png => crate::png::encode(self),
+            Format::Pdf => crate::pdf::write(std::slice::from_ref(self)),
+        }
+    }
+
+    pub fn to_svg(&self) -> String {
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="{font}">"#,
+            w = self.width,
+            h = self.height,
+            font = FONT
+        );
+        let _ = writeln!(
+            svg,
+            r#"<rect width="{}" height="{}" fill="white"/>"#,
+            self.width, self.height
+        );
+
+        let points = |points: &[(f32, f32)]| {
+            points
+                .iter()
+                .map(|(x, y)| format!("{:.1} {:.1}", x, y))
+                .collect::<Vec<_>>()
+                .join(" L")
+        };
+        self.shapes.iter().for_each(|shape| {
+            let _ = match shape {
+                Shape::Rect {
+                    x,
+                    y,
+                    w,
+                    h,
+                    fill,
+                    opacity,
+                    stroke,
+                } => writeln!(
+                    svg,
+                    r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}"{}{}/>"#,
+                    x,
+                    y,
+                    w,
+                    h,
+                    fill.as_deref().unwrap_or("none"),
+                    if *opacity < 1.0 {
+                        format!(r#" fill-opacity="{}""#, opacity)
+                    } else {
+                        String::new()
+                    },
+                    stroke
+                        .as_ref()
+                        .map(|s| format!(r#" stroke="{}""#, s))
+                        .unwrap_or_default()
+                ),
+                Shape::Line {
+                    points: p,
+                    colour,
+                    width,
+                } => writeln!(
+                    svg,
+                    r#"<path d="M{}" fill="none" stroke="{}" stroke-width="{}"/>"#,
+                    points(p),
+                    colour,
+                    width
+                ),
+                Shape::Area {
+                    points: p,
+                    colour,
+                    opacity,
+                } => writeln!(
+                    svg,
+                    r#"<path d="M{} Z" fill="{}" fill-opacity="{}" stroke="none"/>"#,
+                    points(p),
+                    colour,
+                    opacity
+                ),
+                Shape::Dot { x, y, r, colour } => writeln!(
+                    svg,
+                    r#"<circle cx="{:.1}" cy="{:.1}" r="{:.1}" fill="{}"/>"#,
+                    x, y, r, colour
+                ),
+                Shape::Text(t) => writeln!(
+                    svg,
+                    r#"<text x="{x:.1}" y="{y:.1}"{dy} text-anchor="{anchor}" font-size="{size}" fill="{colour}"{rotate}>{text}</text>"#,
+                    x = t.x,
+                    y = t.y,
+                    dy = if t.centred { r#" dy="0.35em""# } else { "" },
+                    anchor = match t.anchor {
+                        Anchor::Start => "start",
+                        Anchor::Middle => "middle",
+                        Anchor::End => "end",
+                    },
+                    size = t.size,
+                    colour = t.colour,
+                    rotate = if t.rotate != 0.0 {
+                        format!(
+                            r#" transform="rotate({} {:.1} {:.1})""#,
+                            t.rotate, t.x, t.y
+                        )
+                    } else {
+                        String::new()
+                    },
+                    text = escape(&t.text)
+                ),
+            };
+        });
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::canvas::{rgb, Anchor, Canvas, Format, Text};
+
+    #[test]
+    fn test_canvas_svg() {
+        assert!("png".parse() == Ok(Format::Png));
+        assert!("gif".parse::<Format>().is_err());
+        assert!(rgb("#d73027") == (0xd7, 0x30, 0x27));
+        assert!(rgb("white") == (0, 0, 0));
+
+        let mut canvas = Canvas::new(200, 100);
+        canvas.fill(0.0, 0.0, 10.0, 10.0, "#ff0000", 0.5);
+        canvas.line(vec![(0.0, 0.0), (10.0, 10.0)], "#000000", 1.2);
+        canvas.area(vec![(0.0, 0.0), (10.0, 10.0), (0.0, 10.0)], "#0000ff", 0.25);
+        canvas.text(Text {
+            x: 10.0,
+            y: 20.0,
+            text: "a < b".to_string(),
+            anchor: Anchor::End,
+            rotate: 80.0,
+            ..Default::default()
+        });
+        let mut page = Canvas::new(400, 200);
+        page.place(canvas.clone(), 5.0, 50.0);
+        assert!(page.to_svg().contains(r#"<path d="M5.0 50.0 L15.0 60.0""#));
+
+        let svg = canvas.to_svg();
+        assert!(svg.contains(r##"fill="#ff0000" fill-opacity="0.5"/>"##));
+        assert!(svg.contains(r#"<path d="M0.0 0.0 L10.0 10.0" fill="none""#));
+        assert!(svg.contains(" L0.0 10.0 Z\""));
+        assert!(svg.contains(r#"text-anchor="end""#));
+        assert!(svg.contains(r#"transform="rotate(80 10.0 20.0)">a &lt; b</text>"#));
+    }
+}
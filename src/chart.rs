@@ -0,0 +1,646 @@
+//! Charts are described here, and drawn onto a canvas without any external
+//! tools.
+//! With the `gnuplot` feature they are handed to gnuplot instead.
+
+use crate::canvas::{Anchor, Canvas, Format, Shape, Text};
+
+pub const BLACK: &str = "#000000";
+pub const BLUE: &str = "#0000ff";
+pub const RED: &str = "#ff0000";
+pub const DARK_GREEN: &str = "#006400";
+pub const ORANGE: &str = "#ffa500";
+pub const PURPLE: &str = "#a020f0";
+pub const BROWN: &str = "#8b4513";
+pub const TEAL: &str = "#008080";
+pub const PINK: &str = "#ff1493";
+pub const GREY: &str = "#808080";
+
+pub const COLOURS: &[&str] = &[
+    BLACK, BLUE, RED, DARK_GREEN, ORANGE, PURPLE, BROWN, TEAL, PINK, GREY,
+];
+
+// Shades for bands, from best to worst.
+const BAND_COLOURS: &[&str] = &["#d8f5d8", "#fff4c2", "#ffdcb5", "#f9c9c9"];
+/// Annotations for when something went wrong, and for weekends.
+pub const MISSING: &str = "#000000";
+pub const WEEKEND: &str = "#3060d0";
+const ANNOTATION_OPACITY: f32 = 0.08;
+const ENVELOPE_OPACITY: f32 = 0.25;
+
+// Heatmaps run from green for low through yellow to red for high.
+const HEAT_COLOURS: &[(u8, u8, u8)] = &[(0x1a, 0x98, 0x50), (0xfe, 0xe0, 0x8b), (0xd7, 0x30, 0x27)];
+const BLANK: &str = "#f0f0f0";
+
+pub const LINE_WIDTH: f32 = 1.2;
+pub const BOLD_WIDTH: f32 = 2.5;
+
+// Room around the plot for the axis labels and ticks.
+const MARGIN_LEFT: f32 = 90.0;
+const MARGIN_RIGHT: f32 = 40.0;
+const MARGIN_TOP: f32 = 30.0;
+const MARGIN_BOTTOM: f32 = 180.0;
+
+// Room around a heatmap for the row and column labels, and the scale.
+const HEAT_MARGIN_LEFT: f32 = 110.0;
+const HEAT_MARGIN_BOTTOM: f32 = 90.0;
+
+// Shades in the scale under a heatmap.
+const SCALE_STEPS: usize = 20;
+
+const Y_TICKS: usize = 5;
+
+/// A labelled point on the time axis, in seconds since the epoch.
+#[derive(Debug, Clone)]
+pub struct Tick {
+    pub at: i64,
+    pub label: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Series {
+    pub caption: String,
+    pub colour: &'static str,
+    pub width: f32,
+    /// Times in seconds since the epoch, oldest first.
+    pub x: Vec<i64>,
+    /// A value for each time. NaN leaves a gap in the line.
+    pub y: Vec<f32>,
+}
+
+/// The area between a low and high value at each time, such as the range
+/// of a day's readings. NaN in either leaves a gap.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub caption: String,
+    pub colour: &'static str,
+    pub x: Vec<i64>,
+    pub lo: Vec<f32>,
+    pub hi: Vec<f32>,
+}
+
+/// A shaded range of values, such as how comfortable a ppm is. Without
+/// an end it runs to the top of the chart.
+#[derive(Debug, Clone)]
+pub struct Band {
+    pub from: f32,
+    pub to: Option<f32>,
+    pub label: String,
+    pub colour: &'static str,
+}
+
+/// The shade for the nth of count bands, running from good to poor.
+pub fn band_colour(n: usize, count: usize) -> &'static str {
+    let last = BAND_COLOURS.len() - 1;
+    match count {
+        0 | 1 => BAND_COLOURS[0],
+        _ => BAND_COLOURS[(n * last / (count - 1)).min(last)],
+    }
+}
+
+/// A stretch of time to point out, such as when a meter was missing. The
+/// label may be empty.
+#[derive(Debug, Clone)]
+pub struct Annotation {
+    pub from: i64,
+    pub to: i64,
+    pub label: String,
+    pub colour: &'static str,
+}
+
+/// Lines that share a time axis, which covers all of them and any
+/// annotations. The value axis always starts from zero. Envelopes are
+/// drawn beneath the lines.
+#[derive(Debug, Clone, Default)]
+pub struct Chart {
+    pub y_label: String,
+    pub ticks: Vec<Tick>,
+    pub series: Vec<Series>,
+    pub envelopes: Vec<Envelope>,
+    pub bands: Vec<Band>,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Values in a grid of cells, shaded from low to high, such as ppm by the
+/// hour of each day. Cells without a value are left blank.
+#[derive(Debug, Clone, Default)]
+pub struct Heatmap {
+    pub title: String,
+    pub rows: Vec<String>,
+    pub columns: Vec<String>,
+    /// A row of cells for each row, with a cell for each column.
+    pub cells: Vec<Vec<Option<f32>>>,
+    /// Values past these are shaded as the ends of the scale.
+    pub low: f32,
+    pub high: f32,
+}
+
+// The shade for f of the way along the heatmap scale.
+fn heat_colour(f: f32) -> String {
+    let f = if f.is_finite() {
+        f.clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let pos = f * (HEAT_COLOURS.len() - 1) as f32;
+    let i = (pos.floor() as usize).min(HEAT_COLOURS.len() - 2);
+    let t = pos - i as f32;
+    let (a, b) = (HEAT_COLOURS[i], HEAT_COLOURS[i + 1]);
+    let mix = |x: u8, y: u8| (x as f32 + (y as f32 - x as f32) * t).round() as u8;
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        mix(a.0, b.0),
+        mix(a.1, b.1),
+        mix(a.2, b.2)
+    )
+}
+
+// Split points into unbroken runs, where None is a gap.
+fn runs<T>(points: impl Iterator<Item = Option<T>>) -> Vec<Vec<T>> {
+    let mut runs: Vec<Vec<T>> = vec![Vec::new()];
+    points.for_each(|p| match p {
+        Some(p) => runs.last_mut().unwrap().push(p),
+        None => {
+            if !runs.last().unwrap().is_empty() {
+                runs.push(Vec::new());
+            }
+        }
+    });
+    runs.retain(|r| !r.is_empty());
+    runs
+}
+
+// A round step that gives about Y_TICKS ticks up to max.
+fn y_step(max: f32) -> f32 {
+    if max <= 0.0 {
+        return 1.0;
+    }
+    let raw = max / Y_TICKS as f32;
+    let mag = 10f32.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|m| m * mag)
+        .find(|s| *s >= raw)
+        .unwrap_or(10.0 * mag)
+}
+
+impl Chart {
+    /// Draw the chart at this size, in the given format.
+    pub fn render(&self, width: u32, height: u32, format: Format) -> Result<Vec<u8>, ()> {
+        #[cfg(feature = "gnuplot")]
+        return crate::plot::render(self, width, height, format);
+
+        #[cfg(not(feature = "gnuplot"))]
+        self.draw(width, height).encode(format)
+    }
+
+    /// The first and last times on the time axis.
+    pub(crate) fn x_axis(&self) -> (i64, i64) {
+        let xs = || {
+            self.series
+                .iter()
+                .flat_map(|s| s.x.iter().copied())
+                .chain(self.envelopes.iter().flat_map(|e| e.x.iter().copied()))
+                .chain(self.annotations.iter().flat_map(|a| vec![a.from, a.to]))
+        };
+        let x_min = xs().min().unwrap_or(0);
+        let x_max = xs().max().unwrap_or(0).max(x_min + 1);
+        (x_min, x_max)
+    }
+
+    /// The top of the value axis and the step between its ticks.
+    pub(crate) fn y_axis(&self) -> (f32, f32) {
+        let max = self
+            .series
+            .iter()
+            .flat_map(|s| s.y.iter())
+            .chain(self.envelopes.iter().flat_map(|e| e.hi.iter()))
+            .filter(|v| v.is_finite())
+            .fold(0.0f32, |m, v| m.max(*v));
+        let step = y_step(max);
+        let top = ((max / step).ceil() * step).max(step);
+        (top, step)
+    }
+
+    pub fn draw(&self, width: u32, height: u32) -> Canvas {
+        let mut canvas = Canvas::new(width, height);
+        let (w, h) = (canvas.width, canvas.height);
+        let (left, right) = (MARGIN_LEFT, w - MARGIN_RIGHT);
+        let (top, bottom) = (MARGIN_TOP, h - MARGIN_BOTTOM);
+
+        let (x_min, x_max) = self.x_axis();
+        let (y_top, y_step) = self.y_axis();
+
+        let sx = |t: i64| left + (t - x_min) as f32 / (x_max - x_min) as f32 * (right - left);
+        let sy = |v: f32| bottom - v / y_top * (bottom - top);
+
+        // Bands sit behind everything, labelled at the right.
+        self.bands.iter().filter(|b| b.from < y_top).for_each(|b| {
+            let (y0, y1) = (sy(b.to.unwrap_or(y_top).min(y_top)), sy(b.from));
+            canvas.fill(left, y0, right - left, y1 - y0, b.colour, 1.0);
+            canvas.text(Text {
+                x: right - 6.0,
+                y: y0 + 14.0,
+                text: b.label.clone(),
+                size: 11.0,
+                anchor: Anchor::End,
+                colour: "#606060".to_string(),
+                ..Default::default()
+            });
+        });
+
+        // Annotations shade their time, and are labelled at the top.
+        self.annotations.iter().for_each(|a| {
+            let (x0, x1) = (sx(a.from), sx(a.to));
+            canvas.fill(
+                x0,
+                top,
+                (x1 - x0).max(1.5),
+                bottom - top,
+                a.colour,
+                ANNOTATION_OPACITY,
+            );
+            if !a.label.is_empty() {
+                canvas.text(Text {
+                    x: x0 + 3.0,
+                    y: top + 12.0,
+                    text: a.label.clone(),
+                    size: 10.0,
+                    colour: "#404040".to_string(),
+                    ..Default::default()
+                });
+            }
+        });
+
+        let decimals = if y_step >= 1.0 {
+            0
+        } else {
+            -y_step.log10().floor() as usize
+        };
+
+        // Value ticks go outward on both sides, with a faint line across.
+        let mut v = 0.0;
+        while v <= y_top + y_step / 2.0 {
+            let y = sy(v);
+            canvas.line(vec![(left, y), (right, y)], "#e0e0e0", 1.0);
+            canvas.line(vec![(left - 6.0, y), (left, y)], BLACK, 1.0);
+            canvas.line(vec![(right, y), (right + 6.0, y)], BLACK, 1.0);
+            canvas.text(Text {
+                x: left - 10.0,
+                y,
+                text: format!("{:.*}", decimals, v),
+                anchor: Anchor::End,
+                centred: true,
+                ..Default::default()
+            });
+            v += y_step;
+        }
+
+        // Time ticks point into the plot, with their labels turned to fit.
+        self.ticks
+            .iter()
+            .filter(|t| t.at >= x_min && t.at <= x_max)
+            .for_each(|t| {
+                let x = sx(t.at);
+                canvas.line(vec![(x, bottom), (x, bottom - 6.0)], BLACK, 1.0);
+                canvas.text(Text {
+                    x,
+                    y: bottom + 10.0,
+                    text: t.label.clone(),
+                    size: 10.0,
+                    rotate: 80.0,
+                    ..Default::default()
+                });
+            });
+
+        canvas.outline(left, top, right - left, bottom - top, BLACK);
+        canvas.text(Text {
+            x: (left + right) / 2.0,
+            y: h - 12.0,
+            text: "Time".to_string(),
+            size: 13.0,
+            anchor: Anchor::Middle,
+            ..Default::default()
+        });
+        canvas.text(Text {
+            x: 20.0,
+            y: (top + bottom) / 2.0,
+            text: self.y_label.clone(),
+            size: 13.0,
+            anchor: Anchor::Middle,
+            rotate: -90.0,
+            ..Default::default()
+        });
+
+        // Each unbroken run of an envelope is its own area, along the top
+        // and back along the bottom.
+        self.envelopes.iter().for_each(|e| {
+            let points =
+                e.x.iter()
+                    .zip(e.lo.iter().zip(e.hi.iter()))
+                    .map(|(t, (lo, hi))| {
+                        if lo.is_finite() && hi.is_finite() {
+                            Some((sx(*t), sy(*lo), sy(*hi)))
+                        } else {
+                            None
+                        }
+                    });
+            runs(points).iter().for_each(|run| {
+                let outline = run
+                    .iter()
+                    .map(|(x, _, hi)| (*x, *hi))
+                    .chain(run.iter().rev().map(|(x, lo, _)| (*x, *lo)))
+                    .collect();
+                canvas.area(outline, e.colour, ENVELOPE_OPACITY);
+            });
+        });
+
+        // Each unbroken run of values is its own line.
+        self.series.iter().for_each(|s| {
+            let points = s.x.iter().zip(s.y.iter()).map(|(t, v)| {
+                if v.is_finite() {
+                    Some((sx(*t), sy(*v)))
+                } else {
+                    None
+                }
+            });
+            runs(points).into_iter().for_each(|run| {
+                if run.len() == 1 {
+                    canvas.dot(run[0].0, run[0].1, s.width.max(1.5), s.colour);
+                } else {
+                    canvas.line(run, s.colour, s.width);
+                }
+            });
+        });
+
+        // The key sits in the top right, as gnuplot puts it.
+        let key_width = self
+            .envelopes
+            .iter()
+            .map(|e| e.caption.chars().count())
+            .chain(self.series.iter().map(|s| s.caption.chars().count()))
+            .max()
+            .unwrap_or(0) as f32
+            * 7.0
+            + 50.0;
+        let x = right - key_width;
+        let key_y = |i: usize| top + 16.0 + i as f32 * 18.0;
+        let key = |canvas: &mut Canvas, y: f32, caption: &str| {
+            canvas.text(Text {
+                x,
+                y,
+                text: caption.to_string(),
+                centred: true,
+                ..Default::default()
+            })
+        };
+        self.envelopes.iter().enumerate().for_each(|(i, e)| {
+            let y = key_y(i);
+            key(&mut canvas, y, &e.caption);
+            canvas.fill(
+                right - 40.0,
+                y - 5.0,
+                30.0,
+                10.0,
+                e.colour,
+                ENVELOPE_OPACITY,
+            );
+        });
+        self.series.iter().enumerate().for_each(|(i, s)| {
+            let y = key_y(self.envelopes.len() + i);
+            key(&mut canvas, y, &s.caption);
+            canvas.line(
+                vec![(right - 40.0, y), (right - 10.0, y)],
+                s.colour,
+                s.width,
+            );
+        });
+
+        canvas
+    }
+}
+
+impl Heatmap {
+    /// Draw the heatmap at this size, in the given format. These are always
+    /// drawn here, even with the `gnuplot` feature.
+    pub fn render(&self, width: u32, height: u32, format: Format) -> Result<Vec<u8>, ()> {
+        self.draw(width, height).encode(format)
+    }
+
+    pub fn draw(&self, width: u32, height: u32) -> Canvas {
+        let mut canvas = Canvas::new(width, height);
+        let (w, h) = (canvas.width, canvas.height);
+        let (left, right) = (HEAT_MARGIN_LEFT, w - MARGIN_RIGHT);
+        let (top, bottom) = (MARGIN_TOP + 10.0, h - HEAT_MARGIN_BOTTOM);
+        let cell_w = (right - left) / self.columns.len().max(1) as f32;
+        let cell_h = (bottom - top) / self.rows.len().max(1) as f32;
+        let scale = |v: f32| (v - self.low) / (self.high - self.low).max(1.0);
+
+        canvas.text(Text {
+            x: left,
+            y: MARGIN_TOP,
+            text: self.title.clone(),
+            size: 13.0,
+            ..Default::default()
+        });
+
+        // Values are written in the cells when there's room.
+        let legible = cell_w >= 28.0 && cell_h >= 16.0;
+        self.cells.iter().enumerate().for_each(|(r, row)| {
+            let y = top + r as f32 * cell_h;
+            row.iter().enumerate().for_each(|(c, v)| {
+                let x = left + c as f32 * cell_w;
+                let fill = v.map(|v| heat_colour(scale(v)));
+                canvas.shapes.push(Shape::Rect {
+                    x,
+                    y,
+                    w: cell_w,
+                    h: cell_h,
+                    fill: Some(fill.unwrap_or_else(|| BLANK.to_string())),
+                    opacity: 1.0,
+                    stroke: Some("#ffffff".to_string()),
+                });
+                if let (Some(v), true) = (v, legible) {
+                    canvas.text(Text {
+                        x: x + cell_w / 2.0,
+                        y: y + cell_h / 2.0,
+                        text: format!("{:.0}", v),
+                        size: 10.0,
+                        anchor: Anchor::Middle,
+                        centred: true,
+                        ..Default::default()
+                    });
+                }
+            });
+        });
+
+        self.rows.iter().enumerate().for_each(|(r, label)| {
+            canvas.text(Text {
+                x: left - 8.0,
+                y: top + (r as f32 + 0.5) * cell_h,
+                text: label.clone(),
+                size: 11.0,
+                anchor: Anchor::End,
+                centred: true,
+                ..Default::default()
+            });
+        });
+        self.columns.iter().enumerate().for_each(|(c, label)| {
+            canvas.text(Text {
+                x: left + (c as f32 + 0.5) * cell_w,
+                y: bottom + 16.0,
+                text: label.clone(),
+                size: 11.0,
+                anchor: Anchor::Middle,
+                ..Default::default()
+            });
+        });
+
+        // The scale runs along the bottom, from low to high, in steps.
+        let y = h - 40.0;
+        (0..SCALE_STEPS).for_each(|i| {
+            canvas.fill(
+                left + i as f32 * 12.0,
+                y,
+                12.0,
+                12.0,
+                &heat_colour(i as f32 / (SCALE_STEPS - 1) as f32),
+                1.0,
+            );
+        });
+        let scale_end = left + SCALE_STEPS as f32 * 12.0;
+        canvas.text(Text {
+            x: left,
+            y: y + 28.0,
+            text: format!("{:.0}", self.low),
+            size: 11.0,
+            ..Default::default()
+        });
+        canvas.text(Text {
+            x: scale_end,
+            y: y + 28.0,
+            text: format!("{:.0} or more", self.high),
+            size: 11.0,
+            anchor: Anchor::End,
+            ..Default::default()
+        });
+
+        canvas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chart::{
+        band_colour, heat_colour, y_step, Annotation, Band, Chart, Envelope, Heatmap, Series, Tick,
+        BLACK, BLANK, LINE_WIDTH, MISSING,
+    };
+
+    #[test]
+    fn test_chart_band_colour() {
+        assert!(band_colour(0, 1) == "#d8f5d8");
+        assert!(band_colour(0, 3) == "#d8f5d8");
+        assert!(band_colour(1, 3) == "#fff4c2");
+        assert!(band_colour(2, 3) == "#f9c9c9");
+        assert!(band_colour(3, 4) == "#f9c9c9");
+    }
+
+    #[test]
+    fn test_chart_y_step() {
+        assert!(y_step(0.0) == 1.0);
+        assert!(y_step(1600.0) == 500.0);
+        assert!(y_step(1000.0) == 200.0);
+        assert!(y_step(42.0) == 10.0);
+        assert!((y_step(0.7) - 0.2).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_chart_svg() {
+        let chart = Chart {
+            y_label: "CO2 PPM".to_string(),
+            ticks: vec![Tick {
+                at: 0,
+                label: "2020-04-05 <13:00>".to_string(),
+            }],
+            series: vec![Series {
+                caption: "a & b".to_string(),
+                colour: BLACK,
+                width: LINE_WIDTH,
+                x: vec![0, 60, 120, 900, 960],
+                y: vec![400.0, 500.0, std::f32::NAN, 700.0, 1600.0],
+            }],
+            bands: vec![
+                Band {
+                    from: 0.0,
+                    to: Some(800.0),
+                    label: "good".to_string(),
+                    colour: band_colour(0, 2),
+                },
+                Band {
+                    from: 800.0,
+                    to: None,
+                    label: "poor".to_string(),
+                    colour: band_colour(1, 2),
+                },
+            ],
+            annotations: vec![Annotation {
+                from: 120,
+                to: 1200,
+                label: "outage".to_string(),
+                colour: MISSING,
+            }],
+            envelopes: vec![Envelope {
+                caption: "range".to_string(),
+                colour: BLACK,
+                x: vec![0, 60, 120, 180],
+                lo: vec![300.0, 400.0, 500.0, std::f32::NAN],
+                hi: vec![600.0, 700.0, 2100.0, 800.0],
+            }],
+        };
+        // The annotation stretches the time axis.
+        assert!(chart.x_axis() == (0, 1200));
+        let svg = chart.draw(1400, 800).to_svg();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        // The gap splits the line in two.
+        assert!(svg.matches(r#"stroke-width="1.2"/>"#).count() == 3);
+        assert!(svg.contains("&lt;13:00&gt;"));
+        assert!(svg.contains("a &amp; b"));
+        assert!(svg.contains(">good</text>"));
+        assert!(svg.contains(">outage</text>"));
+        // One area, with the gap at the end.
+        assert!(svg.matches(" Z\"").count() == 1);
+        // 0 to 2500 in steps of 500, as the envelope goes above the line.
+        assert!(svg.contains(">2500</text>"));
+        assert!(!svg.contains(">3000</text>"));
+    }
+
+    #[test]
+    fn test_chart_heatmap() {
+        assert!(heat_colour(0.0) == "#1a9850");
+        assert!(heat_colour(0.5) == "#fee08b");
+        assert!(heat_colour(1.0) == "#d73027");
+        assert!(heat_colour(2.0) == "#d73027");
+        assert!(heat_colour(std::f32::NAN) == "#1a9850");
+
+        let heatmap = Heatmap {
+            title: "CO2 <PPM>".to_string(),
+            rows: vec!["Mon".to_string(), "Tue".to_string()],
+            columns: vec!["00".to_string(), "01".to_string(), "02".to_string()],
+            cells: vec![
+                vec![Some(400.0), None, Some(1234.4)],
+                vec![Some(2000.0), Some(800.0), None],
+            ],
+            low: 400.0,
+            high: 1200.0,
+        };
+        let svg = heatmap.draw(1400, 800).to_svg();
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert!(svg.contains("CO2 &lt;PPM&gt;"));
+        assert!(svg.matches(BLANK).count() == 2);
+        // Past high is as red as high and the end of the scale, and values
+        // are written rounded.
+        assert!(svg.matches(r##"fill="#d73027""##).count() == 3);
+        assert!(svg.contains(">1234</text>"));
+        assert!(svg.contains(">1200 or more</text>"));
+    }
+}
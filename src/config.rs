@@ -0,0 +1,219 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::db::Retention;
+use crate::render::{parse_comfort_bands, ComfortBand};
+use crate::report::Period;
+use crate::storage::Backend;
+use crate::tz::SiteTz;
+
+/// Runtime configuration, read from `MICD_*` environment variables. Anything
+/// unset falls back to the historical defaults for debug and release builds.
+pub struct Config {
+    pub http_bind: String,
+    pub udp_addr: String,
+    pub udp_port: u16,
+    pub db_path: String,
+    /// Where events and reports are kept.
+    pub storage: Backend,
+    /// A libpq style connection string, for postgres storage.
+    pub pg_url: String,
+    /// Where raw readings are kept, for segment storage.
+    pub segment_dir: PathBuf,
+    pub retention: Retention,
+    /// Daily reports record the minutes spent above each of these ppm.
+    pub ppm_bands: Vec<u16>,
+    /// Days are reported in this timezone.
+    pub tz: SiteTz,
+    /// Nothing named a timezone, so tz is utc.
+    pub tz_unset: bool,
+    /// Retransmitted frames from a meter within this window are dropped. Zero disables.
+    pub dedup_window: Duration,
+    /// Collectors that received frames are forwarded to.
+    pub relay_targets: Vec<SocketAddr>,
+    /// If set, only frames from these meters are relayed.
+    pub relay_macs: Option<BTreeSet<String>>,
+    /// The shared key for sync, both to push with and to accept pushes.
+    pub sync_key: Option<String>,
+    /// A central instance to push our data to.
+    pub sync_url: Option<String>,
+    /// How we identify ourselves to the central instance.
+    pub sync_site: String,
+    pub sync_interval: Duration,
+    /// Where scheduled backups are written. If unset there are none.
+    pub backup_dir: Option<PathBuf>,
+    pub backup_interval: Duration,
+    /// How many scheduled backups to keep.
+    pub backup_keep: usize,
+    /// The key for /admin endpoints. If unset they are disabled.
+    pub admin_key: Option<String>,
+    /// Charts are drawn again after this long, even without new data.
+    pub render_refresh: Duration,
+    /// Shaded behind CO2 charts.
+    pub comfort_bands: Vec<ComfortBand>,
+    /// Where scheduled reports are written. If unset there are none.
+    pub report_dir: Option<PathBuf>,
+    pub report_periods: Vec<Period>,
+    /// Days to keep reports for. Zero keeps them forever.
+    pub report_retain_days: u64,
+    /// New reports are posted here.
+    pub report_webhook: Option<String>,
+}
+
+const DEFAULT_COMFORT: &str = "good:800,moderate:1200,poor";
+const DEFAULT_REPORT_PERIODS: &str = "day,week,month";
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(v) => match v.parse() {
+            Ok(v) => v,
+            Err(_) => {
+                error!("Invalid value for {} -> {:?}, using default", key, v);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// A comma separated list, ignoring empty items.
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .map(|v| {
+            v.split(',')
+                .map(|i| i.trim().to_string())
+                .filter(|i| !i.is_empty())
+                .collect()
+        })
+        .unwrap_or_else(|_| Vec::new())
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let (http_bind, udp_addr, db_path, segment_dir) = if cfg!(debug_assertions) {
+            (
+                "127.0.0.1:8082",
+                "127.0.0.1",
+                "/tmp/micd.db",
+                "/tmp/micd.seg",
+            )
+        } else {
+            (
+                "[::]:8082",
+                "172.24.18.140",
+                "/data/micd.db",
+                "/data/micd.seg",
+            )
+        };
+
+        // Follow the host if we aren't told the site's zone.
+        let host_tz = env::var("TZ")
+            .ok()
+            .and_then(|tz| tz.parse().ok())
+            .or_else(SiteTz::host);
+        let tz_unset = env::var("MICD_TIMEZONE").is_err() && host_tz.is_none();
+
+        Config {
+            http_bind: env_or("MICD_HTTP_BIND", http_bind.to_string()),
+            udp_addr: env_or("MICD_UDP_ADDR", udp_addr.to_string()),
+            udp_port: env_or("MICD_UDP_PORT", 2014),
+            db_path: env_or("MICD_DB_PATH", db_path.to_string()),
+            storage: env_or("MICD_STORAGE", Backend::Sqlite),
+            pg_url: env_or(
+                "MICD_PG_URL",
+                "host=/run/postgresql dbname=micd".to_string(),
+            ),
+            segment_dir: env::var("MICD_SEGMENT_DIR")
+                .ok()
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(segment_dir)),
+            retention: Retention {
+                raw_days: env_or("MICD_RETAIN_RAW_DAYS", 4),
+                hourly_days: env_or("MICD_RETAIN_HOURLY_DAYS", 90),
+                daily_days: env_or("MICD_RETAIN_DAILY_DAYS", 0),
+            },
+            tz: env_or(
+                "MICD_TIMEZONE",
+                host_tz.unwrap_or_else(|| "UTC".parse().expect("UTC is a timezone")),
+            ),
+            tz_unset,
+            ppm_bands: if env::var("MICD_PPM_BANDS").is_ok() {
+                env_list("MICD_PPM_BANDS")
+                    .iter()
+                    .filter_map(|b| match b.parse() {
+                        Ok(b) => Some(b),
+                        Err(_) => {
+                            error!("Invalid ppm band {:?}, ignoring", b);
+                            None
+                        }
+                    })
+                    .collect()
+            } else {
+                vec![800, 1000, 1500]
+            },
+            dedup_window: Duration::from_millis(env_or("MICD_DEDUP_WINDOW_MS", 2000)),
+            relay_targets: env_list("MICD_RELAY_TARGETS")
+                .iter()
+                .flat_map(|t| match t.to_socket_addrs() {
+                    Ok(addrs) => addrs.take(1).collect(),
+                    Err(e) => {
+                        error!("Unable to resolve relay target {} -> {:?}", t, e);
+                        Vec::new()
+                    }
+                })
+                .collect(),
+            relay_macs: Some(
+                env_list("MICD_RELAY_MACS")
+                    .into_iter()
+                    .map(|m| m.to_uppercase())
+                    .collect::<BTreeSet<_>>(),
+            )
+            .filter(|macs| !macs.is_empty()),
+            sync_key: env::var("MICD_SYNC_KEY").ok().filter(|k| !k.is_empty()),
+            sync_url: env::var("MICD_SYNC_URL").ok().filter(|u| !u.is_empty()),
+            sync_site: env_or("MICD_SYNC_SITE", "micd".to_string()),
+            sync_interval: Duration::from_secs(env_or("MICD_SYNC_INTERVAL", 300)),
+            backup_dir: env::var("MICD_BACKUP_DIR")
+                .ok()
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from),
+            backup_interval: Duration::from_secs(env_or("MICD_BACKUP_INTERVAL", 86400)),
+            backup_keep: env_or("MICD_BACKUP_KEEP", 7),
+            admin_key: env::var("MICD_ADMIN_KEY").ok().filter(|k| !k.is_empty()),
+            render_refresh: Duration::from_secs(env_or("MICD_RENDER_REFRESH", 300)),
+            comfort_bands: parse_comfort_bands(
+                &env::var("MICD_COMFORT_BANDS").unwrap_or_else(|_| DEFAULT_COMFORT.to_string()),
+            )
+            .unwrap_or_else(|_| {
+                error!("Invalid MICD_COMFORT_BANDS, using default");
+                parse_comfort_bands(DEFAULT_COMFORT).expect("default comfort bands are valid")
+            }),
+            report_dir: env::var("MICD_REPORT_DIR")
+                .ok()
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from),
+            report_periods: env::var("MICD_REPORT_PERIODS")
+                .unwrap_or_else(|_| DEFAULT_REPORT_PERIODS.to_string())
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .filter_map(|p| match p.parse() {
+                    Ok(p) => Some(p),
+                    Err(_) => {
+                        error!("Invalid report period {:?}, ignoring", p);
+                        None
+                    }
+                })
+                .collect(),
+            report_retain_days: env_or("MICD_REPORT_RETAIN_DAYS", 365),
+            report_webhook: env::var("MICD_REPORT_WEBHOOK")
+                .ok()
+                .filter(|u| !u.is_empty()),
+        }
+    }
+}
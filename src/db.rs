@@ -1,23 +1,35 @@
 use actix::prelude::*;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::NO_PARAMS;
+use rusqlite::{Connection, DatabaseName, OpenFlags, NO_PARAMS};
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use time::OffsetDateTime;
 
 use mic::prelude::*;
 
-const RETAIN_DAYS: u64 = 4;
+use crate::import::{self, ImportMeter, ImportSummary};
+use crate::migrations;
+use crate::storage::Storage;
+use crate::sync::{SyncBatch, SyncCursor, SyncEvent, SyncHistory};
+use crate::tz::SiteTz;
+
 pub const TFMT: &'static str = "%F %H:%M:%S%z";
 
+const STMT_CACHE_CAPACITY: usize = 64;
+
+// Each new event takes the next change sequence, which sync pages through.
+const INSERT_EVENT: &str = "INSERT OR IGNORE INTO event_t (mac, ts, seq, temp, ppm, hum, change_seq) VALUES (:mac, :ts, :seq, :temp, :ppm, :hum, (SELECT seq + 1 FROM change_seq_t))";
+
 macro_rules! ensure_mac {
     ($conn:expr, $mac:expr, $err:expr) => {
         $conn
-            .execute_named(
-                "INSERT OR REPLACE INTO meter_t (mac) VALUES (:mac)",
-                &[(":mac", $mac)],
-            )
+            .prepare_cached("INSERT OR IGNORE INTO meter_t (mac) VALUES (:mac)")
+            .and_then(|mut stmt| stmt.execute_named(&[(":mac", $mac)]))
             .map(|r| {
                 debug!("insert -> {:?}", r);
                 ()
@@ -29,16 +41,22 @@ macro_rules! ensure_mac {
     };
 }
 
-macro_rules! ts_remove_hhmmss {
+// Times are stored as milliseconds since the epoch, so they are always utc.
+macro_rules! ts_to_db {
     ($ts:expr) => {{
-        let date = $ts.date();
-        let offset = $ts.offset();
+        $ts.timestamp() * 1000 + $ts.millisecond() as i64
+    }};
+}
 
-        date.midnight().assume_offset(offset)
+macro_rules! ts_from_db {
+    ($ms:expr) => {{
+        let ms: i64 = $ms;
+        OffsetDateTime::from_unix_timestamp(ms.div_euclid(1000))
+            + time::Duration::milliseconds(ms.rem_euclid(1000))
     }};
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DbEvent {
     pub src: String,
     pub time: OffsetDateTime,
@@ -47,28 +65,330 @@ pub struct DbEvent {
     pub hum: u16,
 }
 
-#[derive(Debug)]
+pub fn serialize_ts<S: Serializer>(t: &OffsetDateTime, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_i64(ts_to_db!(t))
+}
+
+fn serialize_opt_ts<S: Serializer>(t: &Option<OffsetDateTime>, s: S) -> Result<S::Ok, S::Error> {
+    match t {
+        Some(t) => s.serialize_some(&ts_to_db!(t)),
+        None => s.serialize_none(),
+    }
+}
+
+/// A day of readings from a meter. Days without readings have no values.
+#[derive(Debug, Clone, Serialize)]
 pub struct DbHistoryEvent {
+    pub src: String,
+    #[serde(serialize_with = "serialize_ts")]
+    pub time: OffsetDateTime,
+    // Unknown for days reported before they were recorded.
+    pub count: Option<u32>,
+    pub coverage: Option<u8>,
+    pub temp_min: Option<u16>,
+    pub temp_max: Option<u16>,
+    pub temp_avg: Option<u16>,
+    pub ppm_min: Option<u16>,
+    pub ppm_max: Option<u16>,
+    pub ppm_avg: Option<u16>,
+    pub hum_min: Option<u16>,
+    pub hum_max: Option<u16>,
+    pub hum_avg: Option<u16>,
+    pub temp_p50: Option<u16>,
+    pub temp_p95: Option<u16>,
+    pub temp_stddev: Option<u16>,
+    pub ppm_p50: Option<u16>,
+    pub ppm_p95: Option<u16>,
+    pub ppm_stddev: Option<u16>,
+    pub hum_p50: Option<u16>,
+    pub hum_p95: Option<u16>,
+    pub hum_stddev: Option<u16>,
+    /// When the highest ppm of the day was first reached.
+    #[serde(serialize_with = "serialize_opt_ts")]
+    pub ppm_peak: Option<OffsetDateTime>,
+    /// Minutes of the day spent above each ppm band.
+    pub minutes_above: BTreeMap<u16, u32>,
+}
+
+/// An hour of readings from a meter. Time is the start of the hour.
+#[derive(Debug, Clone)]
+pub struct DbHourlyEvent {
     pub src: String,
     pub time: OffsetDateTime,
+    pub count: u32,
     pub temp_min: u16,
     pub temp_max: u16,
     pub temp_avg: u16,
     pub ppm_min: u16,
     pub ppm_max: u16,
     pub ppm_avg: u16,
+    pub ppm_p50: u16,
+    pub ppm_p95: u16,
     pub hum_min: u16,
     pub hum_max: u16,
     pub hum_avg: u16,
 }
 
+/// How many days to keep each tier of data for. Zero keeps it forever.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub raw_days: u64,
+    pub hourly_days: u64,
+    pub daily_days: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Summary {
+    min: u16,
+    max: u16,
+    avg: u16,
+    p50: u16,
+    p95: u16,
+    stddev: u16,
+}
+
+/// Has the database at path made any daily reports yet? Nothing is created
+/// if there's no database there.
+pub fn has_reports(path: &str) -> Result<bool, ()> {
+    if path.is_empty() || !Path::new(path).exists() {
+        return Ok(false);
+    }
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE).map_err(|e| {
+            error!("Unable to open {} -> {:?}", path, e);
+            ()
+        })?;
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'history_t'",
+        NO_PARAMS,
+        |row| row.get::<usize, i64>(0),
+    )
+    .and_then(|tables| {
+        if tables == 0 {
+            return Ok(false);
+        }
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM history_t)",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+    })
+    .map_err(|e| {
+        error!("sqlite query_row error -> {:?}", e);
+        ()
+    })
+}
+
+/// Nearest rank percentile of sorted values.
+pub fn percentile(sorted: &[u16], p: usize) -> u16 {
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+// A reading stands for the time until the next one, up to this many seconds.
+// Past that the meter wasn't reading, and the rest is a gap.
+const MAX_READING_GAP: i64 = 300;
+
+// Each reading, with the seconds it stands for before the next one or end.
+fn reading_spans<'a>(
+    data: &'a [DbEvent],
+    end: &OffsetDateTime,
+) -> impl Iterator<Item = (&'a DbEvent, i64)> + 'a {
+    let end = end.timestamp();
+    data.iter().enumerate().map(move |(i, dbe)| {
+        let next = data.get(i + 1).map_or(end, |n| n.time.timestamp());
+        (dbe, (next - dbe.time.timestamp()).clamp(0, MAX_READING_GAP))
+    })
+}
+
+// The percentage of the time between start and end that readings cover.
+fn coverage(data: &[DbEvent], start: &OffsetDateTime, end: &OffsetDateTime) -> u8 {
+    let secs = end.timestamp() - start.timestamp();
+    if secs <= 0 {
+        return 0;
+    }
+    let covered: i64 = reading_spans(data, end).map(|(_, span)| span).sum();
+    (covered * 100 / secs).min(100) as u8
+}
+
+// How many minutes, up to end, had readings above each band.
+fn minutes_above(data: &[DbEvent], end: &OffsetDateTime, bands: &[u16]) -> BTreeMap<u16, u32> {
+    bands
+        .iter()
+        .map(|band| {
+            let secs: i64 = reading_spans(data, end)
+                .filter(|(dbe, _)| dbe.ppm > *band)
+                .map(|(_, span)| span)
+                .sum();
+            (*band, ((secs + 30) / 60) as u32)
+        })
+        .collect()
+}
+
+fn summarise(mut values: Vec<u16>) -> Option<Summary> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    let sum: u64 = values.iter().map(|v| *v as u64).sum();
+    let mean = sum as f64 / values.len() as f64;
+    let variance = values
+        .iter()
+        .map(|v| (*v as f64 - mean).powi(2))
+        .sum::<f64>()
+        / values.len() as f64;
+    Some(Summary {
+        min: values[0],
+        max: values[values.len() - 1],
+        avg: (sum / values.len() as u64) as u16,
+        p50: percentile(&values, 50),
+        p95: percentile(&values, 95),
+        stddev: variance.sqrt().round() as u16,
+    })
+}
+
+/// Summarise a day of events from a meter, which runs from start until end.
+pub fn report_day(
+    src: &str,
+    start: &OffsetDateTime,
+    end: &OffsetDateTime,
+    data: &[DbEvent],
+    bands: &[u16],
+) -> DbHistoryEvent {
+    // min, max, avg for that meter, or nothing if there was no data.
+    let temp = summarise(data.iter().map(|dbe| dbe.temp).collect());
+    let hum = summarise(data.iter().map(|dbe| dbe.hum).collect());
+    let ppm = summarise(data.iter().map(|dbe| dbe.ppm).collect());
+
+    DbHistoryEvent {
+        src: src.to_string(),
+        time: *start,
+        count: Some(data.len() as u32),
+        coverage: Some(coverage(data, start, end)),
+        temp_min: temp.as_ref().map(|s| s.min),
+        temp_max: temp.as_ref().map(|s| s.max),
+        temp_avg: temp.as_ref().map(|s| s.avg),
+        ppm_min: ppm.as_ref().map(|s| s.min),
+        ppm_max: ppm.as_ref().map(|s| s.max),
+        ppm_avg: ppm.as_ref().map(|s| s.avg),
+        hum_min: hum.as_ref().map(|s| s.min),
+        hum_max: hum.as_ref().map(|s| s.max),
+        hum_avg: hum.as_ref().map(|s| s.avg),
+        temp_p50: temp.as_ref().map(|s| s.p50),
+        temp_p95: temp.as_ref().map(|s| s.p95),
+        temp_stddev: temp.as_ref().map(|s| s.stddev),
+        ppm_p50: ppm.as_ref().map(|s| s.p50),
+        ppm_p95: ppm.as_ref().map(|s| s.p95),
+        ppm_stddev: ppm.as_ref().map(|s| s.stddev),
+        hum_p50: hum.as_ref().map(|s| s.p50),
+        hum_p95: hum.as_ref().map(|s| s.p95),
+        hum_stddev: hum.as_ref().map(|s| s.stddev),
+        // The first reading with the highest ppm.
+        ppm_peak: data
+            .iter()
+            .rev()
+            .max_by_key(|dbe| dbe.ppm)
+            .map(|dbe| dbe.time),
+        minutes_above: if data.is_empty() {
+            BTreeMap::new()
+        } else {
+            minutes_above(data, end, bands)
+        },
+    }
+}
+
+/// Summarise an hour of events from a meter, or nothing if there were none.
+pub fn report_hour(src: &str, start: &OffsetDateTime, data: &[DbEvent]) -> Option<DbHourlyEvent> {
+    let temp = summarise(data.iter().map(|dbe| dbe.temp).collect())?;
+    let ppm = summarise(data.iter().map(|dbe| dbe.ppm).collect())?;
+    let hum = summarise(data.iter().map(|dbe| dbe.hum).collect())?;
+
+    Some(DbHourlyEvent {
+        src: src.to_string(),
+        time: *start,
+        count: data.len() as u32,
+        temp_min: temp.min,
+        temp_max: temp.max,
+        temp_avg: temp.avg,
+        ppm_min: ppm.min,
+        ppm_max: ppm.max,
+        ppm_avg: ppm.avg,
+        ppm_p50: ppm.p50,
+        ppm_p95: ppm.p95,
+        hum_min: hum.min,
+        hum_max: hum.max,
+        hum_avg: hum.avg,
+    })
+}
+
+// Replace the minutes above each band for a day.
+fn write_bands(
+    conn: &rusqlite::Connection,
+    src: &str,
+    t: i64,
+    above: &BTreeMap<u16, u32>,
+) -> Result<(), ()> {
+    conn.execute_named(
+        "DELETE FROM history_band_t WHERE mac = :mac AND t = :t",
+        &[(":mac", &src), (":t", &t)],
+    )
+    .map_err(|e| {
+        error!("sqlite execute_named error -> {:?}", e);
+        ()
+    })?;
+
+    above.iter().try_for_each(|(ppm, minutes)| {
+        conn.execute_named(
+            "INSERT INTO history_band_t (mac, t, ppm, minutes) VALUES (:mac, :t, :ppm, :minutes)",
+            &[
+                (":mac", &src),
+                (":t", &t),
+                (":ppm", ppm),
+                (":minutes", minutes),
+            ],
+        )
+        .map(|_| ())
+        .map_err(|e| {
+            error!("sqlite execute_named error -> {:?}", e);
+            ()
+        })
+    })
+}
+
+// This database's identity, which sync sends so that a central instance can
+// tell when a site's database has been replaced.
+fn get_db_id(conn: &rusqlite::Connection) -> Result<String, ()> {
+    conn.query_row("SELECT id FROM db_id_t", NO_PARAMS, |row| row.get(0))
+        .map_err(|e| {
+            error!("sqlite query_row error -> {:?}", e);
+            ()
+        })
+}
+
 struct Db {
     pool: Pool<SqliteConnectionManager>,
+    path: String,
+}
+
+/// Open the sqlite database at path, creating or upgrading it as needed. An
+/// empty path is an in memory database.
+pub fn open_sqlite(path: &str) -> Result<Box<dyn Storage>, ()> {
+    Db::new(path)
+        .and_then(|db| db.migrate())
+        .map(|db| Box::new(db) as Box<dyn Storage>)
 }
 
 impl Db {
     fn new(path: &str) -> Result<Self, ()> {
-        let manager = SqliteConnectionManager::file(path);
+        // We have the one connection, so wal is for everyone else: anything
+        // reading the file, such as the sqlite3 shell, doesn't block our
+        // writes, and with synchronous = NORMAL a write doesn't wait on a sync
+        // to disk. The statements we run are few enough that they all stay
+        // prepared.
+        let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+            conn.set_prepared_statement_cache_capacity(STMT_CACHE_CAPACITY);
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+        });
         // We only build a single thread. If we need more than one, we'll
         // need to re-do this to account for path = "" for debug.
         let builder1 = Pool::builder().max_size(1);
@@ -76,7 +396,10 @@ impl Db {
             error!("r2d2 error {:?}", e);
             ()
         })?;
-        Ok(Db { pool })
+        Ok(Db {
+            pool,
+            path: path.to_string(),
+        })
     }
 
     fn get_conn(&self) -> Result<r2d2::PooledConnection<SqliteConnectionManager>, ()> {
@@ -87,95 +410,37 @@ impl Db {
     }
 
     fn migrate(self) -> Result<Self, ()> {
-        let conn = self.get_conn()?;
-        // Create our tables if needed.
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS meter_t (
-                mac TEXT PRIMARY KEY,
-                label TEXT
-            )
-            ",
-            NO_PARAMS,
-        )
-        .map_err(|e| {
-            error!("sqlite meter_t create error -> {:?}", e);
-            ()
-        })?;
-
-        /*
-         *  - timestamp (local) --- sqlite supports TEXT as ISO8601 strings ("YYYY-MM-DD HH:MM:SS[+-]HH:MM").
-         */
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS event_t (
-                mac TEXT,
-                ts TEXT NOT NULL,
-                temp INTEGER NOT NULL,
-                ppm INTEGER NOT NULL,
-                hum INTEGER NOT NULL,
-                FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
-            )
-            ",
-            NO_PARAMS,
-        )
-        .map_err(|e| {
-            error!("sqlite event_t create error -> {:?}", e);
-            ()
-        })?;
-
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS event_t_ts_idx ON event_t (ts)",
-            NO_PARAMS,
-        )
-        .map_err(|e| {
-            error!("sqlite event_t_ts_idx create error -> {:?}", e);
-            ()
-        })?;
-
-        /*
-         * - time as YYYY-MM-DD
-         */
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS history_t (
-                mac TEXT,
-                t TEXT NOT NULL,
-                temp_max INTEGER NOT NULL,
-                temp_min INTEGER NOT NULL,
-                temp_avg INTEGER NOT NULL,
-                ppm_max INTEGER NOT NULL,
-                ppm_min INTEGER NOT NULL,
-                ppm_avg INTEGER NOT NULL,
-                hum_max INTEGER NOT NULL,
-                hum_min INTEGER NOT NULL,
-                hum_avg INTEGER NOT NULL,
-                FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
-            )
-            ",
-            NO_PARAMS,
-        )
-        .map_err(|e| {
-            error!("sqlite history_t create error -> {:?}", e);
-            ()
-        })?;
-
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS history_t_t_idx ON history_t (t)",
-            NO_PARAMS,
-        )
-        .map_err(|e| {
-            error!("sqlite history_t_t_idx create error -> {:?}", e);
-            ()
-        })?;
+        let mut conn = self.get_conn()?;
+        migrations::migrate(&mut conn, &self.path)?;
         Ok(self)
     }
+}
 
-    fn purge_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
-        let max_str = max.format("%F");
+impl Storage for Db {
+    fn add_datum(&self, datum: Datum, ct: OffsetDateTime, seq: i64) -> Result<(), ()> {
+        let mac = datum.mac_as_string();
+        let (ppm, hum, temp) = datum.data();
+        let ts = ts_to_db!(ct);
 
         let conn = self.get_conn()?;
+        ensure_mac!(conn, &mac, ());
 
-        conn.execute_named("DELETE FROM event_t WHERE ts < :max", &[(":max", &max_str)])
+        conn.prepare_cached(INSERT_EVENT)
+            .and_then(|mut stmt| {
+                stmt.execute_named(&[
+                    (":mac", &mac),
+                    (":ts", &ts),
+                    (":seq", &seq),
+                    (":temp", &temp),
+                    (":ppm", &ppm),
+                    (":hum", &hum),
+                ])
+            })
             .map(|r| {
-                debug!("delete -> {:?}", r);
+                debug!("insert -> {:?}", r);
+                if r == 0 {
+                    warn!("ignoring duplicate event for {} at {}", mac, ts);
+                }
                 ()
             })
             .map_err(|e| {
@@ -184,38 +449,11 @@ impl Db {
             })
     }
 
-    fn add_datum(&self, datum: Datum, ct: OffsetDateTime) -> Result<(), ()> {
-        let mac = datum.mac_as_string();
-        let (ppm, hum, temp) = datum.data();
-        let ts = ct.format(TFMT);
-
-        let conn = self.get_conn()?;
-        ensure_mac!(conn, &mac, ());
-
-        conn.execute_named(
-            "INSERT OR REPLACE INTO event_t (mac, ts, temp, ppm, hum) VALUES (:mac, :ts, :temp, :ppm, :hum)",
-        &[
-            (":mac", &mac),
-            (":ts", &ts),
-            (":temp", &temp),
-            (":ppm", &ppm),
-            (":hum", &hum),
-        ])
-        .map(|r| {
-            debug!("insert -> {:?}", r);
-            ()
-        })
-        .map_err(|e| {
-            error!("sqlite execute_named error -> {:?}", e);
-            ()
-        })
-    }
-
     fn list_meters(&self) -> Result<Vec<String>, ()> {
         let conn = self.get_conn()?;
 
         let mut stmt = conn
-            .prepare("SELECT DISTINCT mac FROM meter_t")
+            .prepare_cached("SELECT DISTINCT mac FROM meter_t")
             .map_err(|e| {
                 error!("sqlite prepare and query error -> {:?}", e);
                 ()
@@ -236,6 +474,42 @@ impl Db {
         Ok(data)
     }
 
+    fn get_meter_labels(&self) -> Result<BTreeMap<String, String>, ()> {
+        let conn = self.get_conn()?;
+
+        let mut stmt = conn
+            .prepare_cached("SELECT mac, label FROM meter_t WHERE label IS NOT NULL")
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        let labels = stmt
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect::<Result<BTreeMap<String, String>, _>>())
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+        Ok(labels)
+    }
+
+    fn set_meter_label(&self, src: &str, label: Option<&str>) -> Result<(), ()> {
+        let conn = self.get_conn()?;
+        ensure_mac!(conn, &src, ());
+
+        conn.prepare_cached("UPDATE meter_t SET label = :label WHERE mac = :mac")
+            .and_then(|mut stmt| stmt.execute_named(&[(":mac", &src), (":label", &label)]))
+            .map(|r| {
+                debug!("update -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("sqlite execute_named error -> {:?}", e);
+                ()
+            })
+    }
+
     fn get_event_range(
         &self,
         src: &str,
@@ -243,14 +517,14 @@ impl Db {
         max: &OffsetDateTime,
     ) -> Result<Vec<DbEvent>, ()> {
         // select from where >= min and < max
-        let min_str = min.format(TFMT);
-        let max_str = max.format(TFMT);
+        let min_ts = ts_to_db!(min);
+        let max_ts = ts_to_db!(max);
 
         let conn = self.get_conn()?;
 
-        info!("SELECT ts, temp, ppm, hum FROM event_t WHERE mac = '{}' AND ts >= '{}' AND ts < '{}' ORDER BY ts ASC", src, min_str, max_str);
+        info!("SELECT ts, temp, ppm, hum FROM event_t WHERE mac = '{}' AND ts >= {} AND ts < {} ORDER BY ts ASC", src, min_ts, max_ts);
 
-        let mut stmt = conn.prepare(
+        let mut stmt = conn.prepare_cached(
             "SELECT ts, temp, ppm, hum FROM event_t WHERE mac = :mac AND ts >= :min AND ts < :max ORDER BY ts ASC"
         )
         .map_err(|e| {
@@ -260,10 +534,10 @@ impl Db {
 
         let data_iter = stmt
             .query_map_named(
-                &[(":mac", &src), (":min", &min_str), (":max", &max_str)],
+                &[(":mac", &src), (":min", &min_ts), (":max", &max_ts)],
                 |row| {
                     Ok((
-                        row.get_unwrap::<usize, String>(0),
+                        row.get_unwrap::<usize, i64>(0),
                         row.get_unwrap(1),
                         row.get_unwrap(2),
                         row.get_unwrap(3),
@@ -279,7 +553,7 @@ impl Db {
             .map(|row| match row {
                 Ok((ts, temp, ppm, hum)) => DbEvent {
                     src: src.to_string(),
-                    time: OffsetDateTime::parse(ts, TFMT).expect("invalid ts"),
+                    time: ts_from_db!(ts),
                     temp,
                     ppm,
                     hum,
@@ -291,250 +565,861 @@ impl Db {
         Ok(data)
     }
 
-    fn get_history(&self, src: &str) -> Result<Vec<DbHistoryEvent>, ()> {
+    // The first event at or after min, if any.
+    fn get_next_event_time(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>, ()> {
+        let min_ts = ts_to_db!(min);
         let conn = self.get_conn()?;
 
-        info!("SELECT t, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg FROM history_t WHERE mac = '{}'  ORDER BY t ASC", src);
+        conn.prepare_cached("SELECT MIN(ts) FROM event_t WHERE mac = :mac AND ts >= :min")
+            .and_then(|mut stmt| {
+                stmt.query_row_named(&[(":mac", &src), (":min", &min_ts)], |row| {
+                    row.get::<usize, Option<i64>>(0)
+                })
+            })
+            .map(|ts| ts.map(|ts| ts_from_db!(ts)))
+            .map_err(|e| {
+                error!("sqlite query_row_named error -> {:?}", e);
+                ()
+            })
+    }
 
-        let mut stmt = conn.prepare(
-            "SELECT t, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg FROM history_t WHERE mac = :mac ORDER BY t ASC"
-        )
-        .map_err(|e| {
-            error!("sqlite prepare and query error -> {:?}", e);
-            ()
-        })?;
+    fn get_last_event_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        let conn = self.get_conn()?;
 
-        let data_iter = stmt
-            .query_map_named(&[(":mac", &src)], |row| {
-                Ok((
-                    row.get_unwrap::<usize, String>(0),
-                    row.get_unwrap(1),
-                    row.get_unwrap(2),
-                    row.get_unwrap(3),
-                    row.get_unwrap(4),
-                    row.get_unwrap(5),
-                    row.get_unwrap(6),
-                    row.get_unwrap(7),
-                    row.get_unwrap(8),
-                    row.get_unwrap(9),
-                ))
+        conn.prepare_cached("SELECT MAX(ts) FROM event_t WHERE mac = :mac")
+            .and_then(|mut stmt| {
+                stmt.query_row_named(&[(":mac", &src)], |row| row.get::<usize, Option<i64>>(0))
             })
+            .map(|ts| ts.map(|ts| ts_from_db!(ts)))
             .map_err(|e| {
-                error!("sqlite prepare and query error -> {:?}", e);
+                error!("sqlite query_row_named error -> {:?}", e);
                 ()
-            })?;
-
-        let data: Vec<DbHistoryEvent> = data_iter
-            .map(|row| match row {
-                Ok((
-                    ts,
-                    temp_max,
-                    temp_min,
-                    temp_avg,
-                    ppm_max,
-                    ppm_min,
-                    ppm_avg,
-                    hum_max,
-                    hum_min,
-                    hum_avg,
-                )) => DbHistoryEvent {
-                    src: src.to_string(),
-                    time: OffsetDateTime::parse(ts, TFMT).expect("invalid ts"),
-                    temp_max,
-                    temp_min,
-                    temp_avg,
-                    ppm_max,
-                    ppm_min,
-                    ppm_avg,
-                    hum_max,
-                    hum_min,
-                    hum_avg,
-                },
-                _ => panic!(),
             })
-            .collect();
-
-        Ok(data)
     }
 
-    fn get_latest_report_date(&self, src: &str) -> Result<OffsetDateTime, ()> {
+    fn get_history(&self, src: &str) -> Result<Vec<DbHistoryEvent>, ()> {
         let conn = self.get_conn()?;
 
-        let mut stmt = conn
-            .prepare("SELECT MAX(t) FROM history_t WHERE mac = :mac")
+        // The time spent above each band, for each day.
+        let mut bands: BTreeMap<i64, BTreeMap<u16, u32>> = BTreeMap::new();
+        {
+            let mut stmt = conn
+                .prepare_cached("SELECT t, ppm, minutes FROM history_band_t WHERE mac = :mac")
+                .map_err(|e| {
+                    error!("sqlite prepare and query error -> {:?}", e);
+                    ()
+                })?;
+
+            stmt.query_map_named(&[(":mac", &src)], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .and_then(|rows| {
+                rows.map(|row| {
+                    row.map(|(t, ppm, minutes)| {
+                        bands
+                            .entry(t)
+                            .or_insert_with(BTreeMap::new)
+                            .insert(ppm, minutes);
+                    })
+                })
+                .collect::<Result<(), _>>()
+            })
             .map_err(|e| {
                 error!("sqlite prepare and query error -> {:?}", e);
                 ()
             })?;
+        }
+
+        info!("SELECT t, count, coverage, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg, ... FROM history_t WHERE mac = '{}'  ORDER BY t ASC", src);
+
+        let mut stmt = conn.prepare_cached(
+            "SELECT t, count, coverage, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg, temp_p50, temp_p95, temp_stddev, ppm_p50, ppm_p95, ppm_stddev, hum_p50, hum_p95, hum_stddev, ppm_peak_t FROM history_t WHERE mac = :mac ORDER BY t ASC"
+        )
+        .map_err(|e| {
+            error!("sqlite prepare and query error -> {:?}", e);
+            ()
+        })?;
 
         let data_iter = stmt
-            .query_map_named(&[(":mac", &src)], |row| row.get(0))
+            .query_map_named(&[(":mac", &src)], |row| {
+                let t: i64 = row.get(0)?;
+                Ok(DbHistoryEvent {
+                    src: src.to_string(),
+                    time: ts_from_db!(t),
+                    count: row.get(1)?,
+                    coverage: row.get(2)?,
+                    temp_max: row.get(3)?,
+                    temp_min: row.get(4)?,
+                    temp_avg: row.get(5)?,
+                    ppm_max: row.get(6)?,
+                    ppm_min: row.get(7)?,
+                    ppm_avg: row.get(8)?,
+                    hum_max: row.get(9)?,
+                    hum_min: row.get(10)?,
+                    hum_avg: row.get(11)?,
+                    temp_p50: row.get(12)?,
+                    temp_p95: row.get(13)?,
+                    temp_stddev: row.get(14)?,
+                    ppm_p50: row.get(15)?,
+                    ppm_p95: row.get(16)?,
+                    ppm_stddev: row.get(17)?,
+                    hum_p50: row.get(18)?,
+                    hum_p95: row.get(19)?,
+                    hum_stddev: row.get(20)?,
+                    ppm_peak: row.get::<usize, Option<i64>>(21)?.map(|ts| ts_from_db!(ts)),
+                    minutes_above: bands.remove(&t).unwrap_or_default(),
+                })
+            })
             .map_err(|e| {
                 error!("sqlite prepare and query error -> {:?}", e);
                 ()
             })?;
 
-        let data: Result<Vec<OffsetDateTime>, _> = data_iter
-            .map(|row: Result<String, _>| {
-                row.map(|ts| {
-                    ts_remove_hhmmss!(OffsetDateTime::parse(&ts, TFMT).expect("invalid ts"))
-                })
-            })
-            .collect();
+        data_iter.collect::<Result<_, _>>().map_err(|e| {
+            error!("sqlite query_map error -> {:?}", e);
+            ()
+        })
+    }
 
-        match data {
-            Ok(mut v) => {
-                return Ok(v.pop().expect("missing max calc?"));
-            }
-            // Gotta keep going ....
-            Err(_) => {}
-        }
+    fn get_last_history_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        let conn = self.get_conn()?;
 
-        // select min(ts) from event_t where mac = "0:0:0:0:0:0";
-        let mut stmt = conn
-            .prepare("SELECT MIN(ts) FROM event_t WHERE mac = :mac")
+        conn.prepare_cached("SELECT MAX(t) FROM history_t WHERE mac = :mac")
+            .and_then(|mut stmt| {
+                stmt.query_row_named(&[(":mac", &src)], |row| row.get::<usize, Option<i64>>(0))
+            })
+            .map(|t| t.map(|t| ts_from_db!(t)))
             .map_err(|e| {
-                error!("sqlite prepare and query error -> {:?}", e);
+                error!("sqlite query_row_named error -> {:?}", e);
                 ()
-            })?;
+            })
+    }
 
-        let data_iter = stmt
-            .query_map_named(&[(":mac", &src)], |row| row.get(0))
-            .map_err(|e| {
-                error!("sqlite prepare and query error -> {:?}", e);
-                ()
-            })?;
+    fn put_history(&self, h: &DbHistoryEvent) -> Result<(), ()> {
+        let t = ts_to_db!(h.time);
+        let ppm_peak = h.ppm_peak.map(|ts| ts_to_db!(ts));
 
-        let data: Result<Vec<OffsetDateTime>, _> = data_iter
-            .map(|row: Result<String, _>| {
-                row.map(|ts| {
-                    // We remove 1 day here to make it the "day before".
-                    ts_remove_hhmmss!(OffsetDateTime::parse(&ts, TFMT).expect("invalid ts"))
-                        - Duration::from_secs(86400)
-                })
-            })
-            .collect();
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction().map_err(|e| {
+            error!("sqlite transaction error -> {:?}", e);
+            ()
+        })?;
+        ensure_mac!(tx, &h.src, ());
+        write_bands(&tx, &h.src, t, &h.minutes_above)?;
 
-        match data {
-            Ok(mut v) => Ok(v.pop().expect("missing max calc?")),
-            // Gotta keep going ....
-            Err(_) => {
-                error!("No data found, unable to generate report data ...?");
-                Err(())
-            }
-        }
+        tx.execute_named(
+            "INSERT OR REPLACE INTO history_t (mac, t, count, coverage, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg, temp_p50, temp_p95, temp_stddev, ppm_p50, ppm_p95, ppm_stddev, hum_p50, hum_p95, hum_stddev, ppm_peak_t) VALUES (:mac, :t, :count, :coverage, :temp_max, :temp_min, :temp_avg, :ppm_max, :ppm_min, :ppm_avg, :hum_max, :hum_min, :hum_avg, :temp_p50, :temp_p95, :temp_stddev, :ppm_p50, :ppm_p95, :ppm_stddev, :hum_p50, :hum_p95, :hum_stddev, :ppm_peak_t)",
+        &[
+            (":mac", &h.src),
+            (":t", &t),
+            (":count", &h.count),
+            (":coverage", &h.coverage),
+            (":temp_max", &h.temp_max),
+            (":temp_min", &h.temp_min),
+            (":temp_avg", &h.temp_avg),
+            (":ppm_max", &h.ppm_max),
+            (":ppm_min", &h.ppm_min),
+            (":ppm_avg", &h.ppm_avg),
+            (":hum_max", &h.hum_max),
+            (":hum_min", &h.hum_min),
+            (":hum_avg", &h.hum_avg),
+            (":temp_p50", &h.temp_p50),
+            (":temp_p95", &h.temp_p95),
+            (":temp_stddev", &h.temp_stddev),
+            (":ppm_p50", &h.ppm_p50),
+            (":ppm_p95", &h.ppm_p95),
+            (":ppm_stddev", &h.ppm_stddev),
+            (":hum_p50", &h.hum_p50),
+            (":hum_p95", &h.hum_p95),
+            (":hum_stddev", &h.hum_stddev),
+            (":ppm_peak_t", &ppm_peak),
+        ])
+        .map(|r| {
+            debug!("insert -> {:?}", r);
+            ()
+        })
+        .map_err(|e| {
+            error!("sqlite execute_named error -> {:?}", e);
+            ()
+        })?;
+
+        tx.commit().map_err(|e| {
+            error!("sqlite commit error -> {:?}", e);
+            ()
+        })
     }
 
-    fn extract_report(
-        &self,
-        src: &str,
-        ct: &OffsetDateTime,
-    ) -> Result<OffsetDateTime, OffsetDateTime> {
-        // What is the earliest event date we have recorded? (alt to latest)
-        let latest = self
-            .get_latest_report_date(src)
-            .map_err(|_| OffsetDateTime::unix_epoch())?;
-        let upto = ts_remove_hhmmss!(ct);
-
-        info!(
-            "Generating reports between: {:?} -> {:?}",
-            latest.format(TFMT),
-            upto.format(TFMT)
-        );
+    fn purge_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        let max_ts = ts_to_db!(max);
 
-        let mut work_start = latest + Duration::from_secs(86400);
-        // Get what days exist between latest report to ct.
-        while work_start < upto {
-            let work_end = work_start + Duration::from_secs(86400);
-            // select all data from that range
-            let data = self
-                .get_event_range(src, &work_start, &work_end)
-                .map_err(|_| work_start.clone())?;
-            // min, max, avg for that meter.
-            let (t_min, t_max, t_sum) =
-                data.iter()
-                    .fold((0xffff, 0, 0), |(t_min, t_max, t_sum), dbe| {
-                        (
-                            if dbe.temp < t_min { dbe.temp } else { t_min },
-                            if dbe.temp > t_max { dbe.temp } else { t_max },
-                            t_sum + (dbe.temp as u64),
-                        )
-                    });
-            let t_avg = (t_sum / data.len() as u64) as u16;
-            info!("t -> {:?}, {:?}, {:?} -> {:?}", t_min, t_max, t_sum, t_avg);
-
-            let (h_min, h_max, h_sum) =
-                data.iter()
-                    .fold((0xffff, 0, 0), |(h_min, h_max, h_sum), dbe| {
-                        (
-                            if dbe.hum < h_min { dbe.hum } else { h_min },
-                            if dbe.hum > h_max { dbe.hum } else { h_max },
-                            h_sum + (dbe.hum as u64),
-                        )
-                    });
-            let h_avg = (h_sum / data.len() as u64) as u16;
-            info!("h -> {:?}, {:?}, {:?} -> {:?}", h_min, h_max, h_sum, h_avg);
-
-            let (p_min, p_max, p_sum) =
-                data.iter()
-                    .fold((0xffff, 0, 0), |(p_min, p_max, p_sum), dbe| {
-                        (
-                            if dbe.ppm < p_min { dbe.ppm } else { p_min },
-                            if dbe.ppm > p_max { dbe.ppm } else { p_max },
-                            p_sum + (dbe.ppm as u64),
-                        )
-                    });
-            let p_avg = (p_sum / data.len() as u64) as u16;
-            info!("p -> {:?}, {:?}, {:?} -> {:?}", p_min, p_max, p_sum, p_avg);
-            let ts = work_start.format(TFMT);
-
-            // write that as a history event, use work_start as the TS.
-            let conn = self.get_conn().map_err(|_| work_start.clone())?;
-            ensure_mac!(conn, &src, work_start.clone());
-
-            conn.execute_named(
-                "INSERT OR REPLACE INTO history_t (mac, t, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg) VALUES (:mac, :t, :temp_max, :temp_min, :temp_avg, :ppm_max, :ppm_min, :ppm_avg, :hum_max, :hum_min, :hum_avg)",
-            &[
-                (":mac", &src),
-                (":t", &ts),
-                (":temp_max", &t_max),
-                (":temp_min", &t_min),
-                (":temp_avg", &t_avg),
-                (":ppm_max", &p_max),
-                (":ppm_min", &p_min),
-                (":ppm_avg", &p_avg),
-                (":hum_max", &h_max),
-                (":hum_min", &h_min),
-                (":hum_avg", &h_avg),
-            ])
+        let conn = self.get_conn()?;
+
+        conn.execute_named("DELETE FROM event_t WHERE ts < :max", &[(":max", &max_ts)])
             .map(|r| {
-                debug!("insert -> {:?}", r);
+                debug!("delete -> {:?}", r);
                 ()
             })
             .map_err(|e| {
                 error!("sqlite execute_named error -> {:?}", e);
-                work_start.clone()
-            })?;
-
-            // Increment to the next day.
-            work_start = work_start + Duration::from_secs(86400);
-        }
-        Ok(work_start)
+                ()
+            })
     }
-}
+
+    fn checkpoint(&self) -> Result<(), ()> {
+        let conn = self.get_conn()?;
+        // Fold the wal back into the database, so the file alone is complete.
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", NO_PARAMS, |_row| Ok(()))
+            .map(|r| {
+                debug!("checkpoint -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("sqlite checkpoint error -> {:?}", e);
+                ()
+            })
+    }
+
+    /// Write a consistent copy of the database to path while we keep running.
+    fn backup_to(&self, path: &Path) -> Result<(), ()> {
+        // Don't leave a partial backup where a good one is expected.
+        let tmp_path = path.with_extension("tmp");
+
+        let conn = self.get_conn()?;
+        conn.backup(DatabaseName::Main, &tmp_path, None)
+            .map_err(|e| {
+                error!("sqlite backup error -> {:?}", e);
+                let _ = fs::remove_file(&tmp_path);
+                ()
+            })?;
+
+        fs::rename(&tmp_path, path).map_err(|e| {
+            error!("Unable to move backup to {:?} -> {:?}", path, e);
+            ()
+        })
+    }
+
+    fn purge_hourly_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        let max_ts = ts_to_db!(max);
+
+        let conn = self.get_conn()?;
+
+        conn.execute_named("DELETE FROM hourly_t WHERE t < :max", &[(":max", &max_ts)])
+            .map(|r| {
+                debug!("delete -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("sqlite execute_named error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn purge_history_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        let max_ts = ts_to_db!(max);
+
+        let conn = self.get_conn()?;
+
+        conn.execute_named(
+            "DELETE FROM history_band_t WHERE t < :max",
+            &[(":max", &max_ts)],
+        )
+        .map_err(|e| {
+            error!("sqlite execute_named error -> {:?}", e);
+            ()
+        })?;
+
+        conn.execute_named("DELETE FROM history_t WHERE t < :max", &[(":max", &max_ts)])
+            .map(|r| {
+                debug!("delete -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("sqlite execute_named error -> {:?}", e);
+                ()
+            })
+    }
+
+    // Readings src already has from first to last, and days it has reports
+    // for from day until last. Days that were gaps don't count.
+    fn count_overlap(
+        &self,
+        src: &str,
+        day: &OffsetDateTime,
+        first: &OffsetDateTime,
+        last: &OffsetDateTime,
+    ) -> Result<(usize, usize), ()> {
+        let day_ts = ts_to_db!(day);
+        let first_ts = ts_to_db!(first);
+        let last_ts = ts_to_db!(last);
+        let conn = self.get_conn()?;
+
+        let existing: i64 = conn
+            .query_row_named(
+                "SELECT COUNT(*) FROM event_t WHERE mac = :mac AND ts >= :first AND ts <= :last",
+                &[(":mac", &src), (":first", &first_ts), (":last", &last_ts)],
+                |row| row.get(0),
+            )
+            .map_err(|e| {
+                error!("sqlite query_row_named error -> {:?}", e);
+                ()
+            })?;
+
+        let reported: i64 = conn
+            .query_row_named(
+                "SELECT COUNT(*) FROM history_t WHERE mac = :mac AND t >= :day AND t <= :last AND IFNULL(count, 1) > 0",
+                &[(":mac", &src), (":day", &day_ts), (":last", &last_ts)],
+                |row| row.get(0),
+            )
+            .map_err(|e| {
+                error!("sqlite query_row_named error -> {:?}", e);
+                ()
+            })?;
+
+        Ok((existing as usize, reported as usize))
+    }
+
+    /// Add imported readings in a single transaction. Readings at a time src
+    /// already has one for are ignored. Returns how many were added.
+    fn import_events(&self, src: &str, events: &[DbEvent]) -> Result<usize, ()> {
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction().map_err(|e| {
+            error!("sqlite transaction error -> {:?}", e);
+            ()
+        })?;
+
+        ensure_mac!(tx, &src, ());
+
+        let added = events.iter().try_fold(0, |added, e| {
+            let ts = ts_to_db!(e.time);
+            tx.prepare_cached(INSERT_EVENT)
+                .and_then(|mut stmt| {
+                    stmt.execute_named(&[
+                        (":mac", &src),
+                        (":ts", &ts),
+                        (":seq", &0),
+                        (":temp", &e.temp),
+                        (":ppm", &e.ppm),
+                        (":hum", &e.hum),
+                    ])
+                })
+                .map(|r| added + r)
+                .map_err(|e| {
+                    error!("sqlite execute_named error -> {:?}", e);
+                    ()
+                })
+        })?;
+
+        tx.commit().map_err(|e| {
+            error!("sqlite commit error -> {:?}", e);
+            ()
+        })?;
+        Ok(added)
+    }
+
+    fn get_latest_sequences(&self) -> Result<BTreeMap<String, i64>, ()> {
+        let conn = self.get_conn()?;
+
+        let mut stmt = conn
+            .prepare_cached("SELECT mac, MAX(seq) FROM event_t GROUP BY mac")
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        let data_iter = stmt
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        data_iter.collect::<Result<_, _>>().map_err(|e| {
+            error!("sqlite query_map error -> {:?}", e);
+            ()
+        })
+    }
+
+    fn get_hourly_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbHourlyEvent>, ()> {
+        let min_ts = ts_to_db!(min);
+        let max_ts = ts_to_db!(max);
+
+        let conn = self.get_conn()?;
+
+        let mut stmt = conn.prepare_cached(
+            "SELECT t, count, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, ppm_p50, ppm_p95, hum_max, hum_min, hum_avg FROM hourly_t WHERE mac = :mac AND t >= :min AND t < :max ORDER BY t ASC"
+        )
+        .map_err(|e| {
+            error!("sqlite prepare and query error -> {:?}", e);
+            ()
+        })?;
+
+        let data_iter = stmt
+            .query_map_named(
+                &[(":mac", &src), (":min", &min_ts), (":max", &max_ts)],
+                |row| {
+                    Ok(DbHourlyEvent {
+                        src: src.to_string(),
+                        time: ts_from_db!(row.get(0)?),
+                        count: row.get(1)?,
+                        temp_max: row.get(2)?,
+                        temp_min: row.get(3)?,
+                        temp_avg: row.get(4)?,
+                        ppm_max: row.get(5)?,
+                        ppm_min: row.get(6)?,
+                        ppm_avg: row.get(7)?,
+                        ppm_p50: row.get(8)?,
+                        ppm_p95: row.get(9)?,
+                        hum_max: row.get(10)?,
+                        hum_min: row.get(11)?,
+                        hum_avg: row.get(12)?,
+                    })
+                },
+            )
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        data_iter.collect::<Result<_, _>>().map_err(|e| {
+            error!("sqlite query_map error -> {:?}", e);
+            ()
+        })
+    }
+
+    fn get_sync_cursor(&self, site: &str) -> Result<SyncCursor, ()> {
+        let conn = self.get_conn()?;
+
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT event_change, history_t, db_id FROM sync_cursor_t WHERE site = :site",
+            )
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        let mut data_iter = stmt
+            .query_map_named(&[(":site", &site)], |row| {
+                Ok(SyncCursor {
+                    event_change: row.get(0)?,
+                    history_t: row.get(1)?,
+                    db_id: row.get(2)?,
+                })
+            })
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        match data_iter.next() {
+            Some(Ok(cursor)) => Ok(cursor),
+            Some(Err(e)) => {
+                error!("sqlite query_map error -> {:?}", e);
+                Err(())
+            }
+            // A new site, it needs to send us everything.
+            None => Ok(SyncCursor::default()),
+        }
+    }
+
+    fn export_sync_batch(&self, cursor: &SyncCursor, limit: u32) -> Result<SyncBatch, ()> {
+        let conn = self.get_conn()?;
+        let db_id = get_db_id(&conn)?;
+
+        // A cursor for the database this one replaced says nothing about what
+        // has been sent from here, so start over.
+        let fresh = SyncCursor::default();
+        let cursor = match &cursor.db_id {
+            Some(id) if *id != db_id => {
+                info!("Sync cursor is for another database, sending everything");
+                &fresh
+            }
+            _ => cursor,
+        };
+
+        let event_change = cursor.event_change.unwrap_or_default();
+
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT mac, ts, seq, temp, ppm, hum, change_seq FROM event_t WHERE change_seq > :change ORDER BY change_seq ASC LIMIT :limit",
+            )
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        let events = stmt
+            .query_map_named(&[(":change", &event_change), (":limit", &limit)], |row| {
+                Ok(SyncEvent {
+                    mac: row.get(0)?,
+                    ts: row.get(1)?,
+                    seq: row.get(2)?,
+                    temp: row.get(3)?,
+                    ppm: row.get(4)?,
+                    hum: row.get(5)?,
+                    change: row.get(6)?,
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        // Only send history once the events have caught up. The last day is
+        // always resent, incase it was regenerated since.
+        if events.len() >= limit as usize {
+            return Ok(SyncBatch {
+                events,
+                history: Vec::new(),
+                db_id: Some(db_id),
+            });
+        }
+
+        let history_t = cursor.history_t.unwrap_or_default();
+
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT mac, t, count, coverage, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg, temp_p50, temp_p95, temp_stddev, ppm_p50, ppm_p95, ppm_stddev, hum_p50, hum_p95, hum_stddev, ppm_peak_t FROM history_t WHERE t >= :t ORDER BY t ASC",
+            )
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        let mut history = stmt
+            .query_map_named(&[(":t", &history_t)], |row| {
+                Ok(SyncHistory {
+                    mac: row.get(0)?,
+                    t: row.get(1)?,
+                    count: row.get(2)?,
+                    coverage: row.get(3)?,
+                    temp_max: row.get(4)?,
+                    temp_min: row.get(5)?,
+                    temp_avg: row.get(6)?,
+                    ppm_max: row.get(7)?,
+                    ppm_min: row.get(8)?,
+                    ppm_avg: row.get(9)?,
+                    hum_max: row.get(10)?,
+                    hum_min: row.get(11)?,
+                    hum_avg: row.get(12)?,
+                    temp_p50: row.get(13)?,
+                    temp_p95: row.get(14)?,
+                    temp_stddev: row.get(15)?,
+                    ppm_p50: row.get(16)?,
+                    ppm_p95: row.get(17)?,
+                    ppm_stddev: row.get(18)?,
+                    hum_p50: row.get(19)?,
+                    hum_p95: row.get(20)?,
+                    hum_stddev: row.get(21)?,
+                    ppm_peak_t: row.get(22)?,
+                    minutes_above: BTreeMap::new(),
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        let mut stmt = conn
+            .prepare_cached("SELECT mac, t, ppm, minutes FROM history_band_t WHERE t >= :t")
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        let bands = stmt
+            .query_map_named(&[(":t", &history_t)], |row| {
+                Ok((
+                    row.get::<usize, String>(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                ))
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<(String, i64, u16, u32)>, _>>())
+            .map_err(|e| {
+                error!("sqlite prepare and query error -> {:?}", e);
+                ()
+            })?;
+
+        bands.into_iter().for_each(|(mac, t, ppm, minutes)| {
+            if let Some(h) = history.iter_mut().find(|h| h.mac == mac && h.t == t) {
+                h.minutes_above.insert(ppm, minutes);
+            }
+        });
+
+        Ok(SyncBatch {
+            events,
+            history,
+            db_id: Some(db_id),
+        })
+    }
+
+    fn apply_sync_batch(&self, site: &str, batch: &SyncBatch) -> Result<SyncCursor, ()> {
+        let mut cursor = self.get_sync_cursor(site)?;
+        match (&cursor.db_id, &batch.db_id) {
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                info!("{} has a new database, starting its cursor over", site);
+                cursor = SyncCursor::default();
+            }
+            _ => (),
+        }
+        // Sites from before databases had an identity don't send one.
+        if batch.db_id.is_some() {
+            cursor.db_id = batch.db_id.clone();
+        }
+
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction().map_err(|e| {
+            error!("sqlite transaction error -> {:?}", e);
+            ()
+        })?;
+
+        // Replays of the same batch are ignored by the unique indexes.
+        batch.events.iter().try_for_each(|e| {
+            ensure_mac!(tx, &e.mac, ());
+            tx.prepare_cached(INSERT_EVENT)
+                .and_then(|mut stmt| {
+                    stmt.execute_named(&[
+                        (":mac", &e.mac),
+                        (":ts", &e.ts),
+                        (":seq", &e.seq),
+                        (":temp", &e.temp),
+                        (":ppm", &e.ppm),
+                        (":hum", &e.hum),
+                    ])
+                })
+                .map(|_| ())
+                .map_err(|e| {
+                    error!("sqlite execute_named error -> {:?}", e);
+                    ()
+                })
+        })?;
+
+        batch.history.iter().try_for_each(|h| {
+            ensure_mac!(tx, &h.mac, ());
+            write_bands(&tx, &h.mac, h.t, &h.minutes_above)?;
+            tx.execute_named(
+                "INSERT OR REPLACE INTO history_t (mac, t, count, coverage, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg, temp_p50, temp_p95, temp_stddev, ppm_p50, ppm_p95, ppm_stddev, hum_p50, hum_p95, hum_stddev, ppm_peak_t) VALUES (:mac, :t, :count, :coverage, :temp_max, :temp_min, :temp_avg, :ppm_max, :ppm_min, :ppm_avg, :hum_max, :hum_min, :hum_avg, :temp_p50, :temp_p95, :temp_stddev, :ppm_p50, :ppm_p95, :ppm_stddev, :hum_p50, :hum_p95, :hum_stddev, :ppm_peak_t)",
+                &[
+                    (":mac", &h.mac),
+                    (":t", &h.t),
+                    (":count", &h.count),
+                    (":coverage", &h.coverage),
+                    (":temp_max", &h.temp_max),
+                    (":temp_min", &h.temp_min),
+                    (":temp_avg", &h.temp_avg),
+                    (":ppm_max", &h.ppm_max),
+                    (":ppm_min", &h.ppm_min),
+                    (":ppm_avg", &h.ppm_avg),
+                    (":hum_max", &h.hum_max),
+                    (":hum_min", &h.hum_min),
+                    (":hum_avg", &h.hum_avg),
+                    (":temp_p50", &h.temp_p50),
+                    (":temp_p95", &h.temp_p95),
+                    (":temp_stddev", &h.temp_stddev),
+                    (":ppm_p50", &h.ppm_p50),
+                    (":ppm_p95", &h.ppm_p95),
+                    (":ppm_stddev", &h.ppm_stddev),
+                    (":hum_p50", &h.hum_p50),
+                    (":hum_p95", &h.hum_p95),
+                    (":hum_stddev", &h.hum_stddev),
+                    (":ppm_peak_t", &h.ppm_peak_t),
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                error!("sqlite execute_named error -> {:?}", e);
+                ()
+            })
+        })?;
+
+        // Never move the cursor back, as a batch may be a late or replayed one
+        // from the same database.
+        let event_change = batch.events.iter().map(|e| e.change).max();
+        let history_t = batch.history.iter().map(|h| h.t).max();
+        cursor.event_change = cursor.event_change.max(event_change);
+        cursor.history_t = cursor.history_t.max(history_t);
+
+        tx.execute_named(
+            "INSERT OR REPLACE INTO sync_cursor_t (site, event_change, history_t, db_id) VALUES (:site, :event_change, :history_t, :db_id)",
+            &[
+                (":site", &site),
+                (":event_change", &cursor.event_change),
+                (":history_t", &cursor.history_t),
+                (":db_id", &cursor.db_id),
+            ],
+        )
+        .map_err(|e| {
+            error!("sqlite execute_named error -> {:?}", e);
+            ()
+        })?;
+
+        tx.commit().map_err(|e| {
+            error!("sqlite commit error -> {:?}", e);
+            ()
+        })?;
+
+        Ok(cursor)
+    }
+
+    fn get_last_hourly_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        let conn = self.get_conn()?;
+        conn.query_row_named(
+            "SELECT MAX(t) FROM hourly_t WHERE mac = :mac",
+            &[(":mac", &src)],
+            |row| row.get::<usize, Option<i64>>(0),
+        )
+        .map(|t| t.map(|t| ts_from_db!(t)))
+        .map_err(|e| {
+            error!("sqlite query_row_named error -> {:?}", e);
+            ()
+        })
+    }
+
+    fn put_hourly(&self, h: &DbHourlyEvent) -> Result<(), ()> {
+        let t = ts_to_db!(h.time);
+
+        let conn = self.get_conn()?;
+        conn.execute_named(
+            "INSERT OR REPLACE INTO hourly_t (mac, t, count, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, ppm_p50, ppm_p95, hum_max, hum_min, hum_avg) VALUES (:mac, :t, :count, :temp_max, :temp_min, :temp_avg, :ppm_max, :ppm_min, :ppm_avg, :ppm_p50, :ppm_p95, :hum_max, :hum_min, :hum_avg)",
+            &[
+                (":mac", &h.src),
+                (":t", &t),
+                (":count", &h.count),
+                (":temp_max", &h.temp_max),
+                (":temp_min", &h.temp_min),
+                (":temp_avg", &h.temp_avg),
+                (":ppm_max", &h.ppm_max),
+                (":ppm_min", &h.ppm_min),
+                (":ppm_avg", &h.ppm_avg),
+                (":ppm_p50", &h.ppm_p50),
+                (":ppm_p95", &h.ppm_p95),
+                (":hum_max", &h.hum_max),
+                (":hum_min", &h.hum_min),
+                (":hum_avg", &h.hum_avg),
+            ],
+        )
+        .map(|r| {
+            debug!("insert -> {:?}", r);
+            ()
+        })
+        .map_err(|e| {
+            error!("sqlite execute_named error -> {:?}", e);
+            ()
+        })
+    }
+}
 
 impl Actor for DbActor {
     type Context = SyncContext<Self>;
 }
 
 pub struct DbActor {
-    db: Db,
+    db: Box<dyn Storage>,
+    retention: Retention,
+    tz: SiteTz,
+    bands: Vec<u16>,
+    // The last (seq, rx time) we stored for each meter.
+    last_seen: BTreeMap<String, (i64, OffsetDateTime)>,
 }
 
 impl DbActor {
-    pub fn new(path: &str) -> Result<Self, ()> {
-        Ok(DbActor {
-            db: Db::new(path).and_then(|db| db.migrate())?,
+    pub fn new(db: Box<dyn Storage>, retention: Retention, tz: SiteTz, bands: Vec<u16>) -> Self {
+        DbActor {
+            db,
+            retention,
+            tz,
+            bands,
+            last_seen: BTreeMap::new(),
+        }
+    }
+
+    /// Add the readings from an import and report again on the days they
+    /// cover. If a meter already has readings or reports for those days,
+    /// nothing is added unless merge is set.
+    pub fn import(
+        &self,
+        events: Vec<DbEvent>,
+        skipped: usize,
+        merge: bool,
+    ) -> Result<ImportSummary, ()> {
+        let meters = import::by_meter(events);
+
+        let mut summary = ImportSummary {
+            skipped,
+            meters: Vec::with_capacity(meters.len()),
+            imported: false,
+        };
+        for (mac, events) in meters.iter() {
+            let first = events[0].time;
+            let last = events[events.len() - 1].time;
+            let day = self.tz.day_start(self.tz.date_of(first));
+            let (existing, reported) = self.db.count_overlap(mac, &day, &first, &last)?;
+            summary.meters.push(ImportMeter {
+                mac: mac.clone(),
+                first,
+                last,
+                readings: events.len(),
+                existing,
+                reported,
+                added: 0,
+            });
+        }
+
+        if summary.overlaps() && !merge {
+            warn!("Import overlaps existing data, nothing was imported");
+            return Ok(summary);
+        }
+
+        let now = OffsetDateTime::now();
+        for ((mac, events), m) in meters.iter().zip(summary.meters.iter_mut()) {
+            m.added = self.db.import_events(mac, events)?;
+            info!(
+                "Imported {} of {} readings for {}",
+                m.added, m.readings, mac
+            );
+            self.db
+                .rebuild_reports(mac, &self.tz, &self.bands, &m.first, &m.last, &now)
+                .map_err(|t| {
+                    error!(
+                        "Failed to regenerate reports for {} from {:?}",
+                        mac,
+                        t.format(TFMT)
+                    );
+                    ()
+                })?;
+            self.db
+                .rebuild_hourly(mac, &self.tz, &m.first, &m.last, &now)
+                .map_err(|_| error!("Failed to regenerate hourly reports for {}", mac))?;
+        }
+        summary.imported = true;
+        Ok(summary)
+    }
+
+    /// Roll up every meter's hours until now, and report on each day before
+    /// now's, so that anything drawn from them is complete.
+    pub fn extract_reports(&self, now: &OffsetDateTime) -> Result<(), ()> {
+        let ct = self.tz.day_start(self.tz.date_of(*now));
+        self.db.list_meters()?.iter().try_for_each(|src| {
+            self.db.extract_hourly(src, &self.tz, now).map_err(|_| {
+                error!("Failed to extract hourly report for {:?}", src);
+            })?;
+            self.db
+                .extract_report(src, &self.tz, &self.bands, &ct)
+                .map(|_| ())
+                .map_err(|_| error!("Failed to extract report for {:?}", src))
         })
     }
 }
@@ -547,8 +1432,9 @@ impl Handler<DbPurgeEvent> for DbActor {
     type Result = ();
 
     fn handle(&mut self, _msg: DbPurgeEvent, _: &mut SyncContext<Self>) {
-        // Get the current time and strip it to a day
-        let ct = ts_remove_hhmmss!(OffsetDateTime::now_local());
+        // Get the current time and strip it to the start of the local day
+        let now = OffsetDateTime::now();
+        let ct = self.tz.day_start(self.tz.date_of(now));
 
         let meters = match self.db.list_meters() {
             Ok(meters) => meters,
@@ -559,8 +1445,13 @@ impl Handler<DbPurgeEvent> for DbActor {
         };
         // for each meter
         meters.iter().for_each(|src| {
+            // Make sure every hour is rolled up before we remove any raw events.
+            if self.db.extract_hourly(&src, &self.tz, &now).is_err() {
+                error!("Failed to extract hourly report for {:?}", src);
+                return;
+            }
             // Process our historical data as needed. -> should return latest report date?
-            let r = match self.db.extract_report(&src, &ct) {
+            let r = match self.db.extract_report(&src, &self.tz, &self.bands, &ct) {
                 Ok(r) => {
                     info!("Extracted report for {:?}", src);
                     r
@@ -570,8 +1461,11 @@ impl Handler<DbPurgeEvent> for DbActor {
                     r
                 }
             };
-            // purge older than 7 days from the now latest repport
-            let purge_upto = r - Duration::from_secs(86400 * RETAIN_DAYS);
+            if self.retention.raw_days == 0 {
+                return;
+            }
+            // purge older than the retention from the now latest repport
+            let purge_upto = r - Duration::from_secs(86400 * self.retention.raw_days);
             match self.db.purge_older_than(&purge_upto) {
                 Ok(_) => {}
                 Err(_) => error!(
@@ -581,20 +1475,131 @@ impl Handler<DbPurgeEvent> for DbActor {
                 ),
             };
         });
+
+        if self.retention.hourly_days != 0 {
+            let purge_upto = ct - Duration::from_secs(86400 * self.retention.hourly_days);
+            if self.db.purge_hourly_older_than(&purge_upto).is_err() {
+                error!("Failed to purge hourly up to {:?}", purge_upto.format(TFMT));
+            }
+        }
+
+        if self.retention.daily_days != 0 {
+            let purge_upto = ct - Duration::from_secs(86400 * self.retention.daily_days);
+            if self.db.purge_history_older_than(&purge_upto).is_err() {
+                error!(
+                    "Failed to purge history up to {:?}",
+                    purge_upto.format(TFMT)
+                );
+            }
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DbRollupEvent;
+
+impl Handler<DbRollupEvent> for DbActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DbRollupEvent, _: &mut SyncContext<Self>) {
+        let now = OffsetDateTime::now();
+
+        let meters = match self.db.list_meters() {
+            Ok(meters) => meters,
+            Err(_) => {
+                error!("Unable to handle dbrollup due to meter list fail.");
+                return;
+            }
+        };
+
+        meters
+            .iter()
+            .for_each(|src| match self.db.extract_hourly(&src, &self.tz, &now) {
+                Ok(n) => debug!("Extracted {} hourly reports for {:?}", n, src),
+                Err(_) => error!("Failed to extract hourly report for {:?}", src),
+            });
+
+        // So that no more than a rollup's worth of readings is lost in a crash.
+        if self.db.flush().is_err() {
+            error!("Failed to flush storage");
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), ()>")]
+pub struct DbExtractReports {
+    pub now: OffsetDateTime,
+}
+
+impl Handler<DbExtractReports> for DbActor {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: DbExtractReports, _: &mut SyncContext<Self>) -> Result<(), ()> {
+        self.extract_reports(&msg.now)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), ()>")]
+pub struct DbShutdownEvent;
+
+impl Handler<DbShutdownEvent> for DbActor {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, _msg: DbShutdownEvent, _: &mut SyncContext<Self>) -> Result<(), ()> {
+        info!("Checkpointing db ...");
+        self.db.checkpoint()
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), ()>")]
+pub struct DbBackup {
+    pub path: PathBuf,
+}
+
+impl Handler<DbBackup> for DbActor {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: DbBackup, _: &mut SyncContext<Self>) -> Result<(), ()> {
+        self.db.backup_to(&msg.path)
     }
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct DbAddDatumEvent(pub Datum, pub SocketAddr);
+pub struct DbAddDatumEvent {
+    pub datum: Datum,
+    pub addr: SocketAddr,
+    // When the datagram was received, not when we got to it.
+    pub rx: OffsetDateTime,
+    pub seq: i64,
+}
 
 impl Handler<DbAddDatumEvent> for DbActor {
     type Result = ();
 
     fn handle(&mut self, msg: DbAddDatumEvent, _: &mut SyncContext<Self>) {
-        let ct = OffsetDateTime::now_local();
-        match self.db.add_datum(msg.0, ct) {
-            Ok(_) => {}
+        let mac = msg.datum.mac_as_string();
+
+        match self.last_seen.get(&mac) {
+            Some((seq, _)) if msg.seq <= *seq => warn!(
+                "{} seq {} <- {:?} is not after {}, duplicate or reordered?",
+                mac, msg.seq, msg.addr, seq
+            ),
+            Some((_, rx)) if msg.rx < *rx => warn!(
+                "{} seq {} <- {:?} was received before the previous frame, clock step?",
+                mac, msg.seq, msg.addr
+            ),
+            _ => {}
+        }
+
+        match self.db.add_datum(msg.datum, msg.rx, msg.seq) {
+            Ok(_) => {
+                self.last_seen.insert(mac, (msg.seq, msg.rx));
+            }
             Err(_) => {
                 error!("Error adding data to event_t");
             }
@@ -602,6 +1607,38 @@ impl Handler<DbAddDatumEvent> for DbActor {
     }
 }
 
+#[derive(Message)]
+#[rtype(result = "Result<ImportSummary, ()>")]
+pub struct DbImport {
+    pub events: Vec<DbEvent>,
+    pub skipped: usize,
+    pub merge: bool,
+}
+
+impl Handler<DbImport> for DbActor {
+    type Result = Result<ImportSummary, ()>;
+
+    fn handle(&mut self, msg: DbImport, _: &mut SyncContext<Self>) -> Result<ImportSummary, ()> {
+        self.import(msg.events, msg.skipped, msg.merge)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<BTreeMap<String, i64>, ()>")]
+pub struct DbLatestSequences;
+
+impl Handler<DbLatestSequences> for DbActor {
+    type Result = Result<BTreeMap<String, i64>, ()>;
+
+    fn handle(
+        &mut self,
+        _msg: DbLatestSequences,
+        _: &mut SyncContext<Self>,
+    ) -> Result<BTreeMap<String, i64>, ()> {
+        self.db.get_latest_sequences()
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "Result<Vec<DbEvent>, ()>")]
 pub struct DbEventRange {
@@ -620,6 +1657,90 @@ impl Handler<DbEventRange> for DbActor {
     }
 }
 
+#[derive(Message)]
+#[rtype(result = "Result<SyncCursor, ()>")]
+pub struct DbSyncCursor {
+    pub site: String,
+}
+
+impl Handler<DbSyncCursor> for DbActor {
+    type Result = Result<SyncCursor, ()>;
+
+    fn handle(&mut self, msg: DbSyncCursor, _: &mut SyncContext<Self>) -> Result<SyncCursor, ()> {
+        self.db.get_sync_cursor(msg.site.as_str())
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<SyncBatch, ()>")]
+pub struct DbSyncExport {
+    pub cursor: SyncCursor,
+    pub limit: u32,
+}
+
+impl Handler<DbSyncExport> for DbActor {
+    type Result = Result<SyncBatch, ()>;
+
+    fn handle(&mut self, msg: DbSyncExport, _: &mut SyncContext<Self>) -> Result<SyncBatch, ()> {
+        self.db.export_sync_batch(&msg.cursor, msg.limit)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<SyncCursor, ()>")]
+pub struct DbSyncApply {
+    pub site: String,
+    pub batch: SyncBatch,
+}
+
+impl Handler<DbSyncApply> for DbActor {
+    type Result = Result<SyncCursor, ()>;
+
+    fn handle(&mut self, msg: DbSyncApply, _: &mut SyncContext<Self>) -> Result<SyncCursor, ()> {
+        let cursor = self.db.apply_sync_batch(msg.site.as_str(), &msg.batch)?;
+
+        // A site's readings can arrive after we've rolled up those hours.
+        let mut spans: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+        msg.batch.events.iter().for_each(|e| {
+            let span = spans.entry(e.mac.as_str()).or_insert((e.ts, e.ts));
+            *span = (span.0.min(e.ts), span.1.max(e.ts));
+        });
+        let now = OffsetDateTime::now();
+        spans.iter().for_each(|(mac, (first, last))| {
+            let (first, last) = (ts_from_db!(*first), ts_from_db!(*last));
+            if self
+                .db
+                .rebuild_hourly(mac, &self.tz, &first, &last, &now)
+                .is_err()
+            {
+                error!("Failed to regenerate hourly reports for {}", mac);
+            }
+        });
+        Ok(cursor)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Vec<DbHourlyEvent>, ()>")]
+pub struct DbHourlyRange {
+    pub src: String,
+    pub min: OffsetDateTime,
+    pub max: OffsetDateTime,
+}
+
+impl Handler<DbHourlyRange> for DbActor {
+    type Result = Result<Vec<DbHourlyEvent>, ()>;
+
+    fn handle(
+        &mut self,
+        msg: DbHourlyRange,
+        _: &mut SyncContext<Self>,
+    ) -> Result<Vec<DbHourlyEvent>, ()> {
+        self.db
+            .get_hourly_range(msg.src.as_str(), &msg.min, &msg.max)
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "Result<Vec<DbHistoryEvent>, ()>")]
 pub struct DbHistory {
@@ -638,42 +1759,108 @@ impl Handler<DbHistory> for DbActor {
     }
 }
 
+#[derive(Message)]
+#[rtype(result = "Result<Vec<String>, ()>")]
+pub struct DbListMeters;
+
+impl Handler<DbListMeters> for DbActor {
+    type Result = Result<Vec<String>, ()>;
+
+    fn handle(&mut self, _msg: DbListMeters, _: &mut SyncContext<Self>) -> Result<Vec<String>, ()> {
+        self.db.list_meters()
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<BTreeMap<String, String>, ()>")]
+pub struct DbMeterLabels;
+
+impl Handler<DbMeterLabels> for DbActor {
+    type Result = Result<BTreeMap<String, String>, ()>;
+
+    fn handle(
+        &mut self,
+        _msg: DbMeterLabels,
+        _: &mut SyncContext<Self>,
+    ) -> Result<BTreeMap<String, String>, ()> {
+        self.db.get_meter_labels()
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), ()>")]
+pub struct DbSetMeterLabel {
+    pub src: String,
+    pub label: Option<String>,
+}
+
+impl Handler<DbSetMeterLabel> for DbActor {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: DbSetMeterLabel, _: &mut SyncContext<Self>) -> Result<(), ()> {
+        self.db
+            .set_meter_label(msg.src.as_str(), msg.label.as_deref())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::db::{Db, DbEvent, TFMT};
+    use crate::db::{
+        coverage, has_reports, open_sqlite, percentile, Db, DbActor, DbEvent, Retention, TFMT,
+    };
+    use crate::import::{parse, ImportOptions};
+    use crate::storage::tests::check_storage;
+    use crate::storage::Storage;
+    use crate::sync::SyncCursor;
+    use crate::tz::SiteTz;
     use mic::prelude::*;
+    use rusqlite::NO_PARAMS;
     use time::OffsetDateTime;
 
-    fn add_sample_data(db: &Db, mac: [u8; 6], temp: u16, ppm: u16, hum: u16, ts: &str) {
+    fn add_sample_data(db: &dyn Storage, mac: [u8; 6], temp: u16, ppm: u16, hum: u16, ts: &str) {
         let ct = OffsetDateTime::parse(ts, TFMT).expect("invalid ts");
         let datum = Datum::from((mac, ppm, hum, temp));
-        db.add_datum(datum, ct).expect("Failed to add data!")
+        db.add_datum(datum, ct, 0).expect("Failed to add data!")
     }
 
-    fn get_event_range(db: &Db, src: &str, min: &str, max: &str) -> Vec<DbEvent> {
+    fn get_event_range(db: &dyn Storage, src: &str, min: &str, max: &str) -> Vec<DbEvent> {
         let min_ts = OffsetDateTime::parse(min, TFMT).expect("invalid ts");
         let max_ts = OffsetDateTime::parse(max, TFMT).expect("invalid ts");
         db.get_event_range(src, &min_ts, &max_ts)
             .expect("failed to get range")
     }
 
-    fn purge(db: &Db, max: &str) {
+    fn purge(db: &dyn Storage, max: &str) {
         let max_ts = OffsetDateTime::parse(max, TFMT).expect("invalid ts");
         db.purge_older_than(&max_ts).expect("failed to purge data");
     }
 
-    fn assert_latest_report_date(db: &Db, src: &str, expect: &str) {
+    // The sample data is written in +1000, which Brisbane always is.
+    fn site_tz() -> SiteTz {
+        "Australia/Brisbane".parse().unwrap()
+    }
+
+    fn assert_latest_report_date(db: &dyn Storage, src: &str, expect: &str) {
+        assert_latest_report_date_in(db, src, &site_tz(), expect)
+    }
+
+    // Days start at midnight in tz, whatever the host's zone is.
+    fn assert_latest_report_date_in(db: &dyn Storage, src: &str, tz: &SiteTz, expect: &str) {
         let latest = db
-            .get_latest_report_date(src)
+            .get_latest_report_date(src, tz)
             .expect("Unable to get reportdate");
         let expect_ts = OffsetDateTime::parse(expect, TFMT).expect("invalid ts");
-        println!("{:?} == {:?}", latest.format(TFMT), expect_ts.format(TFMT));
+        println!(
+            "{:?} == {:?}",
+            tz.to_local(latest).format(TFMT),
+            expect_ts.format(TFMT)
+        );
         assert!(latest == expect_ts)
     }
 
-    fn generate_report(db: &Db, src: &str, upto: &str) {
+    fn generate_report(db: &dyn Storage, src: &str, upto: &str) {
         let upto_ts = OffsetDateTime::parse(upto, TFMT).expect("invalid ts");
-        db.extract_report(src, &upto_ts)
+        db.extract_report(src, &site_tz(), &[800, 1000, 1500], &upto_ts)
             .expect("report gen failed.");
     }
 
@@ -717,7 +1904,7 @@ mod tests {
         let db = db.migrate().unwrap();
 
         // No report min, no data!
-        assert!(Err(()) == db.get_latest_report_date("00:00:00:00:00:00"));
+        assert!(Err(()) == db.get_latest_report_date("00:00:00:00:00:00", &site_tz()));
 
         // Add data
         add_sample_data(&db, [0; 6], 123, 415, 123, "2020-04-05 13:02:19+1000");
@@ -734,6 +1921,14 @@ mod tests {
 
         // Latest report date should be the day before as there is no reports.
         assert_latest_report_date(&db, "00:00:00:00:00:00", "2020-04-04 00:00:00+1000");
+        // Including where midnight isn't on a utc hour.
+        let kolkata: SiteTz = "Asia/Kolkata".parse().unwrap();
+        assert_latest_report_date_in(
+            &db,
+            "00:00:00:00:00:00",
+            &kolkata,
+            "2020-04-04 00:00:00+0530",
+        );
 
         // Generate a report. for 05 (because it's now 06)
         generate_report(&db, "00:00:00:00:00:00", "2020-04-06 00:00:00+1000");
@@ -747,4 +1942,660 @@ mod tests {
         // The latest report date is now 7
         assert_latest_report_date(&db, "00:00:00:00:00:00", "2020-04-07 00:00:00+1000");
     }
+
+    #[test]
+    fn test_db_rx_precision_and_sequence() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap();
+        let db = db.migrate().unwrap();
+
+        // Two frames in the same second remain distinct and ordered.
+        let ct = OffsetDateTime::parse("2020-04-05 13:02:19+1000", TFMT).expect("invalid ts");
+        let ct_a = ct + time::Duration::milliseconds(100);
+        let ct_b = ct + time::Duration::milliseconds(600);
+        db.add_datum(Datum::from(([0; 6], 400, 123, 123)), ct_b, 2)
+            .expect("Failed to add data!");
+        db.add_datum(Datum::from(([0; 6], 410, 123, 123)), ct_a, 1)
+            .expect("Failed to add data!");
+
+        let data = get_event_range(
+            &db,
+            "00:00:00:00:00:00",
+            "2020-04-05 13:02:19+1000",
+            "2020-04-05 13:02:20+1000",
+        );
+        assert!(data.len() == 2);
+        assert!(data[0].time == ct_a);
+        assert!(data[1].time == ct_b);
+        assert!(data[0].ppm == 410);
+
+        let seqs = db.get_latest_sequences().expect("failed to get sequences");
+        assert!(seqs.get("00:00:00:00:00:00") == Some(&2));
+    }
+
+    #[test]
+    fn test_db_migrate_local_ts() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap();
+        {
+            // The layout of event_t before sequences were added.
+            let conn = db.get_conn().unwrap();
+            conn.execute(
+                "CREATE TABLE event_t (mac TEXT, ts TEXT NOT NULL, temp INTEGER NOT NULL, ppm INTEGER NOT NULL, hum INTEGER NOT NULL)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO event_t VALUES ('00:00:00:00:00:00', '2020-04-05 13:02:19+1000', 123, 415, 123)",
+                NO_PARAMS,
+            )
+            .unwrap();
+        }
+        let db = db.migrate().unwrap();
+
+        let data = get_event_range(
+            &db,
+            "00:00:00:00:00:00",
+            "2020-04-05 00:00:00+1000",
+            "2020-04-06 00:00:00+1000",
+        );
+        assert!(data.len() == 1);
+        assert!(
+            data[0].time
+                == OffsetDateTime::parse("2020-04-05 13:02:19+1000", TFMT).expect("invalid ts")
+        );
+    }
+
+    #[test]
+    fn test_db_duplicate_events() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap();
+        {
+            // Duplicates stored before the unique index existed are removed.
+            let conn = db.get_conn().unwrap();
+            conn.execute(
+                "CREATE TABLE event_t (mac TEXT, ts TEXT NOT NULL, temp INTEGER NOT NULL, ppm INTEGER NOT NULL, hum INTEGER NOT NULL)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO event_t VALUES ('00:00:00:00:00:00', '2020-04-05 13:02:19+1000', 123, 415, 123), ('00:00:00:00:00:00', '2020-04-05 13:02:19+1000', 123, 415, 123)",
+                NO_PARAMS,
+            )
+            .unwrap();
+        }
+        let db = db.migrate().unwrap();
+
+        let data = get_event_range(
+            &db,
+            "00:00:00:00:00:00",
+            "2020-04-05 00:00:00+1000",
+            "2020-04-06 00:00:00+1000",
+        );
+        assert!(data.len() == 1);
+
+        // And a repeat of the same (mac, ts) is ignored.
+        add_sample_data(&db, [0; 6], 123, 415, 123, "2020-04-05 14:02:19+1000");
+        add_sample_data(&db, [0; 6], 123, 999, 123, "2020-04-05 14:02:19+1000");
+        let data = get_event_range(
+            &db,
+            "00:00:00:00:00:00",
+            "2020-04-05 00:00:00+1000",
+            "2020-04-06 00:00:00+1000",
+        );
+        assert!(data.len() == 2);
+        assert!(data[1].ppm == 415);
+    }
+
+    #[test]
+    fn test_db_sync_between_instances() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let site = Db::new("").unwrap().migrate().unwrap();
+        let central = Db::new("").unwrap().migrate().unwrap();
+
+        add_sample_data(&site, [0; 6], 123, 415, 123, "2020-04-05 13:02:19+1000");
+        add_sample_data(&site, [1; 6], 123, 415, 123, "2020-04-05 13:02:19+1000");
+        add_sample_data(&site, [0; 6], 123, 415, 123, "2020-04-05 14:02:19+1000");
+        generate_report(&site, "00:00:00:00:00:00", "2020-04-06 00:00:00+1000");
+
+        // A new site starts from nothing.
+        let cursor = central.get_sync_cursor("site-a").unwrap();
+        assert!(cursor == SyncCursor::default());
+
+        // Send in small batches, as if we were interrupted part way.
+        let first = site.export_sync_batch(&cursor, 2).unwrap();
+        assert!(first.events.len() == 2);
+        assert!(first.history.is_empty());
+        let batch = first.clone();
+        let cursor = central.apply_sync_batch("site-a", &batch).unwrap();
+
+        // Replaying the same batch changes nothing.
+        let replay = central.apply_sync_batch("site-a", &batch).unwrap();
+        assert!(replay == cursor);
+
+        // Resume from what central has stored.
+        let cursor = central.get_sync_cursor("site-a").unwrap();
+        let batch = site.export_sync_batch(&cursor, 2).unwrap();
+        assert!(batch.events.len() == 1);
+        assert!(batch.history.len() == 1);
+        let cursor = central.apply_sync_batch("site-a", &batch).unwrap();
+
+        // Caught up, only the last days history is resent.
+        let batch = site.export_sync_batch(&cursor, 2).unwrap();
+        assert!(batch.events.is_empty());
+        assert!(batch.history.len() == 1);
+
+        // A late batch doesn't move the cursor back.
+        assert!(central.apply_sync_batch("site-a", &first).unwrap() == cursor);
+        assert!(central.get_sync_cursor("site-a").unwrap() == cursor);
+
+        // Readings added behind what has been sent, such as an import, still go.
+        add_sample_data(&site, [0; 6], 123, 415, 123, "2020-04-05 12:02:19+1000");
+        let batch = site.export_sync_batch(&cursor, 2).unwrap();
+        assert!(batch.events.len() == 1);
+        let cursor = central.apply_sync_batch("site-a", &batch).unwrap();
+
+        // And the sequence carries on once everything has been purged.
+        purge(&site, "2020-04-06 00:00:00+1000");
+        add_sample_data(&site, [0; 6], 123, 415, 123, "2020-04-06 13:02:19+1000");
+        let batch = site.export_sync_batch(&cursor, 2).unwrap();
+        assert!(batch.events.len() == 1);
+        central.apply_sync_batch("site-a", &batch).unwrap();
+
+        let data = get_event_range(
+            &central,
+            "00:00:00:00:00:00",
+            "2020-04-05 00:00:00+1000",
+            "2020-04-07 00:00:00+1000",
+        );
+        assert!(data.len() == 4);
+        assert!(central.get_history("00:00:00:00:00:00").unwrap().len() == 1);
+        assert!(central.list_meters().unwrap().len() == 2);
+    }
+
+    #[test]
+    fn test_db_sync_replaced_site() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let site = Db::new("").unwrap().migrate().unwrap();
+        let central = Db::new("").unwrap().migrate().unwrap();
+
+        add_sample_data(&site, [0; 6], 123, 415, 123, "2020-04-05 13:02:19+1000");
+        add_sample_data(&site, [0; 6], 123, 415, 123, "2020-04-05 14:02:19+1000");
+        let cursor = central.get_sync_cursor("site-a").unwrap();
+        let batch = site.export_sync_batch(&cursor, 10).unwrap();
+        let cursor = central.apply_sync_batch("site-a", &batch).unwrap();
+        assert!(cursor.event_change == Some(2));
+        assert!(cursor.db_id == batch.db_id);
+
+        // The site starts again with a new database, its sequence from 1.
+        let site = Db::new("").unwrap().migrate().unwrap();
+        add_sample_data(&site, [0; 6], 123, 415, 123, "2020-04-05 15:02:19+1000");
+        let cursor = central.get_sync_cursor("site-a").unwrap();
+        let batch = site.export_sync_batch(&cursor, 10).unwrap();
+        assert!(batch.events.len() == 1);
+        let cursor = central.apply_sync_batch("site-a", &batch).unwrap();
+        assert!(cursor.event_change == Some(1));
+        assert!(cursor.db_id == batch.db_id);
+
+        // So what it adds next isn't skipped.
+        add_sample_data(&site, [0; 6], 123, 415, 123, "2020-04-05 16:02:19+1000");
+        let batch = site.export_sync_batch(&cursor, 10).unwrap();
+        assert!(batch.events.len() == 1);
+        central.apply_sync_batch("site-a", &batch).unwrap();
+
+        let data = get_event_range(
+            &central,
+            "00:00:00:00:00:00",
+            "2020-04-05 00:00:00+1000",
+            "2020-04-06 00:00:00+1000",
+        );
+        assert!(data.len() == 4);
+    }
+
+    #[test]
+    fn test_db_backup() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap().migrate().unwrap();
+        add_sample_data(&db, [0; 6], 123, 415, 123, "2020-04-05 13:02:19+1000");
+        add_sample_data(&db, [0; 6], 123, 415, 123, "2020-04-05 14:02:19+1000");
+
+        let path = std::env::temp_dir().join(format!("micd_backup_{}.db", std::process::id()));
+        db.backup_to(&path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let copy = Db::new(path.to_str().unwrap()).unwrap().migrate().unwrap();
+        let data = get_event_range(
+            &copy,
+            "00:00:00:00:00:00",
+            "2020-04-05 00:00:00+1000",
+            "2020-04-06 00:00:00+1000",
+        );
+        assert!(data.len() == 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_db_storage() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        check_storage(&Db::new("").unwrap().migrate().unwrap());
+    }
+
+    fn query_plan(db: &Db, sql: &str) -> String {
+        let conn = db.get_conn().unwrap();
+        let mut stmt = conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", sql))
+            .unwrap();
+        let details = stmt
+            .query_map(NO_PARAMS, |row| row.get::<usize, String>(3))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        details.join("; ")
+    }
+
+    #[test]
+    fn test_db_wal_and_query_plans() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let path = std::env::temp_dir().join(format!("micd_plans_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Db::new(path.to_str().unwrap()).unwrap().migrate().unwrap();
+
+        let mode: String = db
+            .get_conn()
+            .unwrap()
+            .query_row("PRAGMA journal_mode", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert!(mode == "wal");
+
+        // Per meter reads only visit that meter's rows, in order.
+        let plan = query_plan(
+            &db,
+            "SELECT ts, temp, ppm, hum FROM event_t WHERE mac = 'a' AND ts >= 1 AND ts < 2 ORDER BY ts ASC",
+        );
+        assert!(plan == "SEARCH event_t USING PRIMARY KEY (mac=? AND ts>? AND ts<?)");
+        let plan = query_plan(
+            &db,
+            "SELECT MIN(ts) FROM event_t WHERE mac = 'a' AND ts >= 1",
+        );
+        assert!(plan == "SEARCH event_t USING PRIMARY KEY (mac=? AND ts>?)");
+        let plan = query_plan(&db, "SELECT MAX(t) FROM history_t WHERE mac = 'a'");
+        assert!(plan == "SEARCH history_t USING PRIMARY KEY (mac=?)");
+        let plan = query_plan(
+            &db,
+            "SELECT t, ppm_avg, ppm_p50, ppm_p95 FROM hourly_t WHERE mac = 'a' AND t >= 1 AND t < 2 ORDER BY t ASC",
+        );
+        assert!(plan == "SEARCH hourly_t USING PRIMARY KEY (mac=? AND t>? AND t<?)");
+        // Purging crosses meters, so it keeps its own index.
+        let plan = query_plan(&db, "DELETE FROM event_t WHERE ts < 1");
+        assert!(plan == "SEARCH event_t USING COVERING INDEX event_t_ts_idx (ts<?)");
+
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_db_extract_reports() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let retention = Retention {
+            raw_days: 0,
+            hourly_days: 0,
+            daily_days: 0,
+        };
+        let actor = DbActor::new(open_sqlite("").unwrap(), retention, site_tz(), vec![1000]);
+        add_sample_data(
+            &*actor.db,
+            [0; 6],
+            220,
+            1200,
+            500,
+            "2020-04-05 13:00:00+1000",
+        );
+        add_sample_data(
+            &*actor.db,
+            [1; 6],
+            220,
+            600,
+            500,
+            "2020-04-06 09:00:00+1000",
+        );
+
+        // Early on the 7th, the 6th is reported on, and the meter that went
+        // quiet after the 5th isn't given empty days.
+        let now = OffsetDateTime::parse("2020-04-07 01:00:00+1000", TFMT).unwrap();
+        actor.extract_reports(&now).unwrap();
+        let day = |s| Some(OffsetDateTime::parse(s, TFMT).unwrap());
+        assert!(
+            actor.db.get_last_history_time("00:00:00:00:00:00")
+                == Ok(day("2020-04-05 00:00:00+1000"))
+        );
+        assert!(
+            actor.db.get_last_history_time("01:01:01:01:01:01")
+                == Ok(day("2020-04-06 00:00:00+1000"))
+        );
+        let hourly = actor.db.get_last_hourly_time("01:01:01:01:01:01").unwrap();
+        assert!(hourly == day("2020-04-06 09:00:00+1000"));
+    }
+
+    #[test]
+    fn test_db_import() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let retention = Retention {
+            raw_days: 0,
+            hourly_days: 0,
+            daily_days: 0,
+        };
+        let actor = DbActor::new(open_sqlite("").unwrap(), retention, site_tz(), vec![1000]);
+        add_sample_data(
+            &*actor.db,
+            [0; 6],
+            220,
+            600,
+            500,
+            "2020-04-07 13:02:19+1000",
+        );
+
+        let text = "Date,Time,CO2,Temperature,Humidity
+05/04/2020,13:00:00,1200,21.5,40
+05/04/2020,13:30:00,1400,21.5,40
+06/04/2020,09:00:00,800,21,41
+07/04/2020,13:02:19,650,22,50
+bad,row,,,
+";
+        let opts = ImportOptions {
+            mac: Some("00:00:00:00:00:00".to_string()),
+            ..Default::default()
+        };
+        let (events, skipped) = parse(text, &opts, &site_tz()).unwrap();
+        assert!(skipped == 1);
+
+        // The last reading overlaps the one we already have.
+        let summary = actor.import(events.clone(), skipped, false).unwrap();
+        assert!(!summary.imported);
+        assert!(summary.meters[0].readings == 4);
+        assert!(summary.meters[0].existing == 1);
+        assert!(summary.meters[0].reported == 0);
+        assert!(actor
+            .db
+            .get_history("00:00:00:00:00:00")
+            .unwrap()
+            .is_empty());
+
+        let summary = actor.import(events, skipped, true).unwrap();
+        assert!(summary.imported);
+        assert!(summary.meters[0].added == 3);
+
+        let history = actor.db.get_history("00:00:00:00:00:00").unwrap();
+        assert!(history.len() == 3);
+        assert!(
+            history[0].time == OffsetDateTime::parse("2020-04-05 00:00:00+1000", TFMT).unwrap()
+        );
+        assert!(history[0].ppm_avg == Some(1300));
+        assert!(history[0].minutes_above.get(&1000) == Some(&10));
+        assert!(history[1].temp_avg == Some(210));
+        // The existing reading was kept.
+        assert!(history[2].ppm_avg == Some(600));
+    }
+
+    #[test]
+    fn test_percentile() {
+        let v: Vec<u16> = (1..=100).collect();
+        assert!(percentile(&v, 50) == 50);
+        assert!(percentile(&v, 95) == 95);
+        assert!(percentile(&[7], 95) == 7);
+        assert!(percentile(&[1, 2], 50) == 1);
+    }
+
+    #[test]
+    fn test_db_has_reports() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let path = std::env::temp_dir().join(format!("micd_has_reports_{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        assert!(has_reports(&path) == Ok(false));
+        assert!(!std::path::Path::new(&path).exists());
+
+        let db = Db::new(&path).unwrap().migrate().unwrap();
+        add_sample_data(&db, [0; 6], 123, 415, 123, "2020-04-05 13:02:19+1000");
+        assert!(has_reports(&path) == Ok(false));
+        generate_report(&db, "00:00:00:00:00:00", "2020-04-06 00:00:00+1000");
+        assert!(has_reports(&path) == Ok(true));
+
+        drop(db);
+        ["", "-wal", "-shm"].iter().for_each(|ext| {
+            let _ = std::fs::remove_file(format!("{}{}", path, ext));
+        });
+    }
+
+    #[test]
+    fn test_db_hourly_rollup() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap();
+        let db = db.migrate().unwrap();
+
+        add_sample_data(&db, [0; 6], 200, 400, 500, "2020-04-05 13:02:19+1000");
+        add_sample_data(&db, [0; 6], 210, 600, 500, "2020-04-05 13:32:19+1000");
+        add_sample_data(&db, [0; 6], 220, 1400, 500, "2020-04-05 13:52:19+1000");
+        // A gap, then a partial hour.
+        add_sample_data(&db, [0; 6], 200, 400, 500, "2020-04-05 17:02:19+1000");
+        add_sample_data(&db, [0; 6], 200, 400, 500, "2020-04-05 18:02:19+1000");
+
+        let ct = OffsetDateTime::parse("2020-04-05 18:30:00+1000", TFMT).expect("invalid ts");
+        assert!(db.extract_hourly("00:00:00:00:00:00", &site_tz(), &ct) == Ok(2));
+        // Nothing new.
+        assert!(db.extract_hourly("00:00:00:00:00:00", &site_tz(), &ct) == Ok(0));
+
+        let min = OffsetDateTime::parse("2020-04-05 00:00:00+1000", TFMT).expect("invalid ts");
+        let max = OffsetDateTime::parse("2020-04-06 00:00:00+1000", TFMT).expect("invalid ts");
+        let hourly = db
+            .get_hourly_range("00:00:00:00:00:00", &min, &max)
+            .unwrap();
+        assert!(hourly.len() == 2);
+        assert!(
+            hourly[0].time
+                == OffsetDateTime::parse("2020-04-05 13:00:00+1000", TFMT).expect("invalid ts")
+        );
+        assert!(hourly[0].count == 3);
+        assert!(hourly[0].ppm_min == 400);
+        assert!(hourly[0].ppm_max == 1400);
+        assert!(hourly[0].ppm_avg == 800);
+        assert!(hourly[0].ppm_p50 == 600);
+        assert!(hourly[0].ppm_p95 == 1400);
+        assert!(hourly[0].temp_avg == 210);
+
+        // The rest of the partial hour is picked up next time.
+        let ct = OffsetDateTime::parse("2020-04-05 19:00:00+1000", TFMT).expect("invalid ts");
+        assert!(db.extract_hourly("00:00:00:00:00:00", &site_tz(), &ct) == Ok(1));
+
+        // Readings that arrive for hours already rolled up are added by a rebuild.
+        add_sample_data(&db, [0; 6], 200, 1000, 500, "2020-04-05 13:42:19+1000");
+        add_sample_data(&db, [0; 6], 200, 400, 500, "2020-04-05 15:12:19+1000");
+        let first = OffsetDateTime::parse("2020-04-05 13:42:19+1000", TFMT).expect("invalid ts");
+        let last = OffsetDateTime::parse("2020-04-05 15:12:19+1000", TFMT).expect("invalid ts");
+        assert!(db.rebuild_hourly("00:00:00:00:00:00", &site_tz(), &first, &last, &ct) == Ok(2));
+        let hourly = db
+            .get_hourly_range("00:00:00:00:00:00", &min, &max)
+            .unwrap();
+        assert!(hourly.len() == 4);
+        assert!(hourly[0].count == 4);
+
+        // And old hours can be purged.
+        let purge = OffsetDateTime::parse("2020-04-05 17:00:00+1000", TFMT).expect("invalid ts");
+        db.purge_hourly_older_than(&purge).unwrap();
+        let hourly = db
+            .get_hourly_range("00:00:00:00:00:00", &min, &max)
+            .unwrap();
+        assert!(hourly.len() == 2);
+    }
+
+    #[test]
+    fn test_db_hourly_rollup_half_hour_offset() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap().migrate().unwrap();
+        let tz: SiteTz = "Asia/Kolkata".parse().unwrap();
+
+        add_sample_data(&db, [0; 6], 200, 400, 500, "2020-04-05 13:10:00+0530");
+        add_sample_data(&db, [0; 6], 200, 600, 500, "2020-04-05 13:50:00+0530");
+        add_sample_data(&db, [0; 6], 200, 800, 500, "2020-04-05 14:10:00+0530");
+
+        let ct = OffsetDateTime::parse("2020-04-05 15:00:00+0530", TFMT).expect("invalid ts");
+        assert!(db.extract_hourly("00:00:00:00:00:00", &tz, &ct) == Ok(2));
+
+        let min = OffsetDateTime::parse("2020-04-05 00:00:00+0530", TFMT).expect("invalid ts");
+        let max = OffsetDateTime::parse("2020-04-06 00:00:00+0530", TFMT).expect("invalid ts");
+        let hourly = db
+            .get_hourly_range("00:00:00:00:00:00", &min, &max)
+            .unwrap();
+        // Bucketed on the local hour, not on the utc one.
+        assert!(hourly.len() == 2);
+        assert!(
+            hourly[0].time
+                == OffsetDateTime::parse("2020-04-05 13:00:00+0530", TFMT).expect("invalid ts")
+        );
+        assert!(hourly[0].count == 2);
+        assert!(hourly[1].count == 1);
+    }
+
+    #[test]
+    fn test_db_hourly_rollup_half_hour_dst() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap().migrate().unwrap();
+        let tz: SiteTz = "Australia/Lord_Howe".parse().unwrap();
+
+        // Daylight saving ends at 02:00+1100, when clocks go back to 01:30+1030.
+        // Nothing is read in the half hour before the first 01:30.
+        add_sample_data(&db, [0; 6], 200, 400, 500, "2020-04-05 00:10:00+1100");
+        add_sample_data(&db, [0; 6], 200, 600, 500, "2020-04-05 01:45:00+1100");
+
+        let ct = OffsetDateTime::parse("2020-04-05 02:30:00+1030", TFMT).expect("invalid ts");
+        assert!(db.extract_hourly("00:00:00:00:00:00", &tz, &ct) == Ok(2));
+
+        let min = OffsetDateTime::parse("2020-04-05 00:00:00+1100", TFMT).expect("invalid ts");
+        let max = OffsetDateTime::parse("2020-04-06 00:00:00+1030", TFMT).expect("invalid ts");
+        let hourly = db
+            .get_hourly_range("00:00:00:00:00:00", &min, &max)
+            .unwrap();
+        assert!(hourly.len() == 2);
+        assert!(
+            hourly[1].time
+                == OffsetDateTime::parse("2020-04-05 01:00:00+1030", TFMT).expect("invalid ts")
+        );
+        assert!(hourly[1].count == 1);
+    }
+
+    #[test]
+    fn test_db_report_dst() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap();
+        let db = db.migrate().unwrap();
+        let tz: SiteTz = "Australia/Sydney".parse().unwrap();
+
+        // Daylight saving ends during 2020-04-05, so both of these are on that day.
+        add_sample_data(&db, [0; 6], 100, 400, 100, "2020-04-05 00:30:00+1100");
+        add_sample_data(&db, [0; 6], 300, 600, 300, "2020-04-05 23:30:00+1000");
+        add_sample_data(&db, [0; 6], 500, 800, 500, "2020-04-06 00:30:00+1000");
+
+        let upto = OffsetDateTime::parse("2020-04-06 12:00:00+1000", TFMT).expect("invalid ts");
+        db.extract_report("00:00:00:00:00:00", &tz, &[], &upto)
+            .expect("report gen failed.");
+
+        let history = db.get_history("00:00:00:00:00:00").unwrap();
+        assert!(history.len() == 1);
+        assert!(
+            history[0].time
+                == OffsetDateTime::parse("2020-04-05 00:00:00+1100", TFMT).expect("invalid ts")
+        );
+        assert!(history[0].ppm_min == Some(400));
+        assert!(history[0].ppm_max == Some(600));
+        assert!(history[0].ppm_avg == Some(500));
+    }
+
+    #[test]
+    fn test_db_report_gaps() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap();
+        let db = db.migrate().unwrap();
+
+        // The meter was unplugged for all of the 6th.
+        add_sample_data(&db, [0; 6], 123, 415, 123, "2020-04-05 13:02:19+1000");
+        add_sample_data(&db, [0; 6], 123, 415, 123, "2020-04-07 13:02:19+1000");
+
+        generate_report(&db, "00:00:00:00:00:00", "2020-04-10 00:00:00+1000");
+
+        // Nothing is reported after the last event.
+        assert_latest_report_date(&db, "00:00:00:00:00:00", "2020-04-07 00:00:00+1000");
+
+        let history = db.get_history("00:00:00:00:00:00").unwrap();
+        assert!(history.len() == 3);
+        assert!(history[0].count == Some(1));
+        assert!(history[0].coverage == Some(0));
+        assert!(history[0].ppm_avg == Some(415));
+        assert!(history[1].count == Some(0));
+        assert!(history[1].coverage == Some(0));
+        assert!(history[1].ppm_min.is_none());
+        assert!(history[1].ppm_avg.is_none());
+        assert!(history[2].ppm_max == Some(415));
+    }
+
+    #[test]
+    fn test_coverage() {
+        let start = OffsetDateTime::parse("2020-04-05 00:00:00+1000", TFMT).expect("invalid ts");
+        let end = OffsetDateTime::parse("2020-04-05 01:40:00+1000", TFMT).expect("invalid ts");
+        // Readings cover until the next one, for at most five minutes.
+        let data: Vec<DbEvent> = [0, 30, 60, 600]
+            .iter()
+            .map(|s| DbEvent {
+                src: "00:00:00:00:00:00".to_string(),
+                time: start + std::time::Duration::from_secs(*s),
+                temp: 0,
+                ppm: 0,
+                hum: 0,
+            })
+            .collect();
+        assert!(coverage(&data, &start, &end) == 11);
+        assert!(coverage(&data[..3], &start, &end) == 6);
+        assert!(coverage(&[], &start, &end) == 0);
+    }
+
+    #[test]
+    fn test_db_report_stats() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = Db::new("").unwrap();
+        let db = db.migrate().unwrap();
+
+        add_sample_data(&db, [0; 6], 200, 600, 400, "2020-04-05 09:00:00+1000");
+        add_sample_data(&db, [0; 6], 200, 900, 400, "2020-04-05 09:01:00+1000");
+        add_sample_data(&db, [0; 6], 200, 1600, 400, "2020-04-05 09:02:00+1000");
+        add_sample_data(&db, [0; 6], 200, 1600, 400, "2020-04-05 09:02:30+1000");
+        // Averages to 1050 for the minute, so it's above 1000 but not 1500.
+        add_sample_data(&db, [0; 6], 200, 1500, 400, "2020-04-05 09:03:00+1000");
+        add_sample_data(&db, [0; 6], 200, 600, 400, "2020-04-05 09:03:30+1000");
+
+        generate_report(&db, "00:00:00:00:00:00", "2020-04-06 00:00:00+1000");
+
+        let history = db.get_history("00:00:00:00:00:00").unwrap();
+        assert!(history.len() == 1);
+        let h = &history[0];
+        assert!(h.count == Some(6));
+        assert!(h.ppm_p50 == Some(900));
+        assert!(h.ppm_p95 == Some(1600));
+        assert!(h.ppm_stddev == Some(446));
+        assert!(h.temp_stddev == Some(0));
+        assert!(
+            h.ppm_peak
+                == Some(
+                    OffsetDateTime::parse("2020-04-05 09:02:00+1000", TFMT).expect("invalid ts")
+                )
+        );
+        assert!(h.minutes_above.get(&800) == Some(&3));
+        assert!(h.minutes_above.get(&1000) == Some(&2));
+        assert!(h.minutes_above.get(&1500) == Some(&1));
+
+        // And they make it to a central instance.
+        let central = Db::new("").unwrap().migrate().unwrap();
+        let batch = db.export_sync_batch(&SyncCursor::default(), 100).unwrap();
+        central.apply_sync_batch("site-a", &batch).unwrap();
+        let history = central.get_history("00:00:00:00:00:00").unwrap();
+        assert!(history[0].ppm_p95 == Some(1600));
+        assert!(history[0].minutes_above.get(&1000) == Some(&2));
+    }
 }
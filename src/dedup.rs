@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+// The last frame we accepted from a meter.
+struct Seen {
+    raw: Vec<u8>,
+    rx: OffsetDateTime,
+    // A moving average of the gaps between two different frames, so about
+    // how often the meter reports. One stray short gap only nudges it.
+    interval: Option<time::Duration>,
+}
+
+/// Meters, and the wifi between them and us, sometimes deliver the same frame
+/// more than once. A retransmit is the same frame, byte for byte, arriving
+/// well before the meter would have sent its next reading. Two readings that
+/// happen to have the same values are both kept.
+pub struct Dedup {
+    window: Duration,
+    meters: BTreeMap<String, Seen>,
+    counts: BTreeMap<String, u64>,
+}
+
+impl Dedup {
+    /// A window of zero keeps every frame.
+    pub fn new(window: Duration) -> Self {
+        Dedup {
+            window,
+            meters: BTreeMap::new(),
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Is raw, received from mac at rx, a retransmit of the last frame we kept?
+    pub fn is_duplicate(&mut self, mac: &str, raw: &[u8], rx: OffsetDateTime) -> bool {
+        if self.window == Duration::from_secs(0) {
+            return false;
+        }
+
+        let seen = match self.meters.get_mut(mac) {
+            Some(seen) => seen,
+            None => {
+                self.meters.insert(
+                    mac.to_string(),
+                    Seen {
+                        raw: raw.to_vec(),
+                        rx,
+                        interval: None,
+                    },
+                );
+                return false;
+            }
+        };
+
+        let gap = rx - seen.rx;
+        if seen.raw.as_slice() == raw {
+            // Never collapse frames the meter could have sent as new readings.
+            let soon = match seen.interval {
+                Some(interval) => gap < interval / 2,
+                None => true,
+            };
+            if gap < self.window && soon {
+                *self.counts.entry(mac.to_string()).or_insert(0) += 1;
+                return true;
+            }
+        } else if gap > time::Duration::zero() {
+            seen.interval = Some(seen.interval.map_or(gap, |i| (i * 3 + gap) / 4));
+            seen.raw = raw.to_vec();
+        }
+        seen.rx = rx;
+        false
+    }
+
+    /// How many frames have been dropped from each meter.
+    pub fn counts(&self) -> &BTreeMap<String, u64> {
+        &self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::Dedup;
+    use std::time::Duration;
+    use time::OffsetDateTime;
+
+    const MAC: &str = "20:F8:5E:BE:29:D8";
+    const FRAME: [u8; 17] = [
+        0x20, 0xF8, 0x5E, 0xBE, 0x29, 0xD8, 0x0A, 0x01, 0x01, 0x01, 0x1B, 0x02, 0x02, 0x79, 0x03,
+        0x02, 0x9F,
+    ];
+
+    fn at(ms: i64) -> OffsetDateTime {
+        OffsetDateTime::unix_epoch() + time::Duration::milliseconds(ms)
+    }
+
+    #[test]
+    fn test_dedup_retransmits() {
+        let mut dedup = Dedup::new(Duration::from_millis(2000));
+        let mut other = FRAME;
+        // Same readings, but a different frame header.
+        other[7] = 0x02;
+
+        assert!(!dedup.is_duplicate(MAC, &FRAME, at(0)));
+        assert!(dedup.is_duplicate(MAC, &FRAME, at(100)));
+        assert!(!dedup.is_duplicate(MAC, &other, at(200)));
+        assert!(!dedup.is_duplicate("00:00:00:00:00:00", &FRAME, at(300)));
+        // Outside the window it's a new reading.
+        assert!(!dedup.is_duplicate(MAC, &other, at(2500)));
+        assert!(dedup.counts().get(MAC) == Some(&1));
+    }
+
+    #[test]
+    fn test_dedup_keeps_repeated_readings() {
+        // A window longer than the meter reports is cut to what it reports at.
+        let mut dedup = Dedup::new(Duration::from_millis(5000));
+        let mut other = FRAME;
+        other[16] = 0xA0;
+
+        assert!(!dedup.is_duplicate(MAC, &FRAME, at(0)));
+        assert!(!dedup.is_duplicate(MAC, &other, at(1000)));
+        // The meter reads the same twice in a row, a second apart.
+        assert!(!dedup.is_duplicate(MAC, &other, at(2000)));
+        assert!(dedup.is_duplicate(MAC, &other, at(2100)));
+        assert!(dedup.counts().get(MAC) == Some(&1));
+
+        // An odd frame close behind another doesn't stop retransmits being
+        // caught from then on.
+        assert!(!dedup.is_duplicate(MAC, &FRAME, at(2150)));
+        assert!(!dedup.is_duplicate(MAC, &other, at(3150)));
+        assert!(dedup.is_duplicate(MAC, &other, at(3250)));
+        assert!(dedup.counts().get(MAC) == Some(&2));
+
+        let mut off = Dedup::new(Duration::from_millis(0));
+        assert!(!off.is_duplicate(MAC, &FRAME, at(0)));
+        assert!(!off.is_duplicate(MAC, &FRAME, at(0)));
+        assert!(off.counts().is_empty());
+    }
+}
@@ -0,0 +1,458 @@
+//! Readings recorded before micd, from csv files or the exports of the
+//! meters' own logging software.
+
+use chrono::{DateTime, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use time::OffsetDateTime;
+
+use crate::db::{serialize_ts, DbEvent};
+use crate::tz::SiteTz;
+
+// Exports have a few lines about the logger before their header.
+const HEADER_SEARCH_LINES: usize = 50;
+
+const FIELDS: &[&str] = &["mac", "ts", "ppm", "hum", "temp"];
+
+// Times with an offset, then local times in the site's timezone. Dates
+// with slashes or dots are read day first.
+const OFFSET_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S%z", "%Y-%m-%d %H:%M:%S%.f%z"];
+const LOCAL_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%Y/%m/%d %H:%M:%S",
+    "%Y/%m/%d %H:%M",
+    "%d/%m/%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M",
+    "%d.%m.%Y %H:%M:%S",
+    "%d.%m.%Y %H:%M",
+];
+
+/// How to read an import. The same options are taken by `micd import` and
+/// the admin endpoint.
+#[derive(Debug, Default, Deserialize)]
+pub struct ImportOptions {
+    /// Every reading is from this meter, for exports that don't name one.
+    pub mac: Option<String>,
+    /// Header names for fields that aren't recognised, as `ppm=CO2 Level,temp=T1`.
+    pub columns: Option<String>,
+    /// Import readings for periods a meter already has data for.
+    #[serde(default)]
+    pub merge: bool,
+}
+
+/// What an import found for one meter.
+#[derive(Debug, Serialize)]
+pub struct ImportMeter {
+    pub mac: String,
+    #[serde(serialize_with = "serialize_ts")]
+    pub first: OffsetDateTime,
+    #[serde(serialize_with = "serialize_ts")]
+    pub last: OffsetDateTime,
+    pub readings: usize,
+    /// Readings already stored between first and last.
+    pub existing: usize,
+    /// Days between first and last that were already reported.
+    pub reported: usize,
+    pub added: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportSummary {
+    /// Rows that couldn't be read.
+    pub skipped: usize,
+    pub meters: Vec<ImportMeter>,
+    /// False if an overlap stopped the import, and nothing was added.
+    pub imported: bool,
+}
+
+impl ImportSummary {
+    pub fn overlaps(&self) -> bool {
+        self.meters.iter().any(|m| m.existing > 0 || m.reported > 0)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Columns {
+    mac: Option<usize>,
+    ts: Option<usize>,
+    // Some exports split the time over two columns.
+    date: Option<usize>,
+    time: Option<usize>,
+    ppm: Option<usize>,
+    hum: Option<usize>,
+    temp: Option<usize>,
+    fahrenheit: bool,
+}
+
+impl Columns {
+    fn has_time(&self) -> bool {
+        self.ts.is_some() || self.date.is_some() || self.time.is_some()
+    }
+}
+
+fn normalise(header: &str) -> String {
+    header
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn recognise(header: &[String], names: &BTreeMap<String, String>) -> Columns {
+    let mut cols = Columns::default();
+    header.iter().enumerate().for_each(|(i, h)| {
+        let slot = match normalise(h).as_str() {
+            "mac" | "macaddress" | "device" | "deviceid" | "meter" | "src" => &mut cols.mac,
+            "ts" | "timestamp" | "datetime" | "dateandtime" => &mut cols.ts,
+            "date" | "day" => &mut cols.date,
+            "time" | "clock" => &mut cols.time,
+            "ppm" | "co2" | "co2ppm" | "carbondioxide" | "carbondioxideppm" => &mut cols.ppm,
+            "hum" | "humidity" | "rh" | "humidityrh" | "relativehumidity" => &mut cols.hum,
+            "temp" | "temperature" | "tempc" | "temperaturec" => &mut cols.temp,
+            "tempf" | "temperaturef" => {
+                cols.fahrenheit = true;
+                &mut cols.temp
+            }
+            _ => return,
+        };
+        slot.get_or_insert(i);
+    });
+
+    // Names that were given override what we recognised.
+    names.iter().for_each(|(field, name)| {
+        let i = header
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name.trim()));
+        match field.as_str() {
+            "mac" => cols.mac = i,
+            "ts" => {
+                cols.ts = i;
+                cols.date = None;
+                cols.time = None;
+            }
+            "ppm" => cols.ppm = i,
+            "hum" => cols.hum = i,
+            "temp" => {
+                cols.temp = i;
+                cols.fahrenheit = false;
+            }
+            _ => {}
+        }
+    });
+    cols
+}
+
+/// Parse `field=header,..` into a map of field to header name.
+pub fn parse_columns(s: &str) -> Result<BTreeMap<String, String>, ()> {
+    s.split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(|p| {
+            let mut kv = p.splitn(2, '=');
+            match (kv.next().map(|k| k.trim().to_lowercase()), kv.next()) {
+                (Some(field), Some(name)) if FIELDS.contains(&field.as_str()) => {
+                    Ok((field, name.trim().to_string()))
+                }
+                _ => {
+                    error!(
+                        "Invalid column mapping {:?}, expected one of {:?} = header",
+                        p, FIELDS
+                    );
+                    Err(())
+                }
+            }
+        })
+        .collect()
+}
+
+// Fields may be quoted, and quotes in them doubled.
+fn split_record(line: &str, delim: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            c if c == delim && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// The canonical form of a mac, as meters send it.
+pub fn parse_mac(s: &str) -> Option<String> {
+    let hex: String = s
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.' | ' '))
+        .collect();
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(
+        hex.to_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|p| String::from_utf8_lossy(p).into_owned())
+            .collect::<Vec<_>>()
+            .join(":"),
+    )
+}
+
+fn parse_time(s: &str, tz: &SiteTz) -> Option<OffsetDateTime> {
+    let s = s.trim();
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+        // Seconds since the epoch, or milliseconds if they're too far out.
+        let n: i64 = s.parse().ok()?;
+        let ms = if n >= 100_000_000_000 { n } else { n * 1000 };
+        return Some(
+            OffsetDateTime::from_unix_timestamp(ms.div_euclid(1000))
+                + time::Duration::milliseconds(ms.rem_euclid(1000)),
+        );
+    }
+
+    let fixed = DateTime::parse_from_rfc3339(s).ok().or_else(|| {
+        OFFSET_FORMATS
+            .iter()
+            .find_map(|f| DateTime::parse_from_str(s, f).ok())
+    });
+    if let Some(t) = fixed {
+        return Some(
+            OffsetDateTime::from_unix_timestamp(t.timestamp())
+                + time::Duration::milliseconds(t.timestamp_subsec_millis() as i64),
+        );
+    }
+
+    LOCAL_FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .and_then(|naive| tz.instant_of(&naive))
+}
+
+fn parse_value(s: &str, decimal_comma: bool) -> Option<f64> {
+    let s = s.trim();
+    if decimal_comma {
+        s.replace(',', ".").parse().ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+// Values are stored as the meters send them, humidity and temperature in tenths.
+fn to_stored(v: f64, scale: f64, max: f64) -> Option<u16> {
+    let v = (v * scale).round();
+    if v.is_finite() && v >= 0.0 && v <= max {
+        Some(v as u16)
+    } else {
+        None
+    }
+}
+
+/// Read the events in a csv file or logger export. Rows that can't be read
+/// are counted and skipped, but there must be a header that names a time
+/// and ppm, humidity and temperature for each reading.
+pub fn parse(text: &str, opts: &ImportOptions, tz: &SiteTz) -> Result<(Vec<DbEvent>, usize), ()> {
+    let names = match &opts.columns {
+        Some(s) => parse_columns(s)?,
+        None => BTreeMap::new(),
+    };
+    let mac = match &opts.mac {
+        Some(m) => Some(parse_mac(m).ok_or_else(|| {
+            error!("Invalid mac {:?}", m);
+        })?),
+        None => None,
+    };
+
+    let text = text.trim_start_matches('\u{feff}');
+    let mut lines = text.lines().enumerate();
+
+    let (delim, cols) = lines
+        .by_ref()
+        .take(HEADER_SEARCH_LINES)
+        .find_map(|(_, line)| {
+            ['\t', ';', ','].iter().find_map(|d| {
+                let header = split_record(line, *d);
+                let cols = recognise(&header, &names);
+                if header.len() > 1 && cols.has_time() && cols.ppm.is_some() {
+                    Some((*d, cols))
+                } else {
+                    None
+                }
+            })
+        })
+        .ok_or_else(|| {
+            error!("No header naming a time and co2 ppm was found, try --columns");
+        })?;
+    debug!("import delimiter {:?} columns {:?}", delim, cols);
+
+    if cols.hum.is_none() || cols.temp.is_none() {
+        error!("The import needs humidity and temperature columns, try --columns");
+        return Err(());
+    }
+    if cols.mac.is_none() && mac.is_none() {
+        error!("The import doesn't name a meter, try --mac");
+        return Err(());
+    }
+    // Comma separated files can't also use decimal commas.
+    let decimal_comma = delim != ',';
+
+    let mut skipped = 0;
+    let events = lines
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(n, line)| {
+            let row = split_record(line, delim);
+            let get = |i: Option<usize>| i.and_then(|i| row.get(i)).map(|s| s.as_str());
+
+            let time = match (get(cols.ts), get(cols.date), get(cols.time)) {
+                (Some(ts), _, _) => parse_time(ts, tz),
+                (None, Some(d), Some(t)) => parse_time(&format!("{} {}", d.trim(), t.trim()), tz),
+                (None, Some(dt), None) | (None, None, Some(dt)) => parse_time(dt, tz),
+                _ => None,
+            };
+            let value = |i: Option<usize>| get(i).and_then(|s| parse_value(s, decimal_comma));
+            let temp = value(cols.temp).map(|t| {
+                if cols.fahrenheit {
+                    (t - 32.0) * 5.0 / 9.0
+                } else {
+                    t
+                }
+            });
+            let src = match &mac {
+                Some(m) => Some(m.clone()),
+                None => get(cols.mac).and_then(parse_mac),
+            };
+
+            let ppm = value(cols.ppm).and_then(|v| to_stored(v, 1.0, 65535.0));
+            let hum = value(cols.hum).and_then(|v| to_stored(v, 10.0, 1000.0));
+            let temp = temp.and_then(|v| to_stored(v, 10.0, 65535.0));
+
+            let event = match (src, time, ppm, hum, temp) {
+                (Some(src), Some(time), Some(ppm), Some(hum), Some(temp)) => Some(DbEvent {
+                    src,
+                    time,
+                    temp,
+                    ppm,
+                    hum,
+                }),
+                _ => None,
+            };
+            if event.is_none() {
+                warn!("Skipping line {} -> {:?}", n + 1, line);
+                skipped += 1;
+            }
+            event
+        })
+        .collect::<Vec<_>>();
+
+    if events.is_empty() {
+        error!("No readings were found to import");
+        return Err(());
+    }
+    Ok((events, skipped))
+}
+
+/// Readings grouped by meter, oldest first.
+pub fn by_meter(events: Vec<DbEvent>) -> BTreeMap<String, Vec<DbEvent>> {
+    let mut meters: BTreeMap<String, Vec<DbEvent>> = BTreeMap::new();
+    events
+        .into_iter()
+        .for_each(|e| meters.entry(e.src.clone()).or_default().push(e));
+    meters
+        .values_mut()
+        .for_each(|events| events.sort_by_key(|e| e.time));
+    meters
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::TFMT;
+    use crate::import::{parse, parse_columns, parse_mac, ImportOptions};
+    use crate::tz::SiteTz;
+    use time::OffsetDateTime;
+
+    fn ts(s: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(s, TFMT).expect("invalid ts")
+    }
+
+    fn tz() -> SiteTz {
+        "Australia/Sydney".parse().unwrap()
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let text = "\u{feff}mac,ts,ppm,hum,temp
+20:f8:5e:be:29:d8,2020-04-05 13:02:19+1000,415,45.5,22.1
+20-F8-5E-BE-29-D8,1586055769,\"900\",50,-1
+not a mac,2020-04-05 13:03:19+1000,415,45.5,22.1
+
+20:F8:5E:BE:29:D8,2020-04-05T03:03:49Z,1200,50,23
+";
+        let (events, skipped) = parse(text, &ImportOptions::default(), &tz()).unwrap();
+        // A negative temperature can't be stored, and the mac isn't one.
+        assert!(skipped == 2);
+        assert!(events.len() == 2);
+        assert!(events[0].src == "20:F8:5E:BE:29:D8");
+        assert!(events[0].time == ts("2020-04-05 13:02:19+1000"));
+        assert!(events[0].hum == 455);
+        assert!(events[0].temp == 221);
+        assert!(events[1].time == ts("2020-04-05 13:03:49+1000"));
+        assert!(events[1].ppm == 1200);
+    }
+
+    #[test]
+    fn test_import_vendor_export() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        // Local times across the end of daylight saving, with decimal commas.
+        let text = "Logger: CO2 Monitor
+Interval: 30 min
+
+Date;Time;CO2(ppm);Temperature(\u{b0}F);Humidity(%RH)
+05.04.2020;02:30:00;612;71,6;48,2
+05.04.2020;03:30:00;640;72,5;47,9
+";
+        let opts = ImportOptions {
+            mac: Some("20f85ebe29d8".to_string()),
+            ..Default::default()
+        };
+        let (events, skipped) = parse(text, &opts, &tz()).unwrap();
+        assert!(skipped == 0);
+        assert!(events.len() == 2);
+        assert!(events[0].src == "20:F8:5E:BE:29:D8");
+        assert!(events[0].time == ts("2020-04-05 02:30:00+1100"));
+        assert!(events[1].time == ts("2020-04-05 03:30:00+1000"));
+        assert!(events[0].temp == 220);
+        assert!(events[0].hum == 482);
+
+        // Without a meter, or with columns we don't know, nothing is read.
+        assert!(parse(text, &ImportOptions::default(), &tz()).is_err());
+        let text = "Zeit\tKohlendioxid\tFeuchte\tT1\n2020-04-05 12:00\t500\t40\t20\n";
+        assert!(parse(text, &opts, &tz()).is_err());
+        let opts = ImportOptions {
+            columns: Some("ts=Zeit, ppm=Kohlendioxid,hum=Feuchte,temp=T1".to_string()),
+            ..opts
+        };
+        let (events, _) = parse(text, &opts, &tz()).unwrap();
+        assert!(events[0].time == ts("2020-04-05 12:00:00+1000"));
+        assert!(events[0].ppm == 500);
+    }
+
+    #[test]
+    fn test_import_names() {
+        assert!(parse_mac("20:f8:5e:be:29:d8") == Some("20:F8:5E:BE:29:D8".to_string()));
+        assert!(parse_mac("20:f8:5e:be:29").is_none());
+        assert!(parse_mac("20:f8:5e:be:29:zz").is_none());
+        assert!(parse_columns("ppm=CO2,temp=T").unwrap().len() == 2);
+        assert!(parse_columns("co2=CO2").is_err());
+        assert!(parse_columns("ppm").is_err());
+    }
+}
@@ -1,19 +1,88 @@
 use crate::db;
+use crate::render::RenderActor;
+use crate::report;
+use crate::stop::{stop_when_idle, StopEvent};
+use crate::tz::SiteTz;
 use actix::prelude::*;
 use std::time::Duration;
+use time::OffsetDateTime;
 
 const PURGE_FREQUENCY: u64 = 14400;
+const ROLLUP_FREQUENCY: u64 = 900;
+const REPORT_FREQUENCY: u64 = 3600;
 
 pub struct IntervalActor {
-    pub db_addr: Addr<db::DbActor>,
+    db_addr: Addr<db::DbActor>,
+    render_addr: Addr<RenderActor>,
+    tz: SiteTz,
+    // If unset, no reports are made on a schedule.
+    reports: Option<report::Schedule>,
+    reporting: bool,
+    // Once set, nothing new is started.
+    stopping: bool,
 }
 
 impl IntervalActor {
+    pub fn new(
+        db_addr: Addr<db::DbActor>,
+        render_addr: Addr<RenderActor>,
+        tz: SiteTz,
+        reports: Option<report::Schedule>,
+    ) -> Self {
+        IntervalActor {
+            db_addr,
+            render_addr,
+            tz,
+            reports,
+            reporting: false,
+            stopping: false,
+        }
+    }
+
     fn purge(&mut self) {
+        if self.stopping {
+            return;
+        }
         info!("Attempting db purge ...");
         // Make a purge request ...
         self.db_addr.do_send(db::DbPurgeEvent)
     }
+
+    fn rollup(&mut self) {
+        if self.stopping {
+            return;
+        }
+        debug!("Attempting hourly rollup ...");
+        self.db_addr.do_send(db::DbRollupEvent)
+    }
+
+    fn report(&mut self, ctx: &mut Context<Self>) {
+        let schedule = match (&self.reports, self.reporting || self.stopping) {
+            (Some(schedule), false) => schedule.clone(),
+            _ => return,
+        };
+        debug!("Checking for reports to make ...");
+        self.reporting = true;
+
+        let fut = report::archive(
+            self.db_addr.clone(),
+            self.render_addr.clone(),
+            self.tz,
+            schedule,
+            OffsetDateTime::now(),
+        );
+        ctx.spawn(fut.into_actor(self).map(|r, act, ctx| {
+            match r {
+                Ok(0) => {}
+                Ok(made) => info!("Made {} reports", made),
+                Err(_) => error!("Failed to make reports, will try again later"),
+            };
+            act.reporting = false;
+            if act.stopping {
+                ctx.stop();
+            }
+        }));
+    }
 }
 
 impl Actor for IntervalActor {
@@ -24,5 +93,19 @@ impl Actor for IntervalActor {
         ctx.run_interval(Duration::from_secs(PURGE_FREQUENCY), move |act, _ctx| {
             act.purge();
         });
+        ctx.run_interval(Duration::from_secs(ROLLUP_FREQUENCY), move |act, _ctx| {
+            act.rollup();
+        });
+        ctx.run_interval(Duration::from_secs(REPORT_FREQUENCY), move |act, ctx| {
+            act.report(ctx);
+        });
+    }
+}
+
+impl Handler<StopEvent> for IntervalActor {
+    type Result = bool;
+
+    fn handle(&mut self, _msg: StopEvent, ctx: &mut Context<Self>) -> bool {
+        stop_when_idle("scheduled tasks", &mut self.stopping, self.reporting, ctx)
     }
 }
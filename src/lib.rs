@@ -4,6 +4,7 @@ extern crate log;
 extern crate nom;
 
 pub mod proto;
+pub mod segment;
 
 pub mod prelude {
     pub use crate::proto::*;
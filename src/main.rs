@@ -2,102 +2,545 @@
 extern crate log;
 
 use actix::prelude::*;
+use futures_util::future::{select, Either};
+use futures_util::pin_mut;
 use futures_util::stream::StreamExt;
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::{Ipv4Addr, SocketAddr};
 use std::str::FromStr;
+use std::sync::Arc;
 use tokio::net::UdpSocket;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio_util::udp::UdpFramed;
 
 use actix_files as fs;
-use actix_web::web::{self, Data, HttpResponse};
-use actix_web::{middleware, App, HttpServer};
+use actix_web::web::{self, Data, HttpResponse, Json, Path};
+use actix_web::{middleware, App, HttpRequest, HttpServer};
 use askama::Template;
+use serde::Deserialize;
 
-use std::time::Duration;
+use std::time::Instant;
 use time::OffsetDateTime;
 
 use mic::prelude::*;
 
+mod backup;
+mod canvas;
+mod chart;
+mod config;
+#[macro_use]
 mod db;
+mod dedup;
+mod import;
 mod interval;
+mod migrations;
+mod pdf;
+#[cfg(feature = "postgres")]
+mod pg;
+#[cfg(feature = "gnuplot")]
+mod plot;
+mod png;
+mod relay;
 mod render;
+mod report;
+mod segstore;
+mod stop;
+mod storage;
+mod sync;
+mod tz;
 
 /* == FE web server == */
 
 struct AppState {
     render_addr: Addr<render::RenderActor>,
+    render_cache: Arc<render::RenderCache>,
     db_addr: Addr<db::DbActor>,
+    server_addr: Addr<Server>,
+    sync_key: Option<String>,
+    admin_key: Option<String>,
+    tz: tz::SiteTz,
 }
 
 #[derive(Template)]
 #[template(path = "index.html")]
-struct IndexTemplate {}
+struct IndexTemplate {
+    mac: &'static str,
+    // The range asked for, passed on to the readings charts.
+    query: String,
+}
 
 async fn status_view() -> HttpResponse {
     HttpResponse::Ok().content_type("text/html").body("OK")
 }
 
-async fn index_view(state: Data<AppState>) -> HttpResponse {
-    // Show last day in detail
-    let ct = OffsetDateTime::now_local();
-    let lower = ct - Duration::from_secs(86400);
+async fn metrics_view(state: Data<AppState>) -> HttpResponse {
+    let duplicates = match state.server_addr.send(ServerStats).await {
+        Ok(d) => d,
+        Err(_) => {
+            error!("udp listener unable to complete!");
+            return HttpResponse::InternalServerError()
+                .content_type("text/plain")
+                .body("udp listener failure");
+        }
+    };
+
+    let body: String = duplicates
+        .iter()
+        .map(|(mac, count)| format!("micd_duplicate_frames_total{{mac=\"{}\"}} {}\n", mac, count))
+        .collect();
+    HttpResponse::Ok().content_type("text/plain").body(body)
+}
 
-    // get_history
+async fn sync_cursor_view(
+    state: Data<AppState>,
+    req: HttpRequest,
+    site: Path<String>,
+) -> HttpResponse {
+    if !sync::authorised(&req, state.sync_key.as_deref()) {
+        return HttpResponse::Unauthorized().finish();
+    }
 
-    let data = match state
+    match state
         .db_addr
-        .send(db::DbEventRange {
-            src: "20:F8:5E:BE:29:D8".to_string(),
-            max: ct,
-            min: lower,
+        .send(db::DbSyncCursor {
+            site: site.into_inner(),
         })
         .await
     {
-        Ok(Ok(d)) => d,
+        Ok(Ok(cursor)) => HttpResponse::Ok().json(cursor),
         _ => {
             error!("db unable to complete!");
-            return HttpResponse::InternalServerError()
-                .content_type("text/html")
-                .body("db failure");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn sync_push_view(
+    state: Data<AppState>,
+    req: HttpRequest,
+    site: Path<String>,
+    batch: Json<sync::SyncBatch>,
+) -> HttpResponse {
+    if !sync::authorised(&req, state.sync_key.as_deref()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let site = site.into_inner();
+    let macs: BTreeSet<String> = batch
+        .events
+        .iter()
+        .map(|e| e.mac.clone())
+        .chain(batch.history.iter().map(|h| h.mac.clone()))
+        .collect();
+    info!(
+        "Receiving {} events and {} history from {}",
+        batch.events.len(),
+        batch.history.len(),
+        site
+    );
+
+    match state
+        .db_addr
+        .send(db::DbSyncApply {
+            site,
+            batch: batch.into_inner(),
+        })
+        .await
+    {
+        Ok(Ok(cursor)) => {
+            macs.iter()
+                .for_each(|mac| state.render_cache.invalidate_meter(mac));
+            HttpResponse::Ok().json(cursor)
+        }
+        _ => {
+            error!("db unable to complete!");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn admin_backup_view(req: HttpRequest, state: Data<AppState>) -> HttpResponse {
+    if !sync::authorised(&req, state.admin_key.as_deref()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let name = backup::backup_name(OffsetDateTime::now());
+    let path = std::env::temp_dir().join(format!("{}.{}", std::process::id(), name));
+
+    match state
+        .db_addr
+        .send(db::DbBackup { path: path.clone() })
+        .await
+    {
+        Ok(Ok(_)) => {}
+        _ => {
+            error!("db unable to complete!");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let data = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+
+    match data {
+        Ok(data) => HttpResponse::Ok()
+            .content_type("application/vnd.sqlite3")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", name),
+            )
+            .body(data),
+        Err(e) => {
+            error!("Unable to read backup {:?} -> {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn admin_import_view(
+    req: HttpRequest,
+    state: Data<AppState>,
+    opts: web::Query<import::ImportOptions>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if !sync::authorised(&req, state.admin_key.as_deref()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let text = String::from_utf8_lossy(&body);
+    let (events, skipped) = match import::parse(&text, &opts, &state.tz) {
+        Ok(r) => r,
+        Err(_) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain")
+                .body("unable to read import, see the micd log")
         }
     };
+    info!(
+        "Importing {} readings, {} rows skipped",
+        events.len(),
+        skipped
+    );
 
-    let history = match state
+    match state
         .db_addr
-        .send(db::DbHistory {
-            src: "20:F8:5E:BE:29:D8".to_string(),
+        .send(db::DbImport {
+            events,
+            skipped,
+            merge: opts.merge,
         })
         .await
     {
-        Ok(Ok(d)) => d,
+        Ok(Ok(summary)) if summary.imported => {
+            summary
+                .meters
+                .iter()
+                .for_each(|m| state.render_cache.invalidate_meter(&m.mac));
+            HttpResponse::Ok().json(summary)
+        }
+        Ok(Ok(summary)) => HttpResponse::Conflict().json(summary),
         _ => {
             error!("db unable to complete!");
-            return HttpResponse::InternalServerError()
-                .content_type("text/html")
-                .body("db failure");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn admin_label_view(
+    req: HttpRequest,
+    state: Data<AppState>,
+    mac: Path<String>,
+    body: String,
+) -> HttpResponse {
+    if !sync::authorised(&req, state.admin_key.as_deref()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let src = match import::parse_mac(&mac) {
+        Some(src) => src,
+        None => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain")
+                .body("invalid mac")
         }
     };
+    // An empty label removes it.
+    let label = Some(body.trim().to_string()).filter(|l| !l.is_empty());
 
-    let r = state
-        .render_addr
-        .send(render::RenderEvent {
-            src: "20:F8:5E:BE:29:D8".to_string(),
-            data,
-            history,
+    match state
+        .db_addr
+        .send(db::DbSetMeterLabel {
+            src: src.clone(),
+            label,
         })
-        .await;
+        .await
+    {
+        Ok(Ok(())) => {
+            state.render_cache.invalidate_meter(&src);
+            HttpResponse::Ok().finish()
+        }
+        _ => {
+            error!("db unable to complete!");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn history_view(state: Data<AppState>, mac: Path<String>) -> HttpResponse {
+    match state
+        .db_addr
+        .send(db::DbHistory {
+            src: mac.into_inner().to_uppercase(),
+        })
+        .await
+    {
+        Ok(Ok(history)) => HttpResponse::Ok().json(history),
+        _ => {
+            error!("db unable to complete!");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RenderRange {
+    // Epoch milliseconds.
+    from: Option<i64>,
+    to: Option<i64>,
+    // Back from to without a from, such as 6h or 7d.
+    range: Option<String>,
+}
 
-    match r {
-        Ok(_) => info!("Render thread complete, sending ..."),
+// The range back from to, once from, to and range are checked to be a span
+// we can draw.
+fn parse_last(
+    from: Option<i64>,
+    to: Option<i64>,
+    range: &Option<String>,
+) -> Result<Option<i64>, HttpResponse> {
+    range
+        .as_deref()
+        .map(render::parse_range)
+        .transpose()
+        .and_then(|last| render::check_span(from, to, last).map(|_| last))
+        .map_err(|_| {
+            HttpResponse::BadRequest()
+                .content_type("text/plain")
+                .body("invalid range")
+        })
+}
+
+// A chart name, such as ppm.png. Charts without a format are svg.
+fn parse_chart(chart: &str) -> Result<(render::ChartKind, canvas::Format), ()> {
+    let (kind, format) = match chart.rfind('.') {
+        Some(i) => (&chart[..i], chart[i + 1..].parse()?),
+        None => (chart, canvas::Format::Svg),
+    };
+    kind.parse().map(|kind| (kind, format))
+}
+
+async fn render_view(
+    state: Data<AppState>,
+    path: Path<(String, String)>,
+    range: web::Query<RenderRange>,
+) -> HttpResponse {
+    let (mac, chart) = path.into_inner();
+    let src = match import::parse_mac(&mac) {
+        Some(src) => src,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let (kind, format) = match parse_chart(&chart) {
+        Ok(chart) => chart,
+        Err(_) => return HttpResponse::NotFound().finish(),
+    };
+
+    let last = match parse_last(range.from, range.to, &range.range) {
+        Ok(last) => last,
+        Err(resp) => return resp,
+    };
+
+    let key = render::RenderKey {
+        srcs: vec![src],
+        kind,
+        format,
+        from: range.from,
+        to: range.to,
+        last,
+    };
+    serve_chart(&state, key).await
+}
+
+#[derive(Deserialize)]
+struct CompareQuery {
+    // Comma separated, every meter if unset.
+    macs: Option<String>,
+    from: Option<i64>,
+    to: Option<i64>,
+    range: Option<String>,
+}
+
+async fn compare_view(
+    state: Data<AppState>,
+    chart: Path<String>,
+    query: web::Query<CompareQuery>,
+) -> HttpResponse {
+    let (kind, format) = match parse_chart(&chart) {
+        // Too many lines to compare, minutes_above_{ppm} compares one band.
+        // Heatmaps are of one meter.
+        Ok((render::ChartKind::PpmBandsHistory, _))
+        | Ok((render::ChartKind::Heatmap(..), _))
+        | Err(_) => return HttpResponse::NotFound().finish(),
+        Ok(chart) => chart,
+    };
+    let srcs: Option<BTreeSet<String>> = query
+        .macs
+        .as_deref()
+        .unwrap_or("")
+        .split(',')
+        .filter(|m| !m.trim().is_empty())
+        .map(import::parse_mac)
+        .collect();
+    let srcs = match srcs {
+        Some(srcs) => srcs.into_iter().collect(),
+        None => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain")
+                .body("invalid mac")
+        }
+    };
+
+    let last = match parse_last(query.from, query.to, &query.range) {
+        Ok(last) => last,
+        Err(resp) => return resp,
+    };
+
+    let key = render::RenderKey {
+        srcs,
+        kind,
+        format,
+        from: query.from,
+        to: query.to,
+        last,
+    };
+    serve_chart(&state, key).await
+}
+
+async fn serve_chart(state: &AppState, key: render::RenderKey) -> HttpResponse {
+    let content_type = key.format.content_type();
+    if let Some(data) = state.render_cache.get(&key) {
+        return HttpResponse::Ok().content_type(content_type).body(data);
+    }
+
+    // Only the first request for a chart waits for it, after that it's
+    // kept up to date in the background.
+    let started = Instant::now();
+    match render::draw(
+        state.db_addr.clone(),
+        state.render_addr.clone(),
+        key.clone(),
+    )
+    .await
+    {
+        Ok(Some(data)) => {
+            state.render_cache.insert(key, started, data.clone());
+            HttpResponse::Ok().content_type(content_type).body(data)
+        }
+        Ok(None) => HttpResponse::NotFound()
+            .content_type("text/plain")
+            .body("no data in range"),
+        Err(_) => HttpResponse::InternalServerError()
+            .content_type("text/plain")
+            .body("renderer failure"),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    // week or month, and week if unset.
+    period: Option<String>,
+    // The report is of the last whole period before this, or now.
+    to: Option<i64>,
+}
+
+async fn report_site_view(state: Data<AppState>, query: web::Query<ReportQuery>) -> HttpResponse {
+    serve_report(&state, None, &query).await
+}
+
+async fn report_view(
+    state: Data<AppState>,
+    name: Path<String>,
+    query: web::Query<ReportQuery>,
+) -> HttpResponse {
+    match name.strip_suffix(".pdf").and_then(import::parse_mac) {
+        Some(src) => serve_report(&state, Some(vec![src]), &query).await,
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn serve_report(
+    state: &AppState,
+    srcs: Option<Vec<String>>,
+    query: &ReportQuery,
+) -> HttpResponse {
+    let period = match query.period.as_deref().unwrap_or("week").parse() {
+        Ok(period) => period,
         Err(_) => {
-            error!("render unable to complete!");
-            return HttpResponse::InternalServerError()
-                .content_type("text/html")
-                .body("renderer failure");
+            return HttpResponse::BadRequest()
+                .content_type("text/plain")
+                .body("invalid period")
+        }
+    };
+    let to = match query.to.map(render::check_time).transpose() {
+        Ok(to) => to
+            .map(|ms| ts_from_db!(ms))
+            .unwrap_or_else(OffsetDateTime::now),
+        Err(_) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain")
+                .body("invalid to")
         }
     };
 
-    let t = IndexTemplate {};
+    match report::build(
+        state.db_addr.clone(),
+        state.render_addr.clone(),
+        state.tz,
+        srcs,
+        period,
+        to,
+    )
+    .await
+    {
+        Ok(Some(pdf)) => HttpResponse::Ok()
+            .content_type(canvas::Format::Pdf.content_type())
+            .body(pdf),
+        Ok(None) => HttpResponse::NotFound()
+            .content_type("text/plain")
+            .body("no data in period"),
+        Err(_) => HttpResponse::InternalServerError()
+            .content_type("text/plain")
+            .body("report failure"),
+    }
+}
+
+async fn index_view(range: web::Query<RenderRange>) -> HttpResponse {
+    if let Err(resp) = parse_last(range.from, range.to, &range.range) {
+        return resp;
+    }
+    let query: Vec<String> = range
+        .from
+        .map(|from| format!("from={}", from))
+        .into_iter()
+        .chain(range.to.map(|to| format!("to={}", to)))
+        .chain(range.range.as_ref().map(|r| format!("range={}", r)))
+        .collect();
+    let t = IndexTemplate {
+        mac: "20:F8:5E:BE:29:D8",
+        query: if query.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", query.join("&"))
+        },
+    };
     match t.render() {
         Ok(s) => HttpResponse::Ok().content_type("text/html").body(s),
         Err(_e) => HttpResponse::InternalServerError()
@@ -110,6 +553,11 @@ async fn index_view(state: Data<AppState>) -> HttpResponse {
 
 struct Server {
     db_addr: Addr<db::DbActor>,
+    // The last sequence number issued to each meter.
+    seqs: BTreeMap<String, i64>,
+    dedup: dedup::Dedup,
+    relay: Option<relay::Relay>,
+    render_cache: Arc<render::RenderCache>,
 }
 
 impl Actor for Server {
@@ -118,7 +566,7 @@ impl Actor for Server {
 
 #[derive(Message)]
 #[rtype(result = "()")]
-struct UdpEvent(pub Option<(MicFrame, SocketAddr)>);
+struct UdpEvent(pub Option<(MicFrame, SocketAddr)>, pub OffsetDateTime);
 
 impl Handler<UdpEvent> for Server {
     type Result = ();
@@ -127,7 +575,24 @@ impl Handler<UdpEvent> for Server {
         match msg.0 {
             Some((frame, addr)) => {
                 debug!("{:?} <- {:?}", frame, addr);
-                self.db_addr.do_send(db::DbAddDatumEvent(frame.data, addr));
+                let mac = frame.data.mac_as_string();
+                // Relay everything we receive, downstream can make its own decisions.
+                if let Some(relay) = &self.relay {
+                    relay.forward(&mac, &frame.raw);
+                }
+                if self.dedup.is_duplicate(&mac, &frame.raw, msg.1) {
+                    debug!("dropping duplicate frame from {} <- {:?}", mac, addr);
+                    return;
+                }
+                let seq = self.seqs.entry(mac.clone()).or_insert(0);
+                *seq += 1;
+                self.db_addr.do_send(db::DbAddDatumEvent {
+                    datum: frame.data,
+                    addr,
+                    rx: msg.1,
+                    seq: *seq,
+                });
+                self.render_cache.invalidate(&mac, ts_to_db!(msg.1));
             }
             _ => {
                 error!("An invalid frame was recieved");
@@ -136,83 +601,570 @@ impl Handler<UdpEvent> for Server {
     }
 }
 
+#[derive(Message)]
+#[rtype(result = "BTreeMap<String, u64>")]
+struct ServerStats;
+
+impl Handler<ServerStats> for Server {
+    type Result = MessageResult<ServerStats>;
+
+    fn handle(&mut self, _msg: ServerStats, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.dedup.counts().clone())
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct UdpShutdownEvent;
+
+impl Handler<UdpShutdownEvent> for Server {
+    type Result = ();
+
+    fn handle(&mut self, _msg: UdpShutdownEvent, ctx: &mut Context<Self>) {
+        // Stopping the actor drops the udp stream, closing the socket. Any
+        // frames queued ahead of this message have already been sent on to
+        // the db actor.
+        info!("Stopping udp listener ...");
+        ctx.stop();
+    }
+}
+
+/* == Shutdown == */
+
+async fn shutdown_signal() {
+    // Docker sends SIGTERM, an interactive session sends SIGINT.
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    let ctrl_c = tokio::signal::ctrl_c();
+    let term = sigterm.recv();
+    pin_mut!(ctrl_c);
+    pin_mut!(term);
+
+    match select(ctrl_c, term).await {
+        Either::Left(_) => info!("Ctrl-C received, shutting down"),
+        Either::Right(_) => info!("SIGTERM received, shutting down"),
+    }
+}
+
+// Days that have been reported on would move if we guessed the zone wrong.
+fn check_timezone(cfg: &config::Config) -> Result<(), ()> {
+    if !cfg.tz_unset {
+        return Ok(());
+    }
+    let reported = match cfg.storage {
+        // Looked at without creating or migrating the database.
+        storage::Backend::Sqlite | storage::Backend::Segment => db::has_reports(&cfg.db_path)?,
+        _ => {
+            open_storage(cfg.storage, &cfg.db_path, &cfg.pg_url, &cfg.segment_dir)?.has_reports()?
+        }
+    };
+    if reported {
+        error!(
+            "MICD_TIMEZONE is not set, and the {:?} storage already has daily reports. Set it to the timezone they were made in, such as UTC, to continue.",
+            cfg.storage
+        );
+        return Err(());
+    }
+    warn!("MICD_TIMEZONE is not set and the host has no timezone, so days are reported in UTC. Set MICD_TIMEZONE to the site's timezone, such as Australia/Brisbane.");
+    Ok(())
+}
+
+// Sync and backups work on the sqlite database itself.
+fn check_storage(cfg: &config::Config) -> Result<(), ()> {
+    if cfg.storage == storage::Backend::Sqlite {
+        return Ok(());
+    }
+    if cfg.sync_key.is_some() || cfg.sync_url.is_some() || cfg.backup_dir.is_some() {
+        error!(
+            "Sync and backups need sqlite storage, unset MICD_SYNC_KEY, MICD_SYNC_URL and MICD_BACKUP_DIR to use {:?} storage.",
+            cfg.storage
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
+fn open_storage(
+    backend: storage::Backend,
+    db_path: &str,
+    pg_url: &str,
+    segment_dir: &std::path::Path,
+) -> Result<Box<dyn storage::Storage>, ()> {
+    match backend {
+        storage::Backend::Sqlite => db::open_sqlite(db_path),
+        storage::Backend::Segment => db::open_sqlite(db_path)
+            .and_then(|db| segstore::SegmentStorage::open(segment_dir, db))
+            .map(|s| Box::new(s) as Box<dyn storage::Storage>),
+        #[cfg(feature = "postgres")]
+        storage::Backend::Postgres => {
+            pg::PgStorage::new(pg_url).map(|s| Box::new(s) as Box<dyn storage::Storage>)
+        }
+        #[cfg(not(feature = "postgres"))]
+        storage::Backend::Postgres => {
+            error!("micd was built without the postgres feature");
+            Err(())
+        }
+        storage::Backend::Memory => Ok(Box::new(storage::MemStorage::new())),
+    }
+}
+
+const USAGE: &str =
+    "usage: micd [restore <file> | import [--mac <mac>] [--columns <field=header,..>] [--merge] <file>..]";
+
+fn import_command(cfg: &config::Config, args: &[String]) -> Result<(), ()> {
+    // Anything imported into memory would be gone as soon as we exit.
+    if cfg.storage == storage::Backend::Memory {
+        eprintln!("Nothing can be imported into memory storage, post the file to /admin/import of the running micd instead");
+        return Err(());
+    }
+    let mut opts = import::ImportOptions::default();
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--merge" => opts.merge = true,
+            "--mac" => opts.mac = args.next().cloned(),
+            "--columns" => opts.columns = args.next().cloned(),
+            a if a.starts_with("--") => {
+                eprintln!("unknown option {}, {}", a, USAGE);
+                return Err(());
+            }
+            file => files.push(file),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("{}", USAGE);
+        return Err(());
+    }
+
+    // A running micd has its own view of the database, such as which days
+    // it has rolled up, so it has to do the import itself.
+    let _lock = match cfg.storage {
+        storage::Backend::Sqlite | storage::Backend::Segment => {
+            Some(backup::DbLock::acquire(&cfg.db_path).map_err(|_| {
+                eprintln!(
+                    "Unable to lock {}, while micd is running post the file to /admin/import instead",
+                    cfg.db_path
+                );
+            })?)
+        }
+        _ => None,
+    };
+
+    let mut events = Vec::new();
+    let mut skipped = 0;
+    for file in files {
+        let data = std::fs::read(file).map_err(|e| {
+            eprintln!("Unable to read {} -> {:?}", file, e);
+        })?;
+        let (mut e, s) =
+            import::parse(&String::from_utf8_lossy(&data), &opts, &cfg.tz).map_err(|_| {
+                eprintln!("Unable to read {}", file);
+            })?;
+        println!("{}: {} readings, {} rows skipped", file, e.len(), s);
+        events.append(&mut e);
+        skipped += s;
+    }
+
+    let store = open_storage(cfg.storage, &cfg.db_path, &cfg.pg_url, &cfg.segment_dir)?;
+    let actor = db::DbActor::new(store, cfg.retention, cfg.tz, cfg.ppm_bands.clone());
+    let summary = actor.import(events, skipped, opts.merge)?;
+    summary.meters.iter().for_each(|m| {
+        println!(
+            "{}: {} -> {}, {} readings, {} added, {} already stored, {} days already reported",
+            m.mac,
+            cfg.tz.to_local(m.first).format(db::TFMT),
+            cfg.tz.to_local(m.last).format(db::TFMT),
+            m.readings,
+            m.added,
+            m.existing,
+            m.reported
+        )
+    });
+    if !summary.imported {
+        eprintln!(
+            "The import overlaps existing data and nothing was added, use --merge to add it anyway"
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
 #[actix_rt::main]
 async fn main() {
     env_logger::init();
 
-    let http_bind = if cfg!(debug_assertions) {
-        "127.0.0.1:8082"
-    } else {
-        "[::]:8082"
-    };
+    let cfg = config::Config::from_env();
 
-    let addr = if cfg!(debug_assertions) {
-        "127.0.0.1"
-    } else {
-        "172.24.18.140"
-    };
-    let port = 2014;
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|a| a.as_str()) {
+        Some("restore") => {
+            let r = match args.get(2) {
+                Some(file) => backup::restore(&cfg.db_path, file),
+                None => {
+                    eprintln!("{}", USAGE);
+                    Err(())
+                }
+            };
+            match r {
+                Ok(_) => println!(
+                    "Restored {}, the previous db was kept as {}.pre-restore.bak",
+                    cfg.db_path, cfg.db_path
+                ),
+                Err(_) => eprintln!("Restore failed, {} is unchanged", cfg.db_path),
+            }
+            std::process::exit(if r.is_ok() { 0 } else { 1 });
+        }
+        Some("import") => {
+            let r = check_timezone(&cfg).and_then(|_| import_command(&cfg, &args[2..]));
+            std::process::exit(if r.is_ok() { 0 } else { 1 });
+        }
+        Some(cmd) => {
+            eprintln!("unknown command {}, {}", cmd, USAGE);
+            std::process::exit(1);
+        }
+        None => {}
+    }
 
-    let db_path = if cfg!(debug_assertions) {
-        "/tmp/micd.db"
-    } else {
-        "/data/micd.db"
-    };
+    if check_timezone(&cfg).is_err() || check_storage(&cfg).is_err() {
+        std::process::exit(1);
+    }
+
+    // Held until we exit, so the db can't be restored while we're using it.
+    let _db_lock =
+        if cfg.storage == storage::Backend::Sqlite || cfg.storage == storage::Backend::Segment {
+            Some(backup::DbLock::acquire(&cfg.db_path).expect("Failed to lock db"))
+        } else {
+            None
+        };
 
-    info!("Micd udp listening on {}:{}", addr, port);
-    info!("Micd http listening on http://{}", http_bind);
-    info!("Micd db: {}", db_path);
+    info!("Micd udp listening on {}:{}", cfg.udp_addr, cfg.udp_port);
+    info!("Micd http listening on http://{}", cfg.http_bind);
+    match cfg.storage {
+        storage::Backend::Sqlite => info!("Micd db: {}", cfg.db_path),
+        storage::Backend::Segment => info!(
+            "Micd db: {}, segments: {}",
+            cfg.db_path,
+            cfg.segment_dir.display()
+        ),
+        backend => info!("Micd storage: {:?}", backend),
+    }
 
-    let v4_addr = Ipv4Addr::from_str(addr).expect("Failed to parse socket addr");
+    let v4_addr = Ipv4Addr::from_str(&cfg.udp_addr).expect("Failed to parse socket addr");
 
-    let sock_addr = (v4_addr, port);
+    let sock_addr = (v4_addr, cfg.udp_port);
     let sock = UdpSocket::bind(sock_addr).await.unwrap();
 
     let stream = UdpFramed::new(sock, MicCodec);
 
+    let storage = cfg.storage;
+    let db_path = cfg.db_path.clone();
+    let pg_url = cfg.pg_url.clone();
+    let segment_dir = cfg.segment_dir.clone();
+    let retention = cfg.retention;
+    let tz = cfg.tz;
+    let ppm_bands = cfg.ppm_bands.clone();
+    info!("Reporting days in {}", tz.name());
     let db_addr = SyncArbiter::start(1, move || {
-        db::DbActor::new(db_path).expect("Failed to start db thread")
+        let store = open_storage(storage, &db_path, &pg_url, &segment_dir)
+            .expect("Failed to start db thread");
+        db::DbActor::new(store, retention, tz, ppm_bands.clone())
     });
     let a_db_addr = db_addr.clone();
     let b_db_addr = db_addr.clone();
     let c_db_addr = db_addr.clone();
 
-    // This runs the scheduled tasks
-    let ia = interval::IntervalActor { db_addr: c_db_addr };
-    let _ = ia.start();
+    // Carry on the sequences from where we left off.
+    let seqs = db_addr
+        .send(db::DbLatestSequences)
+        .await
+        .expect("Failed to contact db thread")
+        .expect("Failed to load meter sequences");
 
-    Server::create(move |ctx| {
+    let dedup_window = cfg.dedup_window;
+    let relay = if cfg.relay_targets.is_empty() {
+        None
+    } else {
+        info!("Micd relaying to {:?}", cfg.relay_targets);
+        Some(
+            relay::Relay::new(cfg.relay_targets.clone(), cfg.relay_macs.clone())
+                .expect("Failed to setup relay"),
+        )
+    };
+    let render_cache = Arc::new(render::RenderCache::new());
+    let a_render_cache = render_cache.clone();
+    let server_addr = Server::create(move |ctx| {
         ctx.add_message_stream(
             // May need to box leak this still?
+            // Stamp each datagram as it arrives, the db may be busy.
             stream.map(|r| match r {
-                Ok((frame, addr)) => UdpEvent(Some((frame, addr))),
-                Err(_) => UdpEvent(None),
+                Ok((frame, addr)) => UdpEvent(Some((frame, addr)), OffsetDateTime::now()),
+                Err(_) => UdpEvent(None, OffsetDateTime::now()),
             }),
         );
-        Server { db_addr: a_db_addr }
+        Server {
+            db_addr: a_db_addr,
+            seqs,
+            dedup: dedup::Dedup::new(dedup_window),
+            relay,
+            render_cache: a_render_cache,
+        }
     });
+    let a_server_addr = server_addr.clone();
 
-    let render_addr = SyncArbiter::start(1, move || render::RenderActor {});
+    let sync_addr = if let (Some(url), Some(key)) = (cfg.sync_url.clone(), cfg.sync_key.clone()) {
+        Some(
+            sync::SyncActor::new(
+                db_addr.clone(),
+                url,
+                cfg.sync_site.clone(),
+                key,
+                cfg.sync_interval,
+            )
+            .start(),
+        )
+    } else {
+        None
+    };
+
+    let backup_addr = cfg.backup_dir.clone().map(|dir| {
+        backup::BackupActor::new(db_addr.clone(), dir, cfg.backup_interval, cfg.backup_keep).start()
+    });
+
+    // So a chart no one has asked for yet needn't wait behind a refresh.
+    let comfort = cfg.comfort_bands.clone();
+    let render_addr = SyncArbiter::start(2, move || render::RenderActor {
+        tz,
+        comfort: comfort.clone(),
+    });
     let a_render_addr = render_addr.clone();
 
+    // This runs the scheduled tasks
+    let reports = cfg.report_dir.clone().map(|dir| report::Schedule {
+        dir,
+        periods: cfg.report_periods.clone(),
+        retain_days: cfg.report_retain_days,
+        webhook: cfg.report_webhook.clone(),
+    });
+    let ia = interval::IntervalActor::new(c_db_addr, render_addr.clone(), tz, reports);
+    let ia_addr = ia.start();
+
+    let refresh_addr = render::RenderRefreshActor::new(
+        db_addr.clone(),
+        render_addr.clone(),
+        render_cache.clone(),
+        cfg.render_refresh,
+    )
+    .start();
+
+    let sync_key = cfg.sync_key.clone();
+    let admin_key = cfg.admin_key.clone();
+
     // Main actix threads are up, get's the webui cracking.
 
     let server = HttpServer::new(move || {
         App::new()
             .data(AppState {
                 render_addr: a_render_addr.clone(),
+                render_cache: render_cache.clone(),
                 db_addr: b_db_addr.clone(),
+                server_addr: a_server_addr.clone(),
+                sync_key: sync_key.clone(),
+                admin_key: admin_key.clone(),
+                tz,
             })
+            // Sync batches and imports are much larger than the default allows.
+            .app_data(web::JsonConfig::default().limit(16 * 1024 * 1024))
+            .app_data(web::PayloadConfig::new(64 * 1024 * 1024))
             .wrap(middleware::Logger::default())
             .service(fs::Files::new("/static", "./static"))
-            .service(fs::Files::new("/render", "./data/render"))
             .route("", web::get().to(index_view))
             .route("/", web::get().to(index_view))
             .route("/status", web::get().to(status_view))
-    });
-    server.bind(http_bind).unwrap().run();
+            .route("/metrics", web::get().to(metrics_view))
+            .route("/history/{mac}", web::get().to(history_view))
+            .route("/render/{mac}/{chart}", web::get().to(render_view))
+            .route("/compare/{chart}", web::get().to(compare_view))
+            .route("/report.pdf", web::get().to(report_site_view))
+            .route("/report/{mac}", web::get().to(report_view))
+            .route("/sync/cursor/{site}", web::get().to(sync_cursor_view))
+            .route("/sync/push/{site}", web::post().to(sync_push_view))
+            .route("/admin/backup", web::get().to(admin_backup_view))
+            .route("/admin/import", web::post().to(admin_import_view))
+            .route("/admin/label/{mac}", web::put().to(admin_label_view))
+    })
+    // We manage signals ourselves so that we can shutdown in order.
+    .disable_signals();
+    let http_server = server.bind(cfg.http_bind.as_str()).unwrap().run();
+
+    shutdown_signal().await;
+
+    // Stop accepting new frames, and stop scheduling new tasks. Anything that
+    // is part way through its work against the db is let finish.
+    if server_addr.send(UdpShutdownEvent).await.is_err() {
+        error!("udp listener already stopped");
+    }
+    stop::stop_actor(&ia_addr, "scheduled tasks").await;
+    if let Some(addr) = sync_addr {
+        stop::stop_actor(&addr, "sync").await;
+    }
+    if let Some(addr) = backup_addr {
+        stop::stop_actor(&addr, "backups").await;
+    }
+    stop::stop_actor(&refresh_addr, "chart refresh").await;
+
+    // Let in-flight requests (and their renders) complete.
+    info!("Stopping http server ...");
+    http_server.stop(true).await;
+
+    // The db actor processes in order, so once this returns every queued
+    // datum and any report extraction has been completed.
+    info!("Draining db queue ...");
+    match db_addr.send(db::DbShutdownEvent).await {
+        Ok(Ok(_)) => info!("Db flushed"),
+        _ => error!("Failed to flush db during shutdown"),
+    }
 
-    tokio::signal::ctrl_c().await.unwrap();
-    info!("Ctrl-C received, shutting down");
     System::current().stop();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compare_view, db, dedup, render, render_view, report_site_view, sync, sync_cursor_view,
+        sync_push_view, tz, AppState, Server,
+    };
+    use actix::prelude::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const KEY: &str = "sync-key";
+
+    fn state() -> AppState {
+        let tz: tz::SiteTz = "Australia/Brisbane".parse().unwrap();
+        let retention = db::Retention {
+            raw_days: 0,
+            hourly_days: 0,
+            daily_days: 0,
+        };
+        let db_addr = SyncArbiter::start(1, move || {
+            db::DbActor::new(db::open_sqlite("").unwrap(), retention, tz, vec![1000])
+        });
+        let render_cache = Arc::new(render::RenderCache::new());
+        let server_addr = Server {
+            db_addr: db_addr.clone(),
+            seqs: BTreeMap::new(),
+            dedup: dedup::Dedup::new(Duration::from_millis(0)),
+            relay: None,
+            render_cache: render_cache.clone(),
+        }
+        .start();
+        AppState {
+            render_addr: SyncArbiter::start(1, move || render::RenderActor {
+                tz,
+                comfort: Vec::new(),
+            }),
+            render_cache,
+            db_addr,
+            server_addr,
+            sync_key: Some(KEY.to_string()),
+            admin_key: None,
+            tz,
+        }
+    }
+
+    fn batch() -> sync::SyncBatch {
+        sync::SyncBatch {
+            events: vec![sync::SyncEvent {
+                mac: "00:00:00:00:00:00".to_string(),
+                ts: 1586055739000,
+                seq: 1,
+                temp: 123,
+                ppm: 415,
+                hum: 123,
+                change: 7,
+            }],
+            history: Vec::new(),
+            db_id: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_sync_push_view() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let mut app = test::init_service(
+            App::new()
+                .data(state())
+                .route("/sync/cursor/{site}", web::get().to(sync_cursor_view))
+                .route("/sync/push/{site}", web::post().to(sync_push_view)),
+        )
+        .await;
+
+        let push = |key: &str| {
+            test::TestRequest::post()
+                .uri("/sync/push/site-a")
+                .header("Authorization", format!("Bearer {}", key))
+                .set_json(&batch())
+                .to_request()
+        };
+        let cursor = |key: &str| {
+            test::TestRequest::get()
+                .uri("/sync/cursor/site-a")
+                .header("Authorization", format!("Bearer {}", key))
+                .to_request()
+        };
+
+        // Nothing is taken from a site without the key.
+        let resp = test::call_service(&mut app, push("wrong")).await;
+        assert!(resp.status() == StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&mut app, cursor("wrong")).await;
+        assert!(resp.status() == StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(
+            &mut app,
+            test::TestRequest::post()
+                .uri("/sync/push/site-a")
+                .set_json(&batch())
+                .to_request(),
+        )
+        .await;
+        assert!(resp.status() == StatusCode::UNAUTHORIZED);
+
+        let got: sync::SyncCursor = test::read_response_json(&mut app, cursor(KEY)).await;
+        assert!(got == sync::SyncCursor::default());
+
+        let got: sync::SyncCursor = test::read_response_json(&mut app, push(KEY)).await;
+        assert!(got.event_change == Some(7));
+        let got: sync::SyncCursor = test::read_response_json(&mut app, cursor(KEY)).await;
+        assert!(got.event_change == Some(7));
+    }
+
+    #[actix_rt::test]
+    async fn test_chart_ranges() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let mut app = test::init_service(
+            App::new()
+                .data(state())
+                .route("/render/{mac}/{chart}", web::get().to(render_view))
+                .route("/compare/{chart}", web::get().to(compare_view))
+                .route("/report.pdf", web::get().to(report_site_view)),
+        )
+        .await;
+
+        // Turned away before anything is fetched or drawn.
+        for uri in &[
+            "/render/00:00:00:00:00:00/ppm?from=-9223372036854775808",
+            "/render/00:00:00:00:00:00/ppm?to=9223372036854775807",
+            "/render/00:00:00:00:00:00/ppm?from=1586095200000&to=1586008800000",
+            "/render/00:00:00:00:00:00/ppm_history?to=1586008800000&range=99999d",
+            "/compare/ppm?from=0",
+            "/report.pdf?to=-9223372036854775808",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert!(resp.status() == StatusCode::BAD_REQUEST);
+        }
+    }
+}
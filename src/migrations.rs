@@ -0,0 +1,1052 @@
+use rusqlite::{Connection, DatabaseName, Error, NO_PARAMS};
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::db::TFMT;
+
+type Migration = fn(&Connection) -> Result<(), ()>;
+
+// Events were stored as utc text with nanoseconds until schema version 4.
+const DB_TFMT: &'static str = "%F %H:%M:%S.%N%z";
+
+/*
+ * Migrations are applied in order, each in its own transaction. Once a
+ * migration has been released it must never be changed, add a new one instead.
+ */
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("base schema", migrate_v1_base),
+    (
+        "event_t and history_t primary keys",
+        migrate_v2_primary_keys,
+    ),
+    ("hourly_t rollups", migrate_v3_hourly),
+    ("utc epoch timestamps", migrate_v4_epoch_ts),
+    ("history_t gaps and coverage", migrate_v5_history_coverage),
+    ("history_t percentiles and bands", migrate_v6_history_stats),
+    ("without rowid tables", migrate_v7_without_rowid),
+    ("database identity", migrate_v8_db_id),
+];
+
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// The schema version of a database, or 0 if it predates versioning.
+pub fn get_version(conn: &Connection) -> Result<i64, ()> {
+    let exists: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'schema_version_t'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .map_err(|e| {
+            error!("sqlite query_row error -> {:?}", e);
+            ()
+        })?;
+
+    if exists == 0 {
+        return Ok(0);
+    }
+
+    conn.query_row(
+        "SELECT IFNULL(MAX(version), 0) FROM schema_version_t",
+        NO_PARAMS,
+        |row| row.get(0),
+    )
+    .map_err(|e| {
+        error!("sqlite query_row error -> {:?}", e);
+        ()
+    })
+}
+
+fn has_tables(conn: &Connection) -> Result<bool, ()> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name != 'schema_version_t'",
+        NO_PARAMS,
+        |row| row.get::<usize, i64>(0),
+    )
+    .map(|c| c > 0)
+    .map_err(|e| {
+        error!("sqlite query_row error -> {:?}", e);
+        ()
+    })
+}
+
+/// Bring the database at path up to the current schema version. Before an
+/// existing database is changed, a copy is saved next to it.
+pub fn migrate(conn: &mut Connection, path: &str) -> Result<(), ()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version_t (
+            version INTEGER PRIMARY KEY,
+            applied TEXT NOT NULL
+        )
+        ",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite schema_version_t create error -> {:?}", e);
+        ()
+    })?;
+
+    let current = get_version(conn)?;
+
+    if current > SCHEMA_VERSION {
+        error!(
+            "Database schema version {} is newer than this micd supports ({}), refusing to continue",
+            current, SCHEMA_VERSION
+        );
+        return Err(());
+    }
+
+    if current == SCHEMA_VERSION {
+        debug!("Database schema is current at version {}", current);
+        return Ok(());
+    }
+
+    // Nothing to save from an in memory db, or one we just created.
+    if !path.is_empty() && has_tables(conn)? {
+        let backup_path = format!("{}.v{}.bak", path, current);
+        info!("Saving database to {} before migrating ...", backup_path);
+        conn.backup(DatabaseName::Main, &backup_path, None)
+            .map_err(|e| {
+                error!("sqlite backup error -> {:?}", e);
+                ()
+            })?;
+    }
+
+    MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(current as usize)
+        .try_for_each(|(i, (name, migration))| {
+            let version = i as i64 + 1;
+            info!("Applying schema migration {} - {} ...", version, name);
+
+            let tx = conn.transaction().map_err(|e| {
+                error!("sqlite transaction error -> {:?}", e);
+                ()
+            })?;
+
+            migration(&tx)?;
+
+            let applied = OffsetDateTime::now().format(DB_TFMT);
+            tx.execute_named(
+                "INSERT INTO schema_version_t (version, applied) VALUES (:version, :applied)",
+                &[(":version", &version), (":applied", &applied)],
+            )
+            .map_err(|e| {
+                error!("sqlite execute_named error -> {:?}", e);
+                ()
+            })?;
+
+            // If anything failed, the transaction is rolled back as it drops.
+            tx.commit().map_err(|e| {
+                error!("sqlite commit error -> {:?}", e);
+                ()
+            })
+        })
+}
+
+/*
+ * Version 1 is the schema as it was before versioning, and accounts for the
+ * fixes that were applied at startup to unversioned databases.
+ */
+fn migrate_v1_base(conn: &Connection) -> Result<(), ()> {
+    // Create our tables if needed.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS meter_t (
+            mac TEXT PRIMARY KEY,
+            label TEXT
+        )
+        ",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite meter_t create error -> {:?}", e);
+        ()
+    })?;
+
+    /*
+     *  - timestamp (utc) --- sqlite supports TEXT as ISO8601 strings ("YYYY-MM-DD HH:MM:SS.SSSSSSSSS+0000").
+     *  - seq is assigned per meter in order of reception.
+     *  - change_seq is assigned across all meters in the order events are added.
+     */
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS event_t (
+            mac TEXT,
+            ts TEXT NOT NULL,
+            seq INTEGER NOT NULL DEFAULT 0,
+            temp INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            hum INTEGER NOT NULL,
+            change_seq INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        )
+        ",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite event_t create error -> {:?}", e);
+        ()
+    })?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS event_t_ts_idx ON event_t (ts)",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite event_t_ts_idx create error -> {:?}", e);
+        ()
+    })?;
+
+    migrate_event_t_seq(conn)?;
+    migrate_event_t_unique(conn)?;
+    migrate_event_t_change_seq(conn)?;
+
+    /*
+     * - time as YYYY-MM-DD
+     */
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS history_t (
+            mac TEXT,
+            t TEXT NOT NULL,
+            temp_max INTEGER NOT NULL,
+            temp_min INTEGER NOT NULL,
+            temp_avg INTEGER NOT NULL,
+            ppm_max INTEGER NOT NULL,
+            ppm_min INTEGER NOT NULL,
+            ppm_avg INTEGER NOT NULL,
+            hum_max INTEGER NOT NULL,
+            hum_min INTEGER NOT NULL,
+            hum_avg INTEGER NOT NULL,
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        )
+        ",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite history_t create error -> {:?}", e);
+        ()
+    })?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS history_t_t_idx ON history_t (t)",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite history_t_t_idx create error -> {:?}", e);
+        ()
+    })?;
+
+    migrate_history_t_unique(conn)?;
+
+    /*
+     * - how far each remote site has synced to us.
+     */
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_cursor_t (
+            site TEXT PRIMARY KEY,
+            event_change INTEGER,
+            history_t TEXT
+        )
+        ",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite sync_cursor_t create error -> {:?}", e);
+        ()
+    })?;
+    Ok(())
+}
+
+fn migrate_event_t_seq(conn: &Connection) -> Result<(), ()> {
+    // Older databases stored local second-precision timestamps, and had no sequence.
+    let has_seq = conn
+        .prepare("PRAGMA table_info(event_t)")
+        .and_then(|mut stmt| {
+            stmt.query_map(NO_PARAMS, |row| row.get::<usize, String>(1))
+                .and_then(|cols| cols.collect::<Result<Vec<String>, _>>())
+        })
+        .map(|cols| cols.iter().any(|c| c == "seq"))
+        .map_err(|e| {
+            error!("sqlite event_t table_info error -> {:?}", e);
+            ()
+        })?;
+
+    if has_seq {
+        return Ok(());
+    }
+
+    info!("Migrating event_t to utc timestamps with sequences ...");
+
+    conn.execute(
+        "ALTER TABLE event_t ADD COLUMN seq INTEGER NOT NULL DEFAULT 0",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite event_t alter error -> {:?}", e);
+        ()
+    })?;
+
+    let rows: Vec<(i64, String)> = conn
+        .prepare("SELECT rowid, ts FROM event_t")
+        .and_then(|mut stmt| {
+            stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(|rows| rows.collect())
+        })
+        .map_err(|e| {
+            error!("sqlite prepare and query error -> {:?}", e);
+            ()
+        })?;
+
+    rows.into_iter().try_for_each(|(rowid, ts)| {
+        let ts = match OffsetDateTime::parse(&ts, TFMT) {
+            Ok(ts) => ts.to_offset(UtcOffset::UTC).format(DB_TFMT),
+            Err(e) => {
+                error!("invalid legacy ts {:?} -> {:?}", ts, e);
+                return Err(());
+            }
+        };
+        conn.execute_named(
+            "UPDATE event_t SET ts = :ts WHERE rowid = :rowid",
+            &[(":ts", &ts), (":rowid", &rowid)],
+        )
+        .map(|_| ())
+        .map_err(|e| {
+            error!("sqlite execute_named error -> {:?}", e);
+            ()
+        })
+    })?;
+
+    Ok(())
+}
+
+// This stops a stored reading being written twice, such as when a sync batch
+// or an import is replayed. It can't catch a retransmitted frame, as ts is
+// when we received it, so those are dropped as they arrive instead.
+fn migrate_event_t_unique(conn: &Connection) -> Result<(), ()> {
+    let exists: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'event_t_mac_ts_idx'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .map_err(|e| {
+            error!("sqlite query_row error -> {:?}", e);
+            ()
+        })?;
+
+    if exists != 0 {
+        return Ok(());
+    }
+
+    info!("Removing duplicate events before adding event_t_mac_ts_idx ...");
+
+    conn.execute(
+        "DELETE FROM event_t WHERE rowid NOT IN (SELECT MIN(rowid) FROM event_t GROUP BY mac, ts)",
+        NO_PARAMS,
+    )
+    .map(|r| {
+        info!("removed {} duplicate events", r);
+        ()
+    })
+    .map_err(|e| {
+        error!("sqlite event_t dedup error -> {:?}", e);
+        ()
+    })?;
+
+    conn.execute(
+        "CREATE UNIQUE INDEX event_t_mac_ts_idx ON event_t (mac, ts)",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite event_t_mac_ts_idx create error -> {:?}", e);
+        ()
+    })?;
+
+    Ok(())
+}
+
+/*
+ * Sync pages through events in the order they were added, rather than by
+ * time, so that readings delayed behind what has already been sent still go.
+ * The sequence is kept in change_seq_t so that it never goes backwards, even
+ * once every event has been purged.
+ */
+fn migrate_event_t_change_seq(conn: &Connection) -> Result<(), ()> {
+    let exists: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'change_seq_t'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .map_err(|e| {
+            error!("sqlite query_row error -> {:?}", e);
+            ()
+        })?;
+
+    if exists != 0 {
+        return Ok(());
+    }
+
+    let has_change_seq = conn
+        .prepare("PRAGMA table_info(event_t)")
+        .and_then(|mut stmt| {
+            stmt.query_map(NO_PARAMS, |row| row.get::<usize, String>(1))
+                .and_then(|cols| cols.collect::<Result<Vec<String>, _>>())
+        })
+        .map(|cols| cols.iter().any(|c| c == "change_seq"))
+        .map_err(|e| {
+            error!("sqlite event_t table_info error -> {:?}", e);
+            ()
+        })?;
+
+    info!("Numbering events for sync ...");
+
+    if !has_change_seq {
+        conn.execute(
+            "ALTER TABLE event_t ADD COLUMN change_seq INTEGER NOT NULL DEFAULT 0",
+            NO_PARAMS,
+        )
+        .map_err(|e| {
+            error!("sqlite event_t alter error -> {:?}", e);
+            ()
+        })?;
+    }
+
+    // Existing events are numbered in time order.
+    let rows: Vec<i64> = conn
+        .prepare("SELECT rowid FROM event_t ORDER BY ts ASC, mac ASC")
+        .and_then(|mut stmt| {
+            stmt.query_map(NO_PARAMS, |row| row.get(0))
+                .and_then(|rows| rows.collect())
+        })
+        .map_err(|e| {
+            error!("sqlite prepare and query error -> {:?}", e);
+            ()
+        })?;
+
+    let last = rows.len() as i64;
+    rows.into_iter()
+        .zip(1..)
+        .try_for_each(|(rowid, change): (i64, i64)| {
+            conn.execute_named(
+                "UPDATE event_t SET change_seq = :change WHERE rowid = :rowid",
+                &[(":change", &change), (":rowid", &rowid)],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                error!("sqlite execute_named error -> {:?}", e);
+                ()
+            })
+        })?;
+
+    conn.execute_batch(
+        "CREATE TABLE change_seq_t (seq INTEGER NOT NULL);
+        CREATE INDEX event_t_change_seq_idx ON event_t (change_seq);
+        CREATE TRIGGER event_t_change_seq_trg AFTER INSERT ON event_t BEGIN
+            UPDATE change_seq_t SET seq = MAX(seq, NEW.change_seq);
+        END;
+        ",
+    )
+    .map_err(|e| {
+        error!("sqlite change sequence create error -> {:?}", e);
+        ()
+    })?;
+
+    conn.execute_named(
+        "INSERT INTO change_seq_t (seq) VALUES (:seq)",
+        &[(":seq", &last)],
+    )
+    .map(|_| ())
+    .map_err(|e| {
+        error!("sqlite execute_named error -> {:?}", e);
+        ()
+    })
+}
+
+fn migrate_history_t_unique(conn: &Connection) -> Result<(), ()> {
+    let exists: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = 'history_t_mac_t_idx'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .map_err(|e| {
+            error!("sqlite query_row error -> {:?}", e);
+            ()
+        })?;
+
+    if exists != 0 {
+        return Ok(());
+    }
+
+    // Keep the most recently generated report for each day.
+    conn.execute(
+        "DELETE FROM history_t WHERE rowid NOT IN (SELECT MAX(rowid) FROM history_t GROUP BY mac, t)",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite history_t dedup error -> {:?}", e);
+        ()
+    })?;
+
+    conn.execute(
+        "CREATE UNIQUE INDEX history_t_mac_t_idx ON history_t (mac, t)",
+        NO_PARAMS,
+    )
+    .map_err(|e| {
+        error!("sqlite history_t_mac_t_idx create error -> {:?}", e);
+        ()
+    })?;
+
+    Ok(())
+}
+
+/*
+ * event_t and history_t never had primary keys, so rebuild them with one.
+ */
+fn migrate_v2_primary_keys(conn: &Connection) -> Result<(), ()> {
+    conn.execute_batch(
+        "CREATE TABLE event_t_new (
+            mac TEXT NOT NULL,
+            ts TEXT NOT NULL,
+            seq INTEGER NOT NULL DEFAULT 0,
+            temp INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            hum INTEGER NOT NULL,
+            change_seq INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (mac, ts),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        INSERT OR IGNORE INTO event_t_new (mac, ts, seq, temp, ppm, hum, change_seq)
+            SELECT mac, ts, seq, temp, ppm, hum, change_seq FROM event_t WHERE mac IS NOT NULL;
+        DROP TABLE event_t;
+        ALTER TABLE event_t_new RENAME TO event_t;
+        CREATE INDEX event_t_ts_idx ON event_t (ts);
+        CREATE INDEX event_t_change_seq_idx ON event_t (change_seq);
+        CREATE TRIGGER event_t_change_seq_trg AFTER INSERT ON event_t BEGIN
+            UPDATE change_seq_t SET seq = MAX(seq, NEW.change_seq);
+        END;
+
+        CREATE TABLE history_t_new (
+            mac TEXT NOT NULL,
+            t TEXT NOT NULL,
+            temp_max INTEGER NOT NULL,
+            temp_min INTEGER NOT NULL,
+            temp_avg INTEGER NOT NULL,
+            ppm_max INTEGER NOT NULL,
+            ppm_min INTEGER NOT NULL,
+            ppm_avg INTEGER NOT NULL,
+            hum_max INTEGER NOT NULL,
+            hum_min INTEGER NOT NULL,
+            hum_avg INTEGER NOT NULL,
+            PRIMARY KEY (mac, t),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        INSERT OR IGNORE INTO history_t_new
+            SELECT mac, t, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg
+            FROM history_t WHERE mac IS NOT NULL;
+        DROP TABLE history_t;
+        ALTER TABLE history_t_new RENAME TO history_t;
+        CREATE INDEX history_t_t_idx ON history_t (t);
+        ",
+    )
+    .map_err(|e| {
+        error!("sqlite primary key migration error -> {:?}", e);
+        ()
+    })
+}
+
+/*
+ * - t is the start of the hour in utc.
+ */
+fn migrate_v3_hourly(conn: &Connection) -> Result<(), ()> {
+    conn.execute_batch(
+        "CREATE TABLE hourly_t (
+            mac TEXT NOT NULL,
+            t TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            temp_max INTEGER NOT NULL,
+            temp_min INTEGER NOT NULL,
+            temp_avg INTEGER NOT NULL,
+            ppm_max INTEGER NOT NULL,
+            ppm_min INTEGER NOT NULL,
+            ppm_avg INTEGER NOT NULL,
+            ppm_p50 INTEGER NOT NULL,
+            ppm_p95 INTEGER NOT NULL,
+            hum_max INTEGER NOT NULL,
+            hum_min INTEGER NOT NULL,
+            hum_avg INTEGER NOT NULL,
+            PRIMARY KEY (mac, t),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        CREATE INDEX hourly_t_t_idx ON hourly_t (t);
+        ",
+    )
+    .map_err(|e| {
+        error!("sqlite hourly_t create error -> {:?}", e);
+        ()
+    })
+}
+
+fn legacy_ts_to_ms(ts: &str) -> Result<i64, String> {
+    OffsetDateTime::parse(ts, DB_TFMT)
+        .or_else(|_| OffsetDateTime::parse(ts, TFMT))
+        .map(|t| t.timestamp() * 1000 + t.millisecond() as i64)
+        .map_err(|e| format!("invalid legacy ts {:?} -> {:?}", ts, e))
+}
+
+/*
+ * Text timestamps only order correctly while they share an offset, and history
+ * was stored in whatever the host's local offset was. Store every time as utc
+ * milliseconds since the epoch instead.
+ */
+fn migrate_v4_epoch_ts(conn: &Connection) -> Result<(), ()> {
+    conn.create_scalar_function("micd_epoch_ms", 1, true, |ctx| {
+        ctx.get::<Option<String>>(0)?
+            .map(|ts| legacy_ts_to_ms(&ts).map_err(|e| Error::UserFunctionError(e.into())))
+            .transpose()
+    })
+    .map_err(|e| {
+        error!("sqlite create_scalar_function error -> {:?}", e);
+        ()
+    })?;
+
+    let r = conn
+        .execute_batch(
+            "CREATE TABLE event_t_new (
+            mac TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            seq INTEGER NOT NULL DEFAULT 0,
+            temp INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            hum INTEGER NOT NULL,
+            change_seq INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (mac, ts),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        INSERT OR IGNORE INTO event_t_new (mac, ts, seq, temp, ppm, hum, change_seq)
+            SELECT mac, micd_epoch_ms(ts), seq, temp, ppm, hum, change_seq FROM event_t;
+        DROP TABLE event_t;
+        ALTER TABLE event_t_new RENAME TO event_t;
+        CREATE INDEX event_t_ts_idx ON event_t (ts);
+        CREATE INDEX event_t_change_seq_idx ON event_t (change_seq);
+        CREATE TRIGGER event_t_change_seq_trg AFTER INSERT ON event_t BEGIN
+            UPDATE change_seq_t SET seq = MAX(seq, NEW.change_seq);
+        END;
+
+        CREATE TABLE history_t_new (
+            mac TEXT NOT NULL,
+            t INTEGER NOT NULL,
+            temp_max INTEGER NOT NULL,
+            temp_min INTEGER NOT NULL,
+            temp_avg INTEGER NOT NULL,
+            ppm_max INTEGER NOT NULL,
+            ppm_min INTEGER NOT NULL,
+            ppm_avg INTEGER NOT NULL,
+            hum_max INTEGER NOT NULL,
+            hum_min INTEGER NOT NULL,
+            hum_avg INTEGER NOT NULL,
+            PRIMARY KEY (mac, t),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        INSERT OR IGNORE INTO history_t_new
+            SELECT mac, micd_epoch_ms(t), temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg
+            FROM history_t;
+        DROP TABLE history_t;
+        ALTER TABLE history_t_new RENAME TO history_t;
+        CREATE INDEX history_t_t_idx ON history_t (t);
+
+        CREATE TABLE hourly_t_new (
+            mac TEXT NOT NULL,
+            t INTEGER NOT NULL,
+            count INTEGER NOT NULL,
+            temp_max INTEGER NOT NULL,
+            temp_min INTEGER NOT NULL,
+            temp_avg INTEGER NOT NULL,
+            ppm_max INTEGER NOT NULL,
+            ppm_min INTEGER NOT NULL,
+            ppm_avg INTEGER NOT NULL,
+            ppm_p50 INTEGER NOT NULL,
+            ppm_p95 INTEGER NOT NULL,
+            hum_max INTEGER NOT NULL,
+            hum_min INTEGER NOT NULL,
+            hum_avg INTEGER NOT NULL,
+            PRIMARY KEY (mac, t),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        INSERT OR IGNORE INTO hourly_t_new
+            SELECT mac, micd_epoch_ms(t), count, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, ppm_p50, ppm_p95, hum_max, hum_min, hum_avg
+            FROM hourly_t;
+        DROP TABLE hourly_t;
+        ALTER TABLE hourly_t_new RENAME TO hourly_t;
+        CREATE INDEX hourly_t_t_idx ON hourly_t (t);
+
+        CREATE TABLE sync_cursor_t_new (
+            site TEXT PRIMARY KEY,
+            event_change INTEGER,
+            history_t INTEGER
+        );
+        INSERT INTO sync_cursor_t_new
+            SELECT site, event_change, micd_epoch_ms(history_t) FROM sync_cursor_t;
+        DROP TABLE sync_cursor_t;
+        ALTER TABLE sync_cursor_t_new RENAME TO sync_cursor_t;
+        ",
+        )
+        .map_err(|e| {
+            error!("sqlite epoch timestamp migration error -> {:?}", e);
+            ()
+        });
+
+    conn.remove_function("micd_epoch_ms", 1).map_err(|e| {
+        error!("sqlite remove_function error -> {:?}", e);
+        ()
+    })?;
+    r
+}
+
+/*
+ * - count and coverage are unknown for days reported before this version.
+ * - days without readings have no values.
+ */
+fn migrate_v5_history_coverage(conn: &Connection) -> Result<(), ()> {
+    conn.execute_batch(
+        "CREATE TABLE history_t_new (
+            mac TEXT NOT NULL,
+            t INTEGER NOT NULL,
+            temp_max INTEGER,
+            temp_min INTEGER,
+            temp_avg INTEGER,
+            ppm_max INTEGER,
+            ppm_min INTEGER,
+            ppm_avg INTEGER,
+            hum_max INTEGER,
+            hum_min INTEGER,
+            hum_avg INTEGER,
+            count INTEGER,
+            coverage INTEGER,
+            PRIMARY KEY (mac, t),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        INSERT INTO history_t_new (mac, t, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg)
+            SELECT mac, t, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg
+            FROM history_t;
+        DROP TABLE history_t;
+        ALTER TABLE history_t_new RENAME TO history_t;
+        CREATE INDEX history_t_t_idx ON history_t (t);
+        ",
+    )
+    .map_err(|e| {
+        error!("sqlite history_t coverage migration error -> {:?}", e);
+        ()
+    })
+}
+
+/*
+ * - percentiles and deviation for each channel, and when ppm peaked.
+ * - history_band_t is the minutes each day spent above a ppm band.
+ */
+fn migrate_v6_history_stats(conn: &Connection) -> Result<(), ()> {
+    conn.execute_batch(
+        "ALTER TABLE history_t ADD COLUMN temp_p50 INTEGER;
+        ALTER TABLE history_t ADD COLUMN temp_p95 INTEGER;
+        ALTER TABLE history_t ADD COLUMN temp_stddev INTEGER;
+        ALTER TABLE history_t ADD COLUMN ppm_p50 INTEGER;
+        ALTER TABLE history_t ADD COLUMN ppm_p95 INTEGER;
+        ALTER TABLE history_t ADD COLUMN ppm_stddev INTEGER;
+        ALTER TABLE history_t ADD COLUMN hum_p50 INTEGER;
+        ALTER TABLE history_t ADD COLUMN hum_p95 INTEGER;
+        ALTER TABLE history_t ADD COLUMN hum_stddev INTEGER;
+        ALTER TABLE history_t ADD COLUMN ppm_peak_t INTEGER;
+
+        CREATE TABLE history_band_t (
+            mac TEXT NOT NULL,
+            t INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            minutes INTEGER NOT NULL,
+            PRIMARY KEY (mac, t, ppm),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        CREATE INDEX history_band_t_t_idx ON history_band_t (t);
+        ",
+    )
+    .map_err(|e| {
+        error!("sqlite history_t stats migration error -> {:?}", e);
+        ()
+    })
+}
+
+/*
+ * Per meter tables are clustered on their primary key, so a range of one
+ * meter's rows is read from neighbouring pages rather than through a separate
+ * index. The time only indexes stay for purging and sync, which cross meters.
+ */
+fn migrate_v7_without_rowid(conn: &Connection) -> Result<(), ()> {
+    conn.execute_batch(
+        "CREATE TABLE event_t_new (
+            mac TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            seq INTEGER NOT NULL DEFAULT 0,
+            temp INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            hum INTEGER NOT NULL,
+            change_seq INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (mac, ts),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        ) WITHOUT ROWID;
+        INSERT INTO event_t_new (mac, ts, seq, temp, ppm, hum, change_seq)
+            SELECT mac, ts, seq, temp, ppm, hum, change_seq FROM event_t;
+        DROP TABLE event_t;
+        ALTER TABLE event_t_new RENAME TO event_t;
+        CREATE INDEX event_t_ts_idx ON event_t (ts);
+        CREATE INDEX event_t_change_seq_idx ON event_t (change_seq);
+        CREATE TRIGGER event_t_change_seq_trg AFTER INSERT ON event_t BEGIN
+            UPDATE change_seq_t SET seq = MAX(seq, NEW.change_seq);
+        END;
+
+        CREATE TABLE history_t_new (
+            mac TEXT NOT NULL,
+            t INTEGER NOT NULL,
+            temp_max INTEGER,
+            temp_min INTEGER,
+            temp_avg INTEGER,
+            ppm_max INTEGER,
+            ppm_min INTEGER,
+            ppm_avg INTEGER,
+            hum_max INTEGER,
+            hum_min INTEGER,
+            hum_avg INTEGER,
+            count INTEGER,
+            coverage INTEGER,
+            temp_p50 INTEGER,
+            temp_p95 INTEGER,
+            temp_stddev INTEGER,
+            ppm_p50 INTEGER,
+            ppm_p95 INTEGER,
+            ppm_stddev INTEGER,
+            hum_p50 INTEGER,
+            hum_p95 INTEGER,
+            hum_stddev INTEGER,
+            ppm_peak_t INTEGER,
+            PRIMARY KEY (mac, t),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        ) WITHOUT ROWID;
+        INSERT INTO history_t_new
+            SELECT mac, t, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg,
+                count, coverage, temp_p50, temp_p95, temp_stddev, ppm_p50, ppm_p95, ppm_stddev,
+                hum_p50, hum_p95, hum_stddev, ppm_peak_t
+            FROM history_t;
+        DROP TABLE history_t;
+        ALTER TABLE history_t_new RENAME TO history_t;
+        CREATE INDEX history_t_t_idx ON history_t (t);
+
+        CREATE TABLE hourly_t_new (
+            mac TEXT NOT NULL,
+            t INTEGER NOT NULL,
+            count INTEGER NOT NULL,
+            temp_max INTEGER NOT NULL,
+            temp_min INTEGER NOT NULL,
+            temp_avg INTEGER NOT NULL,
+            ppm_max INTEGER NOT NULL,
+            ppm_min INTEGER NOT NULL,
+            ppm_avg INTEGER NOT NULL,
+            ppm_p50 INTEGER NOT NULL,
+            ppm_p95 INTEGER NOT NULL,
+            hum_max INTEGER NOT NULL,
+            hum_min INTEGER NOT NULL,
+            hum_avg INTEGER NOT NULL,
+            PRIMARY KEY (mac, t),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        ) WITHOUT ROWID;
+        INSERT INTO hourly_t_new
+            SELECT mac, t, count, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, ppm_p50, ppm_p95, hum_max, hum_min, hum_avg
+            FROM hourly_t;
+        DROP TABLE hourly_t;
+        ALTER TABLE hourly_t_new RENAME TO hourly_t;
+        CREATE INDEX hourly_t_t_idx ON hourly_t (t);
+
+        CREATE TABLE history_band_t_new (
+            mac TEXT NOT NULL,
+            t INTEGER NOT NULL,
+            ppm INTEGER NOT NULL,
+            minutes INTEGER NOT NULL,
+            PRIMARY KEY (mac, t, ppm),
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        ) WITHOUT ROWID;
+        INSERT INTO history_band_t_new SELECT mac, t, ppm, minutes FROM history_band_t;
+        DROP TABLE history_band_t;
+        ALTER TABLE history_band_t_new RENAME TO history_band_t;
+        CREATE INDEX history_band_t_t_idx ON history_band_t (t);
+        ",
+    )
+    .map_err(|e| {
+        error!("sqlite without rowid migration error -> {:?}", e);
+        ()
+    })
+}
+
+/*
+ * Each database has an identity, sent with sync, so that a central instance
+ * can tell when a site's database has been replaced and its change sequence
+ * has started over.
+ */
+fn migrate_v8_db_id(conn: &Connection) -> Result<(), ()> {
+    conn.execute_batch(
+        "CREATE TABLE db_id_t (id TEXT NOT NULL);
+        INSERT INTO db_id_t (id) VALUES (lower(hex(randomblob(16))));
+        ALTER TABLE sync_cursor_t ADD COLUMN db_id TEXT;
+        ",
+    )
+    .map_err(|e| {
+        error!("sqlite database identity migration error -> {:?}", e);
+        ()
+    })
+}
+
+/// Give the database a new identity, once it has been restored from a backup
+/// and so also has the identity and change sequence of the one backed up.
+pub fn renew_db_id(conn: &Connection) -> Result<(), ()> {
+    let exists: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'db_id_t'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .map_err(|e| {
+            error!("sqlite query_row error -> {:?}", e);
+            ()
+        })?;
+
+    if exists == 0 {
+        return Ok(());
+    }
+
+    conn.execute(
+        "UPDATE db_id_t SET id = lower(hex(randomblob(16)))",
+        NO_PARAMS,
+    )
+    .map(|_| ())
+    .map_err(|e| {
+        error!("sqlite execute error -> {:?}", e);
+        ()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migrations::{get_version, migrate, SCHEMA_VERSION};
+    use rusqlite::{Connection, NO_PARAMS};
+
+    // The schema as it was created before any migrations existed, with local
+    // timestamps and duplicated rows.
+    const FIXTURE_V0: &str = "
+        CREATE TABLE meter_t (mac TEXT PRIMARY KEY, label TEXT);
+        CREATE TABLE event_t (
+            mac TEXT, ts TEXT NOT NULL, temp INTEGER NOT NULL, ppm INTEGER NOT NULL, hum INTEGER NOT NULL,
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        CREATE INDEX event_t_ts_idx ON event_t (ts);
+        CREATE TABLE history_t (
+            mac TEXT, t TEXT NOT NULL,
+            temp_max INTEGER NOT NULL, temp_min INTEGER NOT NULL, temp_avg INTEGER NOT NULL,
+            ppm_max INTEGER NOT NULL, ppm_min INTEGER NOT NULL, ppm_avg INTEGER NOT NULL,
+            hum_max INTEGER NOT NULL, hum_min INTEGER NOT NULL, hum_avg INTEGER NOT NULL,
+            FOREIGN KEY(mac) REFERENCES meter_t(mac) ON DELETE CASCADE
+        );
+        CREATE INDEX history_t_t_idx ON history_t (t);
+        INSERT INTO meter_t (mac) VALUES ('00:00:00:00:00:00');
+        INSERT INTO event_t VALUES ('00:00:00:00:00:00', '2020-04-05 13:02:19+1000', 123, 415, 123);
+        INSERT INTO event_t VALUES ('00:00:00:00:00:00', '2020-04-05 13:02:19+1000', 123, 415, 123);
+        INSERT INTO event_t VALUES ('00:00:00:00:00:00', '2020-04-05 14:02:19+1000', 123, 415, 123);
+        INSERT INTO history_t VALUES ('00:00:00:00:00:00', '2020-04-04 00:00:00+1000', 1, 1, 1, 1, 1, 1, 1, 1, 1);
+        INSERT INTO history_t VALUES ('00:00:00:00:00:00', '2020-04-04 00:00:00+1000', 2, 2, 2, 2, 2, 2, 2, 2, 2);
+    ";
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(
+            &format!("SELECT COUNT(*) FROM {}", table),
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_migrate_fresh() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, "").unwrap();
+        assert!(get_version(&conn) == Ok(SCHEMA_VERSION));
+        // Running again is a no-op.
+        migrate(&mut conn, "").unwrap();
+        assert!(count(&conn, "schema_version_t") == SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_migrate_fixture_v0() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let path = std::env::temp_dir().join(format!("micd_migrate_v0_{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let backup_path = format!("{}.v0.bak", path);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&backup_path);
+
+        let mut conn = Connection::open(&path).unwrap();
+        conn.execute_batch(FIXTURE_V0).unwrap();
+        assert!(get_version(&conn) == Ok(0));
+
+        migrate(&mut conn, &path).unwrap();
+        assert!(get_version(&conn) == Ok(SCHEMA_VERSION));
+
+        // Duplicates are gone, and timestamps are now utc epoch milliseconds.
+        assert!(count(&conn, "event_t") == 2);
+        assert!(count(&conn, "history_t") == 1);
+        let ts: i64 = conn
+            .query_row("SELECT MIN(ts) FROM event_t", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        // 2020-04-05 03:02:19+0000
+        assert!(ts == 1586055739000);
+        let t: i64 = conn
+            .query_row("SELECT t FROM history_t", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        // 2020-04-04 00:00:00+1000
+        assert!(t == 1585922400000);
+        // Events are numbered for sync, and carry on from there.
+        let changes: (i64, i64) = conn
+            .query_row(
+                "SELECT MAX(change_seq), (SELECT seq FROM change_seq_t) FROM event_t",
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert!(changes == (2, 2));
+        // The most recent report for a day is kept.
+        let temp_max: i64 = conn
+            .query_row("SELECT temp_max FROM history_t", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(temp_max == 2);
+
+        // And the original was saved first.
+        let old = Connection::open(&backup_path).unwrap();
+        assert!(get_version(&old) == Ok(0));
+        assert!(count(&old, "event_t") == 3);
+
+        drop(old);
+        drop(conn);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&backup_path);
+    }
+
+    #[test]
+    fn test_migrate_refuses_newer() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, "").unwrap();
+        conn.execute(
+            "INSERT INTO schema_version_t (version, applied) VALUES (9999, 'future')",
+            NO_PARAMS,
+        )
+        .unwrap();
+        assert!(migrate(&mut conn, "").is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! Writes canvases as the pages of a pdf. Text is set in Helvetica, which
+//! every reader has, so no font is embedded.
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::canvas::{rgb, Anchor, Canvas, Shape, Text};
+
+// The widths of printable ascii in Helvetica, in thousandths of the font
+// size, from space.
+const WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+    611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+    222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+// Bezier control distance for a quarter circle of radius one.
+const KAPPA: f32 = 0.552_285;
+
+// Characters as Windows-1252 bytes, which matches unicode from 160 to 255.
+// Anything else is a question mark.
+fn encode_text(s: &str) -> Vec<u8> {
+    s.chars()
+        .map(|c| match c as u32 {
+            n @ 32..=126 | n @ 160..=255 => n as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+fn text_width(t: &Text) -> f32 {
+    encode_text(&t.text)
+        .iter()
+        .map(|b| match *b {
+            32..=126 => WIDTHS[(*b - 32) as usize],
+            _ => 556,
+        } as f32)
+        .sum::<f32>()
+        * t.size
+        / 1000.0
+}
+
+// A pdf string, with its delimiters and anything outside ascii escaped.
+fn literal(bytes: &[u8]) -> String {
+    let mut s = String::from("(");
+    bytes.iter().for_each(|b| match *b {
+        b'(' | b')' | b'\\' => {
+            s.push('\\');
+            s.push(*b as char);
+        }
+        32..=126 => s.push(*b as char),
+        _ => {
+            let _ = write!(s, "\\{:03o}", b);
+        }
+    });
+    s.push(')');
+    s
+}
+
+fn colour(c: &str, op: &str) -> String {
+    let (r, g, b) = rgb(c);
+    format!(
+        "{:.3} {:.3} {:.3} {}\n",
+        r as f32 / 255.0,
+        g as f32 / 255.0,
+        b as f32 / 255.0,
+        op
+    )
+}
+
+// The name of the graphics state for an opacity, in hundredths.
+fn opacity_state(opacity: f32) -> u32 {
+    (opacity.clamp(0.0, 1.0) * 100.0).round() as u32
+}
+
+// The drawing operators for a page, and the opacities it uses. Pdf measures
+// up from the bottom, so y is flipped.
+fn content(canvas: &Canvas) -> (String, Vec<u32>) {
+    let h = canvas.height;
+    let mut ops = String::new();
+    let mut states = Vec::new();
+    let path = |ops: &mut String, points: &[(f32, f32)]| {
+        points.iter().enumerate().for_each(|(i, (x, y))| {
+            let _ = writeln!(
+                ops,
+                "{:.2} {:.2} {}",
+                x,
+                h - y,
+                if i == 0 { "m" } else { "l" }
+            );
+        });
+    };
+
+    canvas.shapes.iter().for_each(|shape| {
+        ops.push_str("q\n");
+        match shape {
+            Shape::Rect {
+                x,
+                y,
+                w,
+                h: rh,
+                fill,
+                opacity,
+                stroke,
+            } => {
+                let rect = format!("{:.2} {:.2} {:.2} {:.2} re", x, h - y - rh, w, rh);
+                if let Some(fill) = fill {
+                    if *opacity < 1.0 {
+                        states.push(opacity_state(*opacity));
+                        let _ = writeln!(ops, "/G{} gs", opacity_state(*opacity));
+                    }
+                    ops.push_str(&colour(fill, "rg"));
+                    let _ = writeln!(ops, "{} f", rect);
+                }
+                if let Some(stroke) = stroke {
+                    ops.push_str(&colour(stroke, "RG"));
+                    let _ = writeln!(ops, "1 w {} S", rect);
+                }
+            }
+            Shape::Line {
+                points,
+                colour: c,
+                width,
+            } => {
+                ops.push_str(&colour(c, "RG"));
+                let _ = writeln!(ops, "{} w 1 J 1 j", width);
+                path(&mut ops, points);
+                ops.push_str("S\n");
+            }
+            Shape::Area {
+                points,
+                colour: c,
+                opacity,
+            } => {
+                if *opacity < 1.0 {
+                    states.push(opacity_state(*opacity));
+                    let _ = writeln!(ops, "/G{} gs", opacity_state(*opacity));
+                }
+                ops.push_str(&colour(c, "rg"));
+                path(&mut ops, points);
+                ops.push_str("h f*\n");
+            }
+            Shape::Dot { x, y, r, colour: c } => {
+                let (x, y, k) = (*x, h - y, r * KAPPA);
+                ops.push_str(&colour(c, "rg"));
+                let _ = writeln!(ops, "{:.2} {:.2} m", x + r, y);
+                [(1.0, 1.0), (-1.0, 1.0), (-1.0, -1.0), (1.0, -1.0)]
+                    .iter()
+                    .for_each(|(sx, sy)| {
+                        // From the point on one axis, round to the next.
+                        let (ax, ay) = if sx * sy > 0.0 {
+                            (*sx, 0.0)
+                        } else {
+                            (0.0, *sy)
+                        };
+                        let (bx, by) = if sx * sy > 0.0 {
+                            (0.0, *sy)
+                        } else {
+                            (*sx, 0.0)
+                        };
+                        let _ = writeln!(
+                            ops,
+                            "{:.2} {:.2} {:.2} {:.2} {:.2} {:.2} c",
+                            x + ax * r + bx * k,
+                            y + ay * r + by * k,
+                            x + bx * r + ax * k,
+                            y + by * r + ay * k,
+                            x + bx * r,
+                            y + by * r
+                        );
+                    });
+                ops.push_str("f\n");
+            }
+            Shape::Text(t) => {
+                let (sin, cos) = t.rotate.to_radians().sin_cos();
+                let along = match t.anchor {
+                    Anchor::Start => 0.0,
+                    Anchor::Middle => -text_width(t) / 2.0,
+                    Anchor::End => -text_width(t),
+                };
+                let down = if t.centred { 0.35 * t.size } else { 0.0 };
+                let x = t.x + along * cos - down * sin;
+                let y = t.y + along * sin + down * cos;
+                ops.push_str(&colour(&t.colour, "rg"));
+                let _ = writeln!(
+                    ops,
+                    "BT /F1 {} Tf {:.4} {:.4} {:.4} {:.4} {:.2} {:.2} Tm {} Tj ET",
+                    t.size,
+                    cos,
+                    -sin,
+                    sin,
+                    cos,
+                    x,
+                    h - y,
+                    literal(&encode_text(&t.text))
+                );
+            }
+        }
+        ops.push_str("Q\n");
+    });
+
+    states.sort_unstable();
+    states.dedup();
+    (ops, states)
+}
+
+/// A pdf with a page for each canvas, sized to it in points.
+pub fn write(pages: &[Canvas]) -> Result<Vec<u8>, ()> {
+    // The catalog, the page tree and the font come first, then each page
+    // and its content.
+    let page_obj = |i: usize| 4 + i * 2;
+    let mut objects: Vec<Vec<u8>> = vec![
+        b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            (0..pages.len())
+                .map(|i| format!("{} 0 R", page_obj(i)))
+                .collect::<Vec<_>>()
+                .join(" "),
+            pages.len()
+        )
+        .into_bytes(),
+        b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+            .to_vec(),
+    ];
+
+    for (i, canvas) in pages.iter().enumerate() {
+        let (ops, states) = content(canvas);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        let data = encoder
+            .write_all(ops.as_bytes())
+            .and_then(|_| encoder.finish())
+            .map_err(|e| {
+                error!("pdf compression error -> {:?}", e);
+                ()
+            })?;
+
+        let states: String = states
+            .iter()
+            .map(|s| {
+                format!(
+                    " /G{} << /ca {} /CA {} >>",
+                    s,
+                    *s as f32 / 100.0,
+                    *s as f32 / 100.0
+                )
+            })
+            .collect();
+        objects.push(
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Contents {} 0 R /Resources << /Font << /F1 3 0 R >> /ExtGState <<{} >> >> >>",
+                canvas.width,
+                canvas.height,
+                page_obj(i) + 1,
+                states
+            )
+            .into_bytes(),
+        );
+        let mut stream = format!(
+            "<< /Length {} /Filter /FlateDecode >>\nstream\n",
+            data.len()
+        )
+        .into_bytes();
+        stream.extend_from_slice(&data);
+        stream.extend_from_slice(b"\nendstream");
+        objects.push(stream);
+    }
+
+    let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    objects.iter().enumerate().for_each(|(i, obj)| {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+        pdf.extend_from_slice(obj);
+        pdf.extend_from_slice(b"\nendobj\n");
+    });
+
+    let xref = pdf.len();
+    let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+    offsets.iter().for_each(|o| {
+        let _ = writeln!(table, "{:010} 00000 n ", o);
+    });
+    let _ = write!(
+        table,
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1,
+        xref
+    );
+    pdf.extend_from_slice(table.as_bytes());
+    Ok(pdf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::canvas::{Anchor, Canvas, Text};
+    use crate::pdf::{content, literal, write};
+
+    #[test]
+    fn test_pdf_content() {
+        assert!(literal(b"a (b) \\ \xb0") == "(a \\(b\\) \\\\ \\260)");
+
+        let mut canvas = Canvas::new(200, 100);
+        canvas.fill(10.0, 10.0, 20.0, 30.0, "#ff0000", 0.5);
+        canvas.area(vec![(0.0, 0.0), (10.0, 10.0), (0.0, 10.0)], "#0000ff", 0.25);
+        canvas.text(Text {
+            x: 100.0,
+            y: 20.0,
+            text: "ii".to_string(),
+            size: 10.0,
+            anchor: Anchor::Middle,
+            ..Default::default()
+        });
+        let (ops, states) = content(&canvas);
+        assert!(states == vec![25, 50]);
+        // Measured from the bottom.
+        assert!(ops.contains("/G50 gs\n1.000 0.000 0.000 rg\n10.00 60.00 20.00 30.00 re f\n"));
+        assert!(ops.contains("0.00 100.00 m\n10.00 90.00 l\n"));
+        // Two i's are 4.44 points wide.
+        assert!(ops.contains("BT /F1 10 Tf 1.0000 -0.0000 0.0000 1.0000 97.78 80.00 Tm (ii) Tj ET"));
+    }
+
+    #[test]
+    fn test_pdf_write() {
+        let pages = vec![Canvas::new(842, 595), Canvas::new(842, 595)];
+        let pdf = write(&pages).unwrap();
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.starts_with("%PDF-1.4\n"));
+        assert!(text.ends_with("%%EOF\n"));
+        assert!(text.contains("/Kids [4 0 R 6 0 R] /Count 2"));
+        assert!(text.contains("/MediaBox [0 0 842 595]"));
+        assert!(text.contains("xref\n0 8\n"));
+
+        // Each object is where the table says. Streams aren't text, so
+        // offsets are into the bytes.
+        let start: usize = text[text.rfind("startxref\n").unwrap() + 10..]
+            .trim_end_matches("\n%%EOF\n")
+            .parse()
+            .unwrap();
+        assert!(pdf[start..].starts_with(b"xref\n"));
+        std::str::from_utf8(&pdf[start..])
+            .unwrap()
+            .lines()
+            .skip(3)
+            .take(7)
+            .enumerate()
+            .for_each(|(i, line)| {
+                let offset: usize = line[..10].parse().unwrap();
+                assert!(pdf[offset..].starts_with(format!("{} 0 obj\n", i + 1).as_bytes()));
+            });
+    }
+}
@@ -0,0 +1,612 @@
+use postgres::{Client, NoTls, Row};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+use time::OffsetDateTime;
+
+use mic::prelude::*;
+
+use crate::db::{DbEvent, DbHistoryEvent, DbHourlyEvent};
+use crate::storage::Storage;
+
+// Times are utc milliseconds, the same as in sqlite.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meter_t (
+    mac TEXT PRIMARY KEY,
+    label TEXT
+);
+CREATE TABLE IF NOT EXISTS event_t (
+    mac TEXT NOT NULL REFERENCES meter_t(mac) ON DELETE CASCADE,
+    ts BIGINT NOT NULL,
+    seq BIGINT NOT NULL DEFAULT 0,
+    temp INTEGER NOT NULL,
+    ppm INTEGER NOT NULL,
+    hum INTEGER NOT NULL,
+    PRIMARY KEY (mac, ts)
+);
+CREATE TABLE IF NOT EXISTS hourly_t (
+    mac TEXT NOT NULL REFERENCES meter_t(mac) ON DELETE CASCADE,
+    t BIGINT NOT NULL,
+    count INTEGER NOT NULL,
+    temp_max INTEGER NOT NULL,
+    temp_min INTEGER NOT NULL,
+    temp_avg INTEGER NOT NULL,
+    ppm_max INTEGER NOT NULL,
+    ppm_min INTEGER NOT NULL,
+    ppm_avg INTEGER NOT NULL,
+    ppm_p50 INTEGER NOT NULL,
+    ppm_p95 INTEGER NOT NULL,
+    hum_max INTEGER NOT NULL,
+    hum_min INTEGER NOT NULL,
+    hum_avg INTEGER NOT NULL,
+    PRIMARY KEY (mac, t)
+);
+CREATE TABLE IF NOT EXISTS history_t (
+    mac TEXT NOT NULL REFERENCES meter_t(mac) ON DELETE CASCADE,
+    t BIGINT NOT NULL,
+    count INTEGER,
+    coverage INTEGER,
+    temp_max INTEGER,
+    temp_min INTEGER,
+    temp_avg INTEGER,
+    ppm_max INTEGER,
+    ppm_min INTEGER,
+    ppm_avg INTEGER,
+    hum_max INTEGER,
+    hum_min INTEGER,
+    hum_avg INTEGER,
+    temp_p50 INTEGER,
+    temp_p95 INTEGER,
+    temp_stddev INTEGER,
+    ppm_p50 INTEGER,
+    ppm_p95 INTEGER,
+    ppm_stddev INTEGER,
+    hum_p50 INTEGER,
+    hum_p95 INTEGER,
+    hum_stddev INTEGER,
+    ppm_peak_t BIGINT,
+    PRIMARY KEY (mac, t)
+);
+CREATE TABLE IF NOT EXISTS history_band_t (
+    mac TEXT NOT NULL REFERENCES meter_t(mac) ON DELETE CASCADE,
+    t BIGINT NOT NULL,
+    ppm INTEGER NOT NULL,
+    minutes INTEGER NOT NULL,
+    PRIMARY KEY (mac, t, ppm)
+);
+";
+
+// Postgres has no unsigned types, so values are widened to fit.
+fn opt_i32<T: Into<i32> + Copy>(v: Option<T>) -> Option<i32> {
+    v.map(|v| v.into())
+}
+
+fn get_opt_u16(row: &Row, idx: usize) -> Option<u16> {
+    row.get::<usize, Option<i32>>(idx).map(|v| v as u16)
+}
+
+/// Storage in a PostgreSQL database, for when many sites report to one
+/// central instance.
+pub struct PgStorage {
+    client: Mutex<Client>,
+}
+
+impl PgStorage {
+    /// Connect with a libpq style string, eg "host=/run/postgresql dbname=micd".
+    pub fn new(url: &str) -> Result<Self, ()> {
+        let mut client = Client::connect(url, NoTls).map_err(|e| {
+            error!("postgres connect error -> {:?}", e);
+            ()
+        })?;
+        client.batch_execute(SCHEMA).map_err(|e| {
+            error!("postgres schema error -> {:?}", e);
+            ()
+        })?;
+        Ok(PgStorage {
+            client: Mutex::new(client),
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Client>, ()> {
+        self.client.lock().map_err(|e| {
+            error!("postgres client lock error -> {:?}", e);
+            ()
+        })
+    }
+
+    fn query_time(&self, query: &str, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        self.lock()?
+            .query_one(query, &[&src])
+            .map(|row| row.get::<usize, Option<i64>>(0).map(|t| ts_from_db!(t)))
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })
+    }
+}
+
+impl Storage for PgStorage {
+    fn add_datum(&self, datum: Datum, ct: OffsetDateTime, seq: i64) -> Result<(), ()> {
+        let mac = datum.mac_as_string();
+        let (ppm, hum, temp) = datum.data();
+        let ts = ts_to_db!(ct);
+
+        let mut client = self.lock()?;
+        client
+            .execute(
+                "INSERT INTO meter_t (mac) VALUES ($1) ON CONFLICT DO NOTHING",
+                &[&mac],
+            )
+            .and_then(|_| {
+                client.execute(
+                    "INSERT INTO event_t (mac, ts, seq, temp, ppm, hum) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING",
+                    &[&mac, &ts, &seq, &i32::from(temp), &i32::from(ppm), &i32::from(hum)],
+                )
+            })
+            .map(|r| {
+                debug!("insert -> {:?}", r);
+                if r == 0 {
+                    warn!("ignoring duplicate event for {} at {}", mac, ts);
+                }
+                ()
+            })
+            .map_err(|e| {
+                error!("postgres execute error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn list_meters(&self) -> Result<Vec<String>, ()> {
+        self.lock()?
+            .query("SELECT mac FROM meter_t ORDER BY mac", &[])
+            .map(|rows| rows.iter().map(|row| row.get(0)).collect())
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn get_meter_labels(&self) -> Result<BTreeMap<String, String>, ()> {
+        self.lock()?
+            .query(
+                "SELECT mac, label FROM meter_t WHERE label IS NOT NULL",
+                &[],
+            )
+            .map(|rows| rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn set_meter_label(&self, src: &str, label: Option<&str>) -> Result<(), ()> {
+        self.lock()?
+            .execute(
+                "INSERT INTO meter_t (mac, label) VALUES ($1, $2) ON CONFLICT (mac) DO UPDATE SET label = EXCLUDED.label",
+                &[&src, &label],
+            )
+            .map(|r| {
+                debug!("upsert -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("postgres execute error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn get_event_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbEvent>, ()> {
+        let min_ts = ts_to_db!(min);
+        let max_ts = ts_to_db!(max);
+
+        self.lock()?
+            .query(
+                "SELECT ts, temp, ppm, hum FROM event_t WHERE mac = $1 AND ts >= $2 AND ts < $3 ORDER BY ts ASC",
+                &[&src, &min_ts, &max_ts],
+            )
+            .map(|rows| {
+                rows.iter()
+                    .map(|row| DbEvent {
+                        src: src.to_string(),
+                        time: ts_from_db!(row.get(0)),
+                        temp: row.get::<usize, i32>(1) as u16,
+                        ppm: row.get::<usize, i32>(2) as u16,
+                        hum: row.get::<usize, i32>(3) as u16,
+                    })
+                    .collect()
+            })
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn get_next_event_time(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>, ()> {
+        let min_ts = ts_to_db!(min);
+
+        self.lock()?
+            .query_one(
+                "SELECT MIN(ts) FROM event_t WHERE mac = $1 AND ts >= $2",
+                &[&src, &min_ts],
+            )
+            .map(|row| row.get::<usize, Option<i64>>(0).map(|ts| ts_from_db!(ts)))
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn get_last_event_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        self.query_time("SELECT MAX(ts) FROM event_t WHERE mac = $1", src)
+    }
+
+    fn get_history(&self, src: &str) -> Result<Vec<DbHistoryEvent>, ()> {
+        let mut client = self.lock()?;
+
+        // The time spent above each band, for each day.
+        let mut bands: BTreeMap<i64, BTreeMap<u16, u32>> = BTreeMap::new();
+        client
+            .query(
+                "SELECT t, ppm, minutes FROM history_band_t WHERE mac = $1",
+                &[&src],
+            )
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })?
+            .iter()
+            .for_each(|row| {
+                bands
+                    .entry(row.get(0))
+                    .or_insert_with(BTreeMap::new)
+                    .insert(
+                        row.get::<usize, i32>(1) as u16,
+                        row.get::<usize, i32>(2) as u32,
+                    );
+            });
+
+        client
+            .query(
+                "SELECT t, count, coverage, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg, temp_p50, temp_p95, temp_stddev, ppm_p50, ppm_p95, ppm_stddev, hum_p50, hum_p95, hum_stddev, ppm_peak_t FROM history_t WHERE mac = $1 ORDER BY t ASC",
+                &[&src],
+            )
+            .map(|rows| {
+                rows.iter()
+                    .map(|row| {
+                        let t: i64 = row.get(0);
+                        DbHistoryEvent {
+                            src: src.to_string(),
+                            time: ts_from_db!(t),
+                            count: row.get::<usize, Option<i32>>(1).map(|v| v as u32),
+                            coverage: row.get::<usize, Option<i32>>(2).map(|v| v as u8),
+                            temp_max: get_opt_u16(row, 3),
+                            temp_min: get_opt_u16(row, 4),
+                            temp_avg: get_opt_u16(row, 5),
+                            ppm_max: get_opt_u16(row, 6),
+                            ppm_min: get_opt_u16(row, 7),
+                            ppm_avg: get_opt_u16(row, 8),
+                            hum_max: get_opt_u16(row, 9),
+                            hum_min: get_opt_u16(row, 10),
+                            hum_avg: get_opt_u16(row, 11),
+                            temp_p50: get_opt_u16(row, 12),
+                            temp_p95: get_opt_u16(row, 13),
+                            temp_stddev: get_opt_u16(row, 14),
+                            ppm_p50: get_opt_u16(row, 15),
+                            ppm_p95: get_opt_u16(row, 16),
+                            ppm_stddev: get_opt_u16(row, 17),
+                            hum_p50: get_opt_u16(row, 18),
+                            hum_p95: get_opt_u16(row, 19),
+                            hum_stddev: get_opt_u16(row, 20),
+                            ppm_peak: row
+                                .get::<usize, Option<i64>>(21)
+                                .map(|ts| ts_from_db!(ts)),
+                            minutes_above: bands.remove(&t).unwrap_or_default(),
+                        }
+                    })
+                    .collect()
+            })
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn get_last_history_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        self.query_time("SELECT MAX(t) FROM history_t WHERE mac = $1", src)
+    }
+
+    fn put_history(&self, h: &DbHistoryEvent) -> Result<(), ()> {
+        let t = ts_to_db!(h.time);
+        let ppm_peak = h.ppm_peak.map(|ts| ts_to_db!(ts));
+        let count = h.count.map(|v| v as i32);
+
+        let mut client = self.lock()?;
+        let mut tx = client.transaction().map_err(|e| {
+            error!("postgres transaction error -> {:?}", e);
+            ()
+        })?;
+
+        tx.execute(
+            "INSERT INTO meter_t (mac) VALUES ($1) ON CONFLICT DO NOTHING",
+            &[&h.src],
+        )
+        .and_then(|_| {
+            tx.execute(
+                "DELETE FROM history_band_t WHERE mac = $1 AND t = $2",
+                &[&h.src, &t],
+            )
+        })
+        .and_then(|_| {
+            tx.execute(
+                "DELETE FROM history_t WHERE mac = $1 AND t = $2",
+                &[&h.src, &t],
+            )
+        })
+        .and_then(|_| {
+            tx.execute(
+                "INSERT INTO history_t (mac, t, count, coverage, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, hum_max, hum_min, hum_avg, temp_p50, temp_p95, temp_stddev, ppm_p50, ppm_p95, ppm_stddev, hum_p50, hum_p95, hum_stddev, ppm_peak_t) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23)",
+                &[
+                    &h.src,
+                    &t,
+                    &count,
+                    &opt_i32(h.coverage),
+                    &opt_i32(h.temp_max),
+                    &opt_i32(h.temp_min),
+                    &opt_i32(h.temp_avg),
+                    &opt_i32(h.ppm_max),
+                    &opt_i32(h.ppm_min),
+                    &opt_i32(h.ppm_avg),
+                    &opt_i32(h.hum_max),
+                    &opt_i32(h.hum_min),
+                    &opt_i32(h.hum_avg),
+                    &opt_i32(h.temp_p50),
+                    &opt_i32(h.temp_p95),
+                    &opt_i32(h.temp_stddev),
+                    &opt_i32(h.ppm_p50),
+                    &opt_i32(h.ppm_p95),
+                    &opt_i32(h.ppm_stddev),
+                    &opt_i32(h.hum_p50),
+                    &opt_i32(h.hum_p95),
+                    &opt_i32(h.hum_stddev),
+                    &ppm_peak,
+                ],
+            )
+        })
+        .map_err(|e| {
+            error!("postgres execute error -> {:?}", e);
+            ()
+        })?;
+
+        h.minutes_above.iter().try_for_each(|(ppm, minutes)| {
+            tx.execute(
+                "INSERT INTO history_band_t (mac, t, ppm, minutes) VALUES ($1, $2, $3, $4)",
+                &[&h.src, &t, &i32::from(*ppm), &(*minutes as i32)],
+            )
+            .map(|_| ())
+            .map_err(|e| {
+                error!("postgres execute error -> {:?}", e);
+                ()
+            })
+        })?;
+
+        tx.commit().map_err(|e| {
+            error!("postgres commit error -> {:?}", e);
+            ()
+        })
+    }
+
+    fn purge_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        let max_ts = ts_to_db!(max);
+
+        self.lock()?
+            .execute("DELETE FROM event_t WHERE ts < $1", &[&max_ts])
+            .map(|r| {
+                debug!("delete -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("postgres execute error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn purge_history_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        let max_ts = ts_to_db!(max);
+
+        let mut client = self.lock()?;
+        client
+            .execute("DELETE FROM history_band_t WHERE t < $1", &[&max_ts])
+            .and_then(|_| client.execute("DELETE FROM history_t WHERE t < $1", &[&max_ts]))
+            .map(|r| {
+                debug!("delete -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("postgres execute error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn import_events(&self, src: &str, events: &[DbEvent]) -> Result<usize, ()> {
+        let mut client = self.lock()?;
+        let mut tx = client.transaction().map_err(|e| {
+            error!("postgres transaction error -> {:?}", e);
+            ()
+        })?;
+
+        tx.execute(
+            "INSERT INTO meter_t (mac) VALUES ($1) ON CONFLICT DO NOTHING",
+            &[&src],
+        )
+        .map_err(|e| {
+            error!("postgres execute error -> {:?}", e);
+            ()
+        })?;
+
+        let added = events.iter().try_fold(0, |added, e| {
+            tx.execute(
+                "INSERT INTO event_t (mac, ts, temp, ppm, hum) VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+                &[&src, &ts_to_db!(e.time), &i32::from(e.temp), &i32::from(e.ppm), &i32::from(e.hum)],
+            )
+            .map(|r| added + r as usize)
+            .map_err(|e| {
+                error!("postgres execute error -> {:?}", e);
+                ()
+            })
+        })?;
+
+        tx.commit().map_err(|e| {
+            error!("postgres commit error -> {:?}", e);
+            ()
+        })?;
+        Ok(added)
+    }
+
+    fn get_latest_sequences(&self) -> Result<BTreeMap<String, i64>, ()> {
+        self.lock()?
+            .query("SELECT mac, MAX(seq) FROM event_t GROUP BY mac", &[])
+            .map(|rows| rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn get_hourly_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbHourlyEvent>, ()> {
+        let min_ts = ts_to_db!(min);
+        let max_ts = ts_to_db!(max);
+        let get = |row: &Row, idx: usize| row.get::<usize, i32>(idx) as u16;
+
+        self.lock()?
+            .query(
+                "SELECT t, count, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, ppm_p50, ppm_p95, hum_max, hum_min, hum_avg FROM hourly_t WHERE mac = $1 AND t >= $2 AND t < $3 ORDER BY t ASC",
+                &[&src, &min_ts, &max_ts],
+            )
+            .map(|rows| {
+                rows.iter()
+                    .map(|row| DbHourlyEvent {
+                        src: src.to_string(),
+                        time: ts_from_db!(row.get(0)),
+                        count: row.get::<usize, i32>(1) as u32,
+                        temp_max: get(row, 2),
+                        temp_min: get(row, 3),
+                        temp_avg: get(row, 4),
+                        ppm_max: get(row, 5),
+                        ppm_min: get(row, 6),
+                        ppm_avg: get(row, 7),
+                        ppm_p50: get(row, 8),
+                        ppm_p95: get(row, 9),
+                        hum_max: get(row, 10),
+                        hum_min: get(row, 11),
+                        hum_avg: get(row, 12),
+                    })
+                    .collect()
+            })
+            .map_err(|e| {
+                error!("postgres query error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn get_last_hourly_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        self.query_time("SELECT MAX(t) FROM hourly_t WHERE mac = $1", src)
+    }
+
+    fn put_hourly(&self, h: &DbHourlyEvent) -> Result<(), ()> {
+        let t = ts_to_db!(h.time);
+
+        let mut client = self.lock()?;
+        client
+            .execute(
+                "INSERT INTO meter_t (mac) VALUES ($1) ON CONFLICT DO NOTHING",
+                &[&h.src],
+            )
+            .and_then(|_| {
+                client.execute(
+                    "INSERT INTO hourly_t (mac, t, count, temp_max, temp_min, temp_avg, ppm_max, ppm_min, ppm_avg, ppm_p50, ppm_p95, hum_max, hum_min, hum_avg) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14) ON CONFLICT (mac, t) DO UPDATE SET count = EXCLUDED.count, temp_max = EXCLUDED.temp_max, temp_min = EXCLUDED.temp_min, temp_avg = EXCLUDED.temp_avg, ppm_max = EXCLUDED.ppm_max, ppm_min = EXCLUDED.ppm_min, ppm_avg = EXCLUDED.ppm_avg, ppm_p50 = EXCLUDED.ppm_p50, ppm_p95 = EXCLUDED.ppm_p95, hum_max = EXCLUDED.hum_max, hum_min = EXCLUDED.hum_min, hum_avg = EXCLUDED.hum_avg",
+                    &[
+                        &h.src,
+                        &t,
+                        &(h.count as i32),
+                        &i32::from(h.temp_max),
+                        &i32::from(h.temp_min),
+                        &i32::from(h.temp_avg),
+                        &i32::from(h.ppm_max),
+                        &i32::from(h.ppm_min),
+                        &i32::from(h.ppm_avg),
+                        &i32::from(h.ppm_p50),
+                        &i32::from(h.ppm_p95),
+                        &i32::from(h.hum_max),
+                        &i32::from(h.hum_min),
+                        &i32::from(h.hum_avg),
+                    ],
+                )
+            })
+            .map(|r| {
+                debug!("upsert -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("postgres execute error -> {:?}", e);
+                ()
+            })
+    }
+
+    fn purge_hourly_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        let max_ts = ts_to_db!(max);
+
+        self.lock()?
+            .execute("DELETE FROM hourly_t WHERE t < $1", &[&max_ts])
+            .map(|r| {
+                debug!("delete -> {:?}", r);
+                ()
+            })
+            .map_err(|e| {
+                error!("postgres execute error -> {:?}", e);
+                ()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pg::PgStorage;
+    use crate::storage::tests::check_storage;
+
+    // These need a postgres to talk to, eg
+    //   MICD_TEST_PG_URL="host=/tmp user=micd dbname=micd_test"
+    // Everything in that database is removed.
+    #[test]
+    fn test_pg_storage() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let url = match std::env::var("MICD_TEST_PG_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                println!("MICD_TEST_PG_URL is not set, skipping");
+                return;
+            }
+        };
+
+        let store = PgStorage::new(&url).expect("Unable to connect to postgres");
+        store
+            .lock()
+            .unwrap()
+            .batch_execute("TRUNCATE meter_t, event_t, hourly_t, history_t, history_band_t")
+            .expect("Unable to clear the test database");
+
+        check_storage(&store);
+    }
+}
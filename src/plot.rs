@@ -0,0 +1,139 @@
+//! Draws charts with gnuplot, which must be installed where micd runs.
+
+use gnuplot::AxesCommon;
+use gnuplot::{
+    AutoOption, Caption, Color, Coordinate, Figure, FillAlpha, LabelOption, LineWidth, Tick,
+    TickOption,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// gnuplot can only write to a file, so each render gets its own.
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+use crate::canvas::Format;
+use crate::chart::Chart;
+
+// gnuplot sizes pdfs in inches rather than pixels.
+const PDF_DPI: u32 = 100;
+
+pub fn render(chart: &Chart, width: u32, height: u32, format: Format) -> Result<Vec<u8>, ()> {
+    let ticks: Vec<Tick<i64, String>> = chart
+        .ticks
+        .iter()
+        .map(|t| Tick::Major(t.at, AutoOption::Fix(t.label.clone())))
+        .collect();
+
+    let (x_min, x_max) = chart.x_axis();
+    let (y_top, _) = chart.y_axis();
+
+    let mut fg = Figure::new();
+    {
+        let axes = fg
+            .axes2d()
+            .set_x_label("Time", &[])
+            .set_x_ticks_custom(
+                ticks.as_slice(),
+                &[TickOption::OnAxis(false), TickOption::Inward(true)],
+                &[
+                    LabelOption::TextOffset(-1.0, -1.0),
+                    LabelOption::Font("Helvetica", 10.0),
+                    LabelOption::Rotate(280.0),
+                ],
+            )
+            .set_y_label(chart.y_label.as_str(), &[])
+            .set_y_ticks(
+                Some((AutoOption::Auto, 5)),
+                &[
+                    TickOption::OnAxis(false),
+                    TickOption::Mirror(true),
+                    TickOption::Inward(false),
+                ],
+                &[],
+            )
+            .set_y_range(AutoOption::Fix(0.0), AutoOption::Fix(y_top as f64))
+            .set_x_axis(false, &[]);
+
+        chart.bands.iter().filter(|b| b.from < y_top).for_each(|b| {
+            let to = b.to.unwrap_or(y_top).min(y_top);
+            axes.fill_between(
+                &[x_min, x_max],
+                &[b.from, b.from],
+                &[to, to],
+                &[Caption(b.label.as_str()), Color(b.colour), FillAlpha(0.6)],
+            );
+        });
+
+        chart.annotations.iter().for_each(|a| {
+            axes.fill_between(
+                &[a.from, a.to.max(a.from + 1)],
+                &[0.0, 0.0],
+                &[y_top, y_top],
+                &[Color(a.colour), FillAlpha(0.08)],
+            );
+            if !a.label.is_empty() {
+                axes.label(
+                    a.label.as_str(),
+                    Coordinate::Axis(a.from as f64),
+                    Coordinate::Graph(0.97),
+                    &[LabelOption::Font("Helvetica", 9.0)],
+                );
+            }
+        });
+
+        chart.envelopes.iter().for_each(|e| {
+            axes.fill_between(
+                e.x.as_slice(),
+                e.lo.as_slice(),
+                e.hi.as_slice(),
+                &[
+                    Caption(e.caption.as_str()),
+                    Color(e.colour),
+                    FillAlpha(0.25),
+                ],
+            );
+        });
+
+        chart.series.iter().for_each(|s| {
+            axes.lines(
+                s.x.as_slice(),
+                s.y.as_slice(),
+                &[
+                    Caption(s.caption.as_str()),
+                    Color(s.colour),
+                    LineWidth(s.width as f64),
+                ],
+            );
+        });
+    }
+
+    let path = std::env::temp_dir().join(format!(
+        "micd_{}_{}.{}",
+        std::process::id(),
+        NEXT_FILE.fetch_add(1, Ordering::Relaxed),
+        format.extension()
+    ));
+    let path_str = path.to_str().ok_or_else(|| {
+        error!("temp path is not utf8 -> {:?}", path);
+        ()
+    })?;
+
+    match format {
+        Format::Svg => fg.save_to_svg(path_str, width, height),
+        Format::Png => fg.save_to_png(path_str, width, height),
+        Format::Pdf => fg.save_to_pdf(
+            path_str,
+            (width / PDF_DPI).max(1),
+            (height / PDF_DPI).max(1),
+        ),
+    }
+    .map_err(|e| {
+        error!("gnuplotlib error -> {:?}", e);
+        ()
+    })?;
+    let data = std::fs::read(&path).map_err(|e| {
+        error!("Unable to read chart {} -> {:?}", path_str, e);
+        ()
+    });
+    let _ = std::fs::remove_file(&path);
+    data
+}
@@ -0,0 +1,459 @@
+//! Draws a canvas into pixels and writes them as a png. Text uses a small
+//! built in font, so there is nothing to install.
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+use crate::canvas::{rgb, Anchor, Canvas, Shape, Text};
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// A glyph is 5 pixels wide and 7 high, with a gap of one to the next. Text
+// is scaled so that a glyph pixel is a tenth of the font size.
+const GLYPH_ADVANCE: f32 = 6.0;
+const GLYPH_HEIGHT: usize = 7;
+const GLYPH_UNIT: f32 = 0.1;
+
+// Rows of each printable ascii character, from space, with the leftmost
+// pixel in bit 4.
+const GLYPHS: [[u8; GLYPH_HEIGHT]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04], // !
+    [0x0a, 0x0a, 0x0a, 0x00, 0x00, 0x00, 0x00], // "
+    [0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a], // #
+    [0x04, 0x0f, 0x14, 0x0e, 0x05, 0x1e, 0x04], // $
+    [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03], // %
+    [0x0c, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0d], // &
+    [0x0c, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00], // '
+    [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02], // (
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08], // )
+    [0x00, 0x04, 0x15, 0x0e, 0x15, 0x04, 0x00], // *
+    [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00], // +
+    [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08], // ,
+    [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00], // -
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c], // .
+    [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00], // /
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e], // 0
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e], // 1
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f], // 2
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e], // 3
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02], // 4
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e], // 5
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e], // 6
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // 7
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e], // 8
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c], // 9
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00], // :
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x04, 0x08], // ;
+    [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02], // <
+    [0x00, 0x00, 0x1f, 0x00, 0x1f, 0x00, 0x00], // =
+    [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08], // >
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // ?
+    [0x0e, 0x11, 0x01, 0x0d, 0x15, 0x15, 0x0e], // @
+    [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11], // A
+    [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e], // B
+    [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e], // C
+    [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c], // D
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f], // E
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10], // F
+    [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f], // G
+    [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11], // H
+    [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // I
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c], // J
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // K
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f], // L
+    [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11], // M
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11], // N
+    [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // O
+    [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10], // P
+    [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d], // Q
+    [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11], // R
+    [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e], // S
+    [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // T
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // U
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04], // V
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a], // W
+    [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11], // X
+    [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04], // Y
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f], // Z
+    [0x0e, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0e], // [
+    [0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00], // \
+    [0x0e, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0e], // ]
+    [0x04, 0x0a, 0x11, 0x00, 0x00, 0x00, 0x00], // ^
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f], // _
+    [0x08, 0x04, 0x02, 0x00, 0x00, 0x00, 0x00], // `
+    [0x00, 0x00, 0x0e, 0x01, 0x0f, 0x11, 0x0f], // a
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x1e], // b
+    [0x00, 0x00, 0x0e, 0x10, 0x10, 0x11, 0x0e], // c
+    [0x01, 0x01, 0x0d, 0x13, 0x11, 0x11, 0x0f], // d
+    [0x00, 0x00, 0x0e, 0x11, 0x1f, 0x10, 0x0e], // e
+    [0x06, 0x09, 0x08, 0x1c, 0x08, 0x08, 0x08], // f
+    [0x00, 0x0f, 0x11, 0x11, 0x0f, 0x01, 0x0e], // g
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x11], // h
+    [0x04, 0x00, 0x0c, 0x04, 0x04, 0x04, 0x0e], // i
+    [0x02, 0x00, 0x06, 0x02, 0x02, 0x12, 0x0c], // j
+    [0x10, 0x10, 0x12, 0x14, 0x18, 0x14, 0x12], // k
+    [0x0c, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // l
+    [0x00, 0x00, 0x1a, 0x15, 0x15, 0x11, 0x11], // m
+    [0x00, 0x00, 0x16, 0x19, 0x11, 0x11, 0x11], // n
+    [0x00, 0x00, 0x0e, 0x11, 0x11, 0x11, 0x0e], // o
+    [0x00, 0x00, 0x1e, 0x11, 0x1e, 0x10, 0x10], // p
+    [0x00, 0x00, 0x0d, 0x13, 0x0f, 0x01, 0x01], // q
+    [0x00, 0x00, 0x16, 0x19, 0x10, 0x10, 0x10], // r
+    [0x00, 0x00, 0x0e, 0x10, 0x0e, 0x01, 0x1e], // s
+    [0x08, 0x08, 0x1c, 0x08, 0x08, 0x09, 0x06], // t
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x13, 0x0d], // u
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x0a, 0x04], // v
+    [0x00, 0x00, 0x11, 0x11, 0x15, 0x15, 0x0a], // w
+    [0x00, 0x00, 0x11, 0x0a, 0x04, 0x0a, 0x11], // x
+    [0x00, 0x00, 0x11, 0x11, 0x0f, 0x01, 0x0e], // y
+    [0x00, 0x00, 0x1f, 0x02, 0x04, 0x08, 0x1f], // z
+    [0x02, 0x04, 0x04, 0x08, 0x04, 0x04, 0x02], // {
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // |
+    [0x08, 0x04, 0x04, 0x02, 0x04, 0x04, 0x08], // }
+    [0x00, 0x00, 0x08, 0x15, 0x02, 0x00, 0x00], // ~
+];
+
+struct Raster {
+    width: usize,
+    height: usize,
+    // Red, green and blue for each pixel, row by row from the top.
+    pixels: Vec<u8>,
+}
+
+// How much of the pixel from p to p + 1 lies between a and b.
+fn overlap(p: f32, a: f32, b: f32) -> f32 {
+    (b.min(p + 1.0) - a.max(p)).max(0.0)
+}
+
+// Distance from p to the segment from a to b.
+fn distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len = dx * dx + dy * dy;
+    let t = if len > 0.0 {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (x, y) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - x).powi(2) + (p.1 - y).powi(2)).sqrt()
+}
+
+impl Raster {
+    fn new(width: usize, height: usize) -> Self {
+        Raster {
+            width,
+            height,
+            pixels: vec![255; width * height * 3],
+        }
+    }
+
+    // Mix colour into a pixel, by how much of it is covered.
+    fn blend(&mut self, x: i64, y: i64, colour: (u8, u8, u8), cover: f32) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+        let cover = cover.clamp(0.0, 1.0);
+        let i = (y as usize * self.width + x as usize) * 3;
+        [colour.0, colour.1, colour.2]
+            .iter()
+            .enumerate()
+            .for_each(|(c, v)| {
+                let old = self.pixels[i + c] as f32;
+                self.pixels[i + c] = (old + (*v as f32 - old) * cover).round() as u8;
+            });
+    }
+
+    // The pixels from x0 to x1 and y0 to y1, kept within the raster.
+    fn span(&self, x0: f32, x1: f32, y0: f32, y1: f32) -> (i64, i64, i64, i64) {
+        (
+            (x0.floor() as i64).max(0),
+            (x1.ceil() as i64).min(self.width as i64),
+            (y0.floor() as i64).max(0),
+            (y1.ceil() as i64).min(self.height as i64),
+        )
+    }
+
+    fn fill_rect(&mut self, x: f32, y: f32, w: f32, h: f32, colour: (u8, u8, u8), opacity: f32) {
+        let (x0, x1, y0, y1) = self.span(x, x + w, y, y + h);
+        for py in y0..y1 {
+            let cy = overlap(py as f32, y, y + h);
+            for px in x0..x1 {
+                let cover = overlap(px as f32, x, x + w) * cy;
+                self.blend(px, py, colour, cover * opacity);
+            }
+        }
+    }
+
+    // Each pixel is covered by the nearest segment, so joins aren't drawn
+    // twice.
+    fn stroke(&mut self, points: &[(f32, f32)], colour: (u8, u8, u8), width: f32) {
+        if points.is_empty() {
+            return;
+        }
+        let half = width.max(1.0) / 2.0;
+        let reach = half + 1.0;
+        let xs = points.iter().map(|p| p.0);
+        let ys = points.iter().map(|p| p.1);
+        let (x0, x1, y0, y1) = self.span(
+            xs.clone().fold(f32::MAX, f32::min) - reach,
+            xs.fold(f32::MIN, f32::max) + reach,
+            ys.clone().fold(f32::MAX, f32::min) - reach,
+            ys.fold(f32::MIN, f32::max) + reach,
+        );
+        let w = (x1 - x0).max(0) as usize;
+        let mut cover = vec![0.0f32; w * (y1 - y0).max(0) as usize];
+        let segments: Vec<((f32, f32), (f32, f32))> = if points.len() == 1 {
+            vec![(points[0], points[0])]
+        } else {
+            points.windows(2).map(|s| (s[0], s[1])).collect()
+        };
+        segments.iter().for_each(|(a, b)| {
+            let (sx0, sx1, sy0, sy1) = self.span(
+                a.0.min(b.0) - reach,
+                a.0.max(b.0) + reach,
+                a.1.min(b.1) - reach,
+                a.1.max(b.1) + reach,
+            );
+            for py in sy0..sy1 {
+                for px in sx0..sx1 {
+                    let d = distance((px as f32 + 0.5, py as f32 + 0.5), *a, *b);
+                    let c = (half + 0.5 - d).clamp(0.0, 1.0);
+                    let i = (py - y0) as usize * w + (px - x0) as usize;
+                    cover[i] = cover[i].max(c);
+                }
+            }
+        });
+        for py in y0..y1 {
+            for px in x0..x1 {
+                let c = cover[(py - y0) as usize * w + (px - x0) as usize];
+                if c > 0.0 {
+                    self.blend(px, py, colour, c);
+                }
+            }
+        }
+    }
+
+    // Fill between the crossings of each row of pixels with the outline,
+    // in and out in turn.
+    fn fill_area(&mut self, points: &[(f32, f32)], colour: (u8, u8, u8), opacity: f32) {
+        if points.len() < 3 {
+            return;
+        }
+        let ys = points.iter().map(|p| p.1);
+        let (_, _, y0, y1) = self.span(
+            0.0,
+            0.0,
+            ys.clone().fold(f32::MAX, f32::min),
+            ys.fold(f32::MIN, f32::max),
+        );
+        for py in y0..y1 {
+            let y = py as f32 + 0.5;
+            let mut crossings: Vec<f32> = points
+                .iter()
+                .zip(points.iter().cycle().skip(1))
+                .filter(|(a, b)| (a.1 <= y) != (b.1 <= y))
+                .map(|(a, b)| a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0))
+                .collect();
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            crossings.chunks(2).filter(|c| c.len() == 2).for_each(|c| {
+                let (x0, x1, _, _) = self.span(c[0], c[1], 0.0, 0.0);
+                for px in x0..x1 {
+                    self.blend(px, py, colour, overlap(px as f32, c[0], c[1]) * opacity);
+                }
+            });
+        }
+    }
+
+    fn fill_dot(&mut self, x: f32, y: f32, r: f32, colour: (u8, u8, u8)) {
+        let (x0, x1, y0, y1) = self.span(x - r - 1.0, x + r + 1.0, y - r - 1.0, y + r + 1.0);
+        for py in y0..y1 {
+            for px in x0..x1 {
+                let d = ((px as f32 + 0.5 - x).powi(2) + (py as f32 + 0.5 - y).powi(2)).sqrt();
+                self.blend(px, py, colour, r + 0.5 - d);
+            }
+        }
+    }
+
+    // Each pixel of each glyph becomes a small square, placed along the
+    // text and turned with it.
+    fn text(&mut self, t: &Text) {
+        let unit = t.size * GLYPH_UNIT;
+        let chars: Vec<char> = t.text.chars().collect();
+        let width = (chars.len() as f32 * GLYPH_ADVANCE - 1.0).max(0.0) * unit;
+        let start = match t.anchor {
+            Anchor::Start => 0.0,
+            Anchor::Middle => -width / 2.0,
+            Anchor::End => -width,
+        };
+        // From the baseline to the top of a glyph.
+        let top = if t.centred { 0.35 * t.size } else { 0.0 } - GLYPH_HEIGHT as f32 * unit;
+        let (sin, cos) = t.rotate.to_radians().sin_cos();
+        let colour = rgb(&t.colour);
+
+        chars.iter().enumerate().for_each(|(i, c)| {
+            let glyph = match *c as usize {
+                n @ 32..=126 => &GLYPHS[n - 32],
+                _ => &GLYPHS['?' as usize - 32],
+            };
+            glyph.iter().enumerate().for_each(|(row, bits)| {
+                (0..5)
+                    .filter(|col| bits & (0x10 >> col) != 0)
+                    .for_each(|col| {
+                        let along = start + (i as f32 * GLYPH_ADVANCE + col as f32 + 0.5) * unit;
+                        let down = top + (row as f32 + 0.5) * unit;
+                        let x = t.x + along * cos - down * sin;
+                        let y = t.y + along * sin + down * cos;
+                        self.fill_rect(x - unit / 2.0, y - unit / 2.0, unit, unit, colour, 1.0);
+                    });
+            });
+        });
+    }
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&png[start..]);
+    png.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// Draw the canvas, returning the png.
+pub fn encode(canvas: &Canvas) -> Result<Vec<u8>, ()> {
+    let mut raster = Raster::new(
+        canvas.width.round().max(1.0) as usize,
+        canvas.height.round().max(1.0) as usize,
+    );
+    canvas.shapes.iter().for_each(|shape| match shape {
+        Shape::Rect {
+            x,
+            y,
+            w,
+            h,
+            fill,
+            opacity,
+            stroke,
+        } => {
+            if let Some(fill) = fill {
+                raster.fill_rect(*x, *y, *w, *h, rgb(fill), *opacity);
+            }
+            if let Some(stroke) = stroke {
+                let corners = vec![(*x, *y), (x + w, *y), (x + w, y + h), (*x, y + h), (*x, *y)];
+                raster.stroke(&corners, rgb(stroke), 1.0);
+            }
+        }
+        Shape::Line {
+            points,
+            colour,
+            width,
+        } => raster.stroke(points, rgb(colour), *width),
+        Shape::Area {
+            points,
+            colour,
+            opacity,
+        } => raster.fill_area(points, rgb(colour), *opacity),
+        Shape::Dot { x, y, r, colour } => raster.fill_dot(*x, *y, *r, rgb(colour)),
+        Shape::Text(t) => raster.text(t),
+    });
+
+    // Each row starts with its filter, which is none.
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    let row = raster.width * 3;
+    raster
+        .pixels
+        .chunks(row)
+        .try_for_each(|r| encoder.write_all(&[0]).and_then(|_| encoder.write_all(r)))
+        .map_err(|e| {
+            error!("png compression error -> {:?}", e);
+            ()
+        })?;
+    let data = encoder.finish().map_err(|e| {
+        error!("png compression error -> {:?}", e);
+        ()
+    })?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(raster.width as u32).to_be_bytes());
+    header.extend_from_slice(&(raster.height as u32).to_be_bytes());
+    // 8 bit rgb, without interlacing.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &data);
+    chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::canvas::{Canvas, Text};
+    use crate::png::{encode, Raster, GLYPHS};
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn pixel(r: &Raster, x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * r.width + x) * 3;
+        (r.pixels[i], r.pixels[i + 1], r.pixels[i + 2])
+    }
+
+    #[test]
+    fn test_png_raster() {
+        assert!(GLYPHS['A' as usize - 32] == [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11]);
+
+        let mut r = Raster::new(20, 20);
+        r.fill_rect(2.0, 2.0, 4.0, 4.0, (255, 0, 0), 1.0);
+        assert!(pixel(&r, 3, 3) == (255, 0, 0));
+        assert!(pixel(&r, 7, 7) == (255, 255, 255));
+        // Half covered, and half opacity.
+        r.fill_rect(10.5, 2.0, 2.0, 2.0, (0, 0, 0), 1.0);
+        assert!(pixel(&r, 10, 2) == (128, 128, 128));
+        r.fill_rect(14.0, 2.0, 2.0, 2.0, (0, 0, 0), 0.5);
+        assert!(pixel(&r, 14, 2) == (128, 128, 128));
+
+        r.stroke(&[(0.0, 10.5), (19.0, 10.5)], (0, 0, 255), 1.0);
+        assert!(pixel(&r, 5, 10) == (0, 0, 255));
+        assert!(pixel(&r, 5, 12) == (255, 255, 255));
+
+        r.fill_area(&[(0.0, 14.0), (10.0, 14.0), (0.0, 20.0)], (0, 255, 0), 1.0);
+        assert!(pixel(&r, 1, 15) == (0, 255, 0));
+        assert!(pixel(&r, 9, 19) == (255, 255, 255));
+
+        // A 10 point I, its top bar three pixels wide.
+        let mut r = Raster::new(20, 20);
+        r.text(&Text {
+            x: 2.0,
+            y: 10.0,
+            text: "I".to_string(),
+            size: 10.0,
+            ..Default::default()
+        });
+        assert!((3..6).all(|x| pixel(&r, x, 3) == (0, 0, 0)));
+        assert!(pixel(&r, 2, 3) == (255, 255, 255) && pixel(&r, 4, 6) == (0, 0, 0));
+    }
+
+    #[test]
+    fn test_png_encode() {
+        let mut canvas = Canvas::new(3, 2);
+        canvas.fill(0.0, 0.0, 1.0, 1.0, "#102030", 1.0);
+        let png = encode(&canvas).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert!(&png[12..16] == b"IHDR");
+        assert!(png[16..24] == [0, 0, 0, 3, 0, 0, 0, 2]);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+
+        let idat = 8 + 25;
+        let len = u32::from_be_bytes([png[idat], png[idat + 1], png[idat + 2], png[idat + 3]]);
+        let mut rows = Vec::new();
+        ZlibDecoder::new(&png[idat + 8..idat + 8 + len as usize])
+            .read_to_end(&mut rows)
+            .unwrap();
+        assert!(rows.len() == 2 * (1 + 9));
+        assert!(rows[..4] == [0, 0x10, 0x20, 0x30]);
+        assert!(rows[4..10].iter().all(|v| *v == 255));
+    }
+}
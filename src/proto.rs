@@ -1,4 +1,4 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use std::convert::{TryFrom, TryInto};
 use std::io;
 
@@ -17,7 +17,10 @@ impl Decoder for MicCodec {
         if src.len() >= FRAME_LEN {
             let buf = src.split_to(FRAME_LEN);
             match Datum::try_from(buf.as_ref()) {
-                Ok(data) => Ok(Some(MicFrame { data })),
+                Ok(data) => Ok(Some(MicFrame {
+                    data,
+                    raw: buf.freeze(),
+                })),
                 Err(e) => Err(io::Error::new(
                     io::ErrorKind::Other,
                     format!("Parse error -> {:?}", e),
@@ -44,6 +47,8 @@ impl Encoder<()> for MicCodec {
 #[derive(Debug)]
 pub struct MicFrame {
     pub data: Datum,
+    /// The frame exactly as it was received, so that it can be relayed.
+    pub raw: Bytes,
 }
 
 #[derive(Debug)]
@@ -0,0 +1,133 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Forwards raw frames, unchanged, to downstream collectors.
+pub struct Relay {
+    // One socket for each address family we have targets in, as a v6 socket
+    // can't send to v4 addresses everywhere.
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    targets: Vec<SocketAddr>,
+    // If set, only these meters are relayed.
+    macs: Option<BTreeSet<String>>,
+}
+
+impl Relay {
+    pub fn new(targets: Vec<SocketAddr>, macs: Option<BTreeSet<String>>) -> io::Result<Self> {
+        let v4 = if targets.iter().any(|t| t.is_ipv4()) {
+            Some(Self::bind("0.0.0.0:0")?)
+        } else {
+            None
+        };
+        let v6 = if targets.iter().any(|t| t.is_ipv6()) {
+            Some(Self::bind("[::]:0")?)
+        } else {
+            None
+        };
+        Ok(Relay {
+            v4,
+            v6,
+            targets,
+            macs,
+        })
+    }
+
+    fn bind(addr: &str) -> io::Result<UdpSocket> {
+        let sock = UdpSocket::bind(addr)?;
+        // We are called from the udp listener, so never let a slow peer stall it.
+        sock.set_nonblocking(true)?;
+        Ok(sock)
+    }
+
+    pub fn forward(&self, mac: &str, raw: &[u8]) {
+        if let Some(macs) = &self.macs {
+            if !macs.contains(mac) {
+                return;
+            }
+        }
+
+        self.targets.iter().for_each(|target| {
+            let sock = match target {
+                SocketAddr::V4(_) => self.v4.as_ref(),
+                SocketAddr::V6(_) => self.v6.as_ref(),
+            };
+            // There is always a socket for each target's family.
+            match sock.map(|sock| sock.send_to(raw, target)) {
+                Some(Ok(_)) => debug!("relayed {} -> {:?}", mac, target),
+                Some(Err(e)) => warn!("unable to relay {} -> {:?}: {:?}", mac, target, e),
+                None => (),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::relay::Relay;
+    use std::collections::BTreeSet;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    const FRAME: [u8; 17] = [
+        0x20, 0xF8, 0x5E, 0xBE, 0x29, 0xD8, 0x0A, 0x01, 0x01, 0x01, 0x1B, 0x02, 0x02, 0x79, 0x03,
+        0x02, 0x9F,
+    ];
+
+    fn collector() -> UdpSocket {
+        collector_on("127.0.0.1:0")
+    }
+
+    fn collector_on(addr: &str) -> UdpSocket {
+        let sock = UdpSocket::bind(addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        sock
+    }
+
+    fn recv(sock: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = [0; 64];
+        sock.recv_from(&mut buf)
+            .ok()
+            .map(|(len, _)| buf[..len].to_vec())
+    }
+
+    #[test]
+    fn test_relay_fan_out() {
+        let a = collector();
+        let b = collector();
+        let relay =
+            Relay::new(vec![a.local_addr().unwrap(), b.local_addr().unwrap()], None).unwrap();
+
+        relay.forward("20:F8:5E:BE:29:D8", &FRAME);
+
+        assert!(recv(&a) == Some(FRAME.to_vec()));
+        assert!(recv(&b) == Some(FRAME.to_vec()));
+    }
+
+    #[test]
+    fn test_relay_mac_filter() {
+        let a = collector();
+        let macs: BTreeSet<String> = vec!["20:F8:5E:BE:29:D8".to_string()].into_iter().collect();
+        let relay = Relay::new(vec![a.local_addr().unwrap()], Some(macs)).unwrap();
+
+        relay.forward("00:00:00:00:00:00", &FRAME);
+        assert!(recv(&a).is_none());
+
+        relay.forward("20:F8:5E:BE:29:D8", &FRAME);
+        assert!(recv(&a) == Some(FRAME.to_vec()));
+    }
+
+    #[test]
+    fn test_relay_mixed_families() {
+        let a = collector();
+        let b = collector_on("[::1]:0");
+        let relay =
+            Relay::new(vec![a.local_addr().unwrap(), b.local_addr().unwrap()], None).unwrap();
+
+        relay.forward("20:F8:5E:BE:29:D8", &FRAME);
+
+        assert!(recv(&a) == Some(FRAME.to_vec()));
+        assert!(recv(&b) == Some(FRAME.to_vec()));
+    }
+}
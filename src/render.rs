@@ -1,295 +1,1761 @@
 use actix::prelude::*;
-use gnuplot::AxesCommon;
-use gnuplot::{AutoOption, Caption, Color, Figure, LabelOption, Tick, TickOption};
-use std::iter::once;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::{Date, OffsetDateTime, Weekday};
 
+use crate::canvas::Format;
+use crate::chart::{
+    band_colour, Annotation, Band, Chart, Envelope, Heatmap, Series, Tick, BOLD_WIDTH, COLOURS,
+    LINE_WIDTH, MISSING, WEEKEND,
+};
 use crate::db;
+use crate::stop::{stop_when_idle, StopEvent};
+use crate::tz::SiteTz;
 
 const PNG_WIDTH: u32 = 1400;
 const PNG_HEIGHT: u32 = 800;
 
-const SHORT_DIFF: i64 = 900;
 const LONG_DIFF: i64 = 86400;
+const WEEK: i64 = 7 * 86400;
 
-#[derive(Message)]
-#[rtype(result = "Result<(), ()>")]
-pub struct RenderEvent {
+// Tick steps and how they're labelled, by the longest span each is used for.
+const TICKS: &[(i64, i64, &str)] = &[
+    (2 * 3600, 600, "%H:%M"),
+    (6 * 3600, 1800, "%H:%M"),
+    (LONG_GAP, 3600, "%a %H:%M"),
+    (3 * LONG_DIFF, 6 * 3600, "%a %H:%M"),
+    (16 * LONG_DIFF, LONG_DIFF, "%a %d %b"),
+    (120 * LONG_DIFF, WEEK, "%d %b"),
+    (i64::MAX, 30 * LONG_DIFF, "%b %Y"),
+];
+
+// Readings charts longer than these are drawn from hourly, then daily,
+// rollups.
+const RAW_SPAN: i64 = 2 * LONG_DIFF;
+const HOURLY_SPAN: i64 = 31 * LONG_DIFF;
+
+// Readings further apart than this are not joined up on the chart.
+const SHORT_GAP: i64 = 300;
+// An hour is rolled up a while after it ends, so allow for the last not
+// being there yet.
+const HOURLY_GAP: i64 = 7200;
+// A little over a day, as days can be 25 hours long.
+const LONG_GAP: i64 = 90000;
+// Gaps longer than this are labelled as the meter being out.
+const OUTAGE: i64 = 3600;
+
+// How many charts are kept, and how long one is kept for without being
+// asked for again.
+const CACHE_ENTRIES: usize = 64;
+const CACHE_IDLE: Duration = Duration::from_secs(3600);
+// How often charts with new data are drawn again.
+const REFRESH_PASS: Duration = Duration::from_secs(10);
+
+const DAY_MS: i64 = 86_400_000;
+// Charts and reports can be asked for from 2000 until 2100, and a chart can
+// span at most 20 years. Times far enough outside can't be made into dates.
+const EARLIEST_MS: i64 = 946_684_800_000;
+const LATEST_MS: i64 = 4_102_444_800_000;
+const MAX_SPAN_MS: i64 = 20 * 366 * DAY_MS;
+
+// Roughly the ppm outdoors, where heatmaps start from.
+const OUTDOOR_PPM: f32 = 400.0;
+
+/// The charts we can draw. The names are as they appear in
+/// `/render/{mac}/{chart}.svg` and `/compare/{chart}.svg`, before the
+/// format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChartKind {
+    Ppm,
+    Hum,
+    Temp,
+    PpmHistory,
+    PpmBandsHistory,
+    HumHistory,
+    TempHistory,
+    /// Minutes each day above a ppm.
+    MinutesAbove(u16),
+    /// CO2 by the hour, from the hourly rollups.
+    Heatmap(HeatLayout, HeatStat),
+}
+
+/// How the hours of a heatmap are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HeatLayout {
+    /// Each hour of the day by day of the week, over several weeks.
+    Week,
+    /// Each hour of the day by each day.
+    Month,
+}
+
+/// What each cell of a heatmap is shaded by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HeatStat {
+    Avg,
+    P95,
+}
+
+impl FromStr for ChartKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "ppm" => Ok(ChartKind::Ppm),
+            "hum" => Ok(ChartKind::Hum),
+            "temp" => Ok(ChartKind::Temp),
+            "ppm_history" => Ok(ChartKind::PpmHistory),
+            "ppm_bands_history" => Ok(ChartKind::PpmBandsHistory),
+            "hum_history" => Ok(ChartKind::HumHistory),
+            "temp_history" => Ok(ChartKind::TempHistory),
+            "heatmap_week" => Ok(ChartKind::Heatmap(HeatLayout::Week, HeatStat::Avg)),
+            "heatmap_week_p95" => Ok(ChartKind::Heatmap(HeatLayout::Week, HeatStat::P95)),
+            "heatmap_month" => Ok(ChartKind::Heatmap(HeatLayout::Month, HeatStat::Avg)),
+            "heatmap_month_p95" => Ok(ChartKind::Heatmap(HeatLayout::Month, HeatStat::P95)),
+            _ => s
+                .strip_prefix("minutes_above_")
+                .and_then(|band| band.parse().ok())
+                .map(ChartKind::MinutesAbove)
+                .ok_or(()),
+        }
+    }
+}
+
+impl ChartKind {
+    /// Drawn from daily reports rather than raw events.
+    pub fn is_history(self) -> bool {
+        !matches!(
+            self,
+            ChartKind::Ppm | ChartKind::Hum | ChartKind::Temp | ChartKind::Heatmap(..)
+        )
+    }
+
+    pub fn is_heatmap(self) -> bool {
+        matches!(self, ChartKind::Heatmap(..))
+    }
+
+    /// How far back charts go without a from or range.
+    fn default_span(self) -> Duration {
+        match self {
+            ChartKind::Heatmap(HeatLayout::Week, _) => Duration::from_secs(4 * WEEK as u64),
+            ChartKind::Heatmap(HeatLayout::Month, _) => Duration::from_secs(31 * LONG_DIFF as u64),
+            _ => Duration::from_secs(LONG_DIFF as u64),
+        }
+    }
+
+    /// Values are CO2 ppm, so comfort bands apply.
+    fn is_ppm(self) -> bool {
+        matches!(self, ChartKind::Ppm | ChartKind::PpmHistory)
+    }
+}
+
+/// Read a range back from now written as hours or days, such as `6h` or
+/// `7d`, into milliseconds.
+pub fn parse_range(s: &str) -> Result<i64, ()> {
+    let (n, unit) = s.split_at(s.len().saturating_sub(1));
+    let ms = match unit {
+        "h" => 3_600_000,
+        "d" => DAY_MS,
+        _ => 0,
+    };
+    n.parse::<i64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(ms))
+        .filter(|ms| *ms > 0)
+        .ok_or_else(|| {
+            error!("Invalid range {:?}", s);
+            ()
+        })
+}
+
+/// Check ms (epoch ms) is a time charts and reports can be drawn for.
+pub fn check_time(ms: i64) -> Result<i64, ()> {
+    if !(EARLIEST_MS..=LATEST_MS).contains(&ms) {
+        error!("Time out of range {}", ms);
+        return Err(());
+    }
+    Ok(ms)
+}
+
+/// Check a chart's from, to and range back from to (all ms) can be drawn,
+/// with from before to and not too far apart.
+pub fn check_span(from: Option<i64>, to: Option<i64>, last: Option<i64>) -> Result<(), ()> {
+    from.into_iter()
+        .chain(to)
+        .try_for_each(|ms| check_time(ms).map(|_| ()))?;
+    let to = to.unwrap_or_else(|| ts_to_db!(OffsetDateTime::now()));
+    match from.or_else(|| last.map(|last| to.saturating_sub(last))) {
+        Some(from) if from > to || to - from > MAX_SPAN_MS => {
+            error!("Invalid span {} to {}", from, to);
+            Err(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// What readings charts are drawn from. Long ranges use rollups, as there
+/// would be too many readings, and raw readings are only kept for days.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Raw,
+    Hourly,
+    Daily,
+}
+
+impl Resolution {
+    pub(crate) fn for_span(secs: i64) -> Self {
+        if secs <= RAW_SPAN {
+            Resolution::Raw
+        } else if secs <= HOURLY_SPAN {
+            Resolution::Hourly
+        } else {
+            Resolution::Daily
+        }
+    }
+
+    /// For when there is nothing this fine, as it's been purged.
+    pub(crate) fn coarser(self) -> Option<Self> {
+        match self {
+            Resolution::Raw => Some(Resolution::Hourly),
+            Resolution::Hourly => Some(Resolution::Daily),
+            Resolution::Daily => None,
+        }
+    }
+
+    /// Points further apart than this leave a gap.
+    fn max_gap(self) -> i64 {
+        match self {
+            Resolution::Raw => SHORT_GAP,
+            Resolution::Hourly => HOURLY_GAP,
+            Resolution::Daily => LONG_GAP,
+        }
+    }
+}
+
+/// A range of ppm and how comfortable it is, such as good below 800. Bands
+/// run upward from zero, and the last has no upper bound.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComfortBand {
+    pub label: String,
+    pub below: Option<u16>,
+}
+
+/// Read bands written as `good:800,moderate:1200,poor`. Empty is no bands.
+pub fn parse_comfort_bands(s: &str) -> Result<Vec<ComfortBand>, ()> {
+    let bands: Vec<ComfortBand> = s
+        .split(',')
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            let mut parts = b.splitn(2, ':');
+            let label = parts.next().unwrap_or("").trim().to_string();
+            let below = match parts.next() {
+                Some(ppm) => Some(ppm.trim().parse().map_err(|_| {
+                    error!("Invalid comfort band ppm {:?}", b);
+                    ()
+                })?),
+                None => None,
+            };
+            Ok(ComfortBand { label, below })
+        })
+        .collect::<Result<_, ()>>()?;
+
+    // Only the last is open ended, and they must go up.
+    let mut last = 0;
+    for (i, band) in bands.iter().enumerate() {
+        match band.below {
+            Some(ppm) if ppm > last => last = ppm,
+            None if i == bands.len() - 1 => {}
+            _ => {
+                error!(
+                    "Comfort bands must rise, and only the last can be open -> {:?}",
+                    s
+                );
+                return Err(());
+            }
+        }
+    }
+    Ok(bands)
+}
+
+/// What a rendered chart is for. Ranges are as they were asked for, so
+/// that an open ended range keeps following the latest readings.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RenderKey {
+    /// Sorted, and empty for every meter.
+    pub srcs: Vec<String>,
+    pub kind: ChartKind,
+    pub format: Format,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    /// Without a from, how far back from to the chart goes, in ms.
+    pub last: Option<i64>,
+}
+
+impl RenderKey {
+    fn has(&self, src: &str) -> bool {
+        self.srcs.is_empty() || self.srcs.iter().any(|s| s == src)
+    }
+
+    /// Would a reading taken at ts (epoch ms) appear on this chart.
+    fn covers(&self, ts: i64) -> bool {
+        // Reports and rollups are made later, and drawn again on refresh.
+        if self.kind.is_history() || self.kind.is_heatmap() {
+            return false;
+        }
+        // Without a from, raw charts show the day, or last, before to.
+        let from = self.from.unwrap_or_else(|| {
+            self.to
+                .map(|to| to - self.last.unwrap_or(DAY_MS))
+                .unwrap_or(i64::MIN)
+        });
+        ts >= from && self.to.map(|to| ts < to).unwrap_or(true)
+    }
+}
+
+struct CacheEntry {
+    data: Vec<u8>,
+    // When the render that made data began.
+    rendered: Instant,
+    // When data it was drawn from last changed.
+    changed: Option<Instant>,
+    used: Instant,
+}
+
+impl CacheEntry {
+    fn is_stale(&self) -> bool {
+        self.changed.map(|c| c >= self.rendered).unwrap_or(false)
+    }
+}
+
+/// Rendered charts, shared by every http worker. Charts are served from
+/// here even once new data arrives, and RenderRefreshActor draws them
+/// again in the background.
+pub struct RenderCache {
+    inner: Mutex<BTreeMap<RenderKey, CacheEntry>>,
+}
+
+impl RenderCache {
+    pub fn new() -> Self {
+        RenderCache {
+            inner: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &RenderKey) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().ok()?;
+        inner.get_mut(key).map(|e| {
+            e.used = Instant::now();
+            e.data.clone()
+        })
+    }
+
+    /// Store a chart whose render began at started. If its data changed
+    /// since then it stays stale.
+    pub fn insert(&self, key: RenderKey, started: Instant, data: Vec<u8>) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if let Some(e) = inner.get_mut(&key) {
+                e.data = data;
+                e.rendered = started;
+                return;
+            }
+            // Make room by dropping the one asked for longest ago.
+            while inner.len() >= CACHE_ENTRIES {
+                let idle = inner
+                    .iter()
+                    .min_by_key(|(_, e)| e.used)
+                    .map(|(k, _)| k.clone());
+                match idle {
+                    Some(k) => inner.remove(&k),
+                    None => break,
+                };
+            }
+            inner.insert(
+                key,
+                CacheEntry {
+                    data,
+                    rendered: started,
+                    changed: None,
+                    used: Instant::now(),
+                },
+            );
+        }
+    }
+
+    fn mark(&self, f: impl Fn(&RenderKey) -> bool) {
+        if let Ok(mut inner) = self.inner.lock() {
+            let now = Instant::now();
+            inner
+                .iter_mut()
+                .filter(|(k, _)| f(k))
+                .for_each(|(_, e)| e.changed = Some(now));
+        }
+    }
+
+    /// A reading from src taken at ts (epoch ms) has been stored.
+    pub fn invalidate(&self, src: &str, ts: i64) {
+        self.mark(|k| k.has(src) && k.covers(ts))
+    }
+
+    /// Anything about src may have changed, including its reports.
+    pub fn invalidate_meter(&self, src: &str) {
+        self.mark(|k| k.has(src))
+    }
+
+    /// Charts to draw again, as their data changed or they're older than
+    /// refresh. Charts no one has asked for in a while are dropped instead.
+    fn due(&self, refresh: Duration) -> Vec<RenderKey> {
+        match self.inner.lock() {
+            Ok(mut inner) => {
+                inner.retain(|_, e| e.used.elapsed() < CACHE_IDLE);
+                inner
+                    .iter()
+                    .filter(|(_, e)| e.is_stale() || e.rendered.elapsed() >= refresh)
+                    .map(|(k, _)| k.clone())
+                    .collect()
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+// Readings from src between min and max, or their rollups.
+pub(crate) async fn events(
+    db_addr: &Addr<db::DbActor>,
+    src: &str,
+    resolution: Resolution,
+    min: OffsetDateTime,
+    max: OffsetDateTime,
+) -> Result<Vec<db::DbEvent>, ()> {
+    let src = src.to_string();
+    // Rollups are drawn by their averages.
+    let res = match resolution {
+        Resolution::Raw => db_addr.send(db::DbEventRange { src, min, max }).await,
+        Resolution::Hourly => db_addr
+            .send(db::DbHourlyRange { src, min, max })
+            .await
+            .map(|r| {
+                r.map(|hourly| {
+                    hourly
+                        .into_iter()
+                        .map(|h| db::DbEvent {
+                            src: h.src,
+                            time: h.time,
+                            temp: h.temp_avg,
+                            ppm: h.ppm_avg,
+                            hum: h.hum_avg,
+                        })
+                        .collect()
+                })
+            }),
+        Resolution::Daily => db_addr.send(db::DbHistory { src }).await.map(|r| {
+            r.map(|history| {
+                history
+                    .into_iter()
+                    .filter(|h| h.time >= min && h.time < max)
+                    .filter_map(|h| {
+                        Some(db::DbEvent {
+                            temp: h.temp_avg?,
+                            ppm: h.ppm_avg?,
+                            hum: h.hum_avg?,
+                            src: h.src,
+                            time: h.time,
+                        })
+                    })
+                    .collect()
+            })
+        }),
+    };
+    res.map_err(|e| {
+        error!("db mailbox error -> {:?}", e);
+        ()
+    })?
+}
+
+/// Fetch what key needs and draw it. Ok(None) if there is nothing in range.
+pub async fn draw(
+    db_addr: Addr<db::DbActor>,
+    render_addr: Addr<RenderActor>,
+    key: RenderKey,
+) -> Result<Option<Vec<u8>>, ()> {
+    let to = key.to.map(|ms| ts_from_db!(ms));
+    let max = to.unwrap_or_else(OffsetDateTime::now_local);
+    let from = key.from.map(|ms| ts_from_db!(ms)).or_else(|| {
+        key.last
+            .map(|ms| max - Duration::from_millis(ms.max(0) as u64))
+    });
+    // Show last day in detail
+    let min = from.unwrap_or_else(|| max - key.kind.default_span());
+
+    let srcs = if key.srcs.is_empty() {
+        db_addr.send(db::DbListMeters).await
+    } else {
+        Ok(Ok(key.srcs.clone()))
+    };
+    let (srcs, labels) = match (srcs, db_addr.send(db::DbMeterLabels).await) {
+        (Ok(Ok(srcs)), Ok(Ok(labels))) => (srcs, labels),
+        _ => {
+            error!("db unable to complete!");
+            return Err(());
+        }
+    };
+
+    let mut resolution = Resolution::for_span((max - min).whole_seconds());
+    let meters = loop {
+        let mut meters = Vec::with_capacity(srcs.len());
+        for src in srcs.iter() {
+            let mut meter = RenderMeter {
+                src: src.clone(),
+                label: labels.get(src).cloned(),
+                data: Vec::new(),
+                history: Vec::new(),
+                hourly: Vec::new(),
+            };
+            let ok = if key.kind.is_heatmap() {
+                match db_addr
+                    .send(db::DbHourlyRange {
+                        src: src.clone(),
+                        min,
+                        max,
+                    })
+                    .await
+                {
+                    Ok(Ok(hourly)) => {
+                        meter.hourly = hourly;
+                        Ok(())
+                    }
+                    _ => Err(()),
+                }
+            } else if key.kind.is_history() {
+                match db_addr.send(db::DbHistory { src: src.clone() }).await {
+                    Ok(Ok(history)) => {
+                        meter.history = history
+                            .into_iter()
+                            .filter(|h| from.map(|f| h.time >= f).unwrap_or(true))
+                            .filter(|h| to.map(|t| h.time < t).unwrap_or(true))
+                            .collect();
+                        Ok(())
+                    }
+                    _ => Err(()),
+                }
+            } else {
+                events(&db_addr, src, resolution, min, max)
+                    .await
+                    .map(|data| meter.data = data)
+            };
+            if ok.is_err() {
+                error!("db unable to complete!");
+                return Err(());
+            }
+            // Meters with nothing in range are left off.
+            if !meter.data.is_empty() || !meter.history.is_empty() || !meter.hourly.is_empty() {
+                meters.push(meter);
+            }
+        }
+        match resolution.coarser() {
+            // Older readings may only be left as rollups.
+            Some(coarser)
+                if meters.is_empty() && !key.kind.is_history() && !key.kind.is_heatmap() =>
+            {
+                resolution = coarser
+            }
+            _ => break meters,
+        }
+    };
+
+    if meters.is_empty() {
+        return Ok(None);
+    }
+
+    match render_addr
+        .send(RenderEvent {
+            kind: key.kind,
+            format: key.format,
+            meters,
+            span: if key.kind.is_history() {
+                None
+            } else {
+                Some((min, max))
+            },
+            resolution,
+        })
+        .await
+    {
+        Ok(Ok(data)) => Ok(Some(data)),
+        _ => {
+            error!("render unable to complete!");
+            Err(())
+        }
+    }
+}
+
+/// Keeps cached charts up to date, so requests rarely wait on a render.
+pub struct RenderRefreshActor {
+    db_addr: Addr<db::DbActor>,
+    render_addr: Addr<RenderActor>,
+    cache: Arc<RenderCache>,
+    refresh: Duration,
+    busy: bool,
+    stopping: bool,
+}
+
+impl RenderRefreshActor {
+    pub fn new(
+        db_addr: Addr<db::DbActor>,
+        render_addr: Addr<RenderActor>,
+        cache: Arc<RenderCache>,
+        refresh: Duration,
+    ) -> Self {
+        RenderRefreshActor {
+            db_addr,
+            render_addr,
+            cache,
+            refresh,
+            busy: false,
+            stopping: false,
+        }
+    }
+
+    fn refresh(&mut self, ctx: &mut Context<Self>) {
+        // Let a slow pass finish rather than piling up renders.
+        if self.busy {
+            return;
+        }
+        let due = self.cache.due(self.refresh);
+        if due.is_empty() {
+            return;
+        }
+        debug!("Refreshing {} charts ...", due.len());
+        self.busy = true;
+
+        let db_addr = self.db_addr.clone();
+        let render_addr = self.render_addr.clone();
+        let cache = self.cache.clone();
+        let fut = async move {
+            for key in due {
+                let started = Instant::now();
+                match draw(db_addr.clone(), render_addr.clone(), key.clone()).await {
+                    Ok(Some(data)) => cache.insert(key, started, data),
+                    // Keep serving what we had.
+                    Ok(None) | Err(_) => error!("Unable to refresh chart {:?}", key),
+                }
+            }
+        };
+        ctx.spawn(fut.into_actor(self).map(|_, act, ctx| {
+            act.busy = false;
+            if act.stopping {
+                ctx.stop();
+            }
+        }));
+    }
+}
+
+impl Actor for RenderRefreshActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("Started chart refresh ...");
+        ctx.run_interval(REFRESH_PASS, move |act, ctx| {
+            act.refresh(ctx);
+        });
+    }
+}
+
+impl Handler<StopEvent> for RenderRefreshActor {
+    type Result = bool;
+
+    fn handle(&mut self, _msg: StopEvent, ctx: &mut Context<Self>) -> bool {
+        stop_when_idle("chart refresh", &mut self.stopping, self.busy, ctx)
+    }
+}
+
+/// What to draw from for one meter.
+pub struct RenderMeter {
     pub src: String,
+    pub label: Option<String>,
     pub data: Vec<db::DbEvent>,
     pub history: Vec<db::DbHistoryEvent>,
+    pub hourly: Vec<db::DbHourlyEvent>,
+}
+
+impl RenderMeter {
+    /// How the meter is named in the legend.
+    pub(crate) fn name(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.src)
+    }
+}
+
+/// Draw one chart in format. A single meter is drawn in detail, several
+/// are drawn a line each to compare them.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<u8>, ()>")]
+pub struct RenderEvent {
+    pub kind: ChartKind,
+    pub format: Format,
+    pub meters: Vec<RenderMeter>,
+    /// The time asked for, so that missing readings at either end show.
+    pub span: Option<(OffsetDateTime, OffsetDateTime)>,
+    pub resolution: Resolution,
 }
 
 impl Actor for RenderActor {
     type Context = SyncContext<Self>;
 }
 
-pub struct RenderActor;
-
-macro_rules! format_chart {
-    ($axis:expr, $ticks:expr, $y_lab:expr) => {
-        $axis
-            .set_x_label("Time", &[])
-            .set_x_ticks_custom(
-                $ticks,
-                &[TickOption::OnAxis(false), TickOption::Inward(true)],
-                &[
-                    LabelOption::TextOffset(-1.0, -1.0),
-                    LabelOption::Font("Helvetica", 10.0),
-                    LabelOption::Rotate(280.0),
-                ],
-            )
-            .set_y_label($y_lab, &[])
-            .set_y_ticks(
-                Some((AutoOption::Auto, 5)),
-                &[
-                    TickOption::OnAxis(false),
-                    TickOption::Mirror(true),
-                    TickOption::Inward(false),
-                ],
-                &[],
-            )
-            .set_x_axis(false, &[])
-    };
+pub struct RenderActor {
+    pub tz: SiteTz,
+    pub comfort: Vec<ComfortBand>,
 }
 
-fn render_single_figure(
-    title: &str,
-    caption: &str,
-    colour: &str,
-    x: &[i64],
-    y: &[f32],
-    ticks: &[Tick<i64, String>],
-    path: &str,
-) -> Result<(), ()> {
-    let mut fg = Figure::new();
-    format_chart!(
-        fg.axes2d().lines(x, y, &[Caption(caption), Color(colour)]),
-        ticks,
-        title
-    );
+// A NaN point leaves a gap in the line, so add one wherever the meter went
+// missing.
+fn with_gaps<T>(data: &[T], time: fn(&T) -> i64, max_gap: i64) -> Vec<(i64, Option<&T>)> {
+    let mut points = Vec::with_capacity(data.len());
+    let mut last: Option<i64> = None;
+    data.iter().for_each(|d| {
+        let t = time(d);
+        match last {
+            Some(l) if t - l > max_gap => points.push((l + 1, None)),
+            _ => {}
+        }
+        points.push((t, Some(d)));
+        last = Some(t);
+    });
+    points
+}
 
-    fg.save_to_svg(path, PNG_WIDTH, PNG_HEIGHT)
-        .map(|_| {
-            info!("gnuplotlob success");
-            ()
-        })
-        .map_err(|e| {
-            error!("gnuplotlib error -> {:?}", e);
-            ()
+fn value_or_gap(v: Option<u16>, scale: f32) -> f32 {
+    v.map(|v| (v as f32) / scale).unwrap_or(std::f32::NAN)
+}
+
+// The line for one value of each reading or report, with gaps.
+fn line<T>(
+    data: &[T],
+    time: fn(&T) -> i64,
+    max_gap: i64,
+    value: impl Fn(&T) -> f32,
+) -> (Vec<i64>, Vec<f32>) {
+    with_gaps(data, time, max_gap)
+        .into_iter()
+        .map(|(t, d)| (t, d.map(&value).unwrap_or(std::f32::NAN)))
+        .unzip()
+}
+
+fn event_time(dbe: &db::DbEvent) -> i64 {
+    dbe.time.timestamp()
+}
+
+fn history_time(dbe: &db::DbHistoryEvent) -> i64 {
+    dbe.time.timestamp()
+}
+
+// A caption, with the times and values of its points.
+type Line = (String, (Vec<i64>, Vec<f32>));
+
+type HistoryField = fn(&db::DbHistoryEvent) -> Option<u16>;
+
+// Days with no data have no values, which leaves a gap.
+fn history_line(m: &RenderMeter, name: &str, value: impl Fn(&db::DbHistoryEvent) -> f32) -> Line {
+    (
+        format!("{} - {}", name, m.name()),
+        line(&m.history, history_time, LONG_GAP, value),
+    )
+}
+
+fn minutes_above(band: u16) -> impl Fn(&db::DbHistoryEvent) -> f32 {
+    move |dbe| {
+        dbe.minutes_above
+            .get(&band)
+            .map(|m| *m as f32)
+            .unwrap_or(std::f32::NAN)
+    }
+}
+
+fn series(lines: Vec<Line>, width: f32) -> Vec<Series> {
+    lines
+        .into_iter()
+        .zip(COLOURS.iter().cycle())
+        .map(|((caption, (x, y)), colour)| Series {
+            caption,
+            colour,
+            width,
+            x,
+            y,
         })
+        .collect()
 }
 
-fn render_triple_figure(
-    title: &str,
-    src: &str,
-    colour: &str,
-    x: &[i64],
-    y_min: &[f32],
-    y_max: &[f32],
-    y_avg: &[f32],
-    ticks: &[Tick<i64, String>],
-    path: &str,
-) -> Result<(), ()> {
-    // get the captions
-    let cap_min = format!("min - {}", src);
-    let cap_max = format!("max - {}", src);
-    let cap_avg = format!("avg - {}", src);
-
-    let mut fg = Figure::new();
-    format_chart!(
-        fg.axes2d()
-            .lines(x, y_min, &[Caption(cap_min.as_str()), Color(colour)]),
+// One line per series, each in its own colour.
+fn lines_figure(title: &str, lines: Vec<Line>, ticks: Vec<Tick>) -> Chart {
+    Chart {
+        y_label: title.to_string(),
         ticks,
-        title
-    )
-    .lines(x, y_max, &[Caption(cap_max.as_str()), Color(colour)])
-    .lines(x, y_avg, &[Caption(cap_avg.as_str()), Color(colour)]);
+        series: series(lines, LINE_WIDTH),
+        ..Default::default()
+    }
+}
 
-    fg.save_to_svg(path, PNG_WIDTH, PNG_HEIGHT)
-        .map(|_| {
-            info!("gnuplotlob success");
-            ()
+// The average of the days in the week up to each day, skipping days
+// without readings.
+fn rolling_week(history: &[db::DbHistoryEvent], field: HistoryField) -> BTreeMap<i64, f32> {
+    history
+        .iter()
+        .enumerate()
+        .map(|(i, dbe)| {
+            let t = history_time(dbe);
+            let week: Vec<f32> = history[..=i]
+                .iter()
+                .rev()
+                // Half a day short, so a week is seven reports across a dst change.
+                .take_while(|d| t - history_time(d) < WEEK - LONG_DIFF / 2)
+                .filter_map(field)
+                .map(|v| v as f32)
+                .collect();
+            let avg = if week.is_empty() {
+                std::f32::NAN
+            } else {
+                week.iter().sum::<f32>() / week.len() as f32
+            };
+            (t, avg)
         })
-        .map_err(|e| {
-            error!("gnuplotlib error -> {:?}", e);
-            ()
+        .collect()
+}
+
+// Shade each stretch of times further apart than max_gap, including at
+// either end of span.
+fn gaps(
+    times: &[i64],
+    span: Option<(i64, i64)>,
+    max_gap: i64,
+    label: &dyn Fn(i64) -> String,
+) -> Vec<Annotation> {
+    let mut edges = Vec::with_capacity(times.len() + 2);
+    if let Some((start, _)) = span {
+        edges.push(start);
+    }
+    edges.extend_from_slice(times);
+    if let Some((_, end)) = span {
+        edges.push(end);
+    }
+    edges
+        .windows(2)
+        .filter(|w| w[1] - w[0] > max_gap)
+        .map(|w| Annotation {
+            from: w[0],
+            to: w[1],
+            label: label(w[1] - w[0]),
+            colour: MISSING,
         })
+        .collect()
 }
 
-impl Handler<RenderEvent> for RenderActor {
-    type Result = Result<(), ()>;
+// The first and last of times, for charts that weren't asked for a span.
+fn bounds(times: impl Iterator<Item = OffsetDateTime>) -> Option<(OffsetDateTime, OffsetDateTime)> {
+    times.fold(None, |b, t| match b {
+        Some((first, last)) => Some((first.min(t), last.max(t))),
+        None => Some((t, t)),
+    })
+}
 
-    fn handle(&mut self, msg: RenderEvent, _: &mut SyncContext<Self>) -> Result<(), ()> {
-        // Add a 0,0 point.
-        let ts_init = match msg.data.first() {
-            Some(t) => t.time.timestamp(),
-            None => {
-                error!("no data to render");
-                return Err(());
+impl RenderActor {
+    fn comfort_bands(&self) -> Vec<Band> {
+        let mut from = 0;
+        self.comfort
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let band = Band {
+                    from: from as f32,
+                    to: b.below.map(|ppm| ppm as f32),
+                    label: b.label.clone(),
+                    colour: band_colour(i, self.comfort.len()),
+                };
+                from = b.below.unwrap_or(from);
+                band
+            })
+            .collect()
+    }
+
+    // Where meters were missing. Only long gaps are labelled, by meter
+    // when there are several.
+    fn annotations(
+        &self,
+        kind: ChartKind,
+        meters: &[RenderMeter],
+        resolution: Resolution,
+        span: Option<(OffsetDateTime, OffsetDateTime)>,
+    ) -> Vec<Annotation> {
+        let span = span.map(|(min, max)| {
+            // Nothing can be missing from the future, or from rollups of
+            // the hour or day still going.
+            let end = max.min(OffsetDateTime::now());
+            let end = match resolution {
+                Resolution::Raw => end,
+                Resolution::Hourly => self.tz.hour_start(end),
+                Resolution::Daily => self.tz.day_start(self.tz.date_of(end)),
+            };
+            (min.timestamp(), end.timestamp())
+        });
+        let named = |what: &str, m: &RenderMeter| {
+            if meters.len() == 1 {
+                what.to_string()
+            } else {
+                format!("{} - {}", what, m.name())
             }
         };
+        let weekends = if kind.is_history() {
+            self.weekends(meters)
+        } else {
+            Vec::new()
+        };
+        weekends
+            .into_iter()
+            .chain(meters.iter().flat_map(|m| {
+                if kind.is_history() {
+                    // Days reported without any readings are gaps too.
+                    let days: Vec<i64> = m
+                        .history
+                        .iter()
+                        .filter(|dbe| dbe.count != Some(0))
+                        .map(history_time)
+                        .collect();
+                    gaps(&days, None, LONG_GAP, &|_| named("no data", m))
+                } else {
+                    let times: Vec<i64> = m.data.iter().map(event_time).collect();
+                    gaps(&times, span, resolution.max_gap(), &|gap| {
+                        if gap > OUTAGE {
+                            named("outage", m)
+                        } else {
+                            String::new()
+                        }
+                    })
+                }
+            }))
+            .collect()
+    }
 
-        // Generate the time/tick scale.
-        let mut last_step = 0;
-        let ticks: Vec<Tick<i64, String>> = msg
-            .data
+    // Weekends shaded across the days of every meter, the first labelled.
+    fn weekends(&self, meters: &[RenderMeter]) -> Vec<Annotation> {
+        let days: BTreeSet<OffsetDateTime> = meters
             .iter()
-            .filter_map(|dbe| {
-                if dbe.time.timestamp() >= last_step + SHORT_DIFF {
-                    last_step = dbe.time.timestamp();
-                    Some(Tick::Major(
-                        dbe.time.timestamp(),
-                        AutoOption::Fix(dbe.time.format(db::TFMT)),
-                    ))
-                } else {
-                    None
+            .flat_map(|m| m.history.iter().map(|dbe| dbe.time))
+            .collect();
+        let mut weekends: Vec<Annotation> = Vec::new();
+        days.into_iter()
+            .map(|t| self.tz.date_of(t))
+            .filter(|d| matches!(d.weekday(), Weekday::Saturday | Weekday::Sunday))
+            .for_each(|d| {
+                let from = self.tz.day_start(d).timestamp();
+                let to = self.tz.day_start(d.next_day()).timestamp();
+                match weekends.last_mut() {
+                    Some(w) if w.to == from => w.to = to,
+                    _ => weekends.push(Annotation {
+                        from,
+                        to,
+                        label: String::new(),
+                        colour: WEEKEND,
+                    }),
                 }
+            });
+        if let Some(w) = weekends.first_mut() {
+            w.label = "weekend".to_string();
+        }
+        weekends
+    }
+
+    // Ticks on round local times across span, further apart and labelled
+    // more coarsely the longer it is, and at least min_step apart.
+    fn ticks(&self, span: (OffsetDateTime, OffsetDateTime), min_step: i64) -> Vec<Tick> {
+        let (start, end) = span;
+        let length = (end - start).whole_seconds();
+        let (step, fmt) = TICKS
+            .iter()
+            .find(|(longest, step, _)| length <= *longest && *step >= min_step)
+            .map(|(_, step, fmt)| (*step, *fmt))
+            .unwrap_or((30 * LONG_DIFF, "%b %Y"));
+
+        let times: Vec<OffsetDateTime> = if step < LONG_DIFF {
+            // Steps of a day divide evenly, so align them in site time.
+            let offset = self.tz.offset_at(start).as_seconds() as i64;
+            let local = start.timestamp() + offset;
+            let first = local + (step - local.rem_euclid(step)) % step - offset;
+            (first..=end.timestamp())
+                .step_by(step as usize)
+                .map(OffsetDateTime::from_unix_timestamp)
+                .collect()
+        } else {
+            // Days, weeks from monday, or months from the first.
+            let mut times = Vec::new();
+            let mut d = self.tz.date_of(start);
+            while d <= self.tz.date_of(end) {
+                let on = match step / LONG_DIFF {
+                    1 => true,
+                    7 => d.weekday() == Weekday::Monday,
+                    _ => d.day() == 1,
+                };
+                let t = self.tz.day_start(d);
+                if on && t >= start && t <= end {
+                    times.push(t);
+                }
+                d = d.next_day();
+            }
+            times
+        };
+
+        times
+            .into_iter()
+            .map(|t| Tick {
+                at: t.timestamp(),
+                // Events are stored in utc, but we want to read them in site time.
+                label: self.tz.to_local(t).format(fmt),
             })
-            .collect();
+            .collect()
+    }
 
-        let x: Vec<i64> = once(ts_init)
-            .chain(msg.data.iter().map(|dbe| dbe.time.timestamp()))
-            .collect();
+    fn events_chart(
+        &self,
+        kind: ChartKind,
+        meters: &[RenderMeter],
+        resolution: Resolution,
+        span: Option<(OffsetDateTime, OffsetDateTime)>,
+    ) -> Option<Chart> {
+        let span = span.or_else(|| {
+            bounds(
+                meters
+                    .iter()
+                    .flat_map(|m| m.data.iter().map(|dbe| dbe.time)),
+            )
+        })?;
+        let ticks = self.ticks(span, 0);
 
-        let ppm_y: Vec<f32> = once(0.0)
-            .chain(msg.data.iter().map(|dbe| dbe.ppm as f32))
-            .collect();
-        let hum_y: Vec<f32> = once(0.0)
-            .chain(msg.data.iter().map(|dbe| (dbe.hum as f32) / 10.0))
-            .collect();
-        let temp_y: Vec<f32> = once(0.0)
-            .chain(msg.data.iter().map(|dbe| (dbe.temp as f32) / 10.0))
+        let (title, field, scale): (&str, fn(&db::DbEvent) -> u16, f32) = match kind {
+            ChartKind::Hum => ("Relative (%)", |dbe| dbe.hum, 10.0),
+            ChartKind::Temp => ("Degrees (C)", |dbe| dbe.temp, 10.0),
+            _ => ("CO2 PPM", |dbe| dbe.ppm, 1.0),
+        };
+        let lines = meters
+            .iter()
+            .map(|m| {
+                let l = line(&m.data, event_time, resolution.max_gap(), |dbe| {
+                    field(dbe) as f32 / scale
+                });
+                (m.name().to_string(), l)
+            })
             .collect();
 
-        render_single_figure(
-            "CO2 PPM",
-            msg.src.as_str(),
-            "black",
-            x.as_slice(),
-            ppm_y.as_slice(),
-            ticks.as_slice(),
-            "./data/render/ppm.svg",
-        )?;
+        Some(lines_figure(title, lines, ticks))
+    }
 
-        render_single_figure(
-            "Relative (%)",
-            msg.src.as_str(),
-            "black",
-            x.as_slice(),
-            hum_y.as_slice(),
-            ticks.as_slice(),
-            "./data/render/hum.svg",
+    fn history_chart(&self, kind: ChartKind, meters: &[RenderMeter]) -> Option<Chart> {
+        let span = bounds(
+            meters
+                .iter()
+                .flat_map(|m| m.history.iter().map(|dbe| dbe.time)),
         )?;
+        // A tick a day at most, as there's a report a day.
+        let ticks = self.ticks(span, LONG_DIFF);
 
-        render_single_figure(
-            "Degrees (C)",
-            msg.src.as_str(),
-            "black",
-            x.as_slice(),
-            temp_y.as_slice(),
-            ticks.as_slice(),
-            "./data/render/temp.svg",
-        )?;
+        let (title, scale, min, max, avg): (&str, f32, HistoryField, HistoryField, HistoryField) =
+            match kind {
+                ChartKind::MinutesAbove(band) => {
+                    let lines = meters
+                        .iter()
+                        .map(|m| history_line(m, &format!("> {} ppm", band), minutes_above(band)))
+                        .collect();
+                    return Some(lines_figure("Minutes above CO2 PPM", lines, ticks));
+                }
+                ChartKind::PpmBandsHistory => {
+                    // Each band is only drawn for one meter, as there would
+                    // be too many lines otherwise.
+                    let m = match meters {
+                        [m] => m,
+                        _ => return None,
+                    };
+                    // Every band that any day was measured against.
+                    let bands: BTreeSet<u16> = m
+                        .history
+                        .iter()
+                        .flat_map(|dbe| dbe.minutes_above.keys().cloned())
+                        .collect();
+                    if bands.is_empty() {
+                        return None;
+                    }
+                    let lines = bands
+                        .iter()
+                        .map(|band| {
+                            history_line(m, &format!("> {} ppm", band), minutes_above(*band))
+                        })
+                        .collect();
+                    return Some(lines_figure("Minutes above CO2 PPM", lines, ticks));
+                }
+                ChartKind::HumHistory => (
+                    "Relative (%)",
+                    10.0,
+                    |dbe| dbe.hum_min,
+                    |dbe| dbe.hum_max,
+                    |dbe| dbe.hum_avg,
+                ),
+                ChartKind::TempHistory => (
+                    "Degrees (C)",
+                    10.0,
+                    |dbe| dbe.temp_min,
+                    |dbe| dbe.temp_max,
+                    |dbe| dbe.temp_avg,
+                ),
+                _ => (
+                    "CO2 PPM",
+                    1.0,
+                    |dbe| dbe.ppm_min,
+                    |dbe| dbe.ppm_max,
+                    |dbe| dbe.ppm_avg,
+                ),
+            };
+        let field = |m: &RenderMeter, name: &str, f: HistoryField| {
+            history_line(m, name, |dbe| value_or_gap(f(dbe), scale))
+        };
+
+        let m = match meters {
+            // Comparisons are of the daily average.
+            [_, _, ..] => {
+                return Some(lines_figure(
+                    title,
+                    meters.iter().map(|m| field(m, "avg", avg)).collect(),
+                    ticks,
+                ))
+            }
+            [m] => m,
+            [] => return None,
+        };
+
+        // The day's range is shaded behind its average.
+        let (_, (x, lo)) = field(m, "min", min);
+        let (_, (_, hi)) = field(m, "max", max);
+        let envelopes = vec![Envelope {
+            caption: format!("min to max - {}", m.name()),
+            colour: COLOURS[1],
+            x,
+            lo,
+            hi,
+        }];
+
+        let week = rolling_week(&m.history, avg);
+        let mut thin = vec![history_line(m, "7 day avg", |dbe| {
+            week.get(&history_time(dbe))
+                .map(|v| v / scale)
+                .unwrap_or(std::f32::NAN)
+        })];
+        if kind == ChartKind::PpmHistory {
+            thin.push(field(m, "median", |dbe| dbe.ppm_p50));
+            thin.push(field(m, "p95", |dbe| dbe.ppm_p95));
+        }
+        let mut lines = series(vec![field(m, "avg", avg)], BOLD_WIDTH);
+        // After black and the envelope's blue.
+        lines.extend(
+            series(thin, LINE_WIDTH)
+                .into_iter()
+                .zip(COLOURS[2..].iter())
+                .map(|(s, colour)| Series { colour, ..s }),
+        );
 
-        // === now we render the historical info ===
+        Some(Chart {
+            y_label: title.to_string(),
+            ticks,
+            series: lines,
+            envelopes,
+            ..Default::default()
+        })
+    }
+}
 
-        let ts_init = match msg.history.first() {
-            Some(t) => t.time.timestamp(),
-            None => {
+// The value a heatmap cell is shaded by, from its hours.
+fn heat_value(stat: HeatStat, hours: &[&db::DbHourlyEvent]) -> Option<f32> {
+    match stat {
+        // Weighted by how many readings each hour had.
+        HeatStat::Avg => {
+            let count: u32 = hours.iter().map(|h| h.count).sum();
+            let sum: f32 = hours
+                .iter()
+                .map(|h| h.ppm_avg as f32 * h.count as f32)
+                .sum();
+            if count == 0 {
+                None
+            } else {
+                Some(sum / count as f32)
+            }
+        }
+        HeatStat::P95 => {
+            let mut p95: Vec<u16> = hours.iter().map(|h| h.ppm_p95).collect();
+            if p95.is_empty() {
+                return None;
+            }
+            p95.sort_unstable();
+            Some(db::percentile(&p95, 95) as f32)
+        }
+    }
+}
+
+impl RenderActor {
+    // One meter's CO2 in a cell for each hour of the day, on a row for each
+    // day of the week or each day of span.
+    pub(crate) fn heatmap(
+        &self,
+        layout: HeatLayout,
+        stat: HeatStat,
+        meters: &[RenderMeter],
+        span: Option<(OffsetDateTime, OffsetDateTime)>,
+    ) -> Option<Heatmap> {
+        let m = match meters {
+            [m] => m,
+            _ => return None,
+        };
+        let (first, last) = span.or_else(|| bounds(m.hourly.iter().map(|h| h.time)))?;
+
+        let days: Vec<Date> = match layout {
+            HeatLayout::Week => Vec::new(),
+            HeatLayout::Month => {
+                let mut days = vec![self.tz.date_of(first)];
+                // Span ends before last, often at midnight.
+                let end = self.tz.date_of(last - Duration::from_secs(1));
+                while days[days.len() - 1] < end {
+                    days.push(days[days.len() - 1].next_day());
+                }
+                days
+            }
+        };
+        let rows: Vec<String> = match layout {
+            HeatLayout::Week => ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"]
+                .iter()
+                .map(|d| d.to_string())
+                .collect(),
+            HeatLayout::Month => days.iter().map(|d| d.format("%a %d %b")).collect(),
+        };
+
+        let mut hours: Vec<Vec<Vec<&db::DbHourlyEvent>>> = vec![vec![Vec::new(); 24]; rows.len()];
+        m.hourly.iter().for_each(|h| {
+            let local = self.tz.to_local(h.time);
+            let row = match layout {
+                HeatLayout::Week => Some(local.weekday().number_days_from_monday() as usize),
+                HeatLayout::Month => days.iter().position(|d| *d == local.date()),
+            };
+            if let Some(row) = row {
+                hours[row][local.hour() as usize].push(h);
+            }
+        });
+        let cells: Vec<Vec<Option<f32>>> = hours
+            .iter()
+            .map(|row| row.iter().map(|cell| heat_value(stat, cell)).collect())
+            .collect();
+
+        // Shaded up to the worst comfort band, so the colours mean the same
+        // from one meter or month to the next.
+        let high = self
+            .comfort
+            .iter()
+            .filter_map(|b| b.below)
+            .max()
+            .map(|ppm| ppm as f32)
+            .unwrap_or_else(|| {
+                cells
+                    .iter()
+                    .flatten()
+                    .flatten()
+                    .fold(OUTDOOR_PPM, |m, v| m.max(*v))
+            });
+
+        let stat = match stat {
+            HeatStat::Avg => "average",
+            HeatStat::P95 => "p95",
+        };
+        Some(Heatmap {
+            title: format!("CO2 PPM, {} by hour - {}", stat, m.name()),
+            rows,
+            columns: (0..24).map(|h| format!("{:02}", h)).collect(),
+            cells,
+            low: OUTDOOR_PPM,
+            high,
+        })
+    }
+}
+
+impl RenderActor {
+    /// Any chart but a heatmap, shaded and annotated.
+    pub(crate) fn chart(
+        &self,
+        kind: ChartKind,
+        meters: &[RenderMeter],
+        resolution: Resolution,
+        span: Option<(OffsetDateTime, OffsetDateTime)>,
+    ) -> Option<Chart> {
+        let chart = if kind.is_history() {
+            if meters.iter().all(|m| m.history.is_empty()) {
                 error!("no history data to render");
-                // We return ok here, because it's okay to not have the history
-                return Ok(());
+                return None;
             }
+            self.history_chart(kind, meters)
+        } else {
+            if meters.iter().all(|m| m.data.is_empty()) {
+                error!("no data to render");
+                return None;
+            }
+            self.events_chart(kind, meters, resolution, span)
+        };
+
+        chart.map(|mut chart| {
+            if kind.is_ppm() {
+                chart.bands = self.comfort_bands();
+            }
+            chart.annotations = self.annotations(kind, meters, resolution, span);
+            chart
+        })
+    }
+}
+
+impl Handler<RenderEvent> for RenderActor {
+    type Result = Result<Vec<u8>, ()>;
+
+    fn handle(&mut self, msg: RenderEvent, _: &mut SyncContext<Self>) -> Result<Vec<u8>, ()> {
+        let rendered = if let ChartKind::Heatmap(layout, stat) = msg.kind {
+            self.heatmap(layout, stat, &msg.meters, msg.span)
+                .map(|heatmap| heatmap.render(PNG_WIDTH, PNG_HEIGHT, msg.format))
+        } else {
+            self.chart(msg.kind, &msg.meters, msg.resolution, msg.span)
+                .map(|chart| chart.render(PNG_WIDTH, PNG_HEIGHT, msg.format))
         };
+        rendered.unwrap_or_else(|| {
+            error!("nothing to render for {:?}", msg.kind);
+            Err(())
+        })
+    }
+}
 
-        let mut last_step = 0;
-        let ticks: Vec<Tick<i64, String>> = msg
-            .history
+#[cfg(test)]
+mod tests {
+    use crate::canvas::Format;
+    use crate::chart::{MISSING, WEEKEND};
+    use crate::db::{report_day, DbEvent, DbHourlyEvent, TFMT};
+    use crate::render::{
+        check_span, check_time, parse_comfort_bands, parse_range, rolling_week, ChartKind,
+        ComfortBand, RenderActor, RenderCache, RenderKey, RenderMeter, Resolution, CACHE_ENTRIES,
+        CACHE_IDLE, DAY_MS,
+    };
+    use crate::render::{HeatLayout, HeatStat};
+    use std::time::{Duration, Instant};
+    use time::OffsetDateTime;
+
+    fn ts(s: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(s, TFMT).expect("invalid ts")
+    }
+
+    fn meter(src: &str, label: Option<&str>, ppm: u16) -> RenderMeter {
+        let data: Vec<DbEvent> = ["2020-04-05 13:00:00+1000", "2020-04-05 13:01:00+1000"]
             .iter()
-            .filter_map(|dbe| {
-                if dbe.time.timestamp() >= last_step + LONG_DIFF {
-                    last_step = dbe.time.timestamp();
-                    Some(Tick::Major(
-                        dbe.time.timestamp(),
-                        AutoOption::Fix(dbe.time.format(db::TFMT)),
-                    ))
-                } else {
-                    None
-                }
+            .map(|t| DbEvent {
+                src: src.to_string(),
+                time: ts(t),
+                temp: 220,
+                ppm,
+                hum: 500,
             })
             .collect();
+        let history = vec![report_day(
+            src,
+            &ts("2020-04-05 00:00:00+1000"),
+            &ts("2020-04-06 00:00:00+1000"),
+            &data,
+            &[800, 1000],
+        )];
+        RenderMeter {
+            src: src.to_string(),
+            label: label.map(str::to_string),
+            data,
+            history,
+            hourly: Vec::new(),
+        }
+    }
 
-        let x: Vec<i64> = once(ts_init)
-            .chain(msg.history.iter().map(|dbe| dbe.time.timestamp()))
-            .collect();
+    #[test]
+    fn test_render_compare() {
+        let actor = RenderActor {
+            tz: "Australia/Brisbane".parse().unwrap(),
+            comfort: Vec::new(),
+        };
+        let one = vec![meter("01:00:00:00:00:00", None, 900)];
+        let two = vec![
+            meter("01:00:00:00:00:00", Some("Meeting room"), 900),
+            meter("02:00:00:00:00:00", None, 1200),
+        ];
 
-        // Now we have to make a lot of data sets ..........
-        let ppm_min_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| dbe.ppm_min as f32))
-            .collect();
-        let ppm_max_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| dbe.ppm_max as f32))
-            .collect();
-        let ppm_avg_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| dbe.ppm_avg as f32))
-            .collect();
+        // One meter is drawn in detail, several a line each named by label.
+        let chart = actor.history_chart(ChartKind::TempHistory, &one).unwrap();
+        assert!(chart.envelopes.len() == 1);
+        assert!(chart.envelopes[0].lo == vec![22.0] && chart.envelopes[0].hi == vec![22.0]);
+        assert!(
+            chart
+                .series
+                .iter()
+                .map(|s| s.caption.as_str())
+                .collect::<Vec<_>>()
+                == vec!["avg - 01:00:00:00:00:00", "7 day avg - 01:00:00:00:00:00"]
+        );
+        assert!(chart.series[0].width > chart.series[1].width);
+        let chart = actor.history_chart(ChartKind::PpmHistory, &two).unwrap();
+        assert!(
+            chart
+                .series
+                .iter()
+                .map(|s| s.caption.as_str())
+                .collect::<Vec<_>>()
+                == vec!["avg - Meeting room", "avg - 02:00:00:00:00:00"]
+        );
+        assert!(chart.series[0].colour != chart.series[1].colour);
+        assert!(chart.series[1].y == vec![1200.0]);
 
-        let hum_min_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| (dbe.hum_min as f32) / 10.0))
-            .collect();
-        let hum_max_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| (dbe.hum_max as f32) / 10.0))
-            .collect();
-        let hum_avg_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| (dbe.hum_avg as f32) / 10.0))
-            .collect();
+        let chart = actor
+            .events_chart(ChartKind::Ppm, &two, Resolution::Raw, None)
+            .unwrap();
+        assert!(chart.series.len() == 2);
+        assert!(chart.series[0].caption == "Meeting room");
+        // The meters share their ticks.
+        assert!(chart.ticks.len() == 1);
 
-        let temp_min_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| (dbe.temp_min as f32) / 10.0))
-            .collect();
-        let temp_max_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| (dbe.temp_max as f32) / 10.0))
-            .collect();
-        let temp_avg_y: Vec<f32> = once(0.0)
-            .chain(msg.history.iter().map(|dbe| (dbe.temp_avg as f32) / 10.0))
+        let chart = actor
+            .history_chart(ChartKind::MinutesAbove(1000), &two)
+            .unwrap();
+        assert!(chart.series[0].y == vec![0.0]);
+        assert!(chart.series[1].y == vec![6.0]);
+        // Every band for every meter is too much.
+        assert!(actor
+            .history_chart(ChartKind::PpmBandsHistory, &two)
+            .is_none());
+        assert!(
+            actor
+                .history_chart(ChartKind::PpmBandsHistory, &one)
+                .unwrap()
+                .series
+                .len()
+                == 2
+        );
+    }
+
+    #[test]
+    fn test_render_bands_and_gaps() {
+        let comfort = parse_comfort_bands("good:800, moderate:1200 ,poor").unwrap();
+        assert!(
+            comfort
+                == vec![
+                    ComfortBand {
+                        label: "good".to_string(),
+                        below: Some(800)
+                    },
+                    ComfortBand {
+                        label: "moderate".to_string(),
+                        below: Some(1200)
+                    },
+                    ComfortBand {
+                        label: "poor".to_string(),
+                        below: None
+                    },
+                ]
+        );
+        assert!(parse_comfort_bands("") == Ok(Vec::new()));
+        assert!(parse_comfort_bands("good:800,poor:abc").is_err());
+        assert!(parse_comfort_bands("good:1200,moderate:800").is_err());
+        assert!(parse_comfort_bands("good,poor:800").is_err());
+
+        let actor = RenderActor {
+            tz: "Australia/Brisbane".parse().unwrap(),
+            comfort,
+        };
+        let bands = actor.comfort_bands();
+        assert!(bands.iter().map(|b| b.from).collect::<Vec<_>>() == vec![0.0, 800.0, 1200.0]);
+        assert!(bands[2].to.is_none());
+        assert!(bands[0].colour != bands[2].colour);
+
+        // The readings are a minute apart, an hour into two hours asked for.
+        let m = vec![meter("01:00:00:00:00:00", None, 900)];
+        let span = Some((
+            ts("2020-04-05 12:00:00+1000"),
+            ts("2020-04-05 14:00:00+1000"),
+        ));
+        let notes = actor.annotations(ChartKind::Ppm, &m, Resolution::Raw, span);
+        assert!(notes.len() == 2);
+        assert!(notes[0].from == ts("2020-04-05 12:00:00+1000").timestamp());
+        assert!(notes[0].to == ts("2020-04-05 13:00:00+1000").timestamp());
+        // Exactly an hour isn't an outage, 59 minutes to the end isn't either.
+        assert!(notes[0].label.is_empty());
+        assert!(notes[1].to == ts("2020-04-05 14:00:00+1000").timestamp());
+
+        let span = Some((
+            ts("2020-04-05 10:00:00+1000"),
+            ts("2020-04-05 14:00:00+1000"),
+        ));
+        let notes = actor.annotations(ChartKind::Ppm, &m, Resolution::Raw, span);
+        assert!(notes[0].label == "outage");
+        assert!(actor
+            .annotations(ChartKind::Ppm, &m, Resolution::Raw, None)
+            .is_empty());
+
+        // A day without readings is a gap in the history.
+        let mut m = meter("01:00:00:00:00:00", None, 900);
+        let mut empty = m.history[0].clone();
+        empty.time = ts("2020-04-06 00:00:00+1000");
+        empty.count = Some(0);
+        let mut next = m.history[0].clone();
+        next.time = ts("2020-04-07 00:00:00+1000");
+        m.history.push(empty);
+        m.history.push(next);
+        let notes = actor.annotations(ChartKind::PpmHistory, &[m], Resolution::Daily, None);
+        assert!(notes.len() == 2);
+        // The 5th was a Sunday.
+        assert!(notes[0].label == "weekend" && notes[0].colour == WEEKEND);
+        assert!(notes[0].from == ts("2020-04-05 00:00:00+1000").timestamp());
+        assert!(notes[0].to == ts("2020-04-06 00:00:00+1000").timestamp());
+        assert!(notes[1].label == "no data" && notes[1].colour == MISSING);
+    }
+
+    #[test]
+    fn test_render_rolling_week() {
+        let mut m = meter("01:00:00:00:00:00", None, 900);
+        let day = m.history[0].clone();
+        m.history = (0..10)
+            .map(|i| {
+                let mut d = day.clone();
+                d.time = ts("2020-04-01 00:00:00+1000") + time::Duration::days(i);
+                // A day without readings doesn't count as zero.
+                d.ppm_avg = if i == 8 { None } else { Some(100 * i as u16) };
+                d
+            })
             .collect();
+        let week = rolling_week(&m.history, |dbe| dbe.ppm_avg);
+        let at = |i| week[&(ts("2020-04-01 00:00:00+1000") + time::Duration::days(i)).timestamp()];
+        assert!(at(0) == 0.0);
+        assert!(at(2) == 100.0);
+        // Days 0 to 6, then 3 to 9 without 8.
+        assert!(at(6) == 300.0);
+        assert!(at(9) == 3400.0 / 6.0);
+    }
 
-        render_triple_figure(
-            "CO2 PPM",
-            msg.src.as_str(),
-            "black",
-            x.as_slice(),
-            ppm_min_y.as_slice(),
-            ppm_max_y.as_slice(),
-            ppm_avg_y.as_slice(),
-            ticks.as_slice(),
-            "./data/render/ppm_history.svg",
-        )?;
+    #[test]
+    fn test_render_cache() {
+        assert!("ppm_bands_history".parse() == Ok(ChartKind::PpmBandsHistory));
+        assert!("minutes_above_1000".parse() == Ok(ChartKind::MinutesAbove(1000)));
+        assert!("minutes_above_".parse::<ChartKind>().is_err());
+        assert!("ppm.svg".parse::<ChartKind>().is_err());
+        assert!(ChartKind::TempHistory.is_history());
+        assert!(ChartKind::MinutesAbove(800).is_history());
+        assert!(!ChartKind::Temp.is_history());
 
-        render_triple_figure(
-            "Relative (%)",
-            msg.src.as_str(),
-            "black",
-            x.as_slice(),
-            hum_min_y.as_slice(),
-            hum_max_y.as_slice(),
-            hum_avg_y.as_slice(),
-            ticks.as_slice(),
-            "./data/render/hum_history.svg",
-        )?;
+        let cache = RenderCache::new();
+        let key = |srcs: &[&str], from| RenderKey {
+            srcs: srcs.iter().map(|s| s.to_string()).collect(),
+            kind: ChartKind::Ppm,
+            format: Format::Svg,
+            from,
+            to: None,
+            last: None,
+        };
+        let history = RenderKey {
+            srcs: vec!["a".to_string()],
+            kind: ChartKind::PpmHistory,
+            format: Format::Svg,
+            from: None,
+            to: None,
+            last: None,
+        };
+        let start = Instant::now();
+        cache.insert(key(&["a"], None), start, b"a".to_vec());
+        cache.insert(key(&["a"], Some(1000)), start, b"a1000".to_vec());
+        cache.insert(key(&["b"], None), start, b"b".to_vec());
+        cache.insert(history.clone(), start, b"h".to_vec());
+        // Meters and ranges don't share charts.
+        assert!(cache.get(&key(&["a"], None)) == Some(b"a".to_vec()));
+        assert!(cache.get(&key(&["a"], Some(1000))) == Some(b"a1000".to_vec()));
+        assert!(cache.get(&key(&["a"], Some(0))).is_none());
+        assert!(cache.due(CACHE_IDLE).is_empty());
 
-        render_triple_figure(
-            "Degrees (C)",
-            msg.src.as_str(),
-            "black",
-            x.as_slice(),
-            temp_min_y.as_slice(),
-            temp_max_y.as_slice(),
-            temp_avg_y.as_slice(),
-            ticks.as_slice(),
-            "./data/render/temp_history.svg",
-        )?;
+        // Only charts the reading would appear on need drawing again, but
+        // they're still served until they are.
+        cache.invalidate("a", 500);
+        assert!(cache.due(CACHE_IDLE) == vec![key(&["a"], None)]);
+        assert!(cache.get(&key(&["a"], None)) == Some(b"a".to_vec()));
+        // A render that began before the reading doesn't include it.
+        cache.insert(key(&["a"], None), start, b"a2".to_vec());
+        assert!(cache.due(CACHE_IDLE) == vec![key(&["a"], None)]);
+        cache.insert(key(&["a"], None), Instant::now(), b"a3".to_vec());
+        assert!(cache.due(CACHE_IDLE).is_empty());
+        assert!(cache.get(&key(&["a"], None)) == Some(b"a3".to_vec()));
+
+        cache.invalidate_meter("a");
+        assert!(cache.due(CACHE_IDLE).len() == 3);
+        assert!(cache.due(CACHE_IDLE).contains(&history));
+        // Everything is drawn again once it's old enough.
+        assert!(cache.due(Duration::from_secs(0)).len() == 4);
+
+        // Comparisons include the meter, as do those of every meter.
+        cache.insert(key(&["a", "b"], None), Instant::now(), vec![]);
+        cache.insert(key(&[], None), Instant::now(), vec![]);
+        cache.insert(key(&["a"], None), Instant::now(), vec![]);
+        cache.insert(key(&["a"], Some(1000)), Instant::now(), vec![]);
+        cache.insert(history.clone(), Instant::now(), vec![]);
+        cache.invalidate("b", 500);
+        assert!(
+            cache.due(CACHE_IDLE)
+                == vec![key(&[], None), key(&["a", "b"], None), key(&["b"], None)]
+        );
+
+        (0..100).for_each(|i| cache.insert(key(&["c"], Some(i)), Instant::now(), vec![]));
+        assert!(cache.inner.lock().unwrap().len() == CACHE_ENTRIES);
+        assert!(cache.get(&key(&["c"], Some(99))).is_some());
+    }
+
+    #[test]
+    fn test_render_ranges() {
+        assert!(parse_range("1h") == Ok(3_600_000));
+        assert!(parse_range("7d") == Ok(7 * DAY_MS));
+        assert!(parse_range("0h").is_err());
+        assert!(parse_range("-1d").is_err());
+        assert!(parse_range("6").is_err());
+        assert!(parse_range("h").is_err());
+        assert!(parse_range("").is_err());
+        assert!(parse_range("99999999999999d").is_err());
+
+        let day = 18357 * DAY_MS;
+        assert!(check_span(Some(day), Some(day + DAY_MS), None).is_ok());
+        assert!(check_span(None, Some(day), parse_range("7d").ok()).is_ok());
+        assert!(check_span(None, None, None).is_ok());
+        // Before 2000, after 2100, backwards or too long.
+        assert!(check_span(Some(0), Some(day), None).is_err());
+        assert!(check_span(None, Some(i64::MAX), None).is_err());
+        assert!(check_span(Some(day + DAY_MS), Some(day), None).is_err());
+        assert!(check_span(None, Some(day), parse_range("99999d").ok()).is_err());
+        assert!(check_time(i64::MIN).is_err());
+
+        // A range back from to follows it, and one from now takes anything new.
+        let key = RenderKey {
+            srcs: Vec::new(),
+            kind: ChartKind::Ppm,
+            format: Format::Svg,
+            from: None,
+            to: Some(10 * DAY_MS),
+            last: Some(7 * DAY_MS),
+        };
+        assert!(key.covers(4 * DAY_MS) && !key.covers(2 * DAY_MS));
+        assert!(RenderKey { to: None, ..key }.covers(0));
+
+        assert!(Resolution::for_span(86400) == Resolution::Raw);
+        assert!(Resolution::for_span(7 * 86400) == Resolution::Hourly);
+        assert!(Resolution::for_span(90 * 86400) == Resolution::Daily);
+
+        let actor = RenderActor {
+            tz: "Australia/Brisbane".parse().unwrap(),
+            comfort: Vec::new(),
+        };
+        let ticks = |from, to, min_step| {
+            actor
+                .ticks((ts(from), ts(to)), min_step)
+                .into_iter()
+                .map(|t| t.label)
+                .collect::<Vec<_>>()
+        };
+        // Round times in site time, labelled for the span.
+        assert!(
+            ticks("2020-04-05 02:55:00+0000", "2020-04-05 03:30:00+0000", 0)
+                == vec!["13:00", "13:10", "13:20", "13:30"]
+        );
+        let day = ticks("2020-04-05 13:20:00+1000", "2020-04-06 13:20:00+1000", 0);
+        assert!(day.len() == 24 && day[0] == "Sun 14:00" && day[10] == "Mon 00:00");
+        assert!(
+            ticks("2020-04-05 13:20:00+1000", "2020-04-12 13:20:00+1000", 0)[0] == "Mon 06 Apr"
+        );
+        // Weeks from monday, and months from the first.
+        assert!(
+            ticks("2020-04-05 13:20:00+1000", "2020-06-05 13:20:00+1000", 0)
+                == vec![
+                    "06 Apr", "13 Apr", "20 Apr", "27 Apr", "04 May", "11 May", "18 May", "25 May",
+                    "01 Jun"
+                ]
+        );
+        assert!(
+            ticks("2020-04-05 13:20:00+1000", "2021-01-05 13:20:00+1000", 0)[..2]
+                == ["May 2020", "Jun 2020"]
+        );
+        // History charts tick no more than daily.
+        assert!(
+            ticks(
+                "2020-04-05 00:00:00+1000",
+                "2020-04-06 00:00:00+1000",
+                DAY_MS / 1000
+            ) == vec!["Sun 05 Apr", "Mon 06 Apr"]
+        );
+    }
+
+    #[test]
+    fn test_render_heatmap() {
+        assert!(
+            "heatmap_month_p95".parse() == Ok(ChartKind::Heatmap(HeatLayout::Month, HeatStat::P95))
+        );
+        assert!("heatmap_week".parse() == Ok(ChartKind::Heatmap(HeatLayout::Week, HeatStat::Avg)));
+        assert!(!ChartKind::Heatmap(HeatLayout::Week, HeatStat::Avg).is_history());
+
+        let actor = RenderActor {
+            tz: "Australia/Brisbane".parse().unwrap(),
+            comfort: parse_comfort_bands("good:800,moderate:1200,poor").unwrap(),
+        };
+        let hour = |t: &str, count, ppm_avg, ppm_p95| DbHourlyEvent {
+            src: "01:00:00:00:00:00".to_string(),
+            time: ts(t),
+            count,
+            temp_min: 220,
+            temp_max: 220,
+            temp_avg: 220,
+            ppm_min: 400,
+            ppm_max: ppm_p95,
+            ppm_avg,
+            ppm_p50: ppm_avg,
+            ppm_p95,
+            hum_min: 500,
+            hum_max: 500,
+            hum_avg: 500,
+        };
+        let mut m = meter("01:00:00:00:00:00", None, 900);
+        // Two mondays at 9 and a tuesday at 13, in site time.
+        m.hourly = vec![
+            hour("2020-04-05 23:00:00+0000", 60, 600, 700),
+            hour("2020-04-07 03:00:00+0000", 60, 1500, 2000),
+            hour("2020-04-12 23:00:00+0000", 20, 1000, 1100),
+        ];
+        let span = Some((
+            ts("2020-04-06 00:00:00+1000"),
+            ts("2020-04-14 00:00:00+1000"),
+        ));
+
+        let meters = vec![m];
+
+        let week = actor
+            .heatmap(HeatLayout::Week, HeatStat::Avg, &meters, span)
+            .unwrap();
+        assert!(week.rows.len() == 7 && week.columns.len() == 24);
+        assert!(week.cells[0][9] == Some(700.0));
+        assert!(week.cells[1][13] == Some(1500.0));
+        assert!(week.cells.iter().flatten().filter(|c| c.is_some()).count() == 2);
+        assert!(week.low == 400.0 && week.high == 1200.0);
+        let week = actor
+            .heatmap(HeatLayout::Week, HeatStat::P95, &meters, span)
+            .unwrap();
+        assert!(week.cells[0][9] == Some(1100.0));
 
-        Ok(())
+        // A row a day, which can be empty.
+        let month = actor
+            .heatmap(HeatLayout::Month, HeatStat::Avg, &meters, span)
+            .unwrap();
+        assert!(month.rows.len() == 8 && month.rows[0] == "Mon 06 Apr");
+        assert!(month.cells[0][9] == Some(600.0) && month.cells[7][9] == Some(1000.0));
+        assert!(month.cells[3].iter().all(|c| c.is_none()));
+        // Only one meter at a time.
+        assert!(actor
+            .heatmap(HeatLayout::Week, HeatStat::Avg, &[], span)
+            .is_none());
     }
 }
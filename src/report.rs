@@ -0,0 +1,984 @@
+//! Reports on a day, week or month, for people who won't open the web ui. A
+//! report is a pdf with a summary of each meter, then for each meter its
+//! CO2 over the period, a heatmap, and a table of each day. Scheduled
+//! reports are archived with a csv of each day and the charts as pngs.
+
+use actix::prelude::*;
+use actix_web::client::Client;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use time::{Date, OffsetDateTime, Weekday};
+
+use crate::canvas::{Anchor, Canvas, Format, Text};
+use crate::db;
+use crate::render::{self, ChartKind, HeatLayout, HeatStat, RenderActor, RenderMeter, Resolution};
+use crate::tz::SiteTz;
+
+// A4 landscape, in points.
+const PAGE_WIDTH: u32 = 842;
+const PAGE_HEIGHT: u32 = 595;
+const MARGIN: f32 = 40.0;
+// Below the heading of each page.
+const PAGE_TOP: f32 = 90.0;
+
+const TITLE_SIZE: f32 = 18.0;
+const TEXT_SIZE: f32 = 10.0;
+const ROW: f32 = 15.0;
+const FIRST_COLUMN: f32 = 170.0;
+const ROW_SHADE: &str = "#f0f0f0";
+
+// The size of archived charts.
+const CHART_WIDTH: u32 = 1400;
+const CHART_HEIGHT: u32 = 800;
+
+const ARCHIVE_PREFIX: &str = "micd-report-";
+// Give the last day's readings time to arrive, such as by sync, before
+// reporting on it.
+const ARCHIVE_DELAY: Duration = Duration::from_secs(3600);
+const WEBHOOK_TIMEOUT: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    /// Monday to Sunday.
+    Week,
+    /// A calendar month.
+    Month,
+}
+
+impl FromStr for Period {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Period {
+    /// The first and last local days of the last whole period before the
+    /// day that to is in.
+    pub fn days(self, tz: &SiteTz, to: OffsetDateTime) -> (Date, Date) {
+        let today = tz.date_of(to);
+        match self {
+            Period::Day => (today.previous_day(), today.previous_day()),
+            Period::Week => {
+                let mut last = today.previous_day();
+                while last.weekday() != Weekday::Sunday {
+                    last = last.previous_day();
+                }
+                let first = (0..6).fold(last, |d, _| d.previous_day());
+                (first, last)
+            }
+            Period::Month => {
+                let last = Date::try_from_ymd(today.year(), today.month(), 1)
+                    .map(|d| d.previous_day())
+                    .unwrap_or(today);
+                let first = Date::try_from_ymd(last.year(), last.month(), 1).unwrap_or(last);
+                (first, last)
+            }
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+        }
+    }
+
+    fn title(self, first: Date) -> String {
+        match self {
+            Period::Day => first.format("%a %d %b %Y"),
+            Period::Week => format!("Week of {}", first.format("%a %d %b %Y")),
+            Period::Month => first.format("%B %Y"),
+        }
+    }
+}
+
+/// Draw a report on meters as a pdf.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<u8>, ()>")]
+pub struct RenderReport {
+    pub period: Period,
+    pub days: (Date, Date),
+    pub span: (OffsetDateTime, OffsetDateTime),
+    pub resolution: Resolution,
+    pub meters: Vec<RenderMeter>,
+}
+
+/// Draw a report as the files it's archived as: the pdf, a csv of each
+/// day, and the charts.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<(String, Vec<u8>)>, ()>")]
+pub struct RenderReportFiles(pub RenderReport);
+
+// What a report on srcs, or every meter, needs. Meters with nothing in the
+// period are left off.
+async fn fetch(
+    db_addr: &Addr<db::DbActor>,
+    tz: SiteTz,
+    srcs: Option<Vec<String>>,
+    period: Period,
+    to: OffsetDateTime,
+) -> Result<RenderReport, ()> {
+    let (first, last) = period.days(&tz, to);
+    let (min, max) = (tz.day_start(first), tz.day_start(last.next_day()));
+
+    let srcs = match srcs {
+        Some(srcs) => Ok(Ok(srcs)),
+        None => db_addr.send(db::DbListMeters).await,
+    };
+    let (srcs, labels) = match (srcs, db_addr.send(db::DbMeterLabels).await) {
+        (Ok(Ok(srcs)), Ok(Ok(labels))) => (srcs, labels),
+        _ => {
+            error!("db unable to complete!");
+            return Err(());
+        }
+    };
+
+    let resolution = Resolution::for_span((max - min).whole_seconds());
+    let mut meters = Vec::with_capacity(srcs.len());
+    for src in srcs {
+        let history: Vec<db::DbHistoryEvent> =
+            match db_addr.send(db::DbHistory { src: src.clone() }).await {
+                Ok(Ok(history)) => history
+                    .into_iter()
+                    .filter(|h| h.time >= min && h.time < max)
+                    .collect(),
+                _ => {
+                    error!("db unable to complete!");
+                    return Err(());
+                }
+            };
+        let hourly = match db_addr
+            .send(db::DbHourlyRange {
+                src: src.clone(),
+                min,
+                max,
+            })
+            .await
+        {
+            Ok(Ok(hourly)) => hourly,
+            _ => {
+                error!("db unable to complete!");
+                return Err(());
+            }
+        };
+        // Older hours may only be left as days.
+        let mut data = render::events(db_addr, &src, resolution, min, max).await?;
+        if let (true, Some(coarser)) = (data.is_empty(), resolution.coarser()) {
+            data = render::events(db_addr, &src, coarser, min, max).await?;
+        }
+
+        if !history.is_empty() || !hourly.is_empty() || !data.is_empty() {
+            meters.push(RenderMeter {
+                label: labels.get(&src).cloned(),
+                src,
+                data,
+                history,
+                hourly,
+            });
+        }
+    }
+
+    Ok(RenderReport {
+        period,
+        days: (first, last),
+        span: (min, max),
+        resolution,
+        meters,
+    })
+}
+
+/// Fetch what a report on srcs, or every meter, needs and draw it. Ok(None)
+/// if nothing was recorded in the period.
+pub async fn build(
+    db_addr: Addr<db::DbActor>,
+    render_addr: Addr<RenderActor>,
+    tz: SiteTz,
+    srcs: Option<Vec<String>>,
+    period: Period,
+    to: OffsetDateTime,
+) -> Result<Option<Vec<u8>>, ()> {
+    let report = fetch(&db_addr, tz, srcs, period, to).await?;
+    if report.meters.is_empty() {
+        return Ok(None);
+    }
+
+    match render_addr.send(report).await {
+        Ok(Ok(pdf)) => Ok(Some(pdf)),
+        _ => {
+            error!("report unable to complete!");
+            Err(())
+        }
+    }
+}
+
+type HistoryField = fn(&db::DbHistoryEvent) -> Option<u16>;
+
+// The average of a field over the days, weighted by how many readings each
+// day had.
+fn average(history: &[db::DbHistoryEvent], field: HistoryField) -> Option<f32> {
+    let (sum, n) = history
+        .iter()
+        .filter_map(|h| {
+            let weight = h.count.unwrap_or(1) as f32;
+            field(h).map(|v| (v as f32 * weight, weight))
+        })
+        .fold((0.0, 0.0), |(sum, n), (v, w)| (sum + v, n + w));
+    if n > 0.0 {
+        Some(sum / n)
+    } else {
+        None
+    }
+}
+
+fn highest(history: &[db::DbHistoryEvent], field: HistoryField) -> Option<f32> {
+    history.iter().filter_map(field).max().map(|v| v as f32)
+}
+
+// Temperature and humidity are stored in tenths.
+fn value(v: Option<f32>, scale: f32) -> String {
+    match v {
+        Some(v) if scale > 1.0 => format!("{:.1}", v / scale),
+        Some(v) => format!("{:.0}", v),
+        None => "-".to_string(),
+    }
+}
+
+// Every band that any day recorded minutes above.
+fn bands(meters: &[RenderMeter]) -> Vec<u16> {
+    meters
+        .iter()
+        .flat_map(|m| m.history.iter())
+        .flat_map(|h| h.minutes_above.keys().cloned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+// A line per meter over the whole period. Coverage counts days without a
+// report as none.
+fn summary_rows(meters: &[RenderMeter], days: usize, bands: &[u16]) -> Vec<Vec<String>> {
+    meters
+        .iter()
+        .map(|m| {
+            let h = &m.history;
+            let reported = h.iter().filter(|d| d.ppm_avg.is_some()).count();
+            let coverage: u32 = h.iter().filter_map(|d| d.coverage).map(u32::from).sum();
+            let mut row = vec![
+                m.name().to_string(),
+                format!("{} / {}", reported, days),
+                format!("{}%", coverage / days.max(1) as u32),
+                value(average(h, |d| d.ppm_avg), 1.0),
+                value(highest(h, |d| d.ppm_p95), 1.0),
+                value(highest(h, |d| d.ppm_max), 1.0),
+                value(average(h, |d| d.temp_avg), 10.0),
+                value(average(h, |d| d.hum_avg), 10.0),
+            ];
+            row.extend(bands.iter().map(|b| {
+                let minutes: u32 = h.iter().filter_map(|d| d.minutes_above.get(b)).sum();
+                format!("{:.1}", minutes as f32 / 60.0)
+            }));
+            row
+        })
+        .collect()
+}
+
+// Each day from first to last.
+fn each_day(days: (Date, Date)) -> Vec<Date> {
+    let mut each = Vec::new();
+    let mut day = days.0;
+    while day <= days.1 {
+        each.push(day);
+        day = day.next_day();
+    }
+    each
+}
+
+// Quoted if it would otherwise be read as more than one field.
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl RenderActor {
+    // The day's report, if there were any readings.
+    fn day_report<'a>(&self, m: &'a RenderMeter, day: Date) -> Option<&'a db::DbHistoryEvent> {
+        m.history
+            .iter()
+            .find(|h| self.tz.date_of(h.time) == day)
+            .filter(|h| h.ppm_avg.is_some())
+    }
+
+    // A line for each day of the period, including those without a report.
+    fn daily_rows(&self, m: &RenderMeter, days: (Date, Date), bands: &[u16]) -> Vec<Vec<String>> {
+        each_day(days)
+            .into_iter()
+            .map(|day| {
+                let mut row = vec![day.format("%a %d %b")];
+                match self.day_report(m, day) {
+                    Some(h) => {
+                        row.push(h.coverage.map(|c| format!("{}%", c)).unwrap_or_default());
+                        row.push(value(h.ppm_avg.map(f32::from), 1.0));
+                        row.push(value(h.ppm_p95.map(f32::from), 1.0));
+                        row.push(value(h.ppm_max.map(f32::from), 1.0));
+                        row.push(
+                            h.ppm_peak
+                                .map(|t| self.tz.to_local(t).format("%H:%M"))
+                                .unwrap_or_else(|| "-".to_string()),
+                        );
+                        row.extend(bands.iter().map(|b| {
+                            h.minutes_above
+                                .get(b)
+                                .map(|m| m.to_string())
+                                .unwrap_or_else(|| "-".to_string())
+                        }));
+                    }
+                    None => row.push("no readings".to_string()),
+                }
+                row
+            })
+            .collect()
+    }
+
+    // A line for each meter on each day, with the days without a report
+    // left empty. Temperature and humidity are in degrees and percent.
+    fn daily_csv(&self, msg: &RenderReport) -> String {
+        let bands = bands(&msg.meters);
+        let mut csv = vec![[
+            "mac", "label", "date", "readings", "coverage", "ppm_avg", "ppm_p95", "ppm_max",
+            "ppm_peak", "temp_avg", "hum_avg",
+        ]
+        .iter()
+        .map(|c| c.to_string())
+        .chain(bands.iter().map(|b| format!("minutes_above_{}", b)))
+        .collect::<Vec<_>>()];
+
+        let opt = |v: Option<u16>| v.map(|v| v.to_string()).unwrap_or_default();
+        let tenths = |v: Option<u16>| {
+            v.map(|v| format!("{:.1}", v as f32 / 10.0))
+                .unwrap_or_default()
+        };
+        msg.meters.iter().for_each(|m| {
+            each_day(msg.days).into_iter().for_each(|day| {
+                let mut row = vec![
+                    m.src.clone(),
+                    m.label.clone().unwrap_or_default(),
+                    day.format("%F"),
+                ];
+                match self.day_report(m, day) {
+                    Some(h) => {
+                        row.push(h.count.map(|c| c.to_string()).unwrap_or_default());
+                        row.push(h.coverage.map(|c| c.to_string()).unwrap_or_default());
+                        row.push(opt(h.ppm_avg));
+                        row.push(opt(h.ppm_p95));
+                        row.push(opt(h.ppm_max));
+                        row.push(
+                            h.ppm_peak
+                                .map(|t| self.tz.to_local(t).format("%H:%M"))
+                                .unwrap_or_default(),
+                        );
+                        row.push(tenths(h.temp_avg));
+                        row.push(tenths(h.hum_avg));
+                        row.extend(bands.iter().map(|b| {
+                            h.minutes_above
+                                .get(b)
+                                .map(|m| m.to_string())
+                                .unwrap_or_default()
+                        }));
+                    }
+                    None => row.resize(csv[0].len(), String::new()),
+                }
+                csv.push(row);
+            });
+        });
+
+        csv.iter()
+            .map(|row| {
+                let mut line = row
+                    .iter()
+                    .map(|f| csv_field(f))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                line.push_str("\r\n");
+                line
+            })
+            .collect()
+    }
+
+    // The meter's CO2 over the period, and a heatmap if there are hourly
+    // rollups, named for their files.
+    fn meter_charts(
+        &self,
+        m: &RenderMeter,
+        msg: &RenderReport,
+        w: u32,
+        h: u32,
+    ) -> Vec<(&'static str, Canvas)> {
+        let meter = std::slice::from_ref(m);
+        let mut charts = Vec::new();
+        if let Some(chart) = self.chart(ChartKind::Ppm, meter, msg.resolution, Some(msg.span)) {
+            charts.push(("ppm", chart.draw(w, h)));
+        }
+        if !m.hourly.is_empty() {
+            if let Some(heatmap) =
+                self.heatmap(HeatLayout::Month, HeatStat::Avg, meter, Some(msg.span))
+            {
+                charts.push(("heatmap", heatmap.draw(w, h)));
+            }
+        }
+        charts
+    }
+
+    fn report_pages(&self, msg: &RenderReport) -> Vec<Canvas> {
+        let (first, last) = msg.days;
+        let title = msg.period.title(first);
+        let subtitle = format!(
+            "{} to {}, times in {}",
+            first.format("%a %d %b %Y"),
+            last.format("%a %d %b %Y"),
+            self.tz.name()
+        );
+        let days = (msg.span.1 - msg.span.0).whole_days().max(1) as usize;
+        let bands = bands(&msg.meters);
+        let above = |unit: &'static str| bands.iter().map(move |b| format!("> {} {}", b, unit));
+
+        let columns: Vec<String> = [
+            "Meter",
+            "Days",
+            "Coverage",
+            "Avg ppm",
+            "Worst p95",
+            "Max ppm",
+            "Avg C",
+            "Avg %RH",
+        ]
+        .iter()
+        .map(|c| c.to_string())
+        .chain(above("(h)"))
+        .collect();
+        let mut pages = table_pages(
+            &format!("CO2 report - {}", title),
+            &subtitle,
+            &columns,
+            summary_rows(&msg.meters, days, &bands),
+        );
+
+        let daily: Vec<String> = [
+            "Day", "Coverage", "Avg ppm", "P95 ppm", "Max ppm", "Peak at",
+        ]
+        .iter()
+        .map(|c| c.to_string())
+        .chain(above("(min)"))
+        .collect();
+        msg.meters.iter().for_each(|m| {
+            let heading = format!("{} - {}", m.name(), title);
+            let (w, h) = (PAGE_WIDTH, PAGE_HEIGHT - PAGE_TOP as u32);
+            self.meter_charts(m, msg, w, h)
+                .into_iter()
+                .for_each(|(_, chart)| {
+                    let mut page = heading_page(&heading, &subtitle);
+                    page.place(chart, 0.0, PAGE_TOP);
+                    pages.push(page);
+                });
+            pages.extend(table_pages(
+                &heading,
+                &subtitle,
+                &daily,
+                self.daily_rows(m, msg.days, &bands),
+            ));
+        });
+        pages
+    }
+}
+
+impl Handler<RenderReport> for RenderActor {
+    type Result = Result<Vec<u8>, ()>;
+
+    fn handle(&mut self, msg: RenderReport, _: &mut SyncContext<Self>) -> Result<Vec<u8>, ()> {
+        crate::pdf::write(&self.report_pages(&msg))
+    }
+}
+
+impl Handler<RenderReportFiles> for RenderActor {
+    type Result = Result<Vec<(String, Vec<u8>)>, ()>;
+
+    fn handle(
+        &mut self,
+        msg: RenderReportFiles,
+        _: &mut SyncContext<Self>,
+    ) -> Result<Vec<(String, Vec<u8>)>, ()> {
+        let msg = msg.0;
+        let mut files = vec![
+            (
+                "report.pdf".to_string(),
+                crate::pdf::write(&self.report_pages(&msg))?,
+            ),
+            ("days.csv".to_string(), self.daily_csv(&msg).into_bytes()),
+        ];
+        for m in msg.meters.iter() {
+            for (name, chart) in self.meter_charts(m, &msg, CHART_WIDTH, CHART_HEIGHT) {
+                files.push((
+                    format!("{}-{}.png", m.src.replace(':', ""), name),
+                    chart.encode(Format::Png)?,
+                ));
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Which reports are made on a schedule, and where they go.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub dir: PathBuf,
+    pub periods: Vec<Period>,
+    /// Reports on periods that began longer ago than this are removed. Zero
+    /// keeps them forever.
+    pub retain_days: u64,
+    /// Each new report is posted here.
+    pub webhook: Option<String>,
+}
+
+// Names sort by period, then in the order they cover.
+fn archive_name(period: Period, first: Date) -> String {
+    format!(
+        "{}{}-{}",
+        ARCHIVE_PREFIX,
+        period.name(),
+        first.format("%Y%m%d")
+    )
+}
+
+/// Remove reports on periods that began more than days before today.
+/// Returns how many were removed.
+fn expire(dir: &Path, today: Date, days: u64) -> Result<usize, ()> {
+    if days == 0 {
+        return Ok(0);
+    }
+    let old: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| {
+            error!("Unable to read report dir {:?} -> {:?}", dir, e);
+            ()
+        })?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix(ARCHIVE_PREFIX))
+                .and_then(|n| n.rsplit('-').next())
+                .and_then(|d| Date::parse(d, "%Y%m%d").ok())
+                .map(|first| (today - first).whole_days() > days as i64)
+                .unwrap_or(false)
+        })
+        .collect();
+
+    old.iter().try_for_each(|path| {
+        info!("Removing old report {:?}", path);
+        fs::remove_dir_all(path).map_err(|e| {
+            error!("Unable to remove old report {:?} -> {:?}", path, e);
+            ()
+        })
+    })?;
+    Ok(old.len())
+}
+
+// The files as a multipart/form-data body, each a part named file.
+fn multipart(boundary: &str, name: &str, files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"report\"\r\n\r\n{}\r\n",
+        boundary, name
+    )
+    .into_bytes();
+    files.iter().for_each(|(file, data)| {
+        let content_type = match file.rsplit('.').next() {
+            Some("pdf") => Format::Pdf.content_type(),
+            Some("png") => Format::Png.content_type(),
+            _ => "text/csv",
+        };
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                boundary, file, content_type
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    });
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+async fn post(url: &str, name: &str, files: &[(String, Vec<u8>)]) -> Result<(), ()> {
+    let boundary = format!(
+        "micd-{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+    );
+    let resp = Client::build()
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT))
+        .finish()
+        .post(url)
+        .content_type(format!("multipart/form-data; boundary={}", boundary))
+        .send_body(multipart(&boundary, name, files))
+        .await
+        .map_err(|e| error!("report webhook request error -> {:?}", e))?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        error!("report webhook error -> {}", resp.status());
+        Err(())
+    }
+}
+
+/// Make any scheduled report that isn't in the archive yet, post it to the
+/// webhook, and remove old ones. Returns how many were made.
+pub async fn archive(
+    db_addr: Addr<db::DbActor>,
+    render_addr: Addr<RenderActor>,
+    tz: SiteTz,
+    schedule: Schedule,
+    now: OffsetDateTime,
+) -> Result<usize, ()> {
+    fs::create_dir_all(&schedule.dir).map_err(|e| {
+        error!("Unable to create report dir {:?} -> {:?}", schedule.dir, e);
+        ()
+    })?;
+
+    let to = now - ARCHIVE_DELAY;
+    let mut made = 0;
+    let mut extracted = false;
+    for period in schedule.periods.iter() {
+        let (first, _) = period.days(&tz, to);
+        let name = archive_name(*period, first);
+        let path = schedule.dir.join(&name);
+        if path.exists() {
+            continue;
+        }
+
+        // A report isn't made again once it's archived, so its days must
+        // be rolled up and reported on first. Otherwise try again later.
+        if !extracted {
+            match db_addr.send(db::DbExtractReports { now: to }).await {
+                Ok(Ok(_)) => extracted = true,
+                _ => {
+                    error!("db unable to bring reports up to date!");
+                    return Err(());
+                }
+            }
+        }
+
+        info!("Making report {} ...", name);
+        let report = fetch(&db_addr, tz, None, *period, to).await?;
+        let files = match render_addr.send(RenderReportFiles(report)).await {
+            Ok(Ok(files)) => files,
+            _ => {
+                error!("report unable to complete!");
+                return Err(());
+            }
+        };
+
+        // Written aside first, so a report is only there once it's whole.
+        let partial = schedule.dir.join(format!(".{}", name));
+        let _ = fs::remove_dir_all(&partial);
+        fs::create_dir(&partial)
+            .and_then(|_| {
+                files
+                    .iter()
+                    .try_for_each(|(file, data)| fs::write(partial.join(file), data))
+            })
+            .and_then(|_| fs::rename(&partial, &path))
+            .map_err(|e| {
+                error!("Unable to write report {:?} -> {:?}", path, e);
+                ()
+            })?;
+        made += 1;
+
+        // Not retried, the report is in the archive for anyone who missed it.
+        if let Some(url) = schedule.webhook.as_ref() {
+            if post(url, &name, &files).await.is_err() {
+                error!("Unable to post report {} to the webhook", name);
+            }
+        }
+    }
+
+    expire(&schedule.dir, tz.date_of(now), schedule.retain_days)?;
+    Ok(made)
+}
+
+fn heading_page(heading: &str, subtitle: &str) -> Canvas {
+    let mut page = Canvas::new(PAGE_WIDTH, PAGE_HEIGHT);
+    page.text(Text {
+        x: MARGIN,
+        y: MARGIN + TITLE_SIZE,
+        text: heading.to_string(),
+        size: TITLE_SIZE,
+        ..Default::default()
+    });
+    page.text(Text {
+        x: MARGIN,
+        y: MARGIN + TITLE_SIZE + ROW,
+        text: subtitle.to_string(),
+        size: TEXT_SIZE,
+        colour: "#555555".to_string(),
+        ..Default::default()
+    });
+    page
+}
+
+// Rows of a table under a heading, on as many pages as it takes. The first
+// column is left aligned and wider, the rest are right aligned.
+fn table_pages(
+    heading: &str,
+    subtitle: &str,
+    columns: &[String],
+    rows: Vec<Vec<String>>,
+) -> Vec<Canvas> {
+    let width = PAGE_WIDTH as f32 - 2.0 * MARGIN;
+    let column = (width - FIRST_COLUMN) / (columns.len().max(2) - 1) as f32;
+    // The right edge of each column, or the left of the first.
+    let x = |i: usize| {
+        if i == 0 {
+            MARGIN
+        } else {
+            MARGIN + FIRST_COLUMN + i as f32 * column
+        }
+    };
+    let cell = |page: &mut Canvas, i: usize, y: f32, text: &str| {
+        page.text(Text {
+            x: x(i),
+            y,
+            text: text.to_string(),
+            size: TEXT_SIZE,
+            anchor: if i == 0 { Anchor::Start } else { Anchor::End },
+            centred: true,
+            ..Default::default()
+        })
+    };
+
+    let per_page = ((PAGE_HEIGHT as f32 - PAGE_TOP - MARGIN) / ROW) as usize - 1;
+    let mut chunks: Vec<&[Vec<String>]> = rows.chunks(per_page).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    chunks
+        .into_iter()
+        .map(|chunk| {
+            let mut page = heading_page(heading, subtitle);
+            let top = PAGE_TOP + ROW / 2.0;
+            columns
+                .iter()
+                .enumerate()
+                .for_each(|(i, c)| cell(&mut page, i, top, c));
+            page.line(
+                vec![(MARGIN, PAGE_TOP + ROW), (MARGIN + width, PAGE_TOP + ROW)],
+                "#000000",
+                1.0,
+            );
+            chunk.iter().enumerate().for_each(|(r, row)| {
+                let y = PAGE_TOP + (r + 1) as f32 * ROW;
+                if r % 2 == 1 {
+                    page.fill(MARGIN, y, width, ROW, ROW_SHADE, 1.0);
+                }
+                row.iter()
+                    .enumerate()
+                    .for_each(|(i, c)| cell(&mut page, i, y + ROW / 2.0, c));
+            });
+            page
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::canvas::Shape;
+    use crate::db::{report_day, DbEvent, TFMT};
+    use crate::render::{parse_comfort_bands, RenderActor, RenderMeter, Resolution};
+    use crate::report::{archive_name, expire, multipart, Period, RenderReport};
+    use crate::tz::SiteTz;
+    use std::fs;
+    use time::{Date, OffsetDateTime};
+
+    fn ts(s: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(s, TFMT).expect("invalid ts")
+    }
+
+    // A week with two hours of readings on the tuesday, half an hour of
+    // them above 1000 ppm.
+    fn report(label: &str) -> (RenderActor, RenderReport) {
+        let actor = RenderActor {
+            tz: "Australia/Brisbane".parse().unwrap(),
+            comfort: parse_comfort_bands("good:800,moderate:1200,poor").unwrap(),
+        };
+        let src = "01:00:00:00:00:00";
+        let data: Vec<DbEvent> = (0..120)
+            .map(|i| DbEvent {
+                src: src.to_string(),
+                time: ts("2020-04-07 09:00:00+1000") + time::Duration::minutes(i),
+                temp: 220,
+                ppm: if i < 30 { 1100 } else { 700 },
+                hum: 500,
+            })
+            .collect();
+        let history = vec![report_day(
+            src,
+            &ts("2020-04-07 00:00:00+1000"),
+            &ts("2020-04-08 00:00:00+1000"),
+            &data,
+            &[1000],
+        )];
+        let (days, span) = {
+            let tz = &actor.tz;
+            let (first, last) = Period::Week.days(tz, ts("2020-04-15 12:00:00+1000"));
+            (
+                (first, last),
+                (tz.day_start(first), tz.day_start(last.next_day())),
+            )
+        };
+        let msg = RenderReport {
+            period: Period::Week,
+            days,
+            span,
+            resolution: Resolution::Raw,
+            meters: vec![RenderMeter {
+                src: src.to_string(),
+                label: Some(label.to_string()),
+                data,
+                history,
+                hourly: Vec::new(),
+            }],
+        };
+        (actor, msg)
+    }
+
+    #[test]
+    fn test_report_period() {
+        let tz: SiteTz = "Australia/Brisbane".parse().unwrap();
+        assert!("fortnight".parse::<Period>().is_err());
+
+        let days = |p: Period, to| {
+            let (first, last) = p.days(&tz, ts(to));
+            (first.format("%F"), last.format("%F"))
+        };
+        let week = |a: &str, b: &str| (a.to_string(), b.to_string());
+        assert!(days(Period::Day, "2020-04-15 00:30:00+1000") == week("2020-04-14", "2020-04-14"));
+        // A whole week before, even from the monday after.
+        assert!(days(Period::Week, "2020-04-15 12:00:00+1000") == week("2020-04-06", "2020-04-12"));
+        assert!(days(Period::Week, "2020-04-13 00:00:00+1000") == week("2020-04-06", "2020-04-12"));
+        assert!(days(Period::Week, "2020-04-12 23:00:00+1000") == week("2020-03-30", "2020-04-05"));
+        // Early on the first in utc is already the first in Brisbane.
+        assert!(
+            days(Period::Month, "2020-04-30 15:00:00+0000") == week("2020-04-01", "2020-04-30")
+        );
+        assert!(
+            days(Period::Month, "2020-01-05 12:00:00+1000") == week("2019-12-01", "2019-12-31")
+        );
+    }
+
+    #[test]
+    fn test_report_pages() {
+        let (actor, msg) = report("Meeting room");
+        let pages = actor.report_pages(&msg);
+        let texts = |i: usize| -> Vec<String> {
+            pages[i]
+                .shapes
+                .iter()
+                .filter_map(|s| match s {
+                    Shape::Text(t) => Some(t.text.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        // A summary, the chart and the days. Without hourly rollups there
+        // is no heatmap.
+        assert!(pages.len() == 3);
+        assert!(texts(0)[0] == "CO2 report - Week of Mon 06 Apr 2020");
+        let summary = texts(0);
+        let row = &summary[summary.iter().position(|t| t == "Meeting room").unwrap()..];
+        assert!(
+            row[..9]
+                == [
+                    "Meeting room",
+                    "1 / 7",
+                    "1%",
+                    "800",
+                    "1100",
+                    "1100",
+                    "22.0",
+                    "50.0",
+                    "0.5"
+                ]
+        );
+        assert!(texts(1)[0] == "Meeting room - Week of Mon 06 Apr 2020");
+
+        let daily = texts(2);
+        assert!(daily.iter().filter(|t| *t == "no readings").count() == 6);
+        let tue = &daily[daily.iter().position(|t| t == "Tue 07 Apr").unwrap()..];
+        assert!(tue[..7] == ["Tue 07 Apr", "8%", "800", "1100", "1100", "09:00", "30"]);
+
+        let pdf = crate::pdf::write(&pages).unwrap();
+        assert!(String::from_utf8_lossy(&pdf).contains("/Count 3"));
+    }
+
+    #[test]
+    fn test_report_archive() {
+        let (actor, msg) = report("Room 1, \"east\"");
+        let csv = actor.daily_csv(&msg);
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        // A header, each day and the end of the last line.
+        assert!(lines.len() == 9);
+        assert!(lines[0] == "mac,label,date,readings,coverage,ppm_avg,ppm_p95,ppm_max,ppm_peak,temp_avg,hum_avg,minutes_above_1000");
+        assert!(lines[1] == r#"01:00:00:00:00:00,"Room 1, ""east""",2020-04-06,,,,,,,,,"#);
+        assert!(
+            lines[2]
+                == r#"01:00:00:00:00:00,"Room 1, ""east""",2020-04-07,120,8,800,1100,1100,09:00,22.0,50.0,30"#
+        );
+
+        let charts = actor.meter_charts(&msg.meters[0], &msg, 1400, 800);
+        assert!(charts.iter().map(|(n, _)| *n).collect::<Vec<_>>() == ["ppm"]);
+
+        let first = |s| Date::parse(s, "%F").unwrap();
+        assert!(archive_name(Period::Week, first("2020-04-06")) == "micd-report-week-20200406");
+
+        let dir = std::env::temp_dir().join(format!("micd_reports_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        ["2020-03-01", "2020-04-01", "2020-04-06"]
+            .iter()
+            .for_each(|d| {
+                fs::create_dir_all(dir.join(archive_name(Period::Day, first(d)))).unwrap();
+            });
+        fs::create_dir_all(dir.join("unrelated-20200101")).unwrap();
+        assert!(expire(&dir, first("2020-04-10"), 0) == Ok(0));
+        assert!(expire(&dir, first("2020-04-10"), 30) == Ok(1));
+        assert!(!dir.join("micd-report-day-20200301").exists());
+        assert!(dir.join("micd-report-day-20200401").exists());
+        assert!(dir.join("unrelated-20200101").exists());
+        let _ = fs::remove_dir_all(&dir);
+
+        let files = vec![
+            ("days.csv".to_string(), b"a,b".to_vec()),
+            ("report.pdf".to_string(), b"%PDF".to_vec()),
+        ];
+        let body = String::from_utf8(multipart("xyz", "micd-report-day-20200406", &files)).unwrap();
+        assert!(body.starts_with("--xyz\r\nContent-Disposition: form-data; name=\"report\"\r\n\r\nmicd-report-day-20200406\r\n"));
+        assert!(body.contains("name=\"file\"; filename=\"days.csv\"\r\nContent-Type: text/csv\r\n\r\na,b\r\n--xyz\r\n"));
+        assert!(body.contains("Content-Type: application/pdf\r\n\r\n%PDF\r\n--xyz--\r\n"));
+    }
+}
@@ -0,0 +1,825 @@
+//! An append-only store for raw readings. Each meter has a directory with a
+//! segment file per utc day, and each segment is a sequence of blocks. A
+//! block holds up to BLOCK_READINGS readings with every field delta and
+//! varint encoded, then deflated, which is usually a byte or two per reading.
+//! The first and last time of each block are kept in memory, so a range query
+//! only reads the blocks it needs.
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use time::{Date, OffsetDateTime};
+
+const SEGMENT_MAGIC: &[u8; 8] = b"MICSEG02";
+const SEGMENT_SUFFIX: &str = ".seg";
+// deflated payload len, count, first ms, last ms, checksum
+const BLOCK_HEADER_LEN: usize = 28;
+/// How many readings are buffered before they are written as a block.
+pub const BLOCK_READINGS: usize = 512;
+const DAY_MS: i64 = 86_400_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub time: OffsetDateTime,
+    pub seq: i64,
+    pub temp: u16,
+    pub ppm: u16,
+    pub hum: u16,
+}
+
+impl Reading {
+    fn fields(&self) -> [i64; 5] {
+        [
+            to_ms(&self.time),
+            self.seq,
+            self.temp as i64,
+            self.ppm as i64,
+            self.hum as i64,
+        ]
+    }
+}
+
+fn to_ms(t: &OffsetDateTime) -> i64 {
+    t.timestamp() * 1000 + t.millisecond() as i64
+}
+
+fn from_ms(ms: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(ms.div_euclid(1000))
+        + time::Duration::milliseconds(ms.rem_euclid(1000))
+}
+
+fn day_of(ms: i64) -> i64 {
+    ms.div_euclid(DAY_MS)
+}
+
+fn segment_name(day: i64) -> String {
+    format!(
+        "{}{}",
+        OffsetDateTime::from_unix_timestamp(day * 86400).format("%Y%m%d"),
+        SEGMENT_SUFFIX
+    )
+}
+
+fn segment_day(name: &str) -> Option<i64> {
+    let date = name.strip_suffix(SEGMENT_SUFFIX)?;
+    Date::parse(date, "%Y%m%d")
+        .ok()
+        .map(|d| d.midnight().assume_utc().timestamp() / 86400)
+}
+
+// Macs have colons, which some filesystems don't allow.
+fn series_name(src: &str) -> String {
+    src.replace(':', "-")
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut v: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let b = *buf.get(*pos)?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+        shift += 7;
+        if shift > 63 {
+            return None;
+        }
+    }
+}
+
+// fnv-1a, to notice blocks that were damaged after they were written.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Where a block is within its segment, and the times it covers.
+#[derive(Debug, Clone)]
+struct BlockIndex {
+    offset: u64,
+    len: u32,
+    first: i64,
+    last: i64,
+}
+
+fn encode_block(readings: &[Reading]) -> (Vec<u8>, i64, i64) {
+    let first = readings.iter().map(|r| to_ms(&r.time)).min().unwrap_or(0);
+    let last = readings.iter().map(|r| to_ms(&r.time)).max().unwrap_or(0);
+
+    let mut deltas = Vec::with_capacity(readings.len() * 8);
+    let mut prev = [first, 0, 0, 0, 0];
+    readings.iter().for_each(|r| {
+        let fields = r.fields();
+        fields
+            .iter()
+            .zip(prev.iter())
+            .for_each(|(f, p)| put_varint(&mut deltas, zigzag(f - p)));
+        prev = fields;
+    });
+
+    // Readings repeat a lot from one to the next, so the deltas deflate well.
+    // Writing to a vec can't fail.
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    let payload = encoder
+        .write_all(&deltas)
+        .and_then(|_| encoder.finish())
+        .expect("deflate into a vec failed");
+
+    let mut block = Vec::with_capacity(BLOCK_HEADER_LEN + payload.len());
+    block.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    block.extend_from_slice(&(readings.len() as u32).to_le_bytes());
+    block.extend_from_slice(&first.to_le_bytes());
+    block.extend_from_slice(&last.to_le_bytes());
+    block.extend_from_slice(&checksum(&payload).to_le_bytes());
+    block.extend_from_slice(&payload);
+    (block, first, last)
+}
+
+fn decode_header(header: &[u8]) -> (u32, u32, i64, i64, u32) {
+    let u32_at = |i: usize| {
+        let mut b = [0; 4];
+        b.copy_from_slice(&header[i..i + 4]);
+        u32::from_le_bytes(b)
+    };
+    let i64_at = |i: usize| {
+        let mut b = [0; 8];
+        b.copy_from_slice(&header[i..i + 8]);
+        i64::from_le_bytes(b)
+    };
+    (u32_at(0), u32_at(4), i64_at(8), i64_at(16), u32_at(24))
+}
+
+fn decode_block(block: &[u8]) -> Result<Vec<Reading>, ()> {
+    if block.len() < BLOCK_HEADER_LEN {
+        error!("segment block is truncated");
+        return Err(());
+    }
+    let (len, count, first, _last, sum) = decode_header(&block[..BLOCK_HEADER_LEN]);
+    let payload = &block[BLOCK_HEADER_LEN..];
+    if payload.len() != len as usize || checksum(payload) != sum {
+        error!("segment block failed its checksum");
+        return Err(());
+    }
+
+    let mut deltas = Vec::new();
+    DeflateDecoder::new(payload)
+        .read_to_end(&mut deltas)
+        .map_err(|e| {
+            error!("segment block failed to inflate -> {:?}", e);
+        })?;
+
+    let mut pos = 0;
+    let mut prev = [first, 0, 0, 0, 0];
+    (0..count)
+        .map(|_| {
+            let mut fields = [0; 5];
+            for (f, p) in fields.iter_mut().zip(prev.iter()) {
+                let delta = get_varint(&deltas, &mut pos).ok_or_else(|| {
+                    error!("segment block is corrupt");
+                })?;
+                *f = p + unzigzag(delta);
+            }
+            prev = fields;
+            Ok(Reading {
+                time: from_ms(fields[0]),
+                seq: fields[1],
+                temp: fields[2] as u16,
+                ppm: fields[3] as u16,
+                hum: fields[4] as u16,
+            })
+        })
+        .collect()
+}
+
+/// Find the blocks in a segment. A block that was only partly written, say
+/// from a crash, is cut off so that appends carry on after the last good one.
+fn scan_segment(path: &Path) -> Result<Vec<BlockIndex>, ()> {
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| {
+            error!("Unable to open segment {:?} -> {:?}", path, e);
+            ()
+        })?;
+    let size = f
+        .metadata()
+        .map_err(|e| {
+            error!("Unable to stat segment {:?} -> {:?}", path, e);
+            ()
+        })?
+        .len();
+
+    // We crashed before even the magic was written.
+    if size < SEGMENT_MAGIC.len() as u64 {
+        warn!("Truncating empty segment {:?}", path);
+        return f.set_len(0).map(|_| Vec::new()).map_err(|e| {
+            error!("Unable to truncate segment {:?} -> {:?}", path, e);
+            ()
+        });
+    }
+
+    let mut magic = [0; 8];
+    if f.read_exact(&mut magic).is_err() || &magic != SEGMENT_MAGIC {
+        error!("{:?} is not a segment", path);
+        return Err(());
+    }
+
+    let mut blocks = Vec::new();
+    let mut offset = SEGMENT_MAGIC.len() as u64;
+    let mut header = [0; BLOCK_HEADER_LEN];
+    while offset < size {
+        let complete = f.read_exact(&mut header).is_ok() && {
+            let (len, _, _, _, _) = decode_header(&header);
+            offset + (BLOCK_HEADER_LEN as u64) + len as u64 <= size
+        };
+        if !complete {
+            warn!(
+                "Truncating partly written block in {:?} at {}",
+                path, offset
+            );
+            f.set_len(offset).map_err(|e| {
+                error!("Unable to truncate segment {:?} -> {:?}", path, e);
+                ()
+            })?;
+            break;
+        }
+
+        let (len, _, first, last, _) = decode_header(&header);
+        let block_len = BLOCK_HEADER_LEN as u32 + len;
+        blocks.push(BlockIndex {
+            offset,
+            len: block_len,
+            first,
+            last,
+        });
+        offset += block_len as u64;
+        f.seek(SeekFrom::Start(offset)).map_err(|e| {
+            error!("Unable to seek segment {:?} -> {:?}", path, e);
+            ()
+        })?;
+    }
+    Ok(blocks)
+}
+
+fn read_blocks(path: &Path, blocks: &[&BlockIndex]) -> Result<Vec<Reading>, ()> {
+    let mut f = File::open(path).map_err(|e| {
+        error!("Unable to open segment {:?} -> {:?}", path, e);
+        ()
+    })?;
+
+    let mut readings = Vec::new();
+    blocks.iter().try_for_each(|b| {
+        let mut buf = vec![0; b.len as usize];
+        f.seek(SeekFrom::Start(b.offset))
+            .and_then(|_| f.read_exact(&mut buf))
+            .map_err(|e| {
+                error!("Unable to read segment {:?} -> {:?}", path, e);
+                ()
+            })?;
+        readings.extend(decode_block(&buf)?);
+        Ok(())
+    })?;
+    Ok(readings)
+}
+
+// Write a whole segment, replacing what was there.
+fn write_segment(path: &Path, readings: &[Reading]) -> Result<Vec<BlockIndex>, ()> {
+    if readings.is_empty() {
+        return fs::remove_file(path).map(|_| Vec::new()).map_err(|e| {
+            error!("Unable to remove segment {:?} -> {:?}", path, e);
+            ()
+        });
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut data = SEGMENT_MAGIC.to_vec();
+    let blocks = readings
+        .chunks(BLOCK_READINGS)
+        .map(|chunk| {
+            let (block, first, last) = encode_block(chunk);
+            let b = BlockIndex {
+                offset: data.len() as u64,
+                len: block.len() as u32,
+                first,
+                last,
+            };
+            data.extend_from_slice(&block);
+            b
+        })
+        .collect();
+
+    File::create(&tmp_path)
+        .and_then(|mut f| f.write_all(&data).and_then(|_| f.sync_data()))
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| {
+            error!("Unable to write segment {:?} -> {:?}", path, e);
+            ()
+        })?;
+    Ok(blocks)
+}
+
+struct Series {
+    dir: PathBuf,
+    // The blocks of each day's segment, by day since the epoch.
+    segments: BTreeMap<i64, Vec<BlockIndex>>,
+    // Readings that aren't in a block yet, all from the same day.
+    pending: Vec<Reading>,
+}
+
+impl Series {
+    fn open(dir: PathBuf) -> Result<Self, ()> {
+        let mut segments = BTreeMap::new();
+        fs::read_dir(&dir)
+            .map_err(|e| {
+                error!("Unable to read series {:?} -> {:?}", dir, e);
+                ()
+            })?
+            .filter_map(|entry| entry.ok())
+            .try_for_each(|entry| {
+                let name = entry.file_name();
+                match name.to_str().and_then(segment_day) {
+                    Some(day) => {
+                        segments.insert(day, scan_segment(&entry.path())?);
+                        Ok(())
+                    }
+                    None => Ok(()),
+                }
+            })?;
+        Ok(Series {
+            dir,
+            segments,
+            pending: Vec::new(),
+        })
+    }
+
+    fn segment_path(&self, day: i64) -> PathBuf {
+        self.dir.join(segment_name(day))
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        let day = match self.pending.first() {
+            Some(r) => day_of(to_ms(&r.time)),
+            None => return Ok(()),
+        };
+        let path = self.segment_path(day);
+
+        fs::create_dir_all(&self.dir).map_err(|e| {
+            error!("Unable to create series {:?} -> {:?}", self.dir, e);
+            ()
+        })?;
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| {
+                error!("Unable to open segment {:?} -> {:?}", path, e);
+                ()
+            })?;
+
+        let (block, first, last) = encode_block(&self.pending);
+        // Appends always go to the end, which is where this block starts.
+        let mut offset = f.metadata().map(|m| m.len()).map_err(|e| {
+            error!("Unable to stat segment {:?} -> {:?}", path, e);
+            ()
+        })?;
+        if offset == 0 {
+            f.write_all(SEGMENT_MAGIC).map_err(|e| {
+                error!("Unable to write segment {:?} -> {:?}", path, e);
+                ()
+            })?;
+            offset = SEGMENT_MAGIC.len() as u64;
+        }
+
+        f.write_all(&block)
+            .and_then(|_| f.sync_data())
+            .map_err(|e| {
+                error!("Unable to write segment {:?} -> {:?}", path, e);
+                ()
+            })?;
+
+        self.segments.entry(day).or_default().push(BlockIndex {
+            offset,
+            len: block.len() as u32,
+            first,
+            last,
+        });
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn read_day(&self, day: i64, min: i64, max: i64) -> Result<Vec<Reading>, ()> {
+        let blocks: Vec<&BlockIndex> = match self.segments.get(&day) {
+            Some(blocks) => blocks
+                .iter()
+                .filter(|b| b.last >= min && b.first < max)
+                .collect(),
+            None => return Ok(Vec::new()),
+        };
+        if blocks.is_empty() {
+            return Ok(Vec::new());
+        }
+        read_blocks(&self.segment_path(day), &blocks)
+    }
+
+    fn range(&self, min: i64, max: i64) -> Result<Vec<Reading>, ()> {
+        let mut readings = Vec::new();
+        if min >= max {
+            return Ok(readings);
+        }
+
+        self.segments
+            .range(day_of(min)..=day_of(max - 1))
+            .try_for_each(|(day, _)| {
+                readings.extend(self.read_day(*day, min, max)?);
+                Ok(())
+            })?;
+        readings.extend(self.pending.iter().cloned());
+
+        readings.retain(|r| {
+            let t = to_ms(&r.time);
+            t >= min && t < max
+        });
+        readings.sort_by_key(|r| to_ms(&r.time));
+        Ok(readings)
+    }
+
+    fn purge_older_than(&mut self, max: i64) -> Result<(), ()> {
+        let max_day = day_of(max);
+
+        let old: Vec<i64> = self.segments.range(..max_day).map(|(d, _)| *d).collect();
+        old.into_iter().try_for_each(|day| {
+            let path = self.segment_path(day);
+            self.segments.remove(&day);
+            fs::remove_file(&path).map_err(|e| {
+                error!("Unable to remove segment {:?} -> {:?}", path, e);
+                ()
+            })
+        })?;
+
+        // The day that max falls in is rewritten with only what's left.
+        let partial = self
+            .segments
+            .get(&max_day)
+            .map(|blocks| blocks.iter().any(|b| b.first < max))
+            .unwrap_or(false);
+        if partial {
+            let keep = self.read_day(max_day, max, i64::MAX)?;
+            let keep: Vec<Reading> = keep.into_iter().filter(|r| to_ms(&r.time) >= max).collect();
+            let blocks = write_segment(&self.segment_path(max_day), &keep)?;
+            if blocks.is_empty() {
+                self.segments.remove(&max_day);
+            } else {
+                self.segments.insert(max_day, blocks);
+            }
+        }
+
+        self.pending.retain(|r| to_ms(&r.time) >= max);
+        Ok(())
+    }
+}
+
+/// Raw readings for every meter, kept in dir.
+pub struct SegmentStore {
+    dir: PathBuf,
+    series: BTreeMap<String, Series>,
+}
+
+impl SegmentStore {
+    pub fn open(dir: &Path) -> Result<Self, ()> {
+        fs::create_dir_all(dir).map_err(|e| {
+            error!("Unable to create segment store {:?} -> {:?}", dir, e);
+            ()
+        })?;
+
+        let mut series = BTreeMap::new();
+        fs::read_dir(dir)
+            .map_err(|e| {
+                error!("Unable to read segment store {:?} -> {:?}", dir, e);
+                ()
+            })?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .try_for_each(|entry| {
+                let src = entry.file_name().to_string_lossy().replace('-', ":");
+                series.insert(src, Series::open(entry.path())?);
+                Ok(())
+            })?;
+
+        Ok(SegmentStore {
+            dir: dir.to_path_buf(),
+            series,
+        })
+    }
+
+    pub fn list_meters(&self) -> Vec<String> {
+        self.series.keys().cloned().collect()
+    }
+
+    /// Add a reading. It's only written to disk once a block fills, the
+    /// day changes, or flush is called.
+    pub fn append(&mut self, src: &str, reading: Reading) -> Result<(), ()> {
+        if !self.series.contains_key(src) {
+            let dir = self.dir.join(series_name(src));
+            self.series.insert(
+                src.to_string(),
+                Series {
+                    dir,
+                    segments: BTreeMap::new(),
+                    pending: Vec::new(),
+                },
+            );
+        }
+        let series = self.series.get_mut(src).unwrap();
+
+        let day = day_of(to_ms(&reading.time));
+        if let Some(r) = series.pending.first() {
+            if day_of(to_ms(&r.time)) != day {
+                series.flush()?;
+            }
+        }
+        series.pending.push(reading);
+        if series.pending.len() >= BLOCK_READINGS {
+            series.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write out every partial block.
+    pub fn flush(&mut self) -> Result<(), ()> {
+        self.series.values_mut().try_for_each(|s| s.flush())
+    }
+
+    /// Readings from src at or after min and before max, oldest first.
+    pub fn get_event_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<Reading>, ()> {
+        match self.series.get(src) {
+            Some(series) => series.range(to_ms(min), to_ms(max)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // The first reading at or after min, if any.
+    pub fn get_next_event_time(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>, ()> {
+        let series = match self.series.get(src) {
+            Some(series) => series,
+            None => return Ok(None),
+        };
+        let min = to_ms(min);
+
+        let mut next = series
+            .pending
+            .iter()
+            .map(|r| to_ms(&r.time))
+            .filter(|t| *t >= min)
+            .min();
+        // Days are in order, so the first day with anything after min has the answer.
+        for (day, blocks) in series.segments.range(day_of(min)..) {
+            if blocks.iter().any(|b| b.last >= min) {
+                let found = series
+                    .read_day(*day, min, i64::MAX)?
+                    .iter()
+                    .map(|r| to_ms(&r.time))
+                    .filter(|t| *t >= min)
+                    .min();
+                next = match (next, found) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                break;
+            }
+        }
+        Ok(next.map(from_ms))
+    }
+
+    pub fn get_last_event_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        Ok(self.series.get(src).and_then(|series| {
+            series
+                .segments
+                .values()
+                .flat_map(|blocks| blocks.iter().map(|b| b.last))
+                .chain(series.pending.iter().map(|r| to_ms(&r.time)))
+                .max()
+                .map(from_ms)
+        }))
+    }
+
+    /// Remove readings from every meter before max.
+    pub fn purge_older_than(&mut self, max: &OffsetDateTime) -> Result<(), ()> {
+        let max = to_ms(max);
+        self.series
+            .values_mut()
+            .try_for_each(|s| s.purge_older_than(max))
+    }
+
+    /// How many bytes the segments take on disk.
+    pub fn size_on_disk(&self) -> u64 {
+        self.series
+            .values()
+            .flat_map(|s| s.segments.values())
+            .flat_map(|blocks| blocks.last())
+            .map(|b| b.offset + b.len as u64)
+            .sum()
+    }
+}
+
+impl Drop for SegmentStore {
+    fn drop(&mut self) {
+        if self.flush().is_err() {
+            error!("Unable to flush segments on close, some readings are lost");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::segment::{
+        decode_block, encode_block, get_varint, put_varint, unzigzag, zigzag, Reading,
+        SegmentStore, BLOCK_READINGS,
+    };
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use time::OffsetDateTime;
+
+    const SRC: &str = "20:F8:5E:BE:29:D8";
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("micseg_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn ts(s: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(s, "%F %H:%M:%S%z").expect("invalid ts")
+    }
+
+    // A reading every 30 seconds from start.
+    fn readings(start: &str, n: i64) -> Vec<Reading> {
+        let start = ts(start);
+        (0..n)
+            .map(|i| Reading {
+                time: start + time::Duration::milliseconds(i * 30_000 + i % 7),
+                seq: i,
+                temp: 220 + (i % 5) as u16,
+                ppm: 400 + (i % 300) as u16,
+                hum: 500 - (i % 11) as u16,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_segment_encoding() {
+        let mut buf = Vec::new();
+        let values = [0, 1, -1, 63, -64, 1 << 40, i64::MIN, i64::MAX];
+        values.iter().for_each(|v| put_varint(&mut buf, zigzag(*v)));
+        let mut pos = 0;
+        values.iter().for_each(|v| {
+            assert!(unzigzag(get_varint(&buf, &mut pos).unwrap()) == *v);
+        });
+        assert!(pos == buf.len());
+
+        let data = readings("2020-04-05 13:02:19+0000", 100);
+        let (mut block, first, last) = encode_block(&data);
+        assert!(first == data[0].time.timestamp() * 1000);
+        assert!(last > first);
+        // Well under the 24 bytes of the raw fields.
+        assert!(block.len() < data.len() * 10);
+        assert!(decode_block(&block) == Ok(data));
+
+        // Damage is noticed.
+        let n = block.len();
+        block[n - 1] ^= 0xff;
+        assert!(decode_block(&block).is_err());
+    }
+
+    #[test]
+    fn test_segment_store() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let dir = test_dir("store");
+
+        // Three days, with more than a block in each.
+        let data = readings("2020-04-05 00:00:00+0000", 2880 * 3);
+        {
+            let mut store = SegmentStore::open(&dir).unwrap();
+            data.iter()
+                .for_each(|r| store.append(SRC, r.clone()).unwrap());
+
+            // Unflushed readings are found too.
+            let day = store
+                .get_event_range(
+                    SRC,
+                    &ts("2020-04-07 00:00:00+0000"),
+                    &ts("2020-04-08 00:00:00+0000"),
+                )
+                .unwrap();
+            assert!(day.len() == 2880);
+            assert!(day[..] == data[2880 * 2..]);
+        }
+
+        // Everything comes back after reopening.
+        let mut store = SegmentStore::open(&dir).unwrap();
+        assert!(store.list_meters() == vec![SRC.to_string()]);
+        let range = store
+            .get_event_range(
+                SRC,
+                &ts("2020-04-05 12:00:00+0000"),
+                &ts("2020-04-06 12:00:00+0000"),
+            )
+            .unwrap();
+        assert!(range[..] == data[1440..1440 + 2880]);
+        assert!(store.get_last_event_time(SRC) == Ok(Some(data[data.len() - 1].time)));
+        assert!(
+            store.get_next_event_time(SRC, &ts("2020-04-06 00:00:10+0000"))
+                == Ok(Some(data[2881].time))
+        );
+        assert!(store.get_next_event_time(SRC, &ts("2020-04-09 00:00:00+0000")) == Ok(None));
+        assert!(
+            store.get_event_range("00:00:00:00:00:00", &data[0].time, &data[1].time)
+                == Ok(Vec::new())
+        );
+
+        // Purging part way through a day keeps the rest of that day.
+        store
+            .purge_older_than(&ts("2020-04-06 12:00:00+0000"))
+            .unwrap();
+        assert!(store.get_next_event_time(SRC, &data[0].time) == Ok(Some(data[2880 + 1440].time)));
+        drop(store);
+
+        let store = SegmentStore::open(&dir).unwrap();
+        let all = store
+            .get_event_range(SRC, &data[0].time, &ts("2020-04-09 00:00:00+0000"))
+            .unwrap();
+        assert!(all[..] == data[2880 + 1440..]);
+        assert!(store.size_on_disk() > 0);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_segment_torn_write() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let dir = test_dir("torn");
+
+        let data = readings("2020-04-05 00:00:00+0000", BLOCK_READINGS as i64 + 10);
+        {
+            let mut store = SegmentStore::open(&dir).unwrap();
+            data.iter()
+                .for_each(|r| store.append(SRC, r.clone()).unwrap());
+        }
+
+        // Half of a block header, as if we crashed mid append.
+        let path = dir.join("20-F8-5E-BE-29-D8").join("20200405.seg");
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(&[0x10, 0, 0, 0, 0x20, 0]))
+            .unwrap();
+
+        let mut store = SegmentStore::open(&dir).unwrap();
+        let all = store
+            .get_event_range(SRC, &data[0].time, &ts("2020-04-06 00:00:00+0000"))
+            .unwrap();
+        assert!(all == data);
+
+        // And appends carry on after the last good block.
+        let more = readings("2020-04-05 12:00:00+0000", 1);
+        store.append(SRC, more[0].clone()).unwrap();
+        drop(store);
+        let store = SegmentStore::open(&dir).unwrap();
+        assert!(store.get_last_event_time(SRC) == Ok(Some(more[0].time)));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use time::OffsetDateTime;
+
+use mic::prelude::*;
+use mic::segment::{Reading, SegmentStore};
+
+use crate::db::{DbEvent, DbHistoryEvent, DbHourlyEvent};
+use crate::storage::Storage;
+
+fn to_event(src: &str, r: Reading) -> DbEvent {
+    DbEvent {
+        src: src.to_string(),
+        time: r.time,
+        temp: r.temp,
+        ppm: r.ppm,
+        hum: r.hum,
+    }
+}
+
+/// Raw readings go to a segment store, which takes far less space than
+/// event_t. Meters, rollups and reports are still kept in db.
+pub struct SegmentStorage {
+    segments: Mutex<SegmentStore>,
+    db: Box<dyn Storage>,
+}
+
+impl SegmentStorage {
+    pub fn open(dir: &Path, db: Box<dyn Storage>) -> Result<Self, ()> {
+        Ok(SegmentStorage {
+            segments: Mutex::new(SegmentStore::open(dir)?),
+            db,
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, SegmentStore>, ()> {
+        self.segments.lock().map_err(|e| {
+            error!("segment store lock error -> {:?}", e);
+            ()
+        })
+    }
+
+    // Is there already a reading from src at t?
+    fn exists(segments: &SegmentStore, src: &str, t: &OffsetDateTime) -> Result<bool, ()> {
+        segments
+            .get_event_range(src, t, &(*t + time::Duration::milliseconds(1)))
+            .map(|r| !r.is_empty())
+    }
+}
+
+impl Storage for SegmentStorage {
+    fn add_datum(&self, datum: Datum, ct: OffsetDateTime, seq: i64) -> Result<(), ()> {
+        let src = datum.mac_as_string();
+        let (ppm, hum, temp) = datum.data();
+
+        let mut segments = self.lock()?;
+        if SegmentStorage::exists(&segments, &src, &ct)? {
+            warn!("ignoring duplicate event for {} at {:?}", src, ct);
+            return Ok(());
+        }
+        segments.append(
+            &src,
+            Reading {
+                time: ct,
+                seq,
+                temp,
+                ppm,
+                hum,
+            },
+        )
+    }
+
+    fn list_meters(&self) -> Result<Vec<String>, ()> {
+        let mut meters: BTreeSet<String> = self.db.list_meters()?.into_iter().collect();
+        meters.extend(self.lock()?.list_meters());
+        Ok(meters.into_iter().collect())
+    }
+
+    fn get_meter_labels(&self) -> Result<BTreeMap<String, String>, ()> {
+        self.db.get_meter_labels()
+    }
+
+    fn set_meter_label(&self, src: &str, label: Option<&str>) -> Result<(), ()> {
+        self.db.set_meter_label(src, label)
+    }
+
+    fn get_event_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbEvent>, ()> {
+        self.lock()?
+            .get_event_range(src, min, max)
+            .map(|readings| readings.into_iter().map(|r| to_event(src, r)).collect())
+    }
+
+    fn get_next_event_time(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>, ()> {
+        self.lock()?.get_next_event_time(src, min)
+    }
+
+    fn get_last_event_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        self.lock()?.get_last_event_time(src)
+    }
+
+    fn get_history(&self, src: &str) -> Result<Vec<DbHistoryEvent>, ()> {
+        self.db.get_history(src)
+    }
+
+    fn get_last_history_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        self.db.get_last_history_time(src)
+    }
+
+    fn put_history(&self, history: &DbHistoryEvent) -> Result<(), ()> {
+        self.db.put_history(history)
+    }
+
+    fn purge_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        self.lock()?.purge_older_than(max)
+    }
+
+    fn purge_history_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        self.db.purge_history_older_than(max)
+    }
+
+    fn import_events(&self, src: &str, events: &[DbEvent]) -> Result<usize, ()> {
+        let mut segments = self.lock()?;
+        let first = events.iter().map(|e| e.time).min();
+        let last = events.iter().map(|e| e.time).max();
+        let mut seen: BTreeSet<OffsetDateTime> = match (first, last) {
+            (Some(first), Some(last)) => segments
+                .get_event_range(src, &first, &(last + time::Duration::milliseconds(1)))?
+                .into_iter()
+                .map(|r| r.time)
+                .collect(),
+            _ => return Ok(0),
+        };
+
+        let mut added = 0;
+        events.iter().try_for_each(|e| {
+            if !seen.insert(e.time) {
+                return Ok(());
+            }
+            added += 1;
+            segments.append(
+                src,
+                Reading {
+                    time: e.time,
+                    seq: 0,
+                    temp: e.temp,
+                    ppm: e.ppm,
+                    hum: e.hum,
+                },
+            )
+        })?;
+        // Imports are done in one go, so don't leave them only in memory.
+        segments.flush()?;
+        Ok(added)
+    }
+
+    fn get_latest_sequences(&self) -> Result<BTreeMap<String, i64>, ()> {
+        let segments = self.lock()?;
+        let mut seqs = BTreeMap::new();
+        segments.list_meters().into_iter().try_for_each(|src| {
+            let last = match segments.get_last_event_time(&src)? {
+                Some(last) => last,
+                None => return Ok(()),
+            };
+            let readings =
+                segments.get_event_range(&src, &last, &(last + time::Duration::milliseconds(1)))?;
+            if let Some(r) = readings.last() {
+                seqs.insert(src, r.seq);
+            }
+            Ok(())
+        })?;
+        Ok(seqs)
+    }
+
+    fn get_hourly_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbHourlyEvent>, ()> {
+        self.db.get_hourly_range(src, min, max)
+    }
+
+    fn get_last_hourly_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        self.db.get_last_hourly_time(src)
+    }
+
+    fn put_hourly(&self, hourly: &DbHourlyEvent) -> Result<(), ()> {
+        self.db.put_hourly(hourly)
+    }
+
+    fn purge_hourly_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        self.db.purge_hourly_older_than(max)
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        self.lock()?.flush()
+    }
+
+    fn checkpoint(&self) -> Result<(), ()> {
+        self.flush()?;
+        self.db.checkpoint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::open_sqlite;
+    use crate::segstore::SegmentStorage;
+    use crate::storage::tests::check_storage;
+    use std::fs;
+
+    #[test]
+    fn test_segment_storage() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let dir = std::env::temp_dir().join(format!("micd_segstore_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = SegmentStorage::open(&dir, open_sqlite("").unwrap()).unwrap();
+        check_storage(&store);
+
+        drop(store);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
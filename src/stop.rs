@@ -0,0 +1,113 @@
+use actix::dev::ToEnvelope;
+use actix::prelude::*;
+use std::time::Duration;
+
+// How often we check whether a stopping actor has finished its work.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Ask a background actor to stop. It replies true while it's still part way
+/// through its work, in which case it stops itself once that completes. The
+/// actors that take this are sync, backups, chart refresh and the scheduled
+/// tasks, so that none of them is cut off before the db is closed.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct StopEvent;
+
+/// Handle a StopEvent for an actor with busy set while its work runs. Once
+/// stopping is set, the actor must stop itself when that work completes.
+pub fn stop_when_idle<A>(name: &str, stopping: &mut bool, busy: bool, ctx: &mut Context<A>) -> bool
+where
+    A: Actor<Context = Context<A>>,
+{
+    if !*stopping {
+        info!("Stopping {} ...", name);
+        *stopping = true;
+    }
+    if !busy {
+        ctx.stop();
+    }
+    busy
+}
+
+/// Stop a background actor, waiting for whatever it's part way through.
+pub async fn stop_actor<A>(addr: &Addr<A>, name: &str)
+where
+    A: Handler<StopEvent>,
+    A::Context: ToEnvelope<A, StopEvent>,
+{
+    loop {
+        match addr.send(StopEvent).await {
+            Ok(true) => {
+                debug!("Waiting for {} to finish ...", name);
+                actix_rt::time::delay_for(STOP_POLL).await;
+            }
+            Ok(false) => break,
+            // Finishing its work stopped it between our asks.
+            Err(MailboxError::Closed) => {
+                debug!("{} stopped", name);
+                break;
+            }
+            Err(e) => {
+                error!("Unable to stop {} -> {:?}", name, e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stop::{stop_actor, stop_when_idle, StopEvent};
+    use actix::prelude::*;
+    use std::time::{Duration, Instant};
+
+    struct Worker {
+        busy: bool,
+        stopping: bool,
+    }
+
+    impl Actor for Worker {
+        type Context = Context<Self>;
+
+        fn started(&mut self, ctx: &mut Self::Context) {
+            // Stands in for a backup or sync that's part way through.
+            ctx.run_later(Duration::from_millis(250), |act, ctx| {
+                act.busy = false;
+                if act.stopping {
+                    ctx.stop();
+                }
+            });
+        }
+    }
+
+    impl Handler<StopEvent> for Worker {
+        type Result = bool;
+
+        fn handle(&mut self, _msg: StopEvent, ctx: &mut Context<Self>) -> bool {
+            stop_when_idle("worker", &mut self.stopping, self.busy, ctx)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_stop_actor() {
+        let idle = Worker {
+            busy: false,
+            stopping: false,
+        }
+        .start();
+        let start = Instant::now();
+        stop_actor(&idle, "idle").await;
+        assert!(start.elapsed() < Duration::from_millis(250));
+
+        // Waits for the work, and stopping once it completes isn't an error.
+        let busy = Worker {
+            busy: true,
+            stopping: false,
+        }
+        .start();
+        let start = Instant::now();
+        stop_actor(&busy, "busy").await;
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        stop_actor(&busy, "busy").await;
+    }
+}
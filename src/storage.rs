@@ -0,0 +1,778 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use mic::prelude::*;
+
+use crate::db::{report_day, report_hour, DbEvent, DbHistoryEvent, DbHourlyEvent, TFMT};
+use crate::sync::{SyncBatch, SyncCursor};
+use crate::tz::SiteTz;
+
+/// Which backend events and reports are kept in, from `MICD_STORAGE`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Sqlite,
+    Postgres,
+    /// Raw readings in segments, the rest in sqlite.
+    Segment,
+    Memory,
+}
+
+impl FromStr for Backend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "sqlite" => Ok(Backend::Sqlite),
+            "postgres" => Ok(Backend::Postgres),
+            "segment" => Ok(Backend::Segment),
+            "memory" => Ok(Backend::Memory),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where events and daily reports are kept. Reports are built from the
+/// events in the same way for every backend, so a backend only needs to
+/// store and fetch them.
+pub trait Storage {
+    /// Store an event. A second event from a meter at the same time is ignored.
+    fn add_datum(&self, datum: Datum, ct: OffsetDateTime, seq: i64) -> Result<(), ()>;
+
+    fn list_meters(&self) -> Result<Vec<String>, ()>;
+
+    /// The names given to meters, by mac. Meters without one are left out.
+    fn get_meter_labels(&self) -> Result<BTreeMap<String, String>, ()>;
+
+    /// Name src, or remove its name with None.
+    fn set_meter_label(&self, src: &str, label: Option<&str>) -> Result<(), ()>;
+
+    /// Events from src at or after min and before max, oldest first.
+    fn get_event_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbEvent>, ()>;
+
+    // The first event at or after min, if any.
+    fn get_next_event_time(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>, ()>;
+
+    fn get_last_event_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()>;
+
+    /// Every daily report for src, oldest first.
+    fn get_history(&self, src: &str) -> Result<Vec<DbHistoryEvent>, ()>;
+
+    fn get_last_history_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()>;
+
+    /// Store a daily report, replacing any existing report for that day.
+    fn put_history(&self, history: &DbHistoryEvent) -> Result<(), ()>;
+
+    /// Remove events from every meter before max.
+    fn purge_older_than(&self, max: &OffsetDateTime) -> Result<(), ()>;
+
+    /// Remove daily reports from every meter before max.
+    fn purge_history_older_than(&self, max: &OffsetDateTime) -> Result<(), ()>;
+
+    /// Add imported readings. Readings at a time src already has one for are
+    /// ignored. Returns how many were added.
+    fn import_events(&self, src: &str, events: &[DbEvent]) -> Result<usize, ()>;
+
+    /// The last sequence stored for each meter.
+    fn get_latest_sequences(&self) -> Result<BTreeMap<String, i64>, ()>;
+
+    /// Hourly rollups for src at or after min and before max, oldest first.
+    fn get_hourly_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbHourlyEvent>, ()>;
+
+    fn get_last_hourly_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()>;
+
+    /// Store an hourly rollup, replacing any existing rollup for that hour.
+    fn put_hourly(&self, hourly: &DbHourlyEvent) -> Result<(), ()>;
+
+    /// Remove hourly rollups from every meter before max.
+    fn purge_hourly_older_than(&self, max: &OffsetDateTime) -> Result<(), ()>;
+
+    /// Write out anything that is only held in memory so far.
+    fn flush(&self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    /// Make sure everything written so far is kept, before we exit.
+    fn checkpoint(&self) -> Result<(), ()> {
+        self.flush()
+    }
+
+    /// Write a consistent copy to path while we keep running.
+    fn backup_to(&self, _path: &Path) -> Result<(), ()> {
+        error!("Backups can only be taken of sqlite storage");
+        Err(())
+    }
+
+    fn get_sync_cursor(&self, _site: &str) -> Result<SyncCursor, ()> {
+        error!("Sync needs sqlite storage");
+        Err(())
+    }
+
+    fn export_sync_batch(&self, _cursor: &SyncCursor, _limit: u32) -> Result<SyncBatch, ()> {
+        error!("Sync needs sqlite storage");
+        Err(())
+    }
+
+    fn apply_sync_batch(&self, _site: &str, _batch: &SyncBatch) -> Result<SyncCursor, ()> {
+        error!("Sync needs sqlite storage");
+        Err(())
+    }
+
+    /// Readings src already has from first to last, and days it has reports
+    /// for from day until last. Days that were gaps don't count.
+    fn count_overlap(
+        &self,
+        src: &str,
+        day: &OffsetDateTime,
+        first: &OffsetDateTime,
+        last: &OffsetDateTime,
+    ) -> Result<(usize, usize), ()> {
+        let existing = self
+            .get_event_range(src, first, &(*last + time::Duration::milliseconds(1)))?
+            .len();
+        let reported = self
+            .get_history(src)?
+            .iter()
+            .filter(|h| h.time >= *day && h.time <= *last && h.count.unwrap_or(1) > 0)
+            .count();
+        Ok((existing, reported))
+    }
+
+    /// Has any meter had a daily report made yet?
+    fn has_reports(&self) -> Result<bool, ()> {
+        for src in self.list_meters()? {
+            if self.get_last_history_time(&src)?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn get_latest_report_date(&self, src: &str, tz: &SiteTz) -> Result<OffsetDateTime, ()> {
+        if let Some(t) = self.get_last_history_time(src)? {
+            return Ok(t);
+        }
+
+        match self.get_next_event_time(src, &OffsetDateTime::unix_epoch())? {
+            // We remove 1 day here to make it the "day before" the first event.
+            Some(first) => Ok(tz.day_start(tz.date_of(first).previous_day())),
+            None => {
+                error!("No data found, unable to generate report data ...?");
+                Err(())
+            }
+        }
+    }
+
+    /// Report on each complete day in the site timezone since the last report.
+    /// Days without readings are recorded with no values, so that they show
+    /// as gaps rather than being skipped.
+    fn extract_report(
+        &self,
+        src: &str,
+        tz: &SiteTz,
+        bands: &[u16],
+        ct: &OffsetDateTime,
+    ) -> Result<OffsetDateTime, OffsetDateTime> {
+        // What is the earliest event date we have recorded? (alt to latest)
+        let latest = self
+            .get_latest_report_date(src, tz)
+            .map_err(|_| OffsetDateTime::unix_epoch())?;
+        let work_start = tz.next_day_start(latest);
+
+        // Don't report past the day of the last event, so that a meter that
+        // has gone away isn't given empty days forever. If it comes back, the
+        // days it was missing are filled in then.
+        let upto = match self.get_last_event_time(src).map_err(|_| work_start)? {
+            Some(last) => tz.day_start(tz.date_of(*ct)).min(tz.next_day_start(last)),
+            None => work_start,
+        };
+
+        info!(
+            "Generating reports between: {:?} -> {:?}",
+            tz.to_local(latest).format(TFMT),
+            tz.to_local(upto).format(TFMT)
+        );
+
+        self.report_days(src, tz, bands, work_start, upto)
+    }
+
+    /// Report again on the days from first to last, once readings for them
+    /// have arrived late. Any days since the last report are reported too,
+    /// so that they aren't skipped.
+    fn rebuild_reports(
+        &self,
+        src: &str,
+        tz: &SiteTz,
+        bands: &[u16],
+        first: &OffsetDateTime,
+        last: &OffsetDateTime,
+        ct: &OffsetDateTime,
+    ) -> Result<OffsetDateTime, OffsetDateTime> {
+        let mut work_start = tz.day_start(tz.date_of(*first));
+        if let Some(t) = self.get_last_history_time(src).map_err(|_| work_start)? {
+            work_start = work_start.min(tz.next_day_start(t));
+        }
+        let upto = tz.day_start(tz.date_of(*ct)).min(tz.next_day_start(*last));
+
+        info!(
+            "Regenerating reports between: {:?} -> {:?}",
+            tz.to_local(work_start).format(TFMT),
+            tz.to_local(upto).format(TFMT)
+        );
+
+        self.report_days(src, tz, bands, work_start, upto)
+    }
+
+    /// Roll up each complete local hour of events since the last rollup.
+    fn extract_hourly(&self, src: &str, tz: &SiteTz, ct: &OffsetDateTime) -> Result<usize, ()> {
+        let work_start = match self.get_last_hourly_time(src)? {
+            Some(t) => t + Duration::from_secs(3600),
+            None => match self.get_next_event_time(src, &OffsetDateTime::unix_epoch())? {
+                Some(first) => tz.hour_start(first),
+                None => return Ok(0),
+            },
+        };
+
+        self.rollup_hours(src, tz, work_start, tz.hour_start(*ct))
+    }
+
+    /// Roll up the hours from first to last again, once readings for them
+    /// have arrived late.
+    fn rebuild_hourly(
+        &self,
+        src: &str,
+        tz: &SiteTz,
+        first: &OffsetDateTime,
+        last: &OffsetDateTime,
+        ct: &OffsetDateTime,
+    ) -> Result<usize, ()> {
+        let upto = tz
+            .hour_start(*ct)
+            .min(tz.hour_start(*last + Duration::from_secs(3600)));
+        self.rollup_hours(src, tz, tz.hour_start(*first), upto)
+    }
+
+    // Roll up each hour with events from work_start until upto, replacing any
+    // rollups already made for them.
+    fn rollup_hours(
+        &self,
+        src: &str,
+        tz: &SiteTz,
+        mut work_start: OffsetDateTime,
+        upto: OffsetDateTime,
+    ) -> Result<usize, ()> {
+        let mut count = 0;
+        while work_start < upto {
+            // Normally an hour on, but this also brings work_start back onto
+            // the local hour, such as after a half hour daylight saving change.
+            let work_end = tz.hour_start(work_start + Duration::from_secs(3600));
+            let data = self.get_event_range(src, &work_start, &work_end)?;
+
+            match report_hour(src, &work_start, &data) {
+                Some(hourly) => {
+                    self.put_hourly(&hourly)?;
+                    count += 1;
+                    work_start = work_end;
+                }
+                // Skip the gap rather than stepping through every empty hour.
+                // Where the offset falls back by half an hour, the hour next
+                // is in can start before work_end, so never go back.
+                None => {
+                    work_start = match self.get_next_event_time(src, &work_end)? {
+                        Some(next) => work_end.max(tz.hour_start(next)),
+                        None => break,
+                    }
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    // Report on each day from work_start until upto, replacing any reports
+    // already made for them.
+    fn report_days(
+        &self,
+        src: &str,
+        tz: &SiteTz,
+        bands: &[u16],
+        mut work_start: OffsetDateTime,
+        upto: OffsetDateTime,
+    ) -> Result<OffsetDateTime, OffsetDateTime> {
+        // Days are not always 24 hours long, so each one is found from the
+        // timezone.
+        while work_start < upto {
+            let work_end = tz.next_day_start(work_start);
+            // select all data from that range
+            let data = self
+                .get_event_range(src, &work_start, &work_end)
+                .map_err(|_| work_start)?;
+
+            let history = report_day(src, &work_start, &work_end, &data, bands);
+            info!(
+                "{:?} -> {} readings, {}% coverage",
+                tz.to_local(work_start).format(TFMT),
+                data.len(),
+                history.coverage.unwrap_or(0)
+            );
+            debug!("report -> {:?}", history);
+
+            // write that as a history event, use work_start as the TS.
+            self.put_history(&history).map_err(|_| work_start)?;
+
+            // Increment to the next day.
+            work_start = work_end;
+        }
+        Ok(work_start)
+    }
+}
+
+#[derive(Default)]
+struct MemInner {
+    meters: BTreeSet<String>,
+    labels: BTreeMap<String, String>,
+    events: BTreeMap<String, BTreeMap<OffsetDateTime, DbEvent>>,
+    hourly: BTreeMap<String, BTreeMap<OffsetDateTime, DbHourlyEvent>>,
+    history: BTreeMap<String, BTreeMap<OffsetDateTime, DbHistoryEvent>>,
+    seqs: BTreeMap<String, i64>,
+}
+
+/// Keeps everything in memory, so nothing is kept once micd exits. Useful
+/// for trying micd out, and to test the reporting without a database.
+#[derive(Default)]
+pub struct MemStorage {
+    inner: Mutex<MemInner>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        MemStorage::default()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, MemInner>, ()> {
+        self.inner.lock().map_err(|e| {
+            error!("memory storage lock error -> {:?}", e);
+            ()
+        })
+    }
+}
+
+impl Storage for MemStorage {
+    fn add_datum(&self, datum: Datum, ct: OffsetDateTime, seq: i64) -> Result<(), ()> {
+        let src = datum.mac_as_string();
+        let (ppm, hum, temp) = datum.data();
+
+        let mut inner = self.lock()?;
+        inner.meters.insert(src.clone());
+        let last = inner.seqs.entry(src.clone()).or_insert(seq);
+        *last = (*last).max(seq);
+        let events = inner.events.entry(src.clone()).or_default();
+        if events.contains_key(&ct) {
+            warn!("ignoring duplicate event for {} at {:?}", src, ct);
+            return Ok(());
+        }
+        events.insert(
+            ct,
+            DbEvent {
+                src,
+                time: ct,
+                temp,
+                ppm,
+                hum,
+            },
+        );
+        Ok(())
+    }
+
+    fn list_meters(&self) -> Result<Vec<String>, ()> {
+        Ok(self.lock()?.meters.iter().cloned().collect())
+    }
+
+    fn get_meter_labels(&self) -> Result<BTreeMap<String, String>, ()> {
+        Ok(self.lock()?.labels.clone())
+    }
+
+    fn set_meter_label(&self, src: &str, label: Option<&str>) -> Result<(), ()> {
+        let mut inner = self.lock()?;
+        inner.meters.insert(src.to_string());
+        match label {
+            Some(label) => inner.labels.insert(src.to_string(), label.to_string()),
+            None => inner.labels.remove(src),
+        };
+        Ok(())
+    }
+
+    fn get_event_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbEvent>, ()> {
+        if min >= max {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .lock()?
+            .events
+            .get(src)
+            .map(|events| events.range(*min..*max).map(|(_, e)| e.clone()).collect())
+            .unwrap_or_default())
+    }
+
+    fn get_next_event_time(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>, ()> {
+        Ok(self
+            .lock()?
+            .events
+            .get(src)
+            .and_then(|events| events.range(*min..).next().map(|(t, _)| *t)))
+    }
+
+    fn get_last_event_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        Ok(self
+            .lock()?
+            .events
+            .get(src)
+            .and_then(|events| events.keys().next_back().copied()))
+    }
+
+    fn get_history(&self, src: &str) -> Result<Vec<DbHistoryEvent>, ()> {
+        Ok(self
+            .lock()?
+            .history
+            .get(src)
+            .map(|history| history.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn get_last_history_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        Ok(self
+            .lock()?
+            .history
+            .get(src)
+            .and_then(|history| history.keys().next_back().copied()))
+    }
+
+    fn put_history(&self, history: &DbHistoryEvent) -> Result<(), ()> {
+        let mut inner = self.lock()?;
+        inner.meters.insert(history.src.clone());
+        inner
+            .history
+            .entry(history.src.clone())
+            .or_default()
+            .insert(history.time, history.clone());
+        Ok(())
+    }
+
+    fn purge_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        self.lock()?.events.values_mut().for_each(|events| {
+            *events = events.split_off(max);
+        });
+        Ok(())
+    }
+
+    fn purge_history_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        self.lock()?.history.values_mut().for_each(|history| {
+            *history = history.split_off(max);
+        });
+        Ok(())
+    }
+
+    fn import_events(&self, src: &str, events: &[DbEvent]) -> Result<usize, ()> {
+        let mut inner = self.lock()?;
+        inner.meters.insert(src.to_string());
+        let stored = inner.events.entry(src.to_string()).or_default();
+        Ok(events
+            .iter()
+            .filter(|e| {
+                if stored.contains_key(&e.time) {
+                    return false;
+                }
+                stored.insert(e.time, (*e).clone());
+                true
+            })
+            .count())
+    }
+
+    fn get_latest_sequences(&self) -> Result<BTreeMap<String, i64>, ()> {
+        Ok(self.lock()?.seqs.clone())
+    }
+
+    fn get_hourly_range(
+        &self,
+        src: &str,
+        min: &OffsetDateTime,
+        max: &OffsetDateTime,
+    ) -> Result<Vec<DbHourlyEvent>, ()> {
+        if min >= max {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .lock()?
+            .hourly
+            .get(src)
+            .map(|hourly| hourly.range(*min..*max).map(|(_, h)| h.clone()).collect())
+            .unwrap_or_default())
+    }
+
+    fn get_last_hourly_time(&self, src: &str) -> Result<Option<OffsetDateTime>, ()> {
+        Ok(self
+            .lock()?
+            .hourly
+            .get(src)
+            .and_then(|hourly| hourly.keys().next_back().copied()))
+    }
+
+    fn put_hourly(&self, hourly: &DbHourlyEvent) -> Result<(), ()> {
+        self.lock()?
+            .hourly
+            .entry(hourly.src.clone())
+            .or_default()
+            .insert(hourly.time, hourly.clone());
+        Ok(())
+    }
+
+    fn purge_hourly_older_than(&self, max: &OffsetDateTime) -> Result<(), ()> {
+        self.lock()?.hourly.values_mut().for_each(|hourly| {
+            *hourly = hourly.split_off(max);
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::db::{DbEvent, TFMT};
+    use crate::storage::{Backend, MemStorage, Storage};
+    use crate::tz::SiteTz;
+    use mic::prelude::*;
+    use std::collections::BTreeMap;
+    use time::OffsetDateTime;
+
+    const SRC: &str = "01:00:00:00:00:00";
+
+    fn ts(s: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(s, TFMT).expect("invalid ts")
+    }
+
+    fn add(store: &impl Storage, ppm: u16, t: &str) {
+        let datum = Datum::from(([1, 0, 0, 0, 0, 0], ppm, 500, 220));
+        store
+            .add_datum(datum, ts(t), 0)
+            .expect("Failed to add data!");
+    }
+
+    /// The same checks, whatever is doing the storing.
+    pub fn check_storage(store: &impl Storage) {
+        // The sample data is written in +1000, which Brisbane always is.
+        let tz: SiteTz = "Australia/Brisbane".parse().unwrap();
+
+        add(store, 900, "2020-04-05 13:02:19+1000");
+        add(store, 1100, "2020-04-05 13:02:49+1000");
+        add(store, 1600, "2020-04-05 14:02:19+1000");
+        // A duplicate is ignored rather than replacing the first.
+        add(store, 2000, "2020-04-05 14:02:19+1000");
+        // Nothing on the 6th.
+        add(store, 500, "2020-04-07 13:00:00+1000");
+
+        assert!(store.list_meters() == Ok(vec![SRC.to_string()]));
+
+        assert!(store.get_meter_labels() == Ok(BTreeMap::new()));
+        store.set_meter_label(SRC, Some("Meeting room")).unwrap();
+        // Labels are kept as more events arrive.
+        add(store, 1100, "2020-04-05 13:02:49+1000");
+        assert!(store.get_meter_labels().unwrap().get(SRC) == Some(&"Meeting room".to_string()));
+        store.set_meter_label(SRC, None).unwrap();
+        assert!(store.get_meter_labels() == Ok(BTreeMap::new()));
+        store.set_meter_label(SRC, Some("Meeting room")).unwrap();
+        let data = store
+            .get_event_range(
+                SRC,
+                &ts("2020-04-05 00:00:00+1000"),
+                &ts("2020-04-06 00:00:00+1000"),
+            )
+            .unwrap();
+        assert!(data.iter().map(|e| e.ppm).collect::<Vec<_>>() == vec![900, 1100, 1600]);
+        assert!(store.get_last_event_time(SRC) == Ok(Some(ts("2020-04-07 13:00:00+1000"))));
+
+        assert!(store.get_latest_report_date(SRC, &tz) == Ok(ts("2020-04-04 00:00:00+1000")));
+        assert!(store.has_reports() == Ok(false));
+        assert!(
+            store.extract_report(
+                SRC,
+                &tz,
+                &[800, 1000, 1500],
+                &ts("2020-04-08 00:00:00+1000")
+            ) == Ok(ts("2020-04-08 00:00:00+1000"))
+        );
+        assert!(store.get_latest_report_date(SRC, &tz) == Ok(ts("2020-04-07 00:00:00+1000")));
+        assert!(store.has_reports() == Ok(true));
+
+        let history = store.get_history(SRC).unwrap();
+        assert!(history.len() == 3);
+
+        let day = &history[0];
+        assert!(day.time == ts("2020-04-05 00:00:00+1000"));
+        assert!(day.count == Some(3));
+        assert!(day.ppm_min == Some(900));
+        assert!(day.ppm_max == Some(1600));
+        assert!(day.ppm_avg == Some(1200));
+        assert!(day.temp_avg == Some(220));
+        assert!(day.ppm_peak == Some(ts("2020-04-05 14:02:19+1000")));
+        // Each reading counts until the next, for at most five minutes.
+        assert!(day.minutes_above.get(&800) == Some(&11));
+        assert!(day.minutes_above.get(&1000) == Some(&10));
+        assert!(day.minutes_above.get(&1500) == Some(&5));
+
+        // The empty day is a gap.
+        let day = &history[1];
+        assert!(day.time == ts("2020-04-06 00:00:00+1000"));
+        assert!(day.count == Some(0));
+        assert!(day.ppm_avg.is_none());
+        assert!(day.ppm_peak.is_none());
+        assert!(day.minutes_above.is_empty());
+
+        assert!(history[2].ppm_avg == Some(500));
+
+        // Purging removes events, but not the reports made from them.
+        store
+            .purge_older_than(&ts("2020-04-07 00:00:00+1000"))
+            .unwrap();
+        assert!(
+            store.get_next_event_time(SRC, &OffsetDateTime::unix_epoch())
+                == Ok(Some(ts("2020-04-07 13:00:00+1000")))
+        );
+        assert!(store.get_history(SRC).unwrap().len() == 3);
+        assert!(store.get_latest_sequences().unwrap().get(SRC) == Some(&0));
+
+        // Hours are rolled up from what's left.
+        let ct = ts("2020-04-08 00:00:00+1000");
+        assert!(store.extract_hourly(SRC, &tz, &ct) == Ok(1));
+
+        let import = |ppm: u16, t: &str| DbEvent {
+            src: SRC.to_string(),
+            time: ts(t),
+            temp: 220,
+            ppm,
+            hum: 500,
+        };
+        let events = vec![
+            import(600, "2020-04-07 13:00:00+1000"),
+            import(700, "2020-04-07 14:00:00+1000"),
+        ];
+        let (first, last) = (events[0].time, events[1].time);
+        assert!(
+            store.count_overlap(SRC, &ts("2020-04-07 00:00:00+1000"), &first, &last) == Ok((1, 1))
+        );
+        // Only the reading we didn't have is added.
+        assert!(store.import_events(SRC, &events) == Ok(1));
+        assert!(store.rebuild_hourly(SRC, &tz, &first, &last, &ct) == Ok(2));
+
+        let day = || {
+            store
+                .get_hourly_range(SRC, &ts("2020-04-07 00:00:00+1000"), &ct)
+                .unwrap()
+        };
+        let hourly = day();
+        assert!(hourly.len() == 2);
+        assert!(hourly[0].time == first);
+        assert!(hourly[0].ppm_avg == 500);
+        assert!(hourly[1].ppm_avg == 700);
+        assert!(store.get_last_hourly_time(SRC) == Ok(Some(last)));
+
+        store.purge_hourly_older_than(&last).unwrap();
+        assert!(day().len() == 1);
+        store
+            .purge_history_older_than(&ts("2020-04-07 00:00:00+1000"))
+            .unwrap();
+        assert!(store.get_history(SRC).unwrap().len() == 1);
+    }
+
+    #[test]
+    fn test_rebuild_reports() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let tz: SiteTz = "Australia/Brisbane".parse().unwrap();
+        let store = MemStorage::new();
+        let ct = ts("2020-04-12 09:00:00+1000");
+
+        add(&store, 900, "2020-04-09 13:00:00+1000");
+        add(&store, 900, "2020-04-11 13:00:00+1000");
+        assert!(store.extract_report(SRC, &tz, &[], &ct) == Ok(ts("2020-04-12 00:00:00+1000")));
+        assert!(store.get_history(SRC).unwrap().len() == 3);
+
+        // Older readings arrive, with a few from today.
+        add(&store, 500, "2020-04-05 13:00:00+1000");
+        add(&store, 700, "2020-04-09 14:00:00+1000");
+        add(&store, 700, "2020-04-12 08:00:00+1000");
+        assert!(
+            store.rebuild_reports(
+                SRC,
+                &tz,
+                &[],
+                &ts("2020-04-05 13:00:00+1000"),
+                &ts("2020-04-12 08:00:00+1000"),
+                &ct
+            ) == Ok(ts("2020-04-12 00:00:00+1000"))
+        );
+        let history = store.get_history(SRC).unwrap();
+        assert!(history.len() == 7);
+        assert!(history[0].time == ts("2020-04-05 00:00:00+1000"));
+        assert!(history[0].ppm_avg == Some(500));
+        assert!(history[1].count == Some(0));
+        assert!(history[4].ppm_avg == Some(800));
+        assert!(history[6].time == ts("2020-04-11 00:00:00+1000"));
+
+        // Readings after the last report fill in the days before them.
+        add(&store, 600, "2020-04-14 13:00:00+1000");
+        let ct = ts("2020-04-16 00:00:00+1000");
+        assert!(
+            store.rebuild_reports(
+                SRC,
+                &tz,
+                &[],
+                &ts("2020-04-14 13:00:00+1000"),
+                &ts("2020-04-14 13:00:00+1000"),
+                &ct
+            ) == Ok(ts("2020-04-15 00:00:00+1000"))
+        );
+        let history = store.get_history(SRC).unwrap();
+        assert!(history.len() == 10);
+        assert!(history[7].ppm_avg == Some(700));
+        assert!(history[8].count == Some(0));
+        assert!(history[9].ppm_avg == Some(600));
+    }
+
+    #[test]
+    fn test_storage_backend() {
+        assert!("sqlite".parse() == Ok(Backend::Sqlite));
+        assert!("postgres".parse() == Ok(Backend::Postgres));
+        assert!("segment".parse() == Ok(Backend::Segment));
+        assert!("memory".parse() == Ok(Backend::Memory));
+        assert!("mysql".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn test_mem_storage() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        check_storage(&MemStorage::new());
+    }
+}
@@ -0,0 +1,250 @@
+use actix::prelude::*;
+use actix_web::client::Client;
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::db;
+use crate::stop::{stop_when_idle, StopEvent};
+
+// How many events we push in a single request.
+const SYNC_BATCH_SIZE: u32 = 1000;
+const SYNC_TIMEOUT: u64 = 30;
+
+/// How far a central instance has received data from a site. Events are
+/// ordered by the site's change sequence, history by t. Times are utc
+/// milliseconds. The sequence belongs to the site database db_id, so a
+/// replaced or restored database starts over.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncCursor {
+    pub event_change: Option<i64>,
+    pub history_t: Option<i64>,
+    pub db_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncEvent {
+    pub mac: String,
+    pub ts: i64,
+    pub seq: i64,
+    pub temp: u16,
+    pub ppm: u16,
+    pub hum: u16,
+    // Where the event is in the site's change sequence.
+    pub change: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncHistory {
+    pub mac: String,
+    pub t: i64,
+    pub count: Option<u32>,
+    pub coverage: Option<u8>,
+    pub temp_max: Option<u16>,
+    pub temp_min: Option<u16>,
+    pub temp_avg: Option<u16>,
+    pub ppm_max: Option<u16>,
+    pub ppm_min: Option<u16>,
+    pub ppm_avg: Option<u16>,
+    pub hum_max: Option<u16>,
+    pub hum_min: Option<u16>,
+    pub hum_avg: Option<u16>,
+    pub temp_p50: Option<u16>,
+    pub temp_p95: Option<u16>,
+    pub temp_stddev: Option<u16>,
+    pub ppm_p50: Option<u16>,
+    pub ppm_p95: Option<u16>,
+    pub ppm_stddev: Option<u16>,
+    pub hum_p50: Option<u16>,
+    pub hum_p95: Option<u16>,
+    pub hum_stddev: Option<u16>,
+    pub ppm_peak_t: Option<i64>,
+    pub minutes_above: BTreeMap<u16, u32>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncBatch {
+    pub events: Vec<SyncEvent>,
+    pub history: Vec<SyncHistory>,
+    // The site database the batch was exported from.
+    pub db_id: Option<String>,
+}
+
+impl SyncBatch {
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty() && self.history.is_empty()
+    }
+}
+
+// Don't leak how much of the key matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Check that a sync request carries our shared key. If no key is configured
+/// then sync is disabled and everything is refused.
+pub fn authorised(req: &HttpRequest, key: Option<&str>) -> bool {
+    let key = match key {
+        Some(k) => k,
+        None => return false,
+    };
+
+    req.headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            let mut parts = v.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("Bearer"), Some(token)) => Some(token),
+                _ => None,
+            }
+        })
+        .map(|v| constant_time_eq(v.as_bytes(), key.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Pushes our new events and history to a central instance.
+pub struct SyncActor {
+    db_addr: Addr<db::DbActor>,
+    url: String,
+    site: String,
+    key: String,
+    interval: Duration,
+    running: bool,
+    stopping: bool,
+}
+
+impl SyncActor {
+    pub fn new(
+        db_addr: Addr<db::DbActor>,
+        url: String,
+        site: String,
+        key: String,
+        interval: Duration,
+    ) -> Self {
+        SyncActor {
+            db_addr,
+            url: url.trim_end_matches('/').to_string(),
+            site,
+            key,
+            interval,
+            running: false,
+            stopping: false,
+        }
+    }
+
+    fn sync(&mut self, ctx: &mut Context<Self>) {
+        if self.running {
+            info!("Previous sync is still running, skipping");
+            return;
+        }
+        self.running = true;
+
+        let fut = sync_once(
+            self.db_addr.clone(),
+            self.url.clone(),
+            self.site.clone(),
+            self.key.clone(),
+        );
+
+        ctx.spawn(fut.into_actor(self).map(|r, act, ctx| {
+            match r {
+                Ok(count) => info!("Synced {} rows to {}", count, act.url),
+                Err(_) => error!("Failed to sync to {}, will resume later", act.url),
+            };
+            act.running = false;
+            if act.stopping {
+                ctx.stop();
+            }
+        }));
+    }
+}
+
+async fn sync_once(
+    db_addr: Addr<db::DbActor>,
+    url: String,
+    site: String,
+    key: String,
+) -> Result<usize, ()> {
+    let client = Client::build()
+        .timeout(Duration::from_secs(SYNC_TIMEOUT))
+        .bearer_auth(&key)
+        .finish();
+
+    // Always ask the central server where we are up to, so that we resume
+    // correctly after an outage on either side.
+    let mut cursor: SyncCursor = client
+        .get(format!("{}/sync/cursor/{}", url, site))
+        .send()
+        .await
+        .map_err(|e| error!("sync cursor request error -> {:?}", e))?
+        .json()
+        .await
+        .map_err(|e| error!("sync cursor response error -> {:?}", e))?;
+
+    let mut count = 0;
+    loop {
+        let batch = db_addr
+            .send(db::DbSyncExport {
+                cursor: cursor.clone(),
+                limit: SYNC_BATCH_SIZE,
+            })
+            .await
+            .map_err(|_| error!("db unable to complete!"))??;
+
+        // A short batch means we have caught up, and it carries the history.
+        let done = batch.events.len() < SYNC_BATCH_SIZE as usize;
+
+        if !batch.is_empty() {
+            let sent = batch.events.len() + batch.history.len();
+            cursor = client
+                .post(format!("{}/sync/push/{}", url, site))
+                .send_json(&batch)
+                .await
+                .map_err(|e| error!("sync push request error -> {:?}", e))?
+                .json()
+                .await
+                .map_err(|e| error!("sync push response error -> {:?}", e))?;
+            count += sent;
+        }
+
+        if done {
+            break;
+        }
+    }
+    Ok(count)
+}
+
+impl Actor for SyncActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("Started sync to {} as {} ...", self.url, self.site);
+        self.sync(ctx);
+        ctx.run_interval(self.interval, move |act, ctx| {
+            act.sync(ctx);
+        });
+    }
+}
+
+impl Handler<StopEvent> for SyncActor {
+    type Result = bool;
+
+    fn handle(&mut self, _msg: StopEvent, ctx: &mut Context<Self>) -> bool {
+        stop_when_idle("sync", &mut self.stopping, self.running, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::constant_time_eq;
+
+    #[test]
+    fn test_sync_key_compare() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
@@ -0,0 +1,163 @@
+use chrono::{NaiveDate, NaiveDateTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use std::fs;
+use std::str::FromStr;
+use time::{Date, OffsetDateTime, UtcOffset};
+
+/// The IANA timezone of the site the meters are in. Everything is stored in
+/// utc, and this decides which local day a reading belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SiteTz(Tz);
+
+impl FromStr for SiteTz {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        name.parse::<Tz>().map(SiteTz).map_err(|e| {
+            error!("Unknown timezone {:?} -> {:?}", name, e);
+            ()
+        })
+    }
+}
+
+impl SiteTz {
+    /// The zone the host is set to, from /etc/timezone or the zone file that
+    /// /etc/localtime links to.
+    pub fn host() -> Option<Self> {
+        fs::read_to_string("/etc/timezone")
+            .ok()
+            .and_then(|name| name.trim().parse::<Tz>().ok())
+            .or_else(|| {
+                fs::read_link("/etc/localtime").ok().and_then(|path| {
+                    path.to_string_lossy()
+                        .rsplit("zoneinfo/")
+                        .next()
+                        .and_then(|name| name.parse::<Tz>().ok())
+                })
+            })
+            .map(SiteTz)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    /// The offset from utc in effect at this instant.
+    pub fn offset_at(&self, t: OffsetDateTime) -> UtcOffset {
+        let naive = NaiveDateTime::from_timestamp(t.timestamp(), 0);
+        UtcOffset::seconds(
+            self.0
+                .offset_from_utc_datetime(&naive)
+                .fix()
+                .local_minus_utc(),
+        )
+    }
+
+    pub fn to_local(&self, t: OffsetDateTime) -> OffsetDateTime {
+        t.to_offset(self.offset_at(t))
+    }
+
+    /// The local day this instant falls on.
+    pub fn date_of(&self, t: OffsetDateTime) -> Date {
+        self.to_local(t).date()
+    }
+
+    /// The instant a local day begins. This is normally midnight, but days
+    /// are 23 or 25 hours long across daylight saving changes.
+    pub fn day_start(&self, date: Date) -> OffsetDateTime {
+        let naive = NaiveDate::from_ymd(date.year(), date.month() as u32, date.day() as u32)
+            .and_hms(0, 0, 0);
+        let ts = match self.0.from_local_datetime(&naive).earliest() {
+            Some(t) => t.timestamp(),
+            None => {
+                // Midnight was skipped, so the day starts when the clocks changed,
+                // which is midnight in the offset from before the change.
+                let before = self.offset_at(OffsetDateTime::from_unix_timestamp(
+                    naive.timestamp() - 86400,
+                ));
+                naive.timestamp() - before.as_seconds() as i64
+            }
+        };
+        OffsetDateTime::from_unix_timestamp(ts)
+    }
+
+    /// The instant a local time refers to. Times that are repeated when the
+    /// clocks go back are taken as the first, and skipped times have none.
+    pub fn instant_of(&self, naive: &NaiveDateTime) -> Option<OffsetDateTime> {
+        self.0
+            .from_local_datetime(naive)
+            .earliest()
+            .map(|t| OffsetDateTime::from_unix_timestamp(t.timestamp()))
+    }
+
+    /// The instant the local hour that t is in began. This isn't on a utc
+    /// hour where the offset has half or quarter hours.
+    pub fn hour_start(&self, t: OffsetDateTime) -> OffsetDateTime {
+        let offset = self.offset_at(t).as_seconds() as i64;
+        let local = t.timestamp() + offset;
+        OffsetDateTime::from_unix_timestamp(local - local.rem_euclid(3600) - offset)
+    }
+
+    /// The start of the local day following the one that t is in.
+    pub fn next_day_start(&self, t: OffsetDateTime) -> OffsetDateTime {
+        self.day_start(self.date_of(t).next_day())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::TFMT;
+    use crate::tz::SiteTz;
+    use chrono::NaiveDateTime;
+    use time::{Date, OffsetDateTime, UtcOffset};
+
+    fn ts(s: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(s, TFMT).expect("invalid ts")
+    }
+
+    #[test]
+    fn test_tz_day_boundaries() {
+        let tz: SiteTz = "Australia/Sydney".parse().unwrap();
+        assert!("Not/AZone".parse::<SiteTz>().is_err());
+
+        // Daylight saving ends on 2020-04-05, so that day is 25 hours long.
+        let day = Date::try_from_ymd(2020, 4, 5).unwrap();
+        assert!(tz.day_start(day) == ts("2020-04-05 00:00:00+1100"));
+        assert!(
+            tz.next_day_start(ts("2020-04-05 23:30:00+1000")) == ts("2020-04-06 00:00:00+1000")
+        );
+        assert!(tz.date_of(ts("2020-04-05 00:30:00+1100")) == day);
+        assert!(tz.date_of(ts("2020-04-05 23:30:00+1000")) == day);
+        assert!(tz.offset_at(ts("2020-04-05 12:00:00+1000")) == UtcOffset::hours(10));
+
+        // And begins on 2020-10-04, which is 23 hours long.
+        let day = Date::try_from_ymd(2020, 10, 4).unwrap();
+        assert!(tz.day_start(day) == ts("2020-10-04 00:00:00+1000"));
+        assert!(tz.day_start(day.next_day()) == ts("2020-10-05 00:00:00+1100"));
+
+        let local = |s: &str| tz.instant_of(&NaiveDateTime::parse_from_str(s, "%F %T").unwrap());
+        assert!(local("2020-04-05 02:30:00") == Some(ts("2020-04-05 02:30:00+1100")));
+        assert!(local("2020-10-04 02:30:00").is_none());
+    }
+
+    #[test]
+    fn test_tz_hour_start() {
+        let tz: SiteTz = "Asia/Kolkata".parse().unwrap();
+        assert!(tz.hour_start(ts("2020-04-05 13:02:19+0530")) == ts("2020-04-05 13:00:00+0530"));
+        assert!(tz.hour_start(ts("2020-04-05 13:00:00+0530")) == ts("2020-04-05 13:00:00+0530"));
+        let tz: SiteTz = "Australia/Adelaide".parse().unwrap();
+        assert!(tz.hour_start(ts("2020-04-05 01:59:59+1030")) == ts("2020-04-05 01:00:00+1030"));
+        // After daylight saving ends.
+        assert!(tz.hour_start(ts("2020-04-05 02:10:00+0930")) == ts("2020-04-05 02:00:00+0930"));
+        let tz: SiteTz = "UTC".parse().unwrap();
+        assert!(tz.hour_start(ts("2020-04-05 13:59:59+0000")) == ts("2020-04-05 13:00:00+0000"));
+    }
+
+    #[test]
+    fn test_tz_skipped_midnight() {
+        // Santiago moves its clocks forward at midnight, so 2020-09-06 starts at 01:00.
+        let tz: SiteTz = "America/Santiago".parse().unwrap();
+        let day = Date::try_from_ymd(2020, 9, 6).unwrap();
+        assert!(tz.day_start(day) == ts("2020-09-06 01:00:00-0300"));
+    }
+}